@@ -9,15 +9,21 @@
 //! causes a delay of about 50 seconds (10 observations at 5 seconds each). In
 //! addition, the last few spans may never be exported.
 
-use metrics_tracing_example::{init_metrics, init_tracing, run_observations};
+use metrics_tracing_example::{LongSpanLayer, TracingBuilder, init_metrics, run_observations};
 use std::{collections::VecDeque, time::Duration};
 use tokio::{select, sync::mpsc};
 use tracing::info;
 
 #[tokio::main]
 async fn main() {
-    // Set up the tracing.
-    let _provider = init_tracing();
+    // Set up the tracing. The long span detector will catch us holding on to
+    // spans below, and warn about it. Spans are exported the moment they
+    // close, so any delay you see in the viewer is ours, not the batch
+    // processor's.
+    let _provider = TracingBuilder::new()
+        .with_long_spans(LongSpanLayer::new(Duration::from_secs(20)))
+        .with_immediate_export()
+        .init();
     // Set up a prometheus metrics exporter on port 9000
     init_metrics(None);
 
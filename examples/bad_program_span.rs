@@ -1,12 +1,17 @@
-use metrics_tracing_example::{init_metrics, init_tracing, run_observations};
+use metrics_tracing_example::{LongSpanLayer, TracingBuilder, init_metrics, run_observations};
 use std::time::Duration;
 use tokio::{select, sync::mpsc};
 use tracing::{info, info_span};
 
 #[tokio::main]
 async fn main() {
-    // Set up the tracing.
-    let _provider = init_tracing();
+    // Set up the tracing. The long span detector will catch the forever span
+    // below, and warn about it. Spans are exported the moment they close, so
+    // the viewer shows exactly which spans never do.
+    let _provider = TracingBuilder::new()
+        .with_long_spans(LongSpanLayer::new(Duration::from_secs(20)))
+        .with_immediate_export()
+        .init();
     // Set up a prometheus metrics exporter on port 9000
     init_metrics(None);
 
@@ -0,0 +1,64 @@
+//! Watch the pipeline, and its dashboards, fail on purpose.
+//!
+//! ```sh
+//! RUST_LOG=warn cargo run --example chaos_monkey
+//! curl localhost:9000/
+//! ```
+//!
+//! A `Chaos` actor sits between a `SyntheticMonitor` and the stats
+//! processor. It holds one in five observations back for two seconds, drops
+//! one in ten, and panics, eventually. Every fault is a `warn!` event in the
+//! observation's span, and a tick on `my_cute_app.chaos_faults`.
+//!
+//! Watch the stats go quiet during a delay, and the observations counter
+//! keep climbing past what the stats saw. When the chaos actor panics, its
+//! channels close, and the pipeline winds down around it: the stats
+//! processor drains and exits, and the monitor stops, as its receiver is
+//! gone. The seed is fixed, so every run fails the same way.
+
+use metrics_tracing_example::{
+    Chaos, LoadPattern, ShutdownCoordinator, SyntheticMonitor, SysStats, TracingBuilder,
+    init_metrics,
+};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let provider = TracingBuilder::new().init();
+    init_metrics(None);
+    let shutdown = CancellationToken::new();
+
+    let pattern = LoadPattern::Sine {
+        min: 20.0,
+        max: 80.0,
+        period: Duration::from_secs(60),
+    };
+    let (tx, rx) = mpsc::channel(2);
+    let monitor = SyntheticMonitor::new(pattern, Duration::from_secs(1), tx)
+        .with_cpus(4)
+        .with_shutdown(shutdown.clone())
+        .spawn();
+
+    let (chaos_tx, chaos_rx) = mpsc::channel(2);
+    let chaos = Chaos::new(rx, chaos_tx)
+        .with_delay(0.2, Duration::from_secs(2))
+        .with_drop(0.1)
+        .with_panic(0.02)
+        .with_seed(42)
+        .spawn();
+
+    let stats = SysStats::new(chaos_rx, None).with_window(3).spawn();
+
+    // The coordinator reports the panic, and still waits for the rest, and
+    // exports their spans.
+    ShutdownCoordinator::new(shutdown)
+        .with_monitor(monitor)
+        .with_stats(chaos)
+        .with_stats(stats)
+        .with_tracing(provider)
+        .shutdown_on(tokio::signal::ctrl_c())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! An application that serves the pipeline's endpoints from its own HTTP
+//! server, next to its own routes.
+//!
+//! ```sh
+//! cargo run --example embedded_router --features axum
+//! curl localhost:8080/
+//! curl localhost:8080/metrics
+//! curl localhost:8080/healthz
+//! curl localhost:8080/stats
+//! ```
+
+use axum::{Router, routing::get};
+use metrics_tracing_example::{
+    Health, LiveGaugeCheck, PipelineBuilder, RouterBuilder, TracingBuilder, init_metrics_recorder,
+};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let provider = TracingBuilder::new().with_panic_hook().init();
+
+    // Install the recorder without its own listener. We'll serve it.
+    let metrics = init_metrics_recorder();
+    // With the handle, we can also check the live observations gauge adds up.
+    LiveGaugeCheck::new(metrics.clone(), Duration::from_secs(60)).spawn();
+
+    let every = Duration::from_secs(1);
+    let health = Health::new(every * 3);
+    let pipeline = PipelineBuilder::new(every)
+        .with_health(health.clone())
+        .spawn()?;
+
+    let app = Router::new()
+        .route("/", get(|| async { "hello from the embedding app\n" }))
+        .merge(
+            RouterBuilder::new()
+                .with_metrics(metrics)
+                .with_health(health)
+                .with_stats(pipeline.subscribe_stats())
+                .build(),
+        );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:8080").await?;
+    tracing::info!(addr = %listener.local_addr()?, "serving");
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+
+    pipeline.shutdown().await?;
+    provider.shutdown().map_err(Into::into)
+}
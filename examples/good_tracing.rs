@@ -1,40 +1,104 @@
-use metrics_tracing_example::{init_metrics, init_tracing, run_observations};
+use metrics_tracing_example::{
+    EventMetricsLayer, Health, PipelineBuilder, SpanCountLayer, SpanDurationLayer, TracingBuilder,
+    baggage, fields, init_metrics, serve_health,
+};
+use opentelemetry::KeyValue;
 use std::time::Duration;
 use tokio::{select, sync::mpsc};
-use tracing::info;
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    // Set up the tracing.
-    let provider = init_tracing();
+    // Set up the tracing. The panic hook makes sure that actor panics are
+    // recorded as events, rather than only printed to stderr. The span
+    // counts show up as the `my_cute_app.spans_open` gauge. If you hold on
+    // to observations (or their spans) somewhere, you'll see it grow. The
+    // span durations show up as `my_cute_app.span_duration_seconds`. And
+    // the `finished cpu stats` events emitted by the stats processor are
+    // turned into metrics too, without a single `histogram!` call.
+    let provider = TracingBuilder::new()
+        .with_panic_hook()
+        .with_span_counts(SpanCountLayer::new())
+        .with_span_durations(SpanDurationLayer::new())
+        .with_event_metrics(
+            EventMetricsLayer::new()
+                .with_counter("finished cpu stats", "my_cute_app.stats_computed")
+                .with_histogram(
+                    "finished cpu stats",
+                    fields::stats::AVERAGE_USAGE,
+                    "my_cute_app.average_usage",
+                ),
+        )
+        .init();
     // Set up a prometheus metrics exporter on port 9000
     init_metrics(None);
 
     // We want the observations to be sent to us over a channel.
     let (tx, mut rx) = mpsc::channel(2);
 
-    // We'll run the observations every 5 seconds
-    let jh = run_observations(Duration::from_secs(5), Some(tx));
-    tokio::pin!(jh);
+    // We'll run the observations every 5 seconds, and consider the pipeline
+    // unhealthy if an actor goes 3 intervals without producing output.
+    let every = Duration::from_secs(5);
+    let health = Health::new(every * 3);
+
+    // Serve `GET /healthz` on port 9001
+    let _health_server = serve_health(health.clone(), 9001).await?;
+
+    // Tag every observation with an ID for this run. The ID travels with each
+    // observation's span, as OTEL baggage, through every actor it visits.
+    let mut jh = PipelineBuilder::new(every)
+        .with_baggage([KeyValue::new(baggage::RUN_ID, baggage::new_run_id())])
+        // Link each observation's trace to the previous one's, so we can walk
+        // the chain of samples in Jaeger.
+        .with_span_links()
+        .with_outbound(tx)
+        .with_health(health)
+        .with_watchdog(every * 2)
+        .spawn()?;
 
     let ctrl_c = tokio::signal::ctrl_c();
     tokio::pin!(ctrl_c);
 
+    let mut shutting_down = false;
+    let mut flushed = false;
+
     // The loop select here will run until the observation task exits.
     loop {
         select! {
-            _ = &mut ctrl_c => {
+            _ = &mut ctrl_c, if !shutting_down => {
                 info!("Received Ctrl-C, shutting down");
-                break;
+                // Stop the monitor, and let the stats processor drain any
+                // observations that are still in flight. We keep receiving
+                // until the pipeline exits, so that nothing is left stuck in
+                // our channel.
+                jh.trigger_shutdown();
+                shutting_down = true;
             }
             _ = &mut jh => {
                 info!("Observation task exited");
                 break;
             }
             Some(obs) = rx.recv() => {
+                // The baggage was attached by the monitor. We never see the
+                // context it was attached with, only the observation's span.
+                let run_id = baggage::baggage_value(obs.span(), baggage::RUN_ID);
                 obs.span().in_scope(|| {
-                    info!("Received observation in main");
+                    info!(run_id = run_id.as_ref().map(|id| id.as_str()), "Received observation in main");
                 });
+                // Dropping the observation closes its span.
+                drop(obs);
+
+                // Flush the first trace, so it shows up in the viewer right
+                // away, instead of after the batch processor's 5 second
+                // delay. Flushing blocks until the spans are exported, so it
+                // runs off the runtime's worker threads.
+                if !flushed {
+                    flushed = true;
+                    let provider = provider.clone();
+                    if let Err(error) = tokio::task::spawn_blocking(move || provider.flush()).await? {
+                        warn!(%error, "failed to flush spans");
+                    }
+                }
             },
         }
     }
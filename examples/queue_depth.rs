@@ -0,0 +1,116 @@
+//! Observe something other than CPUs: the depth of a work queue.
+//!
+//! ```sh
+//! cargo run --example queue_depth
+//! ```
+//!
+//! The observations carry a `QueueDepth` instead of CPU stats. Everything
+//! else is the same machinery as the CPU pipeline: each observation has its
+//! own span, travels over a channel, and ends up in a sink, run by a
+//! `SinkDriver`, which keeps a sliding `Window` of them.
+
+use metrics_tracing_example::{
+    Observation, ObservationData, ObservationSink, ShutdownCoordinator, SinkDriver, TracingBuilder,
+    Window,
+};
+use std::{convert::Infallible, time::Duration};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, info_span, warn};
+
+/// The number of jobs waiting in the queue.
+#[derive(Debug)]
+struct QueueDepth(usize);
+
+impl ObservationData for QueueDepth {
+    const KIND: &'static str = "queue_depth";
+}
+
+/// Warns when the queue's average depth over the window is too deep.
+struct DepthAlert {
+    window: Window<usize>,
+    threshold: f64,
+}
+
+impl ObservationSink<QueueDepth> for DepthAlert {
+    type Error = Infallible;
+
+    fn name(&self) -> &'static str {
+        "depth_alert"
+    }
+
+    async fn handle(&mut self, obs: Observation<QueueDepth>) -> Result<(), Self::Error> {
+        self.window.push(obs.data().0);
+        let average = self.window.iter().sum::<usize>() as f64 / self.window.len() as f64;
+        if average > self.threshold {
+            warn!(average, threshold = self.threshold, "queue is backing up");
+        } else {
+            info!(average, depth = obs.data().0, "queue depth");
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let provider = TracingBuilder::new().init();
+    let shutdown = CancellationToken::new();
+
+    // A work queue, with a producer that speeds up and slows down, and a
+    // worker that takes 100ms per job.
+    let (jobs, mut queue) = mpsc::channel::<u64>(64);
+    tokio::spawn(async move {
+        while queue.recv().await.is_some() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+    let producer = jobs.clone();
+    tokio::spawn(async move {
+        for job in 0.. {
+            let pause = if (job / 50) % 2 == 0 { 70 } else { 150 };
+            tokio::time::sleep(Duration::from_millis(pause)).await;
+            if producer.send(job).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // The monitor: one observation of the queue's depth every half second,
+    // each with its own span.
+    let (tx, rx) = mpsc::channel(2);
+    let monitor = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(500));
+            while shutdown
+                .run_until_cancelled(interval.tick())
+                .await
+                .is_some()
+            {
+                let depth = jobs.max_capacity() - jobs.capacity();
+                let span = info_span!("Observation", kind = QueueDepth::KIND);
+                let obs = Observation::from_data(QueueDepth(depth), span);
+                if tx.send(obs).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    let alert = DepthAlert {
+        window: Window::new(10),
+        threshold: 16.0,
+    };
+    let sink = SinkDriver::new(alert, rx).spawn();
+
+    // Run until ctrl-c, then stop the monitor first. The sink exits once
+    // the monitor has, and the channel between them closed. The spans go
+    // last, once nothing is left to make them.
+    ShutdownCoordinator::new(shutdown)
+        .with_monitor(monitor)
+        .with_sink("depth_alert", sink)
+        .with_tracing(provider)
+        .shutdown_on(tokio::signal::ctrl_c())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,29 @@
+//! Watch the span tree of the pipeline, live in the terminal.
+//!
+//! ```sh
+//! cargo run --example span_tree
+//! ```
+//!
+//! Console logging is switched off, because it would draw over the tree.
+//! Set `OTEL_FILTER` to keep exporting spans to your collector.
+
+use metrics_tracing_example::{PipelineBuilder, SpanTreeLayer, TracingBuilder, init_metrics};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let provider = TracingBuilder::new()
+        .with_log_filter("off")
+        .with_span_tree(SpanTreeLayer::new())
+        .init();
+    init_metrics(None);
+
+    // Observe every second, so there's always something to watch.
+    let pipeline = PipelineBuilder::new(Duration::from_secs(1)).spawn()?;
+
+    // Run until ctrl-c.
+    tokio::signal::ctrl_c().await?;
+
+    pipeline.shutdown().await?;
+    provider.shutdown().map_err(Into::into)
+}
@@ -0,0 +1,64 @@
+//! Demo the stats, alerts, and dashboards on made-up load, without stressing
+//! the machine.
+//!
+//! ```sh
+//! cargo run --example synthetic_load
+//! curl localhost:9000/
+//! ```
+//!
+//! A `SyntheticMonitor` stands in for the `SysMonitor`: four CPUs idle at
+//! 20%, and spike to 95% for five seconds every thirty. The spikes land on
+//! cue, every run, so the alert below fires on cue too.
+
+use metrics_tracing_example::{
+    LoadPattern, ShutdownCoordinator, SyntheticMonitor, SysStats, TracingBuilder, init_metrics,
+};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// Warn when the window's average usage is above this percentage.
+const ALERT_THRESHOLD: f64 = 60.0;
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let provider = TracingBuilder::new().init();
+    init_metrics(None);
+    let shutdown = CancellationToken::new();
+
+    let pattern = LoadPattern::Spike {
+        base: 20.0,
+        peak: 95.0,
+        every: Duration::from_secs(30),
+        duration: Duration::from_secs(5),
+    };
+    let (tx, rx) = mpsc::channel(2);
+    let monitor = SyntheticMonitor::new(pattern, Duration::from_secs(1), tx)
+        .with_cpus(4)
+        .with_shutdown(shutdown.clone())
+        .spawn();
+
+    let stats = SysStats::new(rx, None).with_window(3);
+    let mut reports = stats.subscribe();
+    let stats = stats.spawn();
+
+    // The alert: a stand-in for the rule you'd write in your alert manager.
+    let alert = tokio::spawn(async move {
+        while reports.changed().await.is_ok() {
+            let average = reports.borrow_and_update().average_usage;
+            if average > ALERT_THRESHOLD {
+                warn!(average, threshold = ALERT_THRESHOLD, "cpu usage is high");
+            }
+        }
+    });
+
+    ShutdownCoordinator::new(shutdown)
+        .with_monitor(monitor)
+        .with_stats(stats)
+        .with_sink("alert", alert)
+        .with_tracing(provider)
+        .shutdown_on(tokio::signal::ctrl_c())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,35 @@
+//! A live terminal dashboard, built from the stats [`watch`] channel.
+//!
+//! ```sh
+//! cargo run --example tui_dashboard --features tui
+//! ```
+//!
+//! Console logging is switched off, because it would draw over the
+//! dashboard. Set `OTEL_FILTER` to keep exporting spans to your collector.
+//!
+//! [`watch`]: tokio::sync::watch
+
+use metrics_tracing_example::{PipelineBuilder, TracingBuilder, TuiDashboard, init_metrics};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let provider = TracingBuilder::new()
+        .with_panic_hook()
+        .with_log_filter("off")
+        .init();
+    init_metrics(None);
+
+    // Observe every second, so the dashboard feels alive.
+    let pipeline = PipelineBuilder::new(Duration::from_secs(1)).spawn()?;
+
+    // The dashboard runs until the user quits.
+    TuiDashboard::new(pipeline.subscribe_stats())
+        .spawn()
+        .await??;
+
+    let report = pipeline.shutdown().await?;
+    println!("{report:#?}");
+
+    provider.shutdown().map_err(Into::into)
+}
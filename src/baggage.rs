@@ -0,0 +1,76 @@
+//! OpenTelemetry baggage helpers. See [`baggage_context`].
+
+use crate::rng::Rng;
+use opentelemetry::{Context, KeyValue, StringValue, baggage::BaggageExt};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The baggage key for the ID of a pipeline run. See [`new_run_id`].
+pub const RUN_ID: &str = "run_id";
+
+/// Generate a random ID for a pipeline run, as 16 hex digits.
+///
+/// Put this in the baggage with the [`RUN_ID`] key, and every span of the
+/// run can be tied back to it, even across restarts that reuse observation
+/// IDs.
+pub fn new_run_id() -> String {
+    format!("{:016x}", Rng::random().next_u64())
+}
+
+/// Create an OTEL [`Context`] carrying `entries` as baggage.
+///
+/// ## What is baggage?
+///
+/// Span attributes describe a single span. _Baggage_ is a set of key-values
+/// that belongs to the context instead, and is inherited by every span
+/// created in that context, and every span created in _those_ spans'
+/// contexts, and so on. It even crosses process boundaries, if you
+/// propagate the context in your requests. It's the right place for things
+/// like a request ID, a tenant, or, in our case, the ID of a pipeline run.
+///
+/// ## Getting baggage into spans
+///
+/// `tracing` spans don't know about baggage. The `tracing-opentelemetry`
+/// layer keeps an OTEL [`Context`] alongside each span, and a span's context
+/// is derived from its parent's. So we only need to get the baggage into the
+/// _root_ span, with [`attach_baggage`]. Every span below it, including
+/// the ones created by other actors while processing the observation,
+/// inherits it. Read it back with [`baggage_value`].
+///
+/// ```no_run
+/// use metrics_tracing_example::{PipelineBuilder, baggage};
+/// use opentelemetry::KeyValue;
+/// use std::time::Duration;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let _pipeline = PipelineBuilder::new(Duration::from_secs(5))
+///     .with_baggage([KeyValue::new(baggage::RUN_ID, baggage::new_run_id())])
+///     .spawn()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Because the context lives in the OTEL layer, baggage is only available
+/// when that layer is installed. With OTLP export disabled, [`baggage_value`]
+/// always returns `None`.
+pub fn baggage_context(entries: impl IntoIterator<Item = KeyValue>) -> Context {
+    Context::new().with_baggage(entries)
+}
+
+/// Make `cx` the OTEL parent of `span`, so that `span` and its descendants
+/// inherit its baggage. This must be called before `span` is first entered.
+///
+/// Baggage is not exported with spans, so the entries are also recorded as
+/// attributes on `span`, where your trace viewer can see them.
+pub fn attach_baggage(span: &tracing::Span, cx: &Context) {
+    // This fails if the OTEL layer isn't installed, or the span is disabled,
+    // in which case there is nowhere to put the baggage anyway.
+    let _ = span.set_parent(cx.clone());
+    for (key, (value, _)) in cx.baggage() {
+        span.set_attribute(key.clone(), value.clone());
+    }
+}
+
+/// Read the baggage value for `key`, as seen from `span`.
+pub fn baggage_value(span: &tracing::Span, key: &str) -> Option<StringValue> {
+    span.context().baggage().get(key).cloned()
+}
@@ -0,0 +1,1062 @@
+//! A ready-to-run system monitor, built from the library's pieces.
+//!
+//! ```sh
+//! cargo run --bin sysmon -- --interval 1s --window 30 --log-level debug
+//! ```
+//!
+//! Run with `--help` to see all flags. This is a good starting point for
+//! your own `main`: it sets up tracing and metrics, spawns the pipeline, and
+//! shuts everything down gracefully on Ctrl-C.
+//!
+//! With `--adaptive-min` and `--adaptive-max`, the interval follows the
+//! load: it halves while the machine is busy, and doubles while it's idle.
+//! The `sample_interval_seconds` gauge shows where it is:
+//!
+//! ```sh
+//! cargo run --bin sysmon -- --interval 5s --adaptive-min 500ms --adaptive-max 30s
+//! ```
+//!
+//! In a container, `--cpu-view cgroup` reports usage as a share of the
+//! container's CPU quota, rather than of each host CPU:
+//!
+//! ```sh
+//! docker run --cpus 0.5 sysmon --cpu-view cgroup
+//! ```
+//!
+//! `--memory-interval` adds a second monitor, for memory, at its own pace.
+//! Both feed one dispatcher, which routes each kind of observation to its
+//! own stats processor:
+//!
+//! ```sh
+//! cargo run --bin sysmon -- --interval 1s --memory-interval 10s
+//! ```
+//!
+//! `--refresh` picks what is refreshed on each observation, besides CPU
+//! usage. Everything costs something to read, so the default is just CPUs:
+//!
+//! ```sh
+//! cargo run --bin sysmon -- --refresh cpu,memory,components
+//! ```
+//!
+//! `--process` watches one application, as well as the host. By name, it's
+//! followed across restarts:
+//!
+//! ```sh
+//! cargo run --bin sysmon -- --process postgres
+//! ```
+//!
+//! With the `cron` feature, `--cron` takes observations on a schedule
+//! instead, e.g. every 10 seconds during business hours:
+//!
+//! ```sh
+//! cargo run --features cron --bin sysmon -- --cron '*/10 * 9-16 * * Mon-Fri'
+//! ```
+//!
+//! Observations can be recorded to a file with `--record`, and fed back
+//! through the stats processor later with the `replay` subcommand. The
+//! replay goes to the same sinks as a live run, so pass the same flags, like
+//! `--emf` or `--rollup`, to see what they saw:
+//!
+//! ```sh
+//! cargo run --bin sysmon -- --record obs.jsonl
+//! cargo run --bin sysmon -- replay obs.jsonl --speed 10
+//! ```
+//!
+//! The `bench` subcommand measures what all this observability costs. It
+//! runs the pipeline at a high frequency for a fixed time, and reports the
+//! CPU time, heap allocations, and channel latency per observation. Before
+//! that, it measures each operation on its own, with an `Overhead`
+//! measurement, to show where the cost goes:
+//!
+//! ```sh
+//! cargo run --release --bin sysmon -- --log-level off bench --every 10ms --duration 10s
+//! ```
+//!
+//! Under systemd, build with the `systemd` feature and pass `--systemd`. The
+//! service reports ready after its first observation, and pings the
+//! watchdog after every one, so a stalled monitor gets restarted. With the
+//! `journald` feature, `--journald --no-console` sends its events to the
+//! journal with their fields intact, instead of as lines of stdout.
+//!
+//! With the `sentry` feature, `--sentry-dsn` (or `SENTRY_DSN`) reports error
+//! events to Sentry, tagged with the ID of the trace they happened in.
+//!
+//! Several hosts can be watched from one place. Start a collector with the
+//! `collect` subcommand, and point agents at it with `--agent`. The
+//! collector computes stats for each host, and for the whole fleet:
+//!
+//! ```sh
+//! cargo run --bin sysmon -- collect --listen 0.0.0.0:7000
+//! cargo run --bin sysmon -- --agent collector.local:7000
+//! ```
+//!
+//! Where losing an observation now and then is fine, `--agent-udp` sends
+//! them as datagrams instead, to a collector started with `--udp`. The agent
+//! never waits on the network, and never knows what it lost. The collector
+//! does: agents number their observations, and it warns about the ones it
+//! missed, and whether they were lost on the way, or never taken because
+//! the agent stalled.
+//!
+//! With the `mdns` feature, agents can find the collector on the local
+//! network by themselves: start it with `collect --advertise`, and the agents
+//! with `--discover` instead of `--agent`.
+//!
+//! Observations are shipped as JSON. With the `postcard`, `zstd`, and `lz4`
+//! features, `--wire-encoding postcard` and `--wire-compression zstd` (or
+//! `lz4`) make them smaller. The collector reads any of them, from any agent.
+//! It reads older versions of the wire format too, but not newer ones, so
+//! upgrade the collector before its agents, or hold an agent back with
+//! `--wire-version`.
+//!
+//! Anyone who can reach a collector can send it observations. Give the
+//! collector and its agents the same `--secret` (or `SYSMON_SECRET`), and
+//! it only accepts observations signed with it:
+//!
+//! ```bash
+//! export SYSMON_SECRET="$(openssl rand -hex 32)"
+//! cargo run --bin sysmon -- collect
+//! cargo run --bin sysmon -- --agent collector.local:7000
+//! ```
+//!
+//! On AWS, `--emf` writes each stats report to stdout as a CloudWatch
+//! Embedded Metric Format line, which CloudWatch Logs turns into metrics. No
+//! Prometheus needed. With the `cloudwatch` feature, `--emf-log-group` sends
+//! them to CloudWatch Logs directly.
+//!
+//! On Windows, build with the `eventlog` feature and pass `--event-log` to
+//! also write warnings and errors to the Event Log, where a service's
+//! administrator will look for them.
+//!
+//! On unix, `--daemon` detaches from the terminal and runs in the background,
+//! writing its pid to `--pid-file` and its logs to `--log-file`. Stop it with
+//! `kill -INT $(cat sysmon.pid)`, which triggers the same graceful shutdown
+//! as Ctrl-C.
+
+use clap::{Parser, Subcommand, builder::NonEmptyStringValueParser};
+#[cfg(feature = "cron")]
+use metrics_tracing_example::CronSchedule;
+use metrics_tracing_example::{
+    AdaptiveInterval, AlertingConfig, Collector, CountingAllocator, CpuView,
+    DEFAULT_BASELINE_WINDOW, DEFAULT_BUSIEST_CORES, DEFAULT_BUSY_THRESHOLD,
+    DEFAULT_CHANNEL_CAPACITY, DEFAULT_EMF_NAMESPACE, DEFAULT_IDLE_THRESHOLD,
+    DEFAULT_IMBALANCE_THRESHOLD, DEFAULT_LABEL_LIMIT, DEFAULT_MAX_HOSTS, DEFAULT_METRICS_PORT,
+    DEFAULT_WINDOW, EmfConfig, Eviction, LogFormat, MetricsConfig, MissedTicks, Observation,
+    ObservationRecorder, ObservationReplayer, OtlpProtocol, Overhead, OverheadReport,
+    PipelineBuilder, PipelineConfig, ProcessSelector, RefreshSpec, Rollup, SharedSecret,
+    SinkDriver, SinksConfig, SpanDurationLayer, SysStats, TcpSink, TcpSource, TracingConfig,
+    UdpSink, UdpSource, WIRE_VERSION, WireCompression, WireEncoding, doctor, init_metrics,
+    parse_duration, set_host_label, set_label_limit, snapshot,
+};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// How long `--discover` waits for a collector to answer.
+#[cfg(feature = "mdns")]
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counts heap allocations, for `bench`. Counting is cheap enough to leave
+/// on in every mode.
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Monitor CPU usage and frequency, and export traces and metrics.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// How often to take an observation, e.g. `500ms`, `5s`, or `1m`.
+    #[arg(long, default_value = "5s", value_parser = parse_duration)]
+    interval: Duration,
+
+    /// Sample as often as this when the machine is busy. `--interval` is
+    /// where sampling starts.
+    #[arg(long, value_parser = parse_duration, requires = "adaptive_max")]
+    adaptive_min: Option<Duration>,
+
+    /// Sample as rarely as this when the machine is idle.
+    #[arg(long, value_parser = parse_duration, requires = "adaptive_min")]
+    adaptive_max: Option<Duration>,
+
+    /// With `--adaptive-min`, sample faster above this average usage
+    /// percentage.
+    #[arg(long, default_value_t = DEFAULT_BUSY_THRESHOLD, requires = "adaptive_min")]
+    busy_threshold: f64,
+
+    /// With `--adaptive-min`, sample slower below this average usage
+    /// percentage.
+    #[arg(long, default_value_t = DEFAULT_IDLE_THRESHOLD, requires = "adaptive_min")]
+    idle_threshold: f64,
+
+    /// What to do about observations that were due while the monitor was
+    /// busy, or stalled: take them all at once, or carry on from now.
+    #[arg(long, value_enum, default_value_t = MissedTicks::Burst)]
+    missed_ticks: MissedTicks,
+
+    /// Report CPU usage per host CPU, or, in a container, as a share of the
+    /// container's CPU quota. Needs cgroup v2.
+    #[arg(long, value_enum, default_value_t = CpuView::Host)]
+    cpu_view: CpuView,
+
+    /// Take observations when this cron expression fires, instead of every
+    /// `--interval`, e.g. `*/10 * 9-16 * * Mon-Fri`. The first field is
+    /// seconds. Times are local.
+    #[cfg(feature = "cron")]
+    #[arg(long, conflicts_with = "adaptive_min")]
+    cron: Option<CronSchedule>,
+
+    /// The subsystems to refresh on each observation, e.g. `cpu,memory`.
+    /// One or more of: cpu, usage, frequency, memory, processes, components.
+    #[arg(long, default_value_t = RefreshSpec::cpu())]
+    refresh: RefreshSpec,
+
+    /// Also watch this process, by PID, or by name. By name, it is followed
+    /// across restarts.
+    #[arg(long)]
+    process: Option<ProcessSelector>,
+
+    /// Also read the host's memory this often, e.g. `10s`, with a monitor
+    /// of its own.
+    #[arg(long, value_parser = parse_duration)]
+    memory_interval: Option<Duration>,
+
+    /// The number of observations to compute stats over.
+    #[arg(long, default_value_t = DEFAULT_WINDOW)]
+    window: usize,
+
+    /// The number of observations to compare the window's average usage
+    /// against, e.g. 120 for 10 minutes at a 5 second interval.
+    #[arg(long, default_value_t = DEFAULT_BASELINE_WINDOW)]
+    baseline_window: usize,
+
+    /// Slide the window one observation at a time, or empty it once it's
+    /// full, so that no observation is in two reports.
+    #[arg(long, value_enum, default_value_t = Eviction::Sliding)]
+    eviction: Eviction,
+
+    /// Compute stats once every this many observations, e.g. the window size
+    /// with `--eviction tumbling`, for one report per batch.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    emit_every: u64,
+
+    /// Warn when the busiest and idlest core's average usage over the window
+    /// differ by more than this many percentage points.
+    #[arg(long, default_value_t = DEFAULT_IMBALANCE_THRESHOLD)]
+    imbalance_threshold: f64,
+
+    /// The number of busiest cores to report, at most 4.
+    #[arg(long, default_value_t = DEFAULT_BUSIEST_CORES)]
+    busiest_cores: usize,
+
+    /// The port to serve prometheus metrics on.
+    #[arg(long, default_value_t = DEFAULT_METRICS_PORT)]
+    metrics_port: u16,
+
+    /// The maximum number of distinct values per metric label. New values
+    /// beyond this are recorded as `other`.
+    #[arg(long, default_value_t = DEFAULT_LABEL_LIMIT)]
+    max_label_values: usize,
+
+    /// The base URL of the OTLP collector, e.g. `http://localhost:4318`.
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// The OTLP transport. The collector endpoint defaults to the standard
+    /// port for the transport.
+    #[arg(long, value_enum, env = "OTEL_EXPORTER_OTLP_PROTOCOL")]
+    otlp_protocol: Option<OtlpProtocol>,
+
+    /// How long to wait for the last spans to be exported on exit, e.g.
+    /// `500ms` or `5s`. Spans still queued after this are lost.
+    #[arg(long, default_value = "5s", value_parser = parse_duration)]
+    shutdown_timeout: Duration,
+
+    /// The fraction of traces to export, between 0 and 1. Spans at WARN
+    /// level or above are always exported.
+    #[arg(long, default_value_t = 1.0)]
+    sample_ratio: f64,
+
+    /// The console log filter, using `RUST_LOG` syntax.
+    #[arg(long, env = "RUST_LOG", default_value = "info")]
+    log_level: String,
+
+    /// The console output format.
+    #[arg(long, value_enum, default_value_t = LogFormat::Full)]
+    format: LogFormat,
+
+    /// Disable console output, e.g. with `--journald` under systemd.
+    #[arg(long)]
+    no_console: bool,
+
+    /// Also write events to the systemd journal, with their fields.
+    #[cfg(feature = "journald")]
+    #[arg(long)]
+    journald: bool,
+
+    /// Disable OTLP span export, e.g. when no collector is running.
+    #[arg(long)]
+    no_otlp: bool,
+
+    /// Take a single observation, print it as JSON, and exit. Tracing and
+    /// metrics are not initialized, so stdout contains only the JSON.
+    #[arg(long)]
+    once: bool,
+
+    /// Record every processed observation to this file, as JSON lines.
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Run as an agent: also send every observation to the collector at
+    /// this address, e.g. `collector.local:7000`. See the `collect`
+    /// subcommand.
+    #[arg(long, value_name = "ADDR")]
+    agent: Option<String>,
+
+    /// Like `--agent`, but send observations over UDP, one datagram each.
+    /// Never waits on the collector, at the cost of losing observations
+    /// when the network does.
+    #[arg(long, value_name = "ADDR")]
+    agent_udp: Option<String>,
+
+    /// Like `--agent`, but find the collector on the local network with
+    /// mDNS. The collector must be started with `collect --advertise`.
+    #[cfg(feature = "mdns")]
+    #[arg(long, conflicts_with = "agent")]
+    discover: bool,
+
+    /// How to encode observations sent with `--agent` or `--agent-udp`.
+    #[arg(long, value_enum, default_value_t = WireEncoding::Json)]
+    wire_encoding: WireEncoding,
+
+    /// How to compress observations sent with `--agent` or `--agent-udp`.
+    /// Compare the `remote_bytes_encoded` and `remote_bytes_sent` metrics
+    /// to see the difference.
+    #[arg(long, value_enum, default_value_t = WireCompression::None)]
+    wire_compression: WireCompression,
+
+    /// The version of the wire format to send observations in. Lower it to
+    /// ship to a collector that hasn't been upgraded yet.
+    #[arg(long, default_value_t = WIRE_VERSION, value_parser = clap::value_parser!(u8).range(..=i64::from(WIRE_VERSION)))]
+    wire_version: u8,
+
+    /// A secret shared with the collector. Agents sign the observations they
+    /// send with it, and a collector only accepts observations signed with
+    /// it.
+    #[arg(long, env = "SYSMON_SECRET", hide_env_values = true, value_parser = NonEmptyStringValueParser::new())]
+    secret: Option<String>,
+
+    /// The host name to tag observations sent with `--agent` or
+    /// `--agent-udp` with, and, as an agent or collector, to label metrics
+    /// and stats events with. Defaults to this host's name.
+    #[arg(long, value_name = "NAME")]
+    host: Option<String>,
+
+    /// Also roll observations up into min/avg/max aggregates over periods
+    /// of this length, e.g. `1m`.
+    #[arg(long, value_parser = parse_duration)]
+    rollup: Option<Duration>,
+
+    /// Also write each stats report to stdout as a CloudWatch EMF line,
+    /// with its metrics in this namespace.
+    #[arg(long, value_name = "NAMESPACE", num_args = 0..=1, default_missing_value = DEFAULT_EMF_NAMESPACE)]
+    emf: Option<String>,
+
+    /// Send the EMF lines to this CloudWatch Logs log group, instead of
+    /// stdout.
+    #[cfg(feature = "cloudwatch")]
+    #[arg(long, requires = "emf")]
+    emf_log_group: Option<String>,
+
+    /// The log stream in `--emf-log-group`. Defaults to the host name.
+    #[cfg(feature = "cloudwatch")]
+    #[arg(long, requires = "emf_log_group")]
+    emf_log_stream: Option<String>,
+
+    /// Notify systemd when ready, and ping its watchdog after every
+    /// observation. Use with `Type=notify` and `WatchdogSec=` in the unit.
+    #[cfg(feature = "systemd")]
+    #[arg(long)]
+    systemd: bool,
+
+    /// Report error events to Sentry, at this DSN.
+    #[cfg(feature = "sentry")]
+    #[arg(long, env = "SENTRY_DSN")]
+    sentry_dsn: Option<String>,
+
+    /// Also write warnings and errors to the Windows Event Log, under this
+    /// event source.
+    #[cfg(feature = "eventlog")]
+    #[arg(long, value_name = "SOURCE", num_args = 0..=1, default_missing_value = "sysmon")]
+    event_log: Option<String>,
+
+    /// Detach from the terminal and run in the background. Unix only.
+    #[arg(long)]
+    daemon: bool,
+
+    /// Where to write the pid in daemon mode.
+    #[arg(long, default_value = "sysmon.pid", requires = "daemon")]
+    pid_file: PathBuf,
+
+    /// Where to write logs in daemon mode. Logs are appended.
+    #[arg(long, default_value = "sysmon.log", requires = "daemon")]
+    log_file: PathBuf,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Feed a file recorded with `--record` through the stats processor, and
+    /// the sinks.
+    Replay {
+        /// The recorded observation file.
+        file: PathBuf,
+
+        /// Playback speed. `1` replays at the recorded pace, `10` replays ten
+        /// times faster.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Run as a collector: compute stats per host, and for the whole fleet,
+    /// over the observations of agents started with `--agent`, instead of
+    /// observing this host.
+    Collect(CollectArgs),
+    /// Check that the OTLP endpoint, metrics port, and sysinfo all work, and
+    /// print a diagnosis as JSON. Exits non-zero if any check fails.
+    Doctor,
+    /// Run the pipeline at a high frequency, and report its overhead.
+    Bench {
+        /// How often to take an observation.
+        #[arg(long, default_value = "10ms", value_parser = parse_duration)]
+        every: Duration,
+
+        /// How long to run for.
+        #[arg(long, default_value = "10s", value_parser = parse_duration)]
+        duration: Duration,
+    },
+}
+
+/// The flags of the `collect` subcommand.
+#[derive(Debug, clap::Args)]
+struct CollectArgs {
+    /// The address to listen for agents on.
+    #[arg(long, default_value = "0.0.0.0:7000")]
+    listen: String,
+
+    /// Count agents that haven't sent an observation for this long as
+    /// silent, and leave them out of the fleet aggregates.
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    host_timeout: Duration,
+
+    /// Keep stats for at most this many hosts. Past it, new hosts replace
+    /// silent ones.
+    #[arg(long, default_value_t = DEFAULT_MAX_HOSTS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_hosts: usize,
+
+    /// Also listen for agents started with `--agent-udp` on this address.
+    #[arg(long, value_name = "ADDR")]
+    udp: Option<String>,
+
+    /// Advertise the collector on the local network with mDNS, so that
+    /// agents started with `--discover` can find it.
+    #[cfg(feature = "mdns")]
+    #[arg(long)]
+    advertise: bool,
+}
+
+/// The result of the `bench` subcommand.
+#[derive(Debug, Serialize)]
+struct BenchReport {
+    observations: u64,
+    run_duration_ms: u64,
+    cpu_time_ms: u64,
+    cpu_time_per_observation_us: f64,
+    allocations: u64,
+    allocations_per_observation: f64,
+    average_channel_latency_us: u64,
+    /// Each operation, measured on its own.
+    overhead: OverheadReport,
+}
+
+impl Args {
+    /// Whether observations cross hosts: sysmon runs as an agent, or as a
+    /// collector.
+    fn is_distributed(&self) -> bool {
+        #[cfg(feature = "mdns")]
+        if self.discover {
+            return true;
+        }
+        self.agent.is_some()
+            || self.agent_udp.is_some()
+            || matches!(self.command, Some(Command::Collect(_)))
+    }
+
+    /// The name of this host: `--host`, or the name the OS knows it by.
+    fn host_name(&self) -> String {
+        self.host
+            .clone()
+            .or_else(sysinfo::System::host_name)
+            .unwrap_or_else(|| "unknown".to_owned())
+    }
+
+    /// The flags that configure the library, as a [`PipelineConfig`].
+    fn config(&self) -> PipelineConfig {
+        PipelineConfig {
+            interval: self.interval,
+            window: self.window,
+            baseline_window: self.baseline_window,
+            eviction: self.eviction,
+            emit_every: self.emit_every as usize,
+            busiest_cores: self.busiest_cores,
+            missed_ticks: self.missed_ticks,
+            cpu_view: self.cpu_view,
+            metrics: MetricsConfig {
+                port: Some(self.metrics_port),
+                max_label_values: self.max_label_values,
+            },
+            tracing: TracingConfig {
+                log_filter: Some(self.log_level.clone()),
+                format: self.format,
+                console: !self.no_console,
+                #[cfg(feature = "journald")]
+                journald: self.journald,
+                otlp: !self.no_otlp,
+                otlp_endpoint: self.otlp_endpoint.clone(),
+                otlp_protocol: self.otlp_protocol,
+                sample_ratio: self.sample_ratio,
+                shutdown_timeout: self.shutdown_timeout,
+            },
+            sinks: SinksConfig {
+                rollup: self.rollup,
+                emf: self.emf.as_ref().map(|namespace| EmfConfig {
+                    namespace: namespace.clone(),
+                    #[cfg(feature = "cloudwatch")]
+                    log_group: self.emf_log_group.clone(),
+                    #[cfg(feature = "cloudwatch")]
+                    log_stream: self.emf_log_stream.clone(),
+                    ..Default::default()
+                }),
+            },
+            alerting: AlertingConfig {
+                imbalance_threshold: self.imbalance_threshold,
+                watchdog: None,
+                #[cfg(feature = "systemd")]
+                systemd: self.systemd,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// The total CPU time used by this process so far.
+fn process_cpu_time() -> eyre::Result<Duration> {
+    let pid = sysinfo::get_current_pid().map_err(|e| eyre::eyre!(e))?;
+    let mut system = sysinfo::System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        false,
+        ProcessRefreshKind::nothing().with_cpu(),
+    );
+    let process = system
+        .process(pid)
+        .ok_or_else(|| eyre::eyre!("current process not found"))?;
+    Ok(Duration::from_millis(process.accumulated_cpu_time()))
+}
+
+/// Spawn a task that records every observation it receives to `path`.
+async fn spawn_recorder(
+    path: &Path,
+) -> eyre::Result<(mpsc::Sender<Observation>, JoinHandle<ObservationRecorder>)> {
+    let recorder = ObservationRecorder::to_file(path).await?;
+    let (tx, rx) = mpsc::channel(DEFAULT_CHANNEL_CAPACITY);
+    Ok((tx, SinkDriver::new(recorder, rx).spawn()))
+}
+
+/// The sinks configured with flags, downstream of the stats processor: the
+/// agent sinks, the rollup, and the recorder, in that order. Each forwards
+/// everything it sees to the next.
+#[derive(Default)]
+struct Sinks {
+    recorder: Option<JoinHandle<ObservationRecorder>>,
+    agent: Option<JoinHandle<()>>,
+    agent_udp: Option<JoinHandle<()>>,
+    rollup: Option<JoinHandle<()>>,
+}
+
+impl Sinks {
+    /// Spawn the sinks, and return the sender for the first one, if any.
+    async fn spawn(args: &Args) -> eyre::Result<(Self, Option<mpsc::Sender<Observation>>)> {
+        let mut sinks = Self::default();
+        let mut outbound = None;
+        if let Some(path) = &args.record {
+            let (tx, handle) = spawn_recorder(path).await?;
+            outbound = Some(tx);
+            sinks.recorder = Some(handle);
+        }
+        #[cfg_attr(not(feature = "mdns"), expect(unused_mut))]
+        let mut agent_addr = args.agent.clone();
+        #[cfg(feature = "mdns")]
+        if args.discover {
+            let collector = metrics_tracing_example::discover_collector(DISCOVERY_TIMEOUT).await?;
+            agent_addr = Some(collector.tcp.to_string());
+        }
+        if let Some(addr) = &agent_addr {
+            let (tx, rx) = mpsc::channel(DEFAULT_CHANNEL_CAPACITY);
+            let mut sink = TcpSink::new(rx, addr)
+                .with_wire_version(args.wire_version)
+                .with_encoding(args.wire_encoding)
+                .with_compression(args.wire_compression);
+            if let Some(host) = &args.host {
+                sink = sink.with_host(host.as_str());
+            }
+            if let Some(secret) = &args.secret {
+                sink = sink.with_secret(SharedSecret::new(secret));
+            }
+            if let Some(outbound) = outbound {
+                sink = sink.with_outbound(outbound);
+            }
+            sinks.agent = Some(sink.spawn());
+            outbound = Some(tx);
+        }
+        if let Some(addr) = &args.agent_udp {
+            let (tx, rx) = mpsc::channel(DEFAULT_CHANNEL_CAPACITY);
+            let mut sink = UdpSink::connect(rx, addr)
+                .await?
+                .with_wire_version(args.wire_version)
+                .with_encoding(args.wire_encoding)
+                .with_compression(args.wire_compression);
+            if let Some(host) = &args.host {
+                sink = sink.with_host(host.as_str());
+            }
+            if let Some(secret) = &args.secret {
+                sink = sink.with_secret(SharedSecret::new(secret));
+            }
+            if let Some(outbound) = outbound {
+                sink = sink.with_outbound(outbound);
+            }
+            sinks.agent_udp = Some(sink.spawn());
+            outbound = Some(tx);
+        }
+        if let Some(period) = args.rollup {
+            let (tx, rx) = mpsc::channel(DEFAULT_CHANNEL_CAPACITY);
+            sinks.rollup = Some(Rollup::new(rx, outbound).with_period(period).spawn());
+            outbound = Some(tx);
+        }
+        Ok((sinks, outbound))
+    }
+
+    /// Wait for the sinks to drain, in the order observations flow through
+    /// them.
+    async fn join(self) -> eyre::Result<()> {
+        if let Some(rollup) = self.rollup {
+            rollup.await?;
+        }
+        if let Some(agent_udp) = self.agent_udp {
+            agent_udp.await?;
+        }
+        if let Some(agent) = self.agent {
+            agent.await?;
+        }
+        if let Some(recorder) = self.recorder {
+            recorder.await?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the live pipeline until Ctrl-C.
+async fn run(args: &Args) -> eyre::Result<()> {
+    info!(
+        interval_ms = args.interval.as_millis() as u64,
+        window = args.window,
+        "starting sysmon"
+    );
+
+    let config = args.config();
+    let mut builder = PipelineBuilder::from_config(&config);
+    if args.is_distributed() {
+        builder = builder.with_host(args.host_name());
+    }
+    if let (Some(min), Some(max)) = (args.adaptive_min, args.adaptive_max) {
+        let adaptive = AdaptiveInterval::new(min, max)
+            .with_busy_threshold(args.busy_threshold)
+            .with_idle_threshold(args.idle_threshold);
+        builder = builder.with_adaptive_interval(adaptive);
+    }
+    #[cfg(feature = "cron")]
+    if let Some(schedule) = &args.cron {
+        builder = builder.with_cron(schedule.clone());
+    }
+    builder = builder.with_refresh(args.refresh);
+    if let Some(selector) = &args.process {
+        builder = builder.with_process(selector.clone());
+    }
+    if let Some(interval) = args.memory_interval {
+        builder = builder.with_memory_interval(interval);
+    }
+    // The agent sinks and the rollup sit between the stats processor and
+    // the recorder, and forward everything they see.
+    let (sinks, outbound) = Sinks::spawn(args).await?;
+    if let Some(outbound) = outbound {
+        builder = builder.with_outbound(outbound);
+    }
+    let mut pipeline = builder.spawn()?;
+    let emf = config
+        .sinks
+        .emf
+        .as_ref()
+        .map(|emf| emf.sink(pipeline.subscribe_stats()).spawn());
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            info!("Received Ctrl-C, shutting down");
+            pipeline.shutdown().await?;
+        }
+        res = &mut pipeline => {
+            res?;
+        }
+    }
+
+    sinks.join().await?;
+    if let Some(emf) = emf {
+        emf.await??;
+    }
+    Ok(())
+}
+
+/// Feed a recorded file through the stats processor, and the sinks
+/// configured with flags, at `speed` times the recorded pace.
+async fn replay(args: &Args, file: &Path, speed: f64) -> eyre::Result<()> {
+    eyre::ensure!(
+        speed.is_finite() && speed > 0.0,
+        "replay speed must be positive"
+    );
+    info!(file = %file.display(), speed, window = args.window, "starting replay");
+
+    let (sinks, outbound) = Sinks::spawn(args).await?;
+
+    let (tx, rx) = mpsc::channel(DEFAULT_CHANNEL_CAPACITY);
+    let stats = SysStats::new(rx, outbound)
+        .with_window(args.window)
+        .with_baseline_window(args.baseline_window)
+        .with_eviction(args.eviction)
+        .with_emit_every(args.emit_every as usize)
+        .with_imbalance_threshold(args.imbalance_threshold)
+        .with_busiest_cores(args.busiest_cores);
+    let emf = args
+        .config()
+        .sinks
+        .emf
+        .map(|emf| emf.sink(stats.subscribe()).spawn());
+    let stats = stats.spawn();
+
+    let shutdown = CancellationToken::new();
+    let replay = ObservationReplayer::from_file(file)
+        .await?
+        .with_speed(speed)
+        .with_shutdown(shutdown.clone())
+        .spawn(tx);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Received Ctrl-C, stopping replay");
+            shutdown.cancel();
+        }
+    });
+
+    // The replayer drops its sender when it's done, which lets the stats
+    // processor drain and exit, and the sinks after it.
+    replay.await??;
+    stats.await?;
+
+    sinks.join().await?;
+    if let Some(emf) = emf {
+        emf.await??;
+    }
+    Ok(())
+}
+
+/// Compute per-host and fleet stats over the observations of remote agents
+/// until Ctrl-C.
+async fn collect(args: &Args, collect_args: &CollectArgs) -> eyre::Result<()> {
+    let source = TcpSource::bind(&collect_args.listen).await?;
+    info!(addr = %source.local_addr()?, window = args.window, "starting collector");
+    let udp_source = match &collect_args.udp {
+        Some(addr) => {
+            let source = UdpSource::bind(addr).await?;
+            info!(addr = %source.local_addr()?, "listening for UDP agents");
+            Some(source)
+        }
+        None => None,
+    };
+    // Advertised until dropped, at the end of this function.
+    #[cfg(feature = "mdns")]
+    let _advertisement = if collect_args.advertise {
+        let udp_port = match &udp_source {
+            Some(source) => Some(source.local_addr()?.port()),
+            None => None,
+        };
+        Some(metrics_tracing_example::advertise_collector(
+            source.local_addr()?.port(),
+            udp_port,
+        )?)
+    } else {
+        None
+    };
+
+    let (source, udp_source) = match &args.secret {
+        Some(secret) => {
+            let secret = SharedSecret::new(secret);
+            (
+                source.with_secret(secret.clone()),
+                udp_source.map(|source| source.with_secret(secret)),
+            )
+        }
+        None => {
+            warn!("no --secret, accepting observations from anyone");
+            (source, udp_source)
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(DEFAULT_CHANNEL_CAPACITY);
+    let udp_source = udp_source.map(|source| source.spawn(tx.clone()));
+    let source = source.spawn(tx);
+    let collector = Collector::new(rx, None)
+        .with_window(args.window)
+        .with_baseline_window(args.baseline_window)
+        .with_imbalance_threshold(args.imbalance_threshold)
+        .with_busiest_cores(args.busiest_cores)
+        .with_host_timeout(collect_args.host_timeout)
+        .with_max_hosts(collect_args.max_hosts)
+        .spawn();
+
+    tokio::signal::ctrl_c().await?;
+    info!("Received Ctrl-C, shutting down");
+    // Stopping the sources drops their senders, which lets the collector
+    // drain and exit.
+    source.abort();
+    if let Some(udp_source) = udp_source {
+        udp_source.abort();
+    }
+    collector.await?;
+    Ok(())
+}
+
+/// Run the pipeline every `every` for `duration`, and report its overhead.
+///
+/// The CPU time and allocations are measured for the whole process, so they
+/// include the cost of tracing, metrics, and OTLP export, as well as the
+/// actors themselves. That is the point: it is the total cost of observing
+/// the system.
+async fn bench(args: &Args, every: Duration, duration: Duration) -> eyre::Result<()> {
+    info!(
+        every_us = every.as_micros() as u64,
+        duration_ms = duration.as_millis() as u64,
+        "starting benchmark"
+    );
+
+    let overhead = tokio::task::spawn_blocking(|| Overhead::new().measure()).await?;
+
+    let cpu_before = process_cpu_time()?;
+    let allocs_before = CountingAllocator::total();
+
+    let config = PipelineConfig {
+        interval: every,
+        ..args.config()
+    };
+    let pipeline = PipelineBuilder::from_config(&config).spawn()?;
+    tokio::time::sleep(duration).await;
+    let report = pipeline.shutdown().await?;
+
+    let cpu_time = process_cpu_time()?.saturating_sub(cpu_before);
+    let allocations = CountingAllocator::total() - allocs_before;
+    let per_obs = report.observations_processed.max(1) as f64;
+
+    let bench = BenchReport {
+        observations: report.observations_processed,
+        run_duration_ms: report.run_duration.as_millis() as u64,
+        cpu_time_ms: cpu_time.as_millis() as u64,
+        cpu_time_per_observation_us: cpu_time.as_micros() as f64 / per_obs,
+        allocations,
+        allocations_per_observation: allocations as f64 / per_obs,
+        average_channel_latency_us: report.average_latency.as_micros() as u64,
+        overhead,
+    };
+
+    info!(
+        observations = bench.observations,
+        cpu_time_per_observation_us = bench.cpu_time_per_observation_us,
+        allocations_per_observation = bench.allocations_per_observation,
+        average_channel_latency_us = bench.average_channel_latency_us,
+        "benchmark finished"
+    );
+    println!("{}", serde_json::to_string_pretty(&bench)?);
+
+    Ok(())
+}
+
+/// Detach from the terminal, write the pid file, and redirect stdout and
+/// stderr to the log file. This must happen before the tokio runtime starts,
+/// as forking a multi-threaded process only keeps the calling thread.
+#[cfg(unix)]
+fn daemonize(args: &Args) -> eyre::Result<()> {
+    let log = open_log_file(&args.log_file)?;
+    daemonize::Daemonize::new()
+        .pid_file(&args.pid_file)
+        // Keep the working directory, so relative paths still resolve.
+        .working_directory(std::env::current_dir()?)
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .start()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn daemonize(_args: &Args) -> eyre::Result<()> {
+    eyre::bail!("--daemon is only supported on unix")
+}
+
+fn open_log_file(path: &Path) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+
+    if args.daemon {
+        daemonize(&args)?;
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(async_main(args))
+}
+
+async fn async_main(args: Args) -> eyre::Result<()> {
+    if args.once {
+        let obs = snapshot().await;
+        println!("{}", serde_json::to_string_pretty(&*obs)?);
+        return Ok(());
+    }
+
+    // Like `--once`, the doctor runs without tracing, so stdout is only JSON.
+    if let Some(Command::Doctor) = args.command {
+        let diagnosis = doctor(args.otlp_endpoint.as_deref(), args.metrics_port).await;
+        println!("{}", serde_json::to_string_pretty(&diagnosis)?);
+        eyre::ensure!(diagnosis.is_healthy(), "some checks failed");
+        return Ok(());
+    }
+
+    let config = args.config();
+    config.validate()?;
+
+    let mut tracing = config
+        .tracing
+        .builder()
+        .with_panic_hook()
+        .with_span_durations(SpanDurationLayer::new());
+    if args.daemon {
+        tracing = tracing.with_log_file(open_log_file(&args.log_file)?);
+    }
+
+    // Events, exported as OTEL log records too. Tracing isn't initialized
+    // yet, so a failure is reported once it is.
+    #[cfg(feature = "otel-logs")]
+    let (logger_provider, logs_error) = match (!args.no_otlp)
+        .then(metrics_tracing_example::init_otel_logs)
+        .transpose()
+    {
+        Ok(logger_provider) => (logger_provider, None),
+        Err(error) => (None, Some(error)),
+    };
+    #[cfg(feature = "otel-logs")]
+    if let Some(logger_provider) = &logger_provider {
+        tracing = tracing.with_otel_logs(logger_provider);
+    }
+
+    // Errors, as Sentry issues. The guard flushes them when dropped, at the
+    // end of `main`.
+    #[cfg(feature = "sentry")]
+    let _sentry = match &args.sentry_dsn {
+        Some(dsn) => {
+            tracing = tracing.with_sentry();
+            Some(metrics_tracing_example::init_sentry(Some(dsn))?)
+        }
+        None => None,
+    };
+
+    // Warnings and errors, in the Windows Event Log too.
+    #[cfg(feature = "eventlog")]
+    let event_log_error = match args
+        .event_log
+        .as_deref()
+        .map(metrics_tracing_example::EventLogLayer::new)
+        .transpose()
+    {
+        Ok(layer) => {
+            if let Some(layer) = layer {
+                tracing = tracing.with_event_log(layer);
+            }
+            None
+        }
+        Err(error) => Some(error),
+    };
+
+    let provider = tracing.init();
+
+    #[cfg(feature = "eventlog")]
+    if let Some(error) = event_log_error {
+        tracing::error!(%error, "failed to open the event log source, events will not be written to it");
+    }
+
+    #[cfg(feature = "otel-logs")]
+    if let Some(error) = logs_error {
+        tracing::error!(%error, "failed to build OTLP log exporter, logs will not be exported");
+    }
+
+    set_label_limit(config.metrics.max_label_values);
+    // Once several hosts are scraped into one Prometheus, their series
+    // must say which host they're from.
+    if args.is_distributed() {
+        set_host_label(args.host_name());
+    }
+    let metrics_port = init_metrics(config.metrics.port);
+    info!(metrics_port, "serving metrics");
+
+    // The same metrics again, pushed to the collector with the OTEL SDK.
+    #[cfg(feature = "otel-metrics")]
+    let meter_provider = (!args.no_otlp).then(metrics_tracing_example::init_otel_metrics);
+
+    match &args.command {
+        None => run(&args).await?,
+        Some(Command::Replay { file, speed }) => replay(&args, file, *speed).await?,
+        Some(Command::Bench { every, duration }) => bench(&args, *every, *duration).await?,
+        Some(Command::Collect(collect_args)) => collect(&args, collect_args).await?,
+        Some(Command::Doctor) => unreachable!("handled before tracing is initialized"),
+    }
+
+    #[cfg(feature = "otel-metrics")]
+    if let Some(meter_provider) = meter_provider
+        && let Err(error) = meter_provider.shutdown()
+    {
+        // Usually the collector is unreachable. That's no reason to exit
+        // with an error.
+        tracing::warn!(%error, "failed to flush OTEL metrics");
+    }
+
+    #[cfg(feature = "otel-logs")]
+    if let Some(logger_provider) = logger_provider
+        && let Err(error) = logger_provider.shutdown()
+    {
+        // Logged to the console only, the log exporter is gone.
+        tracing::warn!(%error, "failed to flush OTEL logs");
+    }
+
+    provider.shutdown().map_err(Into::into)
+}
@@ -0,0 +1,188 @@
+//! CPU accounting against a container's quota. See [`CpuView`].
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// What CPU usage percentages are relative to. See
+/// [`SysMonitor::with_cpu_view`].
+///
+/// Inside a container, the host's CPUs tell the wrong story. A container
+/// limited to half a core, on a 16 core host, that is using all of its half
+/// core, is as busy as it can be. Per host CPU, it reads about 3%. The
+/// container's cgroup knows its quota, and how much CPU time it has used,
+/// which gives a percentage that means something: 100% is the quota.
+///
+/// [`SysMonitor::with_cpu_view`]: crate::SysMonitor::with_cpu_view
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum CpuView {
+    /// Each of the host's CPUs, as a percentage of that CPU.
+    #[default]
+    Host,
+    /// The cgroup the monitor runs in, as a percentage of its CPU quota, or
+    /// of the CPUs it may run on, if it has no quota. Only cgroup v2 is
+    /// supported.
+    Cgroup,
+}
+
+impl CpuView {
+    /// The name of the view, as used in flags and config files.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Host => "host",
+            Self::Cgroup => "cgroup",
+        }
+    }
+}
+
+/// Where cgroup v2 is mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Reads the CPU usage of the monitor's own cgroup, relative to its quota.
+///
+/// ## cgroup v2
+///
+/// `/proc/self/cgroup` says which cgroup the process is in. Under cgroup v2,
+/// that's a single line, like `0::/system.slice/sysmon.service`. That
+/// cgroup's directory under `/sys/fs/cgroup` has two files of interest:
+///
+/// - `cpu.stat` counts the CPU time the cgroup has used, in microseconds,
+///   on its `usage_usec` line.
+/// - `cpu.max` holds the quota: `50000 100000` means 50ms of CPU time every
+///   100ms, or half a core. `max` instead of a number means no quota.
+///
+/// Like the CPU times in `/proc/stat`, the usage is a counter, so the reader
+/// keeps the previous read, and reports the CPU time used in between, as a
+/// share of the CPU time the quota allowed in between.
+#[derive(Debug)]
+pub(crate) struct CgroupReader {
+    dir: PathBuf,
+    /// The quota, in cores, as of the last read. Quotas can change at
+    /// runtime, e.g. with `docker update`, so it is read every time.
+    limit: f64,
+    /// The usage counter, and when it was read.
+    previous: Option<(u64, Instant)>,
+    /// The usage percentage between the last two reads.
+    usage: Option<f32>,
+    /// Reused between reads, so that reading doesn't allocate.
+    contents: String,
+}
+
+impl CgroupReader {
+    /// Find the cgroup the process is in. Fails if the host doesn't use
+    /// cgroup v2, e.g. it isn't Linux, or it's an old Linux.
+    pub(crate) fn detect() -> io::Result<Self> {
+        let membership = std::fs::read_to_string("/proc/self/cgroup")?;
+        let path = parse_membership(&membership).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "not in a cgroup v2 hierarchy")
+        })?;
+        let mut reader = Self {
+            dir: Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')),
+            limit: 0.0,
+            previous: None,
+            usage: None,
+            contents: String::new(),
+        };
+        // On a host with cgroup v1 too, the v2 hierarchy may exist without
+        // the process's cgroup in it. Reading once makes sure it is.
+        reader.refresh()?;
+        Ok(reader)
+    }
+
+    /// Read the quota from `cpu.max`. Without one, because there is no quota
+    /// or no `cpu` controller, the limit is the number of CPUs the process
+    /// may run on.
+    fn read_limit(&mut self) -> io::Result<()> {
+        let quota = match std::fs::read_to_string(self.dir.join("cpu.max")) {
+            Ok(contents) => parse_cpu_max(&contents),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error),
+        };
+        self.limit = match quota {
+            Some(quota) => quota,
+            None => std::thread::available_parallelism()?.get() as f64,
+        };
+        Ok(())
+    }
+
+    /// Read the counters again. Call this right after refreshing the
+    /// `System`, so that the cgroup's usage and the host's cover the same
+    /// interval.
+    pub(crate) fn refresh(&mut self) -> io::Result<()> {
+        use std::io::Read;
+
+        self.read_limit()?;
+        self.contents.clear();
+        std::fs::File::open(self.dir.join("cpu.stat"))?.read_to_string(&mut self.contents)?;
+        let used = parse_usage_usec(&self.contents).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "no usage_usec in cpu.stat")
+        })?;
+
+        let now = Instant::now();
+        if let Some((before, then)) = self.previous.replace((used, now)) {
+            let allowed = (now - then).as_micros() as f64 * self.limit;
+            self.usage = (allowed > 0.0)
+                .then(|| (used.saturating_sub(before) as f64 / allowed * 100.0) as f32);
+        }
+        Ok(())
+    }
+
+    /// The quota, in cores.
+    pub(crate) const fn limit(&self) -> f64 {
+        self.limit
+    }
+
+    /// The cgroup's usage, as a percentage of its quota, between the last
+    /// two reads. `None` until there have been two reads.
+    pub(crate) const fn usage(&self) -> Option<f32> {
+        self.usage
+    }
+}
+
+/// The cgroup v2 path in the contents of `/proc/self/cgroup`.
+fn parse_membership(contents: &str) -> Option<&str> {
+    contents.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+/// The quota in `cpu.max`, in cores. `None` if there is no quota.
+fn parse_cpu_max(contents: &str) -> Option<f64> {
+    let mut fields = contents.split_ascii_whitespace();
+    let quota: f64 = fields.next()?.parse().ok()?;
+    let period: f64 = fields.next()?.parse().ok()?;
+    (period > 0.0).then_some(quota / period)
+}
+
+/// The `usage_usec` counter in `cpu.stat`.
+fn parse_usage_usec(contents: &str) -> Option<u64> {
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|usage| usage.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cgroup_files() {
+        let membership = "12:cpu,cpuacct:/docker/abc\n0::/system.slice/sysmon.service\n";
+        assert_eq!(
+            parse_membership(membership),
+            Some("/system.slice/sysmon.service")
+        );
+        assert_eq!(parse_membership("4:memory:/docker/abc\n"), None);
+
+        assert_eq!(parse_cpu_max("50000 100000\n"), Some(0.5));
+        assert_eq!(parse_cpu_max("200000 100000\n"), Some(2.0));
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+
+        let stat = "usage_usec 8123456\nuser_usec 6000000\nsystem_usec 2123456\n";
+        assert_eq!(parse_usage_usec(stat), Some(8_123_456));
+        assert_eq!(parse_usage_usec("nr_periods 0\n"), None);
+    }
+}
@@ -0,0 +1,257 @@
+//! Injecting failures, on purpose. See [`Chaos`].
+
+use crate::{CpuData, Observation, ObservationData, rng::Rng};
+use std::time::Duration;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{Instrument, debug, info_span, warn};
+
+/// A fault that [`Chaos`] can inject.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The observation was held back before being sent on.
+    Delay,
+    /// The observation was dropped.
+    Drop,
+    /// The actor panicked.
+    Panic,
+}
+
+impl Fault {
+    /// The fault's name, for logs and metric labels, e.g. `delay`.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Delay => "delay",
+            Self::Drop => "drop",
+            Self::Panic => "panic",
+        }
+    }
+}
+
+/// Asserts that `probability` is in `0..=1`.
+const fn check_probability(probability: f64) {
+    assert!(
+        probability >= 0.0 && probability <= 1.0,
+        "chaos probability must be between 0 and 1"
+    );
+}
+
+/// A pass-through actor that injects failures into the pipeline: it delays
+/// observations, drops them, or panics, each with a configured probability.
+///
+/// Failure handling that has never seen a failure doesn't work. Put chaos
+/// between two actors, and watch what the rest of the pipeline does about
+/// it: the [`Watchdog`] flags the gaps a delay leaves, the dashboards show
+/// the dropped observations as missing points, and the
+/// [`ShutdownCoordinator`] reports the panic, and still shuts the rest down.
+///
+/// For each observation, in order:
+///
+/// 1. With the panic probability, the actor panics, which drops both of its
+///    channels. Upstream sees its sender fail, and downstream sees the
+///    channel close, just as if a real actor had crashed.
+/// 2. With the drop probability, the observation is dropped.
+/// 3. With the delay probability, the observation is held back for the
+///    delay, in a `Chaos delay` span, before being sent on.
+///
+/// Every injected fault is traced, with a `warn!` event in the
+/// observation's span, and a `fault` field, so it shows up in the
+/// observation's trace, right where it happened. And it's counted, on the
+/// `my_cute_app.chaos_faults` counter, labeled by fault. Chaos is off by
+/// default: with no probabilities set, every observation passes straight
+/// through.
+///
+/// The dice are randomly seeded. Set a seed, with [`Chaos::with_seed`], and
+/// the same observations get the same faults on every run.
+///
+/// ```no_run
+/// use metrics_tracing_example::{Chaos, PipelineBuilder, SysStats};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(2);
+/// let (chaos_tx, chaos_rx) = mpsc::channel(2);
+///
+/// let _pipeline = PipelineBuilder::new(Duration::from_secs(1))
+///     .with_outbound(tx)
+///     .spawn()?;
+/// let _chaos = Chaos::new(rx, chaos_tx)
+///     .with_delay(0.1, Duration::from_secs(3))
+///     .with_drop(0.05)
+///     .with_panic(0.001)
+///     .spawn();
+/// let _stats = SysStats::new(chaos_rx, None).spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Watchdog`]: crate::Watchdog
+/// [`ShutdownCoordinator`]: crate::ShutdownCoordinator
+#[derive(Debug)]
+pub struct Chaos<T: ObservationData = CpuData> {
+    inbound: mpsc::Receiver<Observation<T>>,
+    outbound: mpsc::Sender<Observation<T>>,
+    delay: Option<(f64, Duration)>,
+    drop: f64,
+    panic: f64,
+    dice: Rng,
+}
+
+impl<T: ObservationData> Chaos<T> {
+    /// Create a chaos actor that forwards observations from `inbound` to
+    /// `outbound`, injecting no faults until told to.
+    pub fn new(
+        inbound: mpsc::Receiver<Observation<T>>,
+        outbound: mpsc::Sender<Observation<T>>,
+    ) -> Self {
+        Self {
+            inbound,
+            outbound,
+            delay: None,
+            drop: 0.0,
+            panic: 0.0,
+            dice: Rng::random(),
+        }
+    }
+
+    /// Hold each observation back for `delay`, with the given `probability`.
+    ///
+    /// ## Panics
+    ///
+    /// If `probability` isn't between 0 and 1.
+    pub const fn with_delay(mut self, probability: f64, delay: Duration) -> Self {
+        check_probability(probability);
+        self.delay = Some((probability, delay));
+        self
+    }
+
+    /// Drop each observation with the given `probability`.
+    ///
+    /// ## Panics
+    ///
+    /// If `probability` isn't between 0 and 1.
+    pub const fn with_drop(mut self, probability: f64) -> Self {
+        check_probability(probability);
+        self.drop = probability;
+        self
+    }
+
+    /// Panic on each observation with the given `probability`.
+    ///
+    /// ## Panics
+    ///
+    /// If `probability` isn't between 0 and 1. And, with the given
+    /// `probability`, once spawned.
+    pub const fn with_panic(mut self, probability: f64) -> Self {
+        check_probability(probability);
+        self.panic = probability;
+        self
+    }
+
+    /// Roll the dice from `seed`, for the same faults on every run.
+    pub const fn with_seed(mut self, seed: u64) -> Self {
+        self.dice = Rng::new(seed);
+        self
+    }
+
+    /// Trace and count an injected fault, in the observation's span.
+    fn inject(fault: Fault, obs: &Observation<T>) {
+        obs.span().in_scope(|| {
+            warn!(fault = fault.as_str(), kind = T::KIND, "injected fault");
+        });
+        crate::metrics::record_chaos_fault(fault);
+    }
+
+    /// Spawn the chaos task. It exits when the inbound channel closes, or
+    /// the outbound receiver is dropped, or when it panics on purpose.
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(obs) = self.inbound.recv().await {
+                if self.dice.roll(self.panic) {
+                    Self::inject(Fault::Panic, &obs);
+                    panic!("chaos: injected panic");
+                }
+                if self.dice.roll(self.drop) {
+                    Self::inject(Fault::Drop, &obs);
+                    continue;
+                }
+                if let Some((probability, delay)) = self.delay
+                    && self.dice.roll(probability)
+                {
+                    Self::inject(Fault::Delay, &obs);
+                    let span = info_span!(
+                        parent: obs.span(),
+                        "Chaos delay",
+                        delay_ms = delay.as_millis() as u64
+                    );
+                    tokio::time::sleep(delay).instrument(span).await;
+                }
+
+                if self.outbound.send(obs).await.is_err() {
+                    debug!("chaos receiver dropped, exiting");
+                    break;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{EventMatcher, capture, observation};
+    use tracing::Level;
+
+    #[tokio::test]
+    async fn passes_through_without_faults() {
+        let (tx, rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::channel(4);
+        let chaos = Chaos::new(rx, out_tx).spawn();
+
+        for id in 0..3 {
+            tx.send(info_span!("Observation", id = id).in_scope(|| observation(0, 0.0)))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+
+        let mut passed = 0;
+        while out_rx.recv().await.is_some() {
+            passed += 1;
+        }
+        assert_eq!(passed, 3);
+        chaos.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn traces_every_fault() {
+        let (captured, _guard) = capture();
+        let (tx, rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::channel(4);
+        let chaos = Chaos::new(rx, out_tx).with_drop(1.0).spawn();
+
+        tx.send(info_span!("Observation", id = 1).in_scope(|| observation(0, 0.0)))
+            .await
+            .unwrap();
+        drop(tx);
+        assert!(out_rx.recv().await.is_none());
+        chaos.await.unwrap();
+
+        captured.assert_event(
+            &EventMatcher::new()
+                .with_level(Level::WARN)
+                .with_field("fault", "drop")
+                .in_span("Observation"),
+        );
+
+        let (tx, rx) = mpsc::channel(4);
+        let (out_tx, _out_rx) = mpsc::channel(4);
+        let chaos = Chaos::new(rx, out_tx).with_panic(1.0).spawn();
+        tx.send(info_span!("Observation", id = 2).in_scope(|| observation(0, 0.0)))
+            .await
+            .unwrap();
+
+        assert!(chaos.await.unwrap_err().is_panic());
+        captured.assert_event(&EventMatcher::new().with_field("fault", "panic"));
+    }
+}
@@ -0,0 +1,724 @@
+//! Stats over many hosts at once. See [`Collector`].
+
+use crate::{
+    DEFAULT_BASELINE_WINDOW, DEFAULT_BUSIEST_CORES, DEFAULT_IMBALANCE_THRESHOLD, DEFAULT_WINDOW,
+    Observation, StatsReport, SysStats,
+};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, info_span, warn};
+
+/// The host of observations that aren't tagged with one.
+const UNKNOWN_HOST: &str = "unknown";
+
+/// The `host` label, and event field, of the fleet-wide stats.
+pub const FLEET_HOST: &str = "fleet";
+
+/// How long a host may go without sending an observation before the
+/// [`Collector`] counts it as silent, by default.
+pub const DEFAULT_HOST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of hosts a [`Collector`] keeps stats for, by default.
+pub const DEFAULT_MAX_HOSTS: usize = 1024;
+
+/// Aggregates over the hosts of a fleet. See [`Collector::subscribe_fleet`].
+///
+/// The fleet-wide [`StatsReport`] is computed over every observation of
+/// every host, so a host with 64 CPUs weighs 16 times as much as one with 4,
+/// and a host that reports every second weighs 5 times as much as one that
+/// reports every 5. That's the right answer to "how busy are our CPUs?".
+/// This answers "how busy are our hosts?": each host counts once.
+///
+/// Hosts that have gone silent are left out, so that one that was switched
+/// off at its busiest doesn't stay the hottest forever. The report is
+/// computed when an observation arrives, so once every host is silent, the
+/// last report stands.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct FleetReport {
+    /// The number of hosts that sent an observation within the host timeout.
+    pub reporting_hosts: usize,
+    /// The number of hosts that sent observations once, but not within the
+    /// host timeout.
+    pub silent_hosts: usize,
+    /// The mean of the reporting hosts' average usage percentages.
+    pub average_usage: f64,
+    /// The reporting host with the highest average usage, if any.
+    pub hottest_host: Option<Arc<str>>,
+    /// The average usage percentage of the hottest host.
+    pub hottest_usage: f64,
+}
+
+/// The latest [`StatsReport`] of each host, by host name. See
+/// [`Collector::subscribe_hosts`].
+pub type HostReports = BTreeMap<Arc<str>, StatsReport>;
+
+/// A stats processor for observations from many hosts.
+///
+/// A [`SysStats`] fed by a [`TcpSource`] computes stats over whatever
+/// arrives, in the order it arrives. With one agent, that's fine. With ten,
+/// its window holds the last few observations of whichever hosts sent most
+/// recently, its busiest core is `cpu3` of some host or other, and its trend
+/// is a line through ten different machines. None of it means anything.
+///
+/// The collector keeps the hosts apart. It reads the [host tag] of each
+/// observation, and keeps a separate window, with its own stats, per host.
+/// Each host's stats are what a [`SysStats`] on that host would compute. On
+/// top of those, it keeps one window over every observation from every
+/// host, for the fleet-wide stats: the average usage, spread, and so on,
+/// across all the machines.
+///
+/// Observations from any number of sources can be sent to the one inbound
+/// channel. Clone its sender for each [`TcpSource`] or [`UdpSource`].
+/// Observations that aren't tagged with a host are counted as `unknown`.
+///
+/// ```no_run
+/// use metrics_tracing_example::{Collector, TcpSource, UdpSource};
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(16);
+/// let _tcp = TcpSource::bind("0.0.0.0:7000").await?.spawn(tx.clone());
+/// let _udp = UdpSource::bind("0.0.0.0:7001").await?.spawn(tx);
+///
+/// let collector = Collector::new(rx, None).with_window(30);
+/// let fleet = collector.subscribe();
+/// let hosts = collector.subscribe_hosts();
+/// let _collector = collector.spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## Spans and events
+///
+/// Each host's stats are computed in the observation's span, as a
+/// [`SysStats`] would, and its `finished cpu stats` event, and its gauges,
+/// are labeled with its `host`. The fleet's stats are computed in a `Fleet
+/// stats` span, a child of the observation's, and labeled with the host
+/// [`FLEET_HOST`], so the two can be told apart.
+///
+/// After both, the collector aggregates the hosts' latest reports into a
+/// [`FleetReport`], emits it as a `finished fleet stats` event in the
+/// `Fleet stats` span, and records it on the `my_cute_app.fleet_*` gauges.
+///
+/// ## Missed observations
+///
+/// Agents number their observations, and stamp them with the time they were
+/// taken. When a host's numbers skip, the observations in between were
+/// taken, but never arrived: lost on the network, or dropped by an agent
+/// that couldn't reach the collector. When the numbers don't skip, but the
+/// time between two observations is more than twice the host's usual
+/// interval, the agent didn't take them at all: it, or its host, stalled.
+///
+/// Either way, the collector emits an `observations missed` event, with the
+/// `host`, the number `missed`, and the `cause`, `network` or `stall`, and
+/// counts them on the `my_cute_app.observations_missed` counter. The usual
+/// interval is the median of the last few. Numbers going backwards mean
+/// the agent restarted, and tracking starts over. Observations from agents
+/// too old to number them aren't tracked.
+///
+/// ## Hosts coming and going
+///
+/// Each host's window is created the first time the host is seen. The host
+/// names come from the agents, so the number of hosts is capped, at
+/// [`DEFAULT_MAX_HOSTS`] by default. Once the cap is reached, a new host
+/// takes the place of the hosts that have been silent for longer than the
+/// [host timeout], which are forgotten. If none have, its observations are
+/// left out of the host and fleet stats, with a `debug!` event, until one
+/// has. Until then, silent hosts are kept, and counted as silent in the
+/// [`FleetReport`].
+///
+/// [host tag]: Observation::host
+/// [host timeout]: Collector::with_host_timeout
+/// [`TcpSource`]: crate::TcpSource
+/// [`UdpSource`]: crate::UdpSource
+pub struct Collector {
+    inbound: mpsc::Receiver<Observation>,
+
+    window: usize,
+    baseline_window: usize,
+    imbalance_threshold: f64,
+    busiest_cores: usize,
+    host_timeout: Duration,
+    max_hosts: usize,
+
+    hosts: HashMap<Arc<str>, Host>,
+    /// Driven by hand, like the hosts' stats.
+    fleet: SysStats,
+
+    host_reports: watch::Sender<HostReports>,
+    fleet_reports: watch::Sender<FleetReport>,
+}
+
+/// A single host of the fleet.
+struct Host {
+    /// Driven by hand, through [`SysStats::observe`], rather than spawned.
+    /// Its inbound channel is never used.
+    stats: SysStats,
+    last_seen: Instant,
+    gaps: Gaps,
+}
+
+/// Why observations were missed. See [`Collector`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GapCause {
+    /// Taken and sent, but never received.
+    Network,
+    /// Never taken.
+    Stall,
+}
+
+impl GapCause {
+    pub(crate) const fn as_str(self) -> &'static str {
+        match self {
+            Self::Network => "network",
+            Self::Stall => "stall",
+        }
+    }
+}
+
+/// Observations missed between two that were received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Gap {
+    missed: u64,
+    cause: GapCause,
+    /// The time between the two, by the agent's clock.
+    elapsed: Duration,
+}
+
+/// The number of recent intervals a host's usual interval is the median of.
+const INTERVAL_SAMPLES: usize = 8;
+
+/// Tracks a host's sequence numbers and timestamps, to detect [`Gap`]s.
+#[derive(Debug, Default)]
+struct Gaps {
+    last: Option<(u64, SystemTime)>,
+    /// The most recent times between two consecutive observations.
+    intervals: VecDeque<Duration>,
+}
+
+impl Gaps {
+    /// The host's usual interval: the median of the recent ones.
+    fn interval(&self) -> Option<Duration> {
+        let mut sorted: Vec<_> = self.intervals.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted.get(sorted.len() / 2).copied()
+    }
+
+    /// Track the observation numbered `seq`, taken at `taken_at`, and
+    /// return the gap since the last one, if any.
+    fn observe(&mut self, seq: u64, taken_at: SystemTime) -> Option<Gap> {
+        let last = self.last.replace((seq, taken_at));
+        let (last_seq, last_taken_at) = last?;
+        if seq <= last_seq {
+            // The agent restarted, or the network reordered them. Either
+            // way, start over.
+            self.intervals.clear();
+            return None;
+        }
+        // The agent's clock may have been stepped back. Treat it as no time
+        // at all.
+        let elapsed = taken_at.duration_since(last_taken_at).unwrap_or_default();
+        let skipped = seq - last_seq - 1;
+        if skipped > 0 {
+            return Some(Gap {
+                missed: skipped,
+                cause: GapCause::Network,
+                elapsed,
+            });
+        }
+
+        let Some(interval) = self.interval().filter(|interval| !interval.is_zero()) else {
+            self.intervals.push_back(elapsed);
+            return None;
+        };
+        if elapsed > interval * 2 {
+            let missed = (elapsed.as_secs_f64() / interval.as_secs_f64()).round() as u64 - 1;
+            return Some(Gap {
+                missed,
+                cause: GapCause::Stall,
+                elapsed,
+            });
+        }
+        // After a stall, the agent's sampler catches up on the ticks it
+        // missed, in a burst. Those aren't its usual interval.
+        if elapsed >= interval / 2 {
+            if self.intervals.len() == INTERVAL_SAMPLES {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(elapsed);
+        }
+        None
+    }
+}
+
+impl std::fmt::Debug for Collector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Collector")
+            .field("window", &self.window)
+            .field("baseline_window", &self.baseline_window)
+            .field("imbalance_threshold", &self.imbalance_threshold)
+            .field("busiest_cores", &self.busiest_cores)
+            .field("host_timeout", &self.host_timeout)
+            .field("max_hosts", &self.max_hosts)
+            .field("hosts", &self.hosts.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Collector {
+    /// Create a new collector, that forwards every observation to
+    /// `outbound`, if any, after processing it.
+    pub fn new(
+        inbound: mpsc::Receiver<Observation>,
+        outbound: Option<mpsc::Sender<Observation>>,
+    ) -> Self {
+        let (_, fleet_inbound) = mpsc::channel(1);
+        let fleet = SysStats::new(fleet_inbound, outbound).with_host(FLEET_HOST);
+        Self {
+            inbound,
+            window: DEFAULT_WINDOW,
+            baseline_window: DEFAULT_BASELINE_WINDOW,
+            imbalance_threshold: DEFAULT_IMBALANCE_THRESHOLD,
+            busiest_cores: DEFAULT_BUSIEST_CORES,
+            host_timeout: DEFAULT_HOST_TIMEOUT,
+            max_hosts: DEFAULT_MAX_HOSTS,
+            hosts: HashMap::new(),
+            fleet,
+            host_reports: watch::Sender::default(),
+            fleet_reports: watch::Sender::default(),
+        }
+    }
+
+    /// Compute each host's stats over its last `window` observations, and
+    /// the fleet's over the last `window` observations of each host, i.e.
+    /// `window` times the number of hosts, instead of the default of
+    /// [`DEFAULT_WINDOW`].
+    ///
+    /// The fleet window can't know the number of hosts in advance. It grows
+    /// when a new host shows up, keeping what it holds.
+    ///
+    /// ## Panics
+    ///
+    /// If `window` is zero.
+    pub fn with_window(mut self, window: usize) -> Self {
+        assert!(window > 0, "stats window must be non-zero");
+        self.window = window;
+        self
+    }
+
+    /// Compare each host's average usage against its last `window`
+    /// observations, instead of the default of [`DEFAULT_BASELINE_WINDOW`].
+    /// See [`SysStats::with_baseline_window`].
+    ///
+    /// ## Panics
+    ///
+    /// If `window` is zero.
+    pub fn with_baseline_window(mut self, window: usize) -> Self {
+        assert!(window > 0, "baseline window must be non-zero");
+        self.baseline_window = window;
+        self
+    }
+
+    /// Warn when a host's core imbalance rises above `threshold`, instead of
+    /// the default of [`DEFAULT_IMBALANCE_THRESHOLD`]. See
+    /// [`SysStats::with_imbalance_threshold`].
+    pub const fn with_imbalance_threshold(mut self, threshold: f64) -> Self {
+        self.imbalance_threshold = threshold;
+        self
+    }
+
+    /// Report each host's `count` busiest cores, instead of the default of
+    /// [`DEFAULT_BUSIEST_CORES`]. See [`SysStats::with_busiest_cores`].
+    ///
+    /// ## Panics
+    ///
+    /// If `count` is larger than [`MAX_BUSIEST_CORES`].
+    ///
+    /// [`MAX_BUSIEST_CORES`]: crate::MAX_BUSIEST_CORES
+    pub fn with_busiest_cores(mut self, count: usize) -> Self {
+        self.fleet = self.fleet.with_busiest_cores(count);
+        self.busiest_cores = count;
+        self
+    }
+
+    /// Count hosts that haven't sent an observation for `timeout` as
+    /// silent, and leave them out of the [`FleetReport`], instead of the
+    /// default of [`DEFAULT_HOST_TIMEOUT`]. Make it a few times the agents'
+    /// interval, so that a late observation or two doesn't count.
+    pub const fn with_host_timeout(mut self, timeout: Duration) -> Self {
+        self.host_timeout = timeout;
+        self
+    }
+
+    /// Keep stats for up to `max_hosts` hosts, instead of the default of
+    /// [`DEFAULT_MAX_HOSTS`]. See [hosts coming and going].
+    ///
+    /// ## Panics
+    ///
+    /// If `max_hosts` is zero.
+    ///
+    /// [hosts coming and going]: Collector#hosts-coming-and-going
+    pub const fn with_max_hosts(mut self, max_hosts: usize) -> Self {
+        assert!(max_hosts > 0, "max hosts must be non-zero");
+        self.max_hosts = max_hosts;
+        self
+    }
+
+    /// Subscribe to the fleet-wide [`StatsReport`]s, over the observations
+    /// of every host.
+    pub fn subscribe(&self) -> watch::Receiver<StatsReport> {
+        self.fleet.subscribe()
+    }
+
+    /// Subscribe to each host's latest [`StatsReport`]. A host appears the
+    /// first time it sends an observation.
+    pub fn subscribe_hosts(&self) -> watch::Receiver<HostReports> {
+        self.host_reports.subscribe()
+    }
+
+    /// Subscribe to the [`FleetReport`]s, over the latest report of each
+    /// host.
+    pub fn subscribe_fleet(&self) -> watch::Receiver<FleetReport> {
+        self.fleet_reports.subscribe()
+    }
+
+    /// A stats processor for a single host, configured like the collector.
+    fn host_stats(&self, host: &Arc<str>) -> SysStats {
+        let (_, inbound) = mpsc::channel(1);
+        SysStats::new(inbound, None)
+            .with_host(host.clone())
+            .with_window(self.window)
+            .with_baseline_window(self.baseline_window)
+            .with_imbalance_threshold(self.imbalance_threshold)
+            .with_busiest_cores(self.busiest_cores)
+    }
+
+    /// Process a single observation: compute its host's stats, then the
+    /// fleet's, and publish the host's report, and the fleet aggregates.
+    fn process(&mut self, obs: &Observation) {
+        let host = obs.host().cloned().unwrap_or_else(|| UNKNOWN_HOST.into());
+        let now = Instant::now();
+
+        if !self.hosts.contains_key(&host) {
+            if self.hosts.len() >= self.max_hosts {
+                self.forget_silent_hosts(now);
+            }
+            if self.hosts.len() >= self.max_hosts {
+                obs.in_scope(|_| {
+                    debug!(%host, max_hosts = self.max_hosts, "too many hosts, leaving this one out");
+                });
+                return;
+            }
+            let stats = self.host_stats(&host);
+            self.hosts.insert(
+                host.clone(),
+                Host {
+                    stats,
+                    last_seen: now,
+                    gaps: Gaps::default(),
+                },
+            );
+            // Only ever grown, so that a host coming back after it was
+            // forgotten doesn't empty the fleet's window.
+            let fleet_window = self.window * self.hosts.len();
+            self.fleet.grow_window(fleet_window);
+            obs.in_scope(|_| {
+                info!(%host, hosts = self.hosts.len(), fleet_window, "new host");
+            });
+        }
+        let entry = self.hosts.get_mut(&host).expect("inserted above");
+        entry.last_seen = now;
+        if let Some((seq, taken_at)) = obs.sequence()
+            && let Some(gap) = entry.gaps.observe(seq, taken_at)
+        {
+            obs.in_scope(|_| {
+                warn!(
+                    %host,
+                    missed = gap.missed,
+                    cause = gap.cause.as_str(),
+                    gap_ms = gap.elapsed.as_millis() as u64,
+                    "observations missed"
+                );
+            });
+            crate::metrics::record_observations_missed(&host, gap.cause, gap.missed);
+        }
+
+        obs.span().in_scope(|| entry.stats.observe(obs));
+        let report = entry.stats.latest_report();
+        self.host_reports.send_modify(|reports| {
+            reports.insert(host, report);
+        });
+
+        info_span!(parent: obs.span(), "Fleet stats").in_scope(|| {
+            self.fleet.observe(obs);
+            let report = self.aggregate(now);
+            info!(
+                reporting_hosts = report.reporting_hosts,
+                silent_hosts = report.silent_hosts,
+                average_usage = report.average_usage,
+                hottest_host = report.hottest_host.as_deref(),
+                hottest_usage = report.hottest_usage,
+                "finished fleet stats"
+            );
+            crate::metrics::record_fleet(&report);
+            self.fleet_reports.send_replace(report);
+        });
+    }
+
+    /// Forget the hosts that haven't been seen within the host timeout of
+    /// `now`, to make room for new ones.
+    fn forget_silent_hosts(&mut self, now: Instant) {
+        let timeout = self.host_timeout;
+        let is_silent = |host: &Host| now.saturating_duration_since(host.last_seen) > timeout;
+        let silent: Vec<_> = self
+            .hosts
+            .iter()
+            .filter(|(_, entry)| is_silent(entry))
+            .map(|(host, _)| host.clone())
+            .collect();
+        if silent.is_empty() {
+            return;
+        }
+        for host in &silent {
+            self.hosts.remove(host);
+            info!(%host, "forgetting silent host");
+        }
+        self.host_reports.send_modify(|reports| {
+            reports.retain(|host, _| !silent.contains(host));
+        });
+    }
+
+    /// Aggregate the latest report of each host that was seen within the
+    /// host timeout of `now`.
+    fn aggregate(&self, now: Instant) -> FleetReport {
+        let mut report = FleetReport::default();
+        let mut total_usage = 0.0;
+        let host_reports = self.host_reports.borrow();
+        for (host, host_report) in host_reports.iter() {
+            let reporting = self.hosts.get(host).is_some_and(|entry| {
+                now.saturating_duration_since(entry.last_seen) <= self.host_timeout
+            });
+            if !reporting {
+                report.silent_hosts += 1;
+                continue;
+            }
+            report.reporting_hosts += 1;
+            total_usage += host_report.average_usage;
+            if report.hottest_host.is_none() || host_report.average_usage > report.hottest_usage {
+                report.hottest_host = Some(host.clone());
+                report.hottest_usage = host_report.average_usage;
+            }
+        }
+        if report.reporting_hosts > 0 {
+            report.average_usage = total_usage / report.reporting_hosts as f64;
+        }
+        report
+    }
+
+    /// Spawn the collector task. It runs until the inbound channel is
+    /// closed, i.e. until every source has exited, and forwards every
+    /// observation downstream, like a [`SysStats`].
+    pub fn spawn(mut self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(obs) = self.inbound.recv().await {
+                self.process(&obs);
+                self.fleet.forward(obs).await;
+            }
+            debug!(
+                hosts = self.hosts.len(),
+                "Inbound channel closed, closing collector"
+            );
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CpuStats, testing::observation};
+
+    #[test]
+    fn hosts_get_their_own_windows() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut collector = Collector::new(rx, None).with_window(2);
+        let fleet = collector.subscribe();
+        let hosts = collector.subscribe_hosts();
+
+        collector.process(&observation(2, 10.0).with_host("a"));
+        collector.process(&observation(2, 50.0).with_host("b"));
+        collector.process(&observation(2, 30.0).with_host("a"));
+        collector.process(&observation(2, 70.0).with_host("b"));
+
+        let hosts = hosts.borrow();
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts["a"].observations, 2);
+        assert_eq!(hosts["a"].average_usage, 20.0);
+        assert_eq!(hosts["b"].average_usage, 60.0);
+
+        // Host `b` grew the fleet window to 4, keeping `a`'s first.
+        let fleet = fleet.borrow();
+        assert_eq!(fleet.observations, 4);
+        assert_eq!(fleet.average_usage, 40.0);
+    }
+
+    #[test]
+    fn fleet_aggregates_count_each_host_once() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut collector = Collector::new(rx, None).with_window(2);
+        let fleet = collector.subscribe_fleet();
+
+        collector.process(&observation(2, 10.0).with_host("a"));
+        collector.process(&observation(2, 10.0).with_host("a"));
+        collector.process(&observation(2, 50.0).with_host("b"));
+        collector.process(&observation(2, 90.0).with_host("c"));
+
+        let report = fleet.borrow().clone();
+        assert_eq!(report.reporting_hosts, 3);
+        assert_eq!(report.silent_hosts, 0);
+        assert_eq!(report.average_usage, 50.0);
+        assert_eq!(report.hottest_host.as_deref(), Some("c"));
+        assert_eq!(report.hottest_usage, 90.0);
+    }
+
+    #[test]
+    fn silent_hosts_are_left_out() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut collector = Collector::new(rx, None).with_host_timeout(Duration::from_secs(30));
+        collector.process(&observation(2, 10.0).with_host("a"));
+        collector.process(&observation(2, 90.0).with_host("b"));
+
+        // A minute later, `a` is still sending, and `b` has gone quiet.
+        let later = Instant::now() + Duration::from_secs(60);
+        collector.hosts.get_mut("a").unwrap().last_seen = later;
+        let report = collector.aggregate(later);
+        assert_eq!(report.reporting_hosts, 1);
+        assert_eq!(report.silent_hosts, 1);
+        assert_eq!(report.average_usage, 10.0);
+        assert_eq!(report.hottest_host.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn new_hosts_replace_silent_ones_past_the_cap() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut collector = Collector::new(rx, None)
+            .with_window(2)
+            .with_max_hosts(2)
+            .with_host_timeout(Duration::from_secs(30));
+        let hosts = collector.subscribe_hosts();
+        let fleet = collector.subscribe();
+        collector.process(&observation(2, 10.0).with_host("a"));
+        collector.process(&observation(2, 20.0).with_host("b"));
+
+        // Nobody is silent, so there's no room for `c`.
+        collector.process(&observation(2, 90.0).with_host("c"));
+        assert!(!hosts.borrow().contains_key("c"));
+        assert_eq!(fleet.borrow().observations, 2);
+
+        // Once `a` has gone quiet, `c` takes its place.
+        let earlier = Instant::now() - Duration::from_secs(60);
+        collector.hosts.get_mut("a").unwrap().last_seen = earlier;
+        collector.process(&observation(2, 90.0).with_host("c"));
+        let names: Vec<_> = hosts.borrow().keys().cloned().collect();
+        assert_eq!(names, [Arc::from("b"), Arc::from("c")]);
+        assert_eq!(fleet.borrow().observations, 3);
+    }
+
+    /// Observation `seq`, taken `secs` seconds after the epoch.
+    fn numbered(gaps: &mut Gaps, seq: u64, secs: u64) -> Option<Gap> {
+        gaps.observe(seq, SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn skipped_numbers_are_network_loss() {
+        let mut gaps = Gaps::default();
+        assert_eq!(numbered(&mut gaps, 0, 0), None);
+        assert_eq!(numbered(&mut gaps, 1, 1), None);
+
+        let gap = numbered(&mut gaps, 4, 4).unwrap();
+        assert_eq!(gap.missed, 2);
+        assert_eq!(gap.cause, GapCause::Network);
+        assert_eq!(gap.elapsed, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn long_silences_are_stalls() {
+        let mut gaps = Gaps::default();
+        assert_eq!(numbered(&mut gaps, 0, 0), None);
+        assert_eq!(numbered(&mut gaps, 1, 2), None);
+        assert_eq!(numbered(&mut gaps, 2, 4), None);
+        // A late observation isn't a stall.
+        assert_eq!(numbered(&mut gaps, 3, 7), None);
+
+        let gap = numbered(&mut gaps, 4, 13).unwrap();
+        assert_eq!(gap.missed, 2);
+        assert_eq!(gap.cause, GapCause::Stall);
+    }
+
+    #[test]
+    fn catching_up_after_a_stall_is_not_a_stall() {
+        let mut gaps = Gaps::default();
+        for seq in 0..4 {
+            assert_eq!(numbered(&mut gaps, seq, seq), None);
+        }
+        assert_eq!(numbered(&mut gaps, 4, 10).unwrap().cause, GapCause::Stall);
+
+        // The sampler bursts through the ticks it missed, then carries on.
+        for seq in 5..10 {
+            assert_eq!(numbered(&mut gaps, seq, 10), None);
+        }
+        assert_eq!(numbered(&mut gaps, 10, 11), None);
+        assert_eq!(numbered(&mut gaps, 11, 12), None);
+    }
+
+    #[test]
+    fn restarts_start_over() {
+        let mut gaps = Gaps::default();
+        assert_eq!(numbered(&mut gaps, 10, 10), None);
+        assert_eq!(numbered(&mut gaps, 11, 11), None);
+
+        // The agent restarted, and took a while about it.
+        assert_eq!(numbered(&mut gaps, 0, 60), None);
+        assert_eq!(numbered(&mut gaps, 1, 61), None);
+        assert_eq!(numbered(&mut gaps, 2, 62), None);
+    }
+
+    #[test]
+    fn collector_tracks_gaps_per_host() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut collector = Collector::new(rx, None);
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+
+        collector.process(&observation(2, 10.0).with_host("a").with_sequence(0, at(0)));
+        collector.process(&observation(2, 10.0).with_host("b").with_sequence(5, at(0)));
+        collector.process(&observation(2, 10.0).with_host("a").with_sequence(1, at(1)));
+
+        // `b`'s numbers didn't skip: `a`'s are its own.
+        let b = &mut collector.hosts.get_mut("b").unwrap().gaps;
+        assert_eq!(numbered(b, 6, 1), None);
+        let a = &mut collector.hosts.get_mut("a").unwrap().gaps;
+        assert_eq!(numbered(a, 3, 3).unwrap().missed, 1);
+    }
+
+    #[tokio::test]
+    async fn untagged_observations_are_unknown() {
+        let (tx, rx) = mpsc::channel(1);
+        let (out_tx, mut out_rx) = mpsc::channel(1);
+        let collector = Collector::new(rx, Some(out_tx));
+        let hosts = collector.subscribe_hosts();
+        let handle = collector.spawn();
+
+        let cpus: Arc<[CpuStats]> = observation(2, 0.0).with_host("").cpus().clone();
+        tx.send(Observation::new(cpus, tracing::Span::none()))
+            .await
+            .unwrap();
+        assert!(out_rx.recv().await.is_some());
+        drop(tx);
+        handle.await.unwrap();
+
+        assert!(hosts.borrow().contains_key(UNKNOWN_HOST));
+    }
+}
@@ -0,0 +1,475 @@
+//! One serializable configuration for the whole pipeline. See
+//! [`PipelineConfig`].
+
+use crate::{
+    ConfigError, CpuView, DEFAULT_BASELINE_WINDOW, DEFAULT_BUSIEST_CORES, DEFAULT_CHANNEL_CAPACITY,
+    DEFAULT_EMF_NAMESPACE, DEFAULT_IMBALANCE_THRESHOLD, DEFAULT_LABEL_LIMIT,
+    DEFAULT_SHUTDOWN_TIMEOUT, DEFAULT_WINDOW, EmfSink, Eviction, LogFormat, MissedTicks,
+    OtlpProtocol, PipelineBuilder, StatsReport, TargetSampler, TracingBuilder,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::sync::watch;
+
+/// The default observation interval.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The default port to serve Prometheus metrics on.
+pub const DEFAULT_METRICS_PORT: u16 = 9000;
+
+/// Everything needed to run the pipeline, in one place: the observation
+/// interval, the stats window, the channels, metrics, tracing, sinks, and
+/// alerting.
+///
+/// The builders ([`PipelineBuilder`], [`TracingBuilder`], and friends) are
+/// the right tool when configuration lives in code. When it lives in a file,
+/// or comes from your application's own config system, one plain struct is
+/// easier to work with. It derives [`Deserialize`], so it can be read from
+/// any format `serde` supports. Every field has a default, so a config only
+/// needs the fields it changes, and unknown fields are rejected, so a typo
+/// is an error instead of a silently ignored setting.
+///
+/// Durations are written with a unit suffix, as in `sysmon`'s flags: `500ms`,
+/// `5s`, or `1m`.
+///
+/// Hand it to [`Pipeline::from_config`] to start everything at once, or use
+/// [`PipelineBuilder::from_config`] and [`TracingConfig::builder`] to take
+/// only the parts you want.
+///
+/// ```
+/// use metrics_tracing_example::PipelineConfig;
+/// use std::time::Duration;
+///
+/// let config: PipelineConfig = serde_json::from_str(
+///     r#"{
+///         "interval": "1s",
+///         "window": 30,
+///         "tracing": { "format": "json", "otlp": false },
+///         "sinks": { "rollup": "1m" },
+///         "alerting": { "watchdog": "10s" }
+///     }"#,
+/// )?;
+///
+/// assert_eq!(config.interval, Duration::from_secs(1));
+/// assert_eq!(config.metrics.port, Some(9000));
+/// config.validate()?;
+/// # Ok::<_, Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`Pipeline::from_config`]: crate::Pipeline::from_config
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// How often to take an observation. Defaults to [`DEFAULT_INTERVAL`].
+    #[serde(with = "duration")]
+    pub interval: Duration,
+    /// The number of observations to compute stats over. Defaults to
+    /// [`DEFAULT_WINDOW`].
+    pub window: usize,
+    /// The number of observations to compare the window's average usage
+    /// against. Defaults to [`DEFAULT_BASELINE_WINDOW`].
+    pub baseline_window: usize,
+    /// What to do with the window once it's full. Defaults to
+    /// [`Eviction::Sliding`].
+    pub eviction: Eviction,
+    /// Compute stats once every this many observations. Defaults to 1,
+    /// every observation.
+    pub emit_every: usize,
+    /// The number of busiest cores to report. Defaults to
+    /// [`DEFAULT_BUSIEST_CORES`].
+    pub busiest_cores: usize,
+    /// The capacity of the channel between the monitor and the stats
+    /// processor. Defaults to [`DEFAULT_CHANNEL_CAPACITY`].
+    pub channel_capacity: usize,
+    /// Link each observation's span to the previous one's.
+    pub span_links: bool,
+    /// What the monitor does about ticks it missed. Defaults to
+    /// [`MissedTicks::Burst`].
+    pub missed_ticks: MissedTicks,
+    /// What CPU usage percentages are relative to. Defaults to
+    /// [`CpuView::Host`].
+    pub cpu_view: CpuView,
+    /// The Prometheus exporter.
+    pub metrics: MetricsConfig,
+    /// The tracing subscriber and OTLP export.
+    pub tracing: TracingConfig,
+    /// Where observations go after the stats processor.
+    pub sinks: SinksConfig,
+    /// When to complain.
+    pub alerting: AlertingConfig,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+            window: DEFAULT_WINDOW,
+            baseline_window: DEFAULT_BASELINE_WINDOW,
+            eviction: Eviction::Sliding,
+            emit_every: 1,
+            busiest_cores: DEFAULT_BUSIEST_CORES,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            span_links: false,
+            missed_ticks: MissedTicks::Burst,
+            cpu_view: CpuView::Host,
+            metrics: MetricsConfig::default(),
+            tracing: TracingConfig::default(),
+            sinks: SinksConfig::default(),
+            alerting: AlertingConfig::default(),
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Check the configuration, returning the first problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        PipelineBuilder::from_config(self).validate()?;
+        if !(0.0..=1.0).contains(&self.tracing.sample_ratio) {
+            return Err(ConfigError::InvalidSampleRatio);
+        }
+        if self.sinks.rollup.is_some_and(|period| period.is_zero()) {
+            return Err(ConfigError::ZeroRollupPeriod);
+        }
+        Ok(())
+    }
+}
+
+/// The metrics section of a [`PipelineConfig`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// The port to serve Prometheus metrics on. Defaults to
+    /// [`DEFAULT_METRICS_PORT`]. Set to `null` to skip installing the
+    /// exporter, e.g. because your application installs its own recorder.
+    pub port: Option<u16>,
+    /// The maximum number of distinct values per metric label. Defaults to
+    /// [`DEFAULT_LABEL_LIMIT`]. See [`set_label_limit`].
+    ///
+    /// [`set_label_limit`]: crate::set_label_limit
+    pub max_label_values: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            port: Some(DEFAULT_METRICS_PORT),
+            max_label_values: DEFAULT_LABEL_LIMIT,
+        }
+    }
+}
+
+/// The tracing section of a [`PipelineConfig`]. Unset values fall back to
+/// the same env vars as the [`TracingBuilder`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    /// The console log filter, using `RUST_LOG` syntax. Defaults to
+    /// `RUST_LOG`.
+    pub log_filter: Option<String>,
+    /// The console output format.
+    pub format: LogFormat,
+    /// Write logs to the console. Defaults to `true`.
+    pub console: bool,
+    /// Also write events to the systemd journal. See
+    /// [`TracingBuilder::with_journald`]. Off by default.
+    #[cfg(feature = "journald")]
+    pub journald: bool,
+    /// Export spans over OTLP. Defaults to `true`.
+    pub otlp: bool,
+    /// The base URL of the OTLP collector. Defaults to
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT`, or the protocol's standard endpoint.
+    pub otlp_endpoint: Option<String>,
+    /// The OTLP transport. Defaults to `OTEL_EXPORTER_OTLP_PROTOCOL`.
+    pub otlp_protocol: Option<OtlpProtocol>,
+    /// The fraction of traces to export, between 0 and 1. Spans at WARN
+    /// level or above are always exported. Defaults to 1.
+    pub sample_ratio: f64,
+    /// How long to wait for the last spans to be exported on shutdown.
+    /// Defaults to [`DEFAULT_SHUTDOWN_TIMEOUT`].
+    #[serde(with = "duration")]
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            log_filter: None,
+            format: LogFormat::default(),
+            console: true,
+            #[cfg(feature = "journald")]
+            journald: false,
+            otlp: true,
+            otlp_endpoint: None,
+            otlp_protocol: None,
+            sample_ratio: 1.0,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+}
+
+impl TracingConfig {
+    /// A [`TracingBuilder`] with this configuration. Add any layers you
+    /// want, then call [`TracingBuilder::init`].
+    pub fn builder(&self) -> TracingBuilder {
+        let mut builder = TracingBuilder::new()
+            .with_format(self.format)
+            .with_console(self.console)
+            .with_shutdown_timeout(self.shutdown_timeout);
+        if let Some(directives) = &self.log_filter {
+            builder = builder.with_log_filter(directives);
+        }
+        #[cfg(feature = "otlp")]
+        {
+            builder = builder.with_otlp(self.otlp);
+            if let Some(endpoint) = &self.otlp_endpoint {
+                builder = builder.with_otlp_endpoint(endpoint);
+            }
+            if let Some(protocol) = self.otlp_protocol {
+                builder = builder.with_otlp_protocol(protocol);
+            }
+        }
+        #[cfg(feature = "journald")]
+        if self.journald {
+            builder = builder.with_journald();
+        }
+        if self.sample_ratio < 1.0 {
+            builder = builder.with_sampler(TargetSampler::new(self.sample_ratio));
+        }
+        builder
+    }
+}
+
+/// The sinks section of a [`PipelineConfig`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinksConfig {
+    /// Roll observations up into min/avg/max aggregates over periods of
+    /// this length. See [`Rollup`]. Off by default.
+    ///
+    /// [`Rollup`]: crate::Rollup
+    #[serde(with = "duration::option")]
+    pub rollup: Option<Duration>,
+    /// Write each stats report as a CloudWatch EMF line. See [`EmfSink`].
+    /// Off by default.
+    pub emf: Option<EmfConfig>,
+}
+
+/// The CloudWatch EMF sink of a [`SinksConfig`]. See [`EmfSink`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmfConfig {
+    /// The CloudWatch namespace. Defaults to [`DEFAULT_EMF_NAMESPACE`].
+    pub namespace: String,
+    /// Dimensions added to every metric, by name.
+    pub dimensions: BTreeMap<String, String>,
+    /// Send lines to this CloudWatch Logs log group, instead of writing them
+    /// to stdout. See [`EmfSink::with_log_group`].
+    #[cfg(feature = "cloudwatch")]
+    pub log_group: Option<String>,
+    /// The log stream in `log_group`. Defaults to the host name.
+    #[cfg(feature = "cloudwatch")]
+    pub log_stream: Option<String>,
+}
+
+impl Default for EmfConfig {
+    fn default() -> Self {
+        Self {
+            namespace: DEFAULT_EMF_NAMESPACE.to_owned(),
+            dimensions: BTreeMap::new(),
+            #[cfg(feature = "cloudwatch")]
+            log_group: None,
+            #[cfg(feature = "cloudwatch")]
+            log_stream: None,
+        }
+    }
+}
+
+impl EmfConfig {
+    /// An [`EmfSink`] with this configuration, reading from `reports`.
+    pub fn sink(&self, reports: watch::Receiver<StatsReport>) -> EmfSink {
+        let mut sink = EmfSink::new(reports).with_namespace(&self.namespace);
+        for (name, value) in &self.dimensions {
+            sink = sink.with_dimension(name, value);
+        }
+        #[cfg(feature = "cloudwatch")]
+        if let Some(group) = &self.log_group {
+            let stream = self
+                .log_stream
+                .clone()
+                .or_else(sysinfo::System::host_name)
+                .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_owned());
+            sink = sink.with_log_group(group, stream);
+        }
+        sink
+    }
+}
+
+/// The alerting section of a [`PipelineConfig`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertingConfig {
+    /// Warn when the busiest and idlest core's average usage over the window
+    /// differ by more than this many percentage points. Defaults to
+    /// [`DEFAULT_IMBALANCE_THRESHOLD`].
+    pub imbalance_threshold: f64,
+    /// Flag the monitor as stalled if it goes this long without producing
+    /// an observation. See [`Watchdog`]. Off by default.
+    ///
+    /// [`Watchdog`]: crate::Watchdog
+    #[serde(with = "duration::option")]
+    pub watchdog: Option<Duration>,
+    /// Notify systemd of readiness, and ping its watchdog after every
+    /// observation. See [`SysMonitor::with_systemd`]. Off by default.
+    ///
+    /// [`SysMonitor::with_systemd`]: crate::SysMonitor::with_systemd
+    #[cfg(feature = "systemd")]
+    pub systemd: bool,
+}
+
+impl Default for AlertingConfig {
+    fn default() -> Self {
+        Self {
+            imbalance_threshold: DEFAULT_IMBALANCE_THRESHOLD,
+            watchdog: None,
+            #[cfg(feature = "systemd")]
+            systemd: false,
+        }
+    }
+}
+
+/// Parse a duration with a unit suffix: `ms`, `s`, or `m`.
+///
+/// ```
+/// use metrics_tracing_example::parse_duration;
+/// use std::time::Duration;
+///
+/// assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+/// assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+/// assert!(parse_duration("5").is_err());
+/// ```
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, to_duration): (&str, fn(u64) -> Duration) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, Duration::from_millis)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, Duration::from_secs)
+    } else if let Some(mins) = s.strip_suffix('m') {
+        (mins, |m| Duration::from_secs(m * 60))
+    } else {
+        return Err(format!(
+            "missing unit in `{s}`, expected one of `ms`, `s`, `m`"
+        ));
+    };
+
+    value
+        .trim()
+        .parse()
+        .map(to_duration)
+        .map_err(|e| format!("invalid duration `{s}`: {e}"))
+}
+
+/// Durations as strings with a unit suffix. See [`parse_duration`].
+///
+/// Durations are written in the largest unit that represents them exactly.
+/// Anything finer than a millisecond is truncated.
+mod duration {
+    use super::parse_duration;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+    use std::time::Duration;
+
+    pub(super) fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        let ms = d.as_millis();
+        if ms.is_multiple_of(60_000) && ms > 0 {
+            s.collect_str(&format_args!("{}m", ms / 60_000))
+        } else if ms.is_multiple_of(1_000) {
+            s.collect_str(&format_args!("{}s", ms / 1_000))
+        } else {
+            s.collect_str(&format_args!("{ms}ms"))
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let s = String::deserialize(d)?;
+        parse_duration(&s).map_err(D::Error::custom)
+    }
+
+    pub(super) mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::time::Duration;
+
+        pub(crate) fn serialize<S: Serializer>(
+            d: &Option<Duration>,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            match d {
+                Some(d) => super::serialize(d, s),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            #[derive(Deserialize)]
+            struct Wrapper(#[serde(with = "super")] Duration);
+
+            Ok(Option::<Wrapper>::deserialize(d)?.map(|Wrapper(d)| d))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trips_through_json() {
+        let config = PipelineConfig {
+            interval: Duration::from_millis(1_500),
+            missed_ticks: MissedTicks::Skip,
+            cpu_view: CpuView::Cgroup,
+            eviction: Eviction::Tumbling,
+            sinks: SinksConfig {
+                rollup: Some(Duration::from_secs(120)),
+                emf: Some(EmfConfig::default()),
+            },
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["interval"], "1500ms");
+        assert_eq!(json["missed_ticks"], "skip");
+        assert_eq!(json["cpu_view"], "cgroup");
+        assert_eq!(json["eviction"], "tumbling");
+        assert_eq!(json["sinks"]["rollup"], "2m");
+        assert_eq!(json["sinks"]["emf"]["namespace"], DEFAULT_EMF_NAMESPACE);
+        assert_eq!(json["alerting"]["watchdog"], serde_json::Value::Null);
+
+        let back: PipelineConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(back, config);
+    }
+
+    #[test]
+    fn missing_fields_take_defaults() {
+        let config: PipelineConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, PipelineConfig::default());
+        config.validate().unwrap();
+
+        let typo = serde_json::from_str::<PipelineConfig>(r#"{ "windw": 30 }"#);
+        assert!(typo.is_err());
+    }
+
+    #[test]
+    fn validate_checks_every_section() {
+        let mut config = PipelineConfig::default();
+        config.tracing.sample_ratio = 1.5;
+        assert_eq!(config.validate(), Err(ConfigError::InvalidSampleRatio));
+
+        let mut config = PipelineConfig::default();
+        config.sinks.rollup = Some(Duration::ZERO);
+        assert_eq!(config.validate(), Err(ConfigError::ZeroRollupPeriod));
+
+        let mut config = PipelineConfig::default();
+        config.alerting.watchdog = Some(Duration::ZERO);
+        assert_eq!(config.validate(), Err(ConfigError::ZeroWatchdogTolerance));
+    }
+}
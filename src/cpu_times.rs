@@ -0,0 +1,169 @@
+//! The per-CPU time breakdown. See [`CpuTimesReader`].
+
+use crate::CpuTimes;
+
+/// Reads the [`CpuTimes`] of each CPU from `/proc/stat`, on Linux. On other
+/// platforms it reads nothing, and every CPU's times are `None`.
+///
+/// ## `/proc/stat`
+///
+/// For each CPU, the kernel counts the time spent in each mode since boot,
+/// in clock ticks. A line looks like this:
+///
+/// ```text
+/// cpu0 4705 150 1120 16250 520 0 34 0 0 0
+/// ```
+///
+/// The columns are user, nice, system, idle, iowait, irq, softirq, steal,
+/// guest and guest_nice. Guest time is already counted in user time, so we
+/// ignore it. Totals since boot say little about _now_, so the reader keeps
+/// the previous read, and reports each mode's share of the ticks in between.
+/// This is how `top` computes the `us`, `sy`, `wa` and `st` in its header.
+#[derive(Debug, Default)]
+pub(crate) struct CpuTimesReader {
+    #[cfg(target_os = "linux")]
+    previous: Vec<Ticks>,
+    #[cfg(target_os = "linux")]
+    current: Vec<Ticks>,
+    /// Reused between reads, so that reading doesn't allocate.
+    #[cfg(target_os = "linux")]
+    contents: String,
+}
+
+/// The tick counters of a single CPU.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default, Clone, Copy)]
+struct Ticks {
+    user: u64,
+    system: u64,
+    iowait: u64,
+    steal: u64,
+    total: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl Ticks {
+    /// Parse a `cpuN` line. Older kernels have fewer columns, which count as
+    /// zero.
+    fn parse(line: &str) -> Self {
+        let mut columns = line.split_ascii_whitespace().skip(1);
+        let [user, nice, system, idle, iowait, irq, softirq, steal] =
+            std::array::from_fn(|_| columns.next().and_then(|c| c.parse().ok()).unwrap_or(0));
+        Self {
+            user: user + nice,
+            system: system + irq + softirq,
+            iowait,
+            steal,
+            total: user + nice + system + idle + iowait + irq + softirq + steal,
+        }
+    }
+
+    /// Each mode's share of the ticks since `previous`.
+    fn since(self, previous: Self) -> Option<CpuTimes> {
+        let total = self.total.checked_sub(previous.total).filter(|&t| t > 0)? as f32;
+        let share = |now: u64, before: u64| now.saturating_sub(before) as f32 / total;
+        Some(CpuTimes {
+            user: share(self.user, previous.user),
+            system: share(self.system, previous.system),
+            iowait: share(self.iowait, previous.iowait),
+            steal: share(self.steal, previous.steal),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl CpuTimesReader {
+    /// Read the counters again. Call this right after refreshing the
+    /// `System`, so that the times and the usage cover the same interval.
+    pub(crate) fn refresh(&mut self) -> std::io::Result<()> {
+        use std::io::Read;
+
+        self.contents.clear();
+        std::fs::File::open("/proc/stat")?.read_to_string(&mut self.contents)?;
+
+        std::mem::swap(&mut self.previous, &mut self.current);
+        self.current.clear();
+        // The `cpu` line is the sum over all CPUs. `cpu0`, `cpu1`, ... follow.
+        let cpus = self
+            .contents
+            .lines()
+            .filter(|line| line.starts_with("cpu") && !line.starts_with("cpu "));
+        self.current.extend(cpus.map(Ticks::parse));
+        Ok(())
+    }
+
+    /// The times of the CPU at `index`. `/proc/stat` lists the online CPUs in
+    /// the same order as `sysinfo`. `None` until there have been two reads.
+    pub(crate) fn times(&self, index: usize) -> Option<CpuTimes> {
+        let current = self.current.get(index)?;
+        current.since(*self.previous.get(index)?)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl CpuTimesReader {
+    /// There is nothing to read outside of Linux.
+    pub(crate) fn refresh(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Always `None` outside of Linux.
+    pub(crate) fn times(&self, _index: usize) -> Option<CpuTimes> {
+        None
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_full_line() {
+        let ticks = Ticks::parse("cpu0 4705 150 1120 16250 520 0 34 10 7 0");
+        assert_eq!(ticks.user, 4855);
+        assert_eq!(ticks.system, 1154);
+        assert_eq!(ticks.iowait, 520);
+        assert_eq!(ticks.steal, 10);
+        // Guest time is already in user time.
+        assert_eq!(ticks.total, 4705 + 150 + 1120 + 16250 + 520 + 34 + 10);
+    }
+
+    #[test]
+    fn missing_and_garbled_columns_count_as_zero() {
+        let short = Ticks::parse("cpu0 100 0 50 850");
+        assert_eq!((short.iowait, short.steal, short.total), (0, 0, 1000));
+
+        let garbled = Ticks::parse("cpu0 100 x 50 850 -3");
+        assert_eq!(
+            (garbled.user, garbled.iowait, garbled.total),
+            (100, 0, 1000)
+        );
+
+        assert_eq!(Ticks::parse("cpu0").total, 0);
+    }
+
+    #[test]
+    fn shares_are_of_the_ticks_in_between() {
+        let before = Ticks::parse("cpu0 100 0 50 850 0 0 0 0");
+        let after = Ticks::parse("cpu0 150 0 70 870 5 0 0 5");
+        let times = after.since(before).unwrap();
+        assert_eq!(times.user, 0.5);
+        assert_eq!(times.system, 0.2);
+        assert_eq!(times.iowait, 0.05);
+        assert_eq!(times.steal, 0.05);
+    }
+
+    #[test]
+    fn wrapped_or_stalled_counters_report_nothing() {
+        let before = Ticks::parse("cpu0 100 0 50 850 0 0 0 0");
+        // No ticks in between.
+        assert_eq!(before.since(before), None);
+        // The total went backwards, e.g. a counter wrapped, or the CPU went
+        // offline and came back.
+        let wrapped = Ticks::parse("cpu0 10 0 5 85 0 0 0 0");
+        assert_eq!(wrapped.since(before), None);
+        // One mode went backwards, but the total didn't: it counts as zero.
+        let odd = Ticks::parse("cpu0 90 0 80 900 0 0 0 0");
+        assert_eq!(odd.since(before).unwrap().user, 0.0);
+    }
+}
@@ -0,0 +1,166 @@
+//! Datadog unified service tagging and log-trace correlation. See
+//! [`LogFormat::Datadog`].
+//!
+//! [`LogFormat::Datadog`]: crate::LogFormat::Datadog
+
+use opentelemetry::{KeyValue, SpanId, TraceId};
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::{
+    fmt::{
+        FmtContext, FormatEvent, FormatFields,
+        format::Writer,
+        time::{FormatTime, SystemTime},
+    },
+    registry::LookupSpan,
+};
+
+/// Datadog's unified service tags, as env vars. The Datadog agent sets
+/// these for you in most deployments.
+const DD_SERVICE: &str = "DD_SERVICE";
+const DD_ENV: &str = "DD_ENV";
+const DD_VERSION: &str = "DD_VERSION";
+
+/// The resource attributes Datadog's OTLP intake reads its unified service
+/// tags from, set from `DD_SERVICE`, `DD_ENV`, and `DD_VERSION`.
+///
+/// The environment is set under both its current and its deprecated name.
+/// Agents older than 7.58 only read `deployment.environment`.
+pub(crate) fn resource_attributes() -> Vec<KeyValue> {
+    let mut attributes = Vec::new();
+    if let Some(service) = env(DD_SERVICE) {
+        attributes.push(KeyValue::new("service.name", service));
+    }
+    if let Some(environment) = env(DD_ENV) {
+        attributes.push(KeyValue::new(
+            "deployment.environment.name",
+            environment.clone(),
+        ));
+        attributes.push(KeyValue::new("deployment.environment", environment));
+    }
+    if let Some(version) = env(DD_VERSION) {
+        attributes.push(KeyValue::new("service.version", version));
+    }
+    attributes
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Datadog's trace ID for an OTEL trace ID. Datadog IDs are 64 bits, printed
+/// in decimal, so it takes the low 64 bits of the 128 bit OTEL ID.
+fn dd_trace_id(trace_id: TraceId) -> String {
+    let bytes = trace_id.to_bytes();
+    let low: [u8; 8] = bytes[8..].try_into().expect("8 bytes");
+    u64::from_be_bytes(low).to_string()
+}
+
+/// Datadog's span ID for an OTEL span ID: the same 64 bits, in decimal.
+fn dd_span_id(span_id: SpanId) -> String {
+    u64::from_be_bytes(span_id.to_bytes()).to_string()
+}
+
+/// Collects an event's fields into a JSON object.
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonFields<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+/// Formats events as JSON lines that the Datadog agent's log collection
+/// understands, with the trace correlation attributes it looks for.
+///
+/// See [`LogFormat::Datadog`].
+///
+/// [`LogFormat::Datadog`]: crate::LogFormat::Datadog
+#[derive(Debug, Clone)]
+pub(crate) struct DatadogFormat {
+    service: Option<String>,
+    env: Option<String>,
+    version: Option<String>,
+}
+
+impl DatadogFormat {
+    /// Read the unified service tags from `DD_SERVICE`, `DD_ENV`, and
+    /// `DD_VERSION`.
+    pub(crate) fn from_env() -> Self {
+        Self {
+            service: env(DD_SERVICE),
+            env: env(DD_ENV),
+            version: env(DD_VERSION),
+        }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for DatadogFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut line = Map::new();
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        line.insert("timestamp".into(), timestamp.into());
+        line.insert("status".into(), metadata.level().as_str().into());
+        line.insert("logger.name".into(), metadata.target().into());
+        event.record(&mut JsonFields(&mut line));
+
+        if let Some(span) = ctx.parent_span() {
+            let extensions = span.extensions();
+            if let Some(otel) = extensions.get::<OtelData>()
+                && let (Some(trace_id), Some(span_id)) = (otel.trace_id(), otel.span_id())
+            {
+                line.insert("dd.trace_id".into(), dd_trace_id(trace_id).into());
+                line.insert("dd.span_id".into(), dd_span_id(span_id).into());
+            }
+        }
+        for (key, value) in [
+            ("dd.service", &self.service),
+            ("dd.env", &self.env),
+            ("dd.version", &self.version),
+        ] {
+            if let Some(value) = value {
+                line.insert(key.into(), value.as_str().into());
+            }
+        }
+
+        let line = serde_json::to_string(&line).map_err(|_| fmt::Error)?;
+        writeln!(writer, "{line}")
+    }
+}
@@ -0,0 +1,206 @@
+//! One actor fed by many monitors. See [`Dispatcher`].
+
+use crate::{CpuData, MemoryObservation, MemoryUsage, Observation, ObservationData};
+use tokio::sync::mpsc;
+use tracing::{debug, trace};
+
+/// Anything a monitor can observe. See [`Dispatcher`].
+#[derive(Debug)]
+pub enum Sample {
+    /// CPU stats, from a [`SysMonitor`].
+    ///
+    /// [`SysMonitor`]: crate::SysMonitor
+    Cpu(Observation),
+    /// Memory usage, from a [`MemoryMonitor`].
+    ///
+    /// [`MemoryMonitor`]: crate::MemoryMonitor
+    Memory(MemoryObservation),
+}
+
+impl Sample {
+    /// The kind of sample, as used in the `kind` metric label.
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::Cpu(_) => CpuData::KIND,
+            Self::Memory(_) => MemoryUsage::KIND,
+        }
+    }
+
+    /// The span the sample was taken in.
+    pub fn span(&self) -> &tracing::Span {
+        match self {
+            Self::Cpu(obs) => obs.span(),
+            Self::Memory(obs) => obs.span(),
+        }
+    }
+}
+
+impl From<Observation> for Sample {
+    fn from(obs: Observation) -> Self {
+        Self::Cpu(obs)
+    }
+}
+
+impl From<MemoryObservation> for Sample {
+    fn from(obs: MemoryObservation) -> Self {
+        Self::Memory(obs)
+    }
+}
+
+/// Routes [`Sample`]s from any number of monitors to the actor for their
+/// kind.
+///
+/// So far, every pipeline has been a line: one monitor, one stats processor,
+/// one sink after another. Real systems have more than one source, sampling
+/// different things at different rates: CPU every second, memory every ten.
+/// Giving each its own pipeline works, until something needs to see them
+/// all, in order. The dispatcher is that something. Every monitor sends to
+/// one channel, and the dispatcher reads them in the order they arrived,
+/// and sends each on to the route for its kind.
+///
+/// Monitors send their own observation types, not [`Sample`]s, so each
+/// gets a channel of its own from [`Dispatcher::source`], which converts
+/// and forwards into the shared one. Samples of a kind with no route are
+/// dropped, and counted.
+///
+/// ```no_run
+/// use metrics_tracing_example::{Dispatcher, MemoryMonitor, MemoryStats, SysStats};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (cpu_tx, cpu_rx) = mpsc::channel(2);
+/// let (memory_tx, memory_rx) = mpsc::channel(2);
+/// let dispatcher = Dispatcher::new(4)
+///     .with_cpu_route(cpu_tx)
+///     .with_memory_route(memory_tx);
+///
+/// let _memory = MemoryMonitor::new(Duration::from_secs(10), dispatcher.source()).spawn();
+/// let _memory_stats = MemoryStats::new(memory_rx).spawn();
+/// let _cpu_stats = SysStats::new(cpu_rx, None).spawn();
+/// let _dispatcher = dispatcher.spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`PipelineBuilder::with_memory_interval`] wires all this up next to the
+/// CPU monitor and stats processor.
+///
+/// [`PipelineBuilder::with_memory_interval`]: crate::PipelineBuilder::with_memory_interval
+#[derive(Debug)]
+pub struct Dispatcher {
+    inbound: mpsc::Receiver<Sample>,
+    /// Cloned for each source. Dropped when the dispatcher is spawned, so
+    /// that the inbound channel closes once every source has.
+    sender: mpsc::Sender<Sample>,
+    routes: Routes,
+}
+
+/// Where each kind of [`Sample`] goes.
+#[derive(Debug, Default)]
+struct Routes {
+    cpu: Option<mpsc::Sender<Observation>>,
+    memory: Option<mpsc::Sender<MemoryObservation>>,
+}
+
+impl Routes {
+    /// Send `sample` to the route for its kind, if there is one.
+    async fn dispatch(&mut self, sample: Sample) {
+        let kind = sample.kind();
+        let sent = match sample {
+            Sample::Cpu(obs) => send(&mut self.cpu, obs).await,
+            Sample::Memory(obs) => send(&mut self.memory, obs).await,
+        };
+        if sent {
+            crate::metrics::record_sample_dispatched(kind);
+        } else {
+            crate::metrics::record_sample_unrouted(kind);
+        }
+    }
+}
+
+/// Send `item` to `route`, if there is one. A route whose receiver has gone
+/// is removed. `false` if there was nowhere to send it.
+async fn send<T>(route: &mut Option<mpsc::Sender<T>>, item: T) -> bool {
+    let Some(sender) = route else {
+        return false;
+    };
+    if sender.send(item).await.is_err() {
+        debug!("Route receiver dropped, removing route");
+        *route = None;
+        return false;
+    }
+    true
+}
+
+impl Dispatcher {
+    /// Create a dispatcher, whose inbound channel holds up to `capacity`
+    /// samples.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, inbound) = mpsc::channel(capacity);
+        Self {
+            inbound,
+            sender,
+            routes: Routes::default(),
+        }
+    }
+
+    /// Send [`Sample::Cpu`] observations to `route`, e.g. a [`SysStats`]'s
+    /// inbound channel.
+    ///
+    /// [`SysStats`]: crate::SysStats
+    pub fn with_cpu_route(mut self, route: mpsc::Sender<Observation>) -> Self {
+        self.routes.cpu = Some(route);
+        self
+    }
+
+    /// Send [`Sample::Memory`] observations to `route`, e.g. a
+    /// [`MemoryStats`]'s inbound channel.
+    ///
+    /// [`MemoryStats`]: crate::MemoryStats
+    pub fn with_memory_route(mut self, route: mpsc::Sender<MemoryObservation>) -> Self {
+        self.routes.memory = Some(route);
+        self
+    }
+
+    /// A channel for a monitor to send its observations to. Each one is
+    /// converted to a [`Sample`], and sent on to the dispatcher.
+    ///
+    /// This spawns a task that forwards from the new channel to the shared
+    /// one, until the returned sender is dropped, so it must be called from
+    /// within a tokio runtime.
+    pub fn source<T>(&self) -> mpsc::Sender<T>
+    where
+        T: Into<Sample> + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel::<T>(1);
+        let shared = self.sender.clone();
+        tokio::spawn(async move {
+            while let Some(item) = rx.recv().await {
+                if shared.send(item.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tx
+    }
+
+    /// Spawn the dispatcher. It runs until every source has been dropped,
+    /// then drops its routes, so that the actors behind them drain and exit
+    /// too.
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        let Self {
+            mut inbound,
+            sender,
+            mut routes,
+        } = self;
+        drop(sender);
+        tokio::spawn(async move {
+            while let Some(sample) = inbound.recv().await {
+                trace!(parent: sample.span(), kind = sample.kind(), "Dispatching sample");
+                routes.dispatch(sample).await;
+            }
+            debug!("Every source closed, closing dispatcher");
+        })
+    }
+}
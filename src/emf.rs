@@ -0,0 +1,361 @@
+//! CloudWatch Embedded Metric Format output. See [`EmfSink`].
+
+use crate::StatsReport;
+use serde_json::{Map, Value, json};
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::debug;
+
+/// The default CloudWatch namespace of the [`EmfSink`]'s metrics.
+pub const DEFAULT_EMF_NAMESPACE: &str = "my_cute_app";
+
+/// A [`StatsReport`] field published as a metric.
+type Metric = (&'static str, &'static str, fn(&StatsReport) -> f64);
+
+/// The [`StatsReport`] fields published as metrics, with their CloudWatch
+/// units.
+const METRICS: [Metric; 9] = [
+    ("observations", "Count", |report| report.observations as f64),
+    ("cpus", "Count", |report| report.cpus as f64),
+    ("average_usage", "Percent", |report| report.average_usage),
+    ("usage_stddev", "Percent", |report| report.usage_stddev),
+    ("average_freq_mhz", "None", |report| report.average_freq_mhz),
+    ("core_imbalance", "Percent", |report| report.core_imbalance),
+    ("usage_trend_per_min", "None", |report| {
+        report.usage_trend_per_min
+    }),
+    ("baseline_usage", "Percent", |report| report.baseline_usage),
+    ("usage_vs_baseline", "None", |report| {
+        report.usage_vs_baseline
+    }),
+];
+
+/// Where the [`EmfSink`] writes its lines.
+enum Output {
+    Writer(Box<dyn Write + Send>),
+    #[cfg(feature = "cloudwatch")]
+    LogGroup {
+        group: String,
+        stream: String,
+    },
+}
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Writer(_) => f.write_str("Writer"),
+            #[cfg(feature = "cloudwatch")]
+            Self::LogGroup { group, stream } => f
+                .debug_struct("LogGroup")
+                .field("group", group)
+                .field("stream", stream)
+                .finish(),
+        }
+    }
+}
+
+/// A sink that writes each [`StatsReport`] as a CloudWatch [Embedded Metric
+/// Format] (EMF) log line.
+///
+/// On AWS, the path of least resistance for metrics isn't Prometheus. It's
+/// CloudWatch, and the cheapest way into CloudWatch is a log line. An EMF
+/// line is a JSON object with an `_aws` member that says which of its other
+/// members are metrics. CloudWatch Logs extracts those into CloudWatch
+/// Metrics as the line is ingested. No agent, no scraper, and no
+/// `PutMetricData` calls: Lambda, ECS with the `awslogs` driver, and the
+/// CloudWatch agent all ship stdout to CloudWatch Logs already.
+///
+/// Each report becomes one line, in the `my_cute_app` namespace by default,
+/// with the window's average usage, frequency, spread, trend, and baseline
+/// as metrics. The line is a log event too, so the values can also be
+/// queried with CloudWatch Logs Insights.
+///
+/// ```no_run
+/// use metrics_tracing_example::{EmfSink, PipelineBuilder};
+/// use std::time::Duration;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let pipeline = PipelineBuilder::new(Duration::from_secs(60)).spawn()?;
+/// let _emf = EmfSink::new(pipeline.subscribe_stats())
+///     .with_namespace("sysmon")
+///     .with_dimension("host", "box-1")
+///     .spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// EMF lines go to stdout by default. Switch console logging to
+/// [`LogFormat::Json`], or off, so that every line on stdout is JSON. EMF
+/// lines mixed with plain text lines still work, but the text lines can't be
+/// queried as structured logs. Outside of those environments, the
+/// `cloudwatch` feature adds [`EmfSink::with_log_group`], which sends the
+/// lines straight to CloudWatch Logs.
+///
+/// Each report is a CloudWatch metric sample, and CloudWatch bills by the
+/// metric. The stats processor publishes a report per observation, so at
+/// short intervals, consider a longer one.
+///
+/// [Embedded Metric Format]: https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+/// [`LogFormat::Json`]: crate::LogFormat::Json
+#[derive(Debug)]
+pub struct EmfSink {
+    reports: watch::Receiver<StatsReport>,
+    namespace: String,
+    dimensions: Vec<(String, String)>,
+    output: Output,
+}
+
+impl EmfSink {
+    /// Create a new sink that writes reports from `reports` to stdout.
+    pub fn new(reports: watch::Receiver<StatsReport>) -> Self {
+        Self {
+            reports,
+            namespace: DEFAULT_EMF_NAMESPACE.to_owned(),
+            dimensions: Vec::new(),
+            output: Output::Writer(Box::new(io::stdout())),
+        }
+    }
+
+    /// Publish metrics in `namespace`, instead of [`DEFAULT_EMF_NAMESPACE`].
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    /// Add a dimension to every metric, e.g. the host or the environment.
+    /// Dimensions split metrics into separate series, so keep their values
+    /// few. EMF allows at most 30.
+    pub fn with_dimension(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.dimensions.push((name.into(), value.into()));
+        self
+    }
+
+    /// Write lines to `writer`, instead of stdout.
+    pub fn with_writer(mut self, writer: impl Write + Send + 'static) -> Self {
+        self.output = Output::Writer(Box::new(writer));
+        self
+    }
+
+    /// Send lines to the CloudWatch Logs log group `group`, with
+    /// `PutLogEvents`, instead of writing them to stdout. The log stream
+    /// `stream` is created if it doesn't exist. The group must exist already.
+    ///
+    /// Credentials and the region come from the usual places: env vars, the
+    /// shared config files, or the instance or task role. See
+    /// [`aws_config::load_defaults`]. The role needs
+    /// `logs:CreateLogStream` and `logs:PutLogEvents` on the group.
+    #[cfg(feature = "cloudwatch")]
+    pub fn with_log_group(mut self, group: impl Into<String>, stream: impl Into<String>) -> Self {
+        self.output = Output::LogGroup {
+            group: group.into(),
+            stream: stream.into(),
+        };
+        self
+    }
+
+    /// The EMF line for `report`, timestamped `timestamp_ms`.
+    fn line(&self, report: &StatsReport, timestamp_ms: u64) -> String {
+        let dimension_names: Vec<_> = self.dimensions.iter().map(|(name, _)| name).collect();
+        let metrics: Vec<_> = METRICS
+            .iter()
+            .map(|(name, unit, _)| json!({ "Name": name, "Unit": unit }))
+            .collect();
+
+        let mut line = Map::new();
+        line.insert(
+            "_aws".into(),
+            json!({
+                "Timestamp": timestamp_ms,
+                "CloudWatchMetrics": [{
+                    "Namespace": self.namespace,
+                    "Dimensions": [dimension_names],
+                    "Metrics": metrics,
+                }],
+            }),
+        );
+        for (name, value) in &self.dimensions {
+            line.insert(name.clone(), value.as_str().into());
+        }
+        for (name, _, value) in METRICS {
+            line.insert(name.into(), value(report).into());
+        }
+        Value::Object(line).to_string()
+    }
+
+    /// Spawn the sink.
+    ///
+    /// The sink writes a line for every new report, and exits when the stats
+    /// processor does. It returns an error if writing to its writer fails,
+    /// or if the log stream can't be created. Sending a line to CloudWatch
+    /// Logs is retried, and if every attempt fails, the failure is logged and
+    /// the line dropped.
+    pub fn spawn(mut self) -> JoinHandle<io::Result<()>> {
+        tokio::spawn(async move {
+            #[cfg(feature = "cloudwatch")]
+            let logs = match &self.output {
+                Output::LogGroup { group, stream } => {
+                    Some(cloudwatch::LogStream::create(group, stream).await?)
+                }
+                Output::Writer(_) => None,
+            };
+
+            // `changed` errors once the sender is dropped.
+            while self.reports.changed().await.is_ok() {
+                let report = self.reports.borrow_and_update().clone();
+                // The stats processor publishes an empty report before the
+                // first observation. There is nothing to measure yet.
+                if report.observations == 0 {
+                    continue;
+                }
+                let timestamp_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64;
+                let line = self.line(&report, timestamp_ms);
+
+                match &mut self.output {
+                    Output::Writer(writer) => {
+                        // Writing blocks, so it runs off the runtime's
+                        // worker threads.
+                        let mut owned = std::mem::replace(writer, Box::new(io::sink()));
+                        let (owned, written) = tokio::task::spawn_blocking(move || {
+                            let written = writeln!(owned, "{line}").and_then(|()| owned.flush());
+                            (owned, written)
+                        })
+                        .await
+                        .map_err(io::Error::other)?;
+                        *writer = owned;
+                        written?;
+                    }
+                    #[cfg(feature = "cloudwatch")]
+                    Output::LogGroup { .. } => {
+                        if let Some(logs) = &logs {
+                            logs.put(line, timestamp_ms).await;
+                        }
+                    }
+                }
+            }
+            debug!("Stats processor exited, closing EMF sink");
+            Ok(())
+        })
+    }
+}
+
+#[cfg(feature = "cloudwatch")]
+mod cloudwatch {
+    use crate::Backoff;
+    use aws_config::BehaviorVersion;
+    use aws_sdk_cloudwatchlogs::{Client, types::InputLogEvent};
+    use std::io;
+    use tracing::warn;
+
+    /// A CloudWatch Logs log stream to put EMF lines to.
+    pub(super) struct LogStream {
+        client: Client,
+        group: String,
+        stream: String,
+    }
+
+    impl LogStream {
+        /// Load the AWS config, and create the log stream if it doesn't
+        /// exist.
+        pub(super) async fn create(group: &str, stream: &str) -> io::Result<Self> {
+            let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+            let client = Client::new(&config);
+            let created = client
+                .create_log_stream()
+                .log_group_name(group)
+                .log_stream_name(stream)
+                .send()
+                .await;
+            if let Err(error) = created
+                && !error
+                    .as_service_error()
+                    .is_some_and(|error| error.is_resource_already_exists_exception())
+            {
+                return Err(io::Error::other(error));
+            }
+            Ok(Self {
+                client,
+                group: group.to_owned(),
+                stream: stream.to_owned(),
+            })
+        }
+
+        /// Put a single EMF line, retrying with the default [`Backoff`].
+        pub(super) async fn put(&self, line: String, timestamp_ms: u64) {
+            let event = match InputLogEvent::builder()
+                .message(line)
+                .timestamp(timestamp_ms as i64)
+                .build()
+            {
+                Ok(event) => event,
+                Err(error) => {
+                    warn!(%error, "failed to build EMF log event");
+                    return;
+                }
+            };
+            let put = crate::retry("cloudwatch_put", &Backoff::default(), || {
+                self.client
+                    .put_log_events()
+                    .log_group_name(&self.group)
+                    .log_stream_name(&self.stream)
+                    .log_events(event.clone())
+                    .customize()
+                    // Without this header, CloudWatch Logs stores the line,
+                    // but doesn't extract the metrics from it.
+                    .mutate_request(|request| {
+                        request
+                            .headers_mut()
+                            .insert("x-amzn-logs-format", "json/emf");
+                    })
+                    .send()
+            })
+            .await;
+            if let Err(error) = put {
+                warn!(error = %aws_sdk_cloudwatchlogs::error::DisplayErrorContext(&error), "failed to put EMF line to CloudWatch Logs");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_declares_every_metric_at_the_top_level() {
+        let (_tx, rx) = watch::channel(StatsReport::default());
+        let sink = EmfSink::new(rx).with_dimension("host", "box-1");
+        let report = StatsReport {
+            observations: 3,
+            cpus: 4,
+            average_usage: 42.5,
+            average_freq_mhz: 2400.0,
+            ..Default::default()
+        };
+
+        let line: Value = serde_json::from_str(&sink.line(&report, 1_000)).unwrap();
+
+        let directive = &line["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(directive["Namespace"], DEFAULT_EMF_NAMESPACE);
+        assert_eq!(directive["Dimensions"], json!([["host"]]));
+        let names: Vec<_> = directive["Metrics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|metric| metric["Name"].as_str().unwrap())
+            .collect();
+        let expected: Vec<_> = METRICS.iter().map(|(name, _, _)| *name).collect();
+        assert_eq!(names, expected);
+
+        for (name, _, value) in METRICS {
+            assert_eq!(line[name].as_f64(), Some(value(&report)), "{name}");
+        }
+        assert_eq!(line["host"], "box-1");
+        assert_eq!(line["_aws"]["Timestamp"], 1_000);
+    }
+}
@@ -0,0 +1,228 @@
+//! A [`Layer`] that writes warnings and errors to the Windows Event Log.
+//! See [`EventLogLayer`].
+
+use std::{
+    fmt::{self, Write as _},
+    io,
+    sync::Arc,
+};
+use tracing::{
+    Event, Level, Metadata, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    Layer,
+    filter::{FilterFn, filter_fn},
+    layer::Context,
+    registry::LookupSpan,
+};
+
+/// The longest string a single event log entry accepts, in UTF-16 units.
+const MAX_ENTRY_LEN: usize = 31_839;
+
+/// Formats an event as its message, followed by its fields as `key=value`.
+#[derive(Default)]
+struct EntryWriter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for EntryWriter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+}
+
+/// A [`Layer`] that writes `WARN` and `ERROR` events to the Windows Event
+/// Log, as warning and error entries.
+///
+/// A Windows service has no console. Its stdout goes nowhere, and the first
+/// place an administrator looks when it misbehaves is the Event Viewer. This
+/// layer puts the events that matter there: each entry is the event's
+/// message, its fields as `key=value`, and the names of its enclosing spans.
+/// Everything below `WARN` stays out of it, as the Event Log is a shared,
+/// size-limited resource, not a place for per-observation chatter.
+///
+/// ```no_run
+/// use metrics_tracing_example::{EventLogLayer, TracingBuilder};
+///
+/// # async fn _main() -> std::io::Result<()> {
+/// let event_log = EventLogLayer::new("sysmon")?;
+/// let _provider = TracingBuilder::new().with_event_log(event_log).init();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## Registering the source
+///
+/// Entries are written to the `Application` log, under the event source
+/// `source`. Windows writes them even if the source was never registered,
+/// but the Event Viewer then prefixes each with a complaint that the
+/// description for the event ID cannot be found. Registering the source
+/// once, as an administrator, at install time, quiets it:
+///
+/// ```powershell
+/// New-EventLog -LogName Application -Source sysmon
+/// ```
+#[derive(Debug, Clone)]
+pub struct EventLogLayer {
+    source: Arc<sys::EventSource>,
+}
+
+impl EventLogLayer {
+    /// Open the event source named `source`.
+    ///
+    /// ## Errors
+    ///
+    /// If the source can't be opened, or always on platforms other than
+    /// Windows, with [`io::ErrorKind::Unsupported`].
+    pub fn new(source: &str) -> io::Result<Self> {
+        Ok(Self {
+            source: Arc::new(sys::EventSource::register(source)?),
+        })
+    }
+
+    /// A per-layer filter that enables `WARN` and `ERROR` events only.
+    pub(crate) fn filter(&self) -> FilterFn<impl Fn(&Metadata<'_>) -> bool + use<>> {
+        filter_fn(|metadata| metadata.is_event() && *metadata.level() <= Level::WARN)
+    }
+}
+
+impl<S> Layer<S> for EventLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut writer = EntryWriter::default();
+        event.record(&mut writer);
+
+        let mut entry = writer.message;
+        entry.push_str(&writer.fields);
+        if let Some(scope) = ctx.event_scope(event) {
+            let spans: Vec<_> = scope.from_root().map(|span| span.name()).collect();
+            if !spans.is_empty() {
+                let _ = write!(entry, "\n\nin {}", spans.join(" > "));
+            }
+        }
+        let _ = write!(entry, "\nat {}", event.metadata().target());
+
+        self.source.report(*event.metadata().level(), &entry);
+    }
+}
+
+/// `entry` as a nul-terminated UTF-16 string, truncated to what the Event
+/// Log accepts.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn wide(entry: &str) -> Vec<u16> {
+    entry
+        .encode_utf16()
+        .take(MAX_ENTRY_LEN)
+        .chain([0])
+        .collect()
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::wide;
+    use std::{io, ptr};
+    use tracing::Level;
+    use windows_sys::Win32::{
+        Foundation::HANDLE,
+        System::EventLog::{
+            DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_WARNING_TYPE,
+            RegisterEventSourceW, ReportEventW,
+        },
+    };
+
+    /// An open event source handle.
+    #[derive(Debug)]
+    pub(super) struct EventSource(HANDLE);
+
+    // SAFETY: event source handles may be used from any thread. The Event
+    // Log API does its own locking.
+    unsafe impl Send for EventSource {}
+    // SAFETY: as above.
+    unsafe impl Sync for EventSource {}
+
+    impl EventSource {
+        pub(super) fn register(name: &str) -> io::Result<Self> {
+            let name = wide(name);
+            // SAFETY: `name` is a nul-terminated UTF-16 string that outlives
+            // the call. A null server name means the local machine.
+            let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self(handle))
+        }
+
+        pub(super) fn report(&self, level: Level, entry: &str) {
+            let kind = if level == Level::ERROR {
+                EVENTLOG_ERROR_TYPE
+            } else {
+                EVENTLOG_WARNING_TYPE
+            };
+            let entry = wide(entry);
+            let strings = [entry.as_ptr()];
+            // SAFETY: the handle is open until drop, and `strings` holds one
+            // nul-terminated UTF-16 string that outlives the call. A failure
+            // is ignored: there is nowhere left to report it.
+            unsafe {
+                ReportEventW(
+                    self.0,
+                    kind,
+                    0,
+                    0,
+                    ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    ptr::null(),
+                );
+            }
+        }
+    }
+
+    impl Drop for EventSource {
+        fn drop(&mut self) {
+            // SAFETY: the handle was returned by `RegisterEventSourceW`, and
+            // is closed exactly once.
+            unsafe {
+                DeregisterEventSource(self.0);
+            }
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod sys {
+    use std::io;
+    use tracing::Level;
+
+    /// The Event Log only exists on Windows.
+    #[derive(Debug)]
+    pub(super) struct EventSource;
+
+    impl EventSource {
+        pub(super) fn register(_name: &str) -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the Windows Event Log is only available on Windows",
+            ))
+        }
+
+        pub(super) fn report(&self, _level: Level, _entry: &str) {}
+    }
+}
@@ -0,0 +1,340 @@
+//! A [`Layer`] that turns tracing events into metrics. See
+//! [`EventMetricsLayer`].
+
+use metrics::Key;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::{Arc, PoisonError, RwLock},
+};
+use tracing::{
+    Event, Metadata, Subscriber,
+    callsite::Identifier,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    Layer,
+    filter::{FilterFn, filter_fn},
+    layer::Context,
+};
+
+/// The field `tracing` stores an event's message in.
+const MESSAGE: &str = "message";
+
+/// A single event-to-metric mapping.
+#[derive(Debug, Clone, Copy)]
+struct Rule {
+    /// The message of the events this rule applies to.
+    message: &'static str,
+    /// The numeric field to record, or `None` to count the events instead.
+    field: Option<&'static str>,
+    /// The metric to record to.
+    metric: &'static str,
+}
+
+impl Rule {
+    /// The rule as it applies to the events of a callsite, if it can.
+    fn for_callsite(&self, metadata: &Metadata<'_>) -> Option<CallsiteRule> {
+        let field = match self.field {
+            Some(field) => Some(metadata.fields().field(field)?),
+            None => None,
+        };
+        Some(CallsiteRule {
+            message: self.message,
+            field,
+            key: Key::from_static_name(self.metric),
+        })
+    }
+}
+
+/// A [`Rule`], as it applies to the events of a single callsite.
+#[derive(Debug)]
+struct CallsiteRule {
+    message: &'static str,
+    /// The callsite's field to record, or `None` to count the events.
+    field: Option<Field>,
+    /// The metric's key, built once, rather than for every event.
+    key: Key,
+}
+
+/// The message and numeric field values of a single event.
+#[derive(Default)]
+struct EventValues {
+    message: String,
+    values: Vec<(Field, f64)>,
+}
+
+thread_local! {
+    /// Reused for every event, so that recording one doesn't allocate.
+    static EVENT_VALUES: RefCell<EventValues> = RefCell::default();
+}
+
+impl Visit for EventValues {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.values.push((field.clone(), value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_f64(field, value as f64);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_f64(field, value as f64);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == MESSAGE {
+            self.message.clear();
+            self.message.push_str(value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // Messages are recorded as `fmt::Arguments`, so this is where they
+        // usually end up.
+        if field.name() == MESSAGE {
+            self.message.clear();
+            let _ = write!(self.message, "{value:?}");
+        }
+    }
+}
+
+impl EventValues {
+    fn clear(&mut self) {
+        self.message.clear();
+        self.values.clear();
+    }
+
+    fn get(&self, field: &Field) -> Option<f64> {
+        self.values
+            .iter()
+            .find_map(|(name, value)| (name == field).then_some(*value))
+    }
+}
+
+/// A [`Layer`] that records selected tracing events as metrics, based on
+/// their message and fields.
+///
+/// ## Events are metrics, too
+///
+/// A structured event with a numeric field is already a measurement. The
+/// stats processor emits a `finished cpu stats` event with the average
+/// usage every time it runs. If we also want that value as a metric, we
+/// could add a `histogram!` call right next to the `info!`. Or we could
+/// notice that the event contains everything the histogram needs, and
+/// derive one from the other.
+///
+/// This layer does the latter. Each rule names an event by its message, and
+/// either a field to record in a histogram, or nothing, to count the events.
+/// The code emitting the events doesn't change at all.
+///
+/// ```no_run
+/// use metrics_tracing_example::{EventMetricsLayer, TracingBuilder, fields};
+///
+/// # async fn _main() {
+/// let events = EventMetricsLayer::new()
+///     .with_counter("finished cpu stats", "my_cute_app.stats_computed")
+///     .with_histogram(
+///         "finished cpu stats",
+///         fields::stats::AVERAGE_USAGE,
+///         "my_cute_app.average_usage",
+///     );
+/// let _provider = TracingBuilder::new().with_event_metrics(events).init();
+/// # }
+/// ```
+///
+/// ## Caveats
+///
+/// The coupling goes both ways. Rewording an event's message, or renaming a
+/// field, silently breaks the metric. That's a good reason to use the
+/// standard names in [`fields`] for anything a rule depends on.
+///
+/// The metrics are described after their rule, like "The total number of
+/// `finished cpu stats` events", which is as much as the layer knows about
+/// them. Only fields recorded as numbers can be recorded in a histogram. A
+/// field recorded with `%` or `?` is a string, and is ignored.
+///
+/// [`fields`]: crate::fields
+#[derive(Debug, Clone, Default)]
+pub struct EventMetricsLayer {
+    rules: Arc<[Rule]>,
+    /// The rules that apply to each callsite seen so far.
+    callsites: Arc<RwLock<HashMap<Identifier, Arc<[CallsiteRule]>>>>,
+}
+
+impl EventMetricsLayer {
+    /// Create a new layer with no rules. Add some with
+    /// [`EventMetricsLayer::with_counter`] and
+    /// [`EventMetricsLayer::with_histogram`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_rule(mut self, rule: Rule) -> Self {
+        crate::metrics::describe_event_metric(rule.metric, rule.message, rule.field);
+        self.rules = self.rules.iter().copied().chain([rule]).collect();
+        // The callsites seen so far were matched against the old rules.
+        self.callsites = Arc::default();
+        self
+    }
+
+    /// Increment the counter `metric` for each event with `message`.
+    pub fn with_counter(self, message: &'static str, metric: &'static str) -> Self {
+        self.with_rule(Rule {
+            message,
+            field: None,
+            metric,
+        })
+    }
+
+    /// Record the value of `field` in the histogram `metric` for each event
+    /// with `message`. Events without the field are ignored.
+    pub fn with_histogram(
+        self,
+        message: &'static str,
+        field: &'static str,
+        metric: &'static str,
+    ) -> Self {
+        self.with_rule(Rule {
+            message,
+            field: Some(field),
+            metric,
+        })
+    }
+
+    /// Whether any rule could apply to events with this metadata.
+    fn is_interesting(&self, metadata: &Metadata<'_>) -> bool {
+        let fields = metadata.fields();
+        metadata.is_event()
+            && fields.field(MESSAGE).is_some()
+            && self
+                .rules
+                .iter()
+                .any(|rule| rule.field.is_none_or(|field| fields.field(field).is_some()))
+    }
+
+    /// A per-layer filter that enables the events the rules could apply to.
+    ///
+    /// This only depends on the callsite, so `tracing` caches it, and other
+    /// events cost this layer nothing at all.
+    pub(crate) fn filter(&self) -> FilterFn<impl Fn(&Metadata<'_>) -> bool + use<>> {
+        let this = self.clone();
+        filter_fn(move |metadata| this.is_interesting(metadata))
+    }
+
+    /// The rules that apply to events from the callsite of `metadata`.
+    fn callsite_rules(&self, metadata: &Metadata<'_>) -> Arc<[CallsiteRule]> {
+        let callsite = metadata.callsite();
+
+        // Only the first event from each callsite needs the write lock.
+        if let Some(rules) = self
+            .callsites
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&callsite)
+        {
+            return rules.clone();
+        }
+
+        let rules: Arc<[CallsiteRule]> = self
+            .rules
+            .iter()
+            .filter_map(|rule| rule.for_callsite(metadata))
+            .collect();
+        self.callsites
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(callsite)
+            .or_insert(rules)
+            .clone()
+    }
+
+    /// Record `event` with `rules`, using `values` as scratch space.
+    fn record(rules: &[CallsiteRule], event: &Event<'_>, values: &mut EventValues) {
+        values.clear();
+        event.record(values);
+
+        for rule in rules.iter().filter(|rule| rule.message == values.message) {
+            match &rule.field {
+                None => crate::metrics::record_event_count(&rule.key),
+                Some(field) => {
+                    if let Some(value) = values.get(field) {
+                        crate::metrics::record_event_value(&rule.key, value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for EventMetricsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let rules = self.callsite_rules(event.metadata());
+        if rules.is_empty() {
+            return;
+        }
+
+        EVENT_VALUES.with(|values| match values.try_borrow_mut() {
+            Ok(mut values) => Self::record(&rules, event, &mut values),
+            // An event emitted while recording one, by the recorder itself.
+            Err(_) => Self::record(&rules, event, &mut EventValues::default()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use tracing::info;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn records_events_as_metrics() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            let layer = EventMetricsLayer::new()
+                .with_counter("finished cpu stats", "test.stats_computed")
+                .with_histogram("finished cpu stats", "average_usage", "test.average_usage");
+            let subscriber =
+                tracing_subscriber::registry().with(layer.clone().with_filter(layer.filter()));
+
+            tracing::subscriber::with_default(subscriber, || {
+                for usage in [25.0, 75.0] {
+                    info!(average_usage = usage, "finished cpu stats");
+                }
+                // Counted, but without the field to record.
+                info!("finished cpu stats");
+                info!(average_usage = 50.0, "something else");
+            });
+        });
+
+        let metrics: HashMap<_, _> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, description, value)| (key.key().name().to_owned(), (description, value)))
+            .collect();
+
+        let (description, value) = &metrics["test.stats_computed"];
+        assert_eq!(value, &DebugValue::Counter(3));
+        assert_eq!(
+            description.as_deref(),
+            Some("The total number of `finished cpu stats` events")
+        );
+
+        let (description, value) = &metrics["test.average_usage"];
+        let DebugValue::Histogram(values) = value else {
+            panic!("not a histogram: {value:?}");
+        };
+        let values: Vec<f64> = values.iter().map(|value| value.0).collect();
+        assert_eq!(values, [25.0, 75.0]);
+        assert_eq!(
+            description.as_deref(),
+            Some("The `average_usage` field of `finished cpu stats` events")
+        );
+    }
+}
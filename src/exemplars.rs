@@ -0,0 +1,181 @@
+//! Exemplars linking the `cpu_usage` histogram to traces. See
+//! [`render_openmetrics`].
+
+use crate::metrics::CPU_USAGE_HISTOGRAM;
+use metrics_exporter_prometheus::{PrometheusHandle, formatting::sanitize_metric_name};
+use opentelemetry::{TraceId, trace::TraceContextExt};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The upper bounds of the `cpu_usage` histogram buckets, in percent. The
+/// `+Inf` bucket is implied.
+pub(crate) const CPU_USAGE_BUCKETS: [f64; 10] =
+    [10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0, 80.0, 90.0, 100.0];
+
+/// The content type of [`render_openmetrics`] output.
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// A sample, and the trace it was recorded in.
+#[derive(Debug, Clone, Copy)]
+struct Exemplar {
+    trace_id: TraceId,
+    value: f64,
+    timestamp: SystemTime,
+}
+
+/// The latest exemplar per bucket, the last one being `+Inf`.
+type Buckets = [Option<Exemplar>; CPU_USAGE_BUCKETS.len() + 1];
+
+/// The latest exemplars of the `cpu_usage` histogram, by `name` label.
+static CPU_USAGE_EXEMPLARS: Mutex<Option<HashMap<Box<str>, Buckets>>> = Mutex::new(None);
+
+/// The index of the bucket `value` falls in. Bucket bounds are inclusive.
+fn bucket(value: f64) -> usize {
+    CPU_USAGE_BUCKETS
+        .iter()
+        .position(|bound| value <= *bound)
+        .unwrap_or(CPU_USAGE_BUCKETS.len())
+}
+
+/// Record the `cpu_usage` samples of an observation, by `name` label, as
+/// exemplars of the observation's `span`.
+///
+/// Only sampled traces make exemplars. An exemplar pointing at a trace that
+/// was never exported is a link to nowhere.
+#[cfg_attr(not(feature = "sysinfo"), allow(dead_code))]
+pub(crate) fn record_cpu_usage<'a>(
+    span: &tracing::Span,
+    samples: impl IntoIterator<Item = (&'a str, f64)>,
+) {
+    let context = span.context();
+    let otel_span = context.span();
+    let span_context = otel_span.span_context();
+    if !span_context.is_valid() || !span_context.is_sampled() {
+        return;
+    }
+    let trace_id = span_context.trace_id();
+    let timestamp = SystemTime::now();
+
+    let mut exemplars = CPU_USAGE_EXEMPLARS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let exemplars = exemplars.get_or_insert_with(HashMap::new);
+    for (name, value) in samples {
+        let exemplar = Some(Exemplar {
+            trace_id,
+            value,
+            timestamp,
+        });
+        match exemplars.get_mut(name) {
+            Some(buckets) => buckets[bucket(value)] = exemplar,
+            None => {
+                let mut buckets = Buckets::default();
+                buckets[bucket(value)] = exemplar;
+                exemplars.insert(name.into(), buckets);
+            }
+        }
+    }
+}
+
+/// Render the metrics in the [OpenMetrics] text format, with the trace of
+/// the latest observation in each `my_cute_app.cpu_usage` bucket attached as
+/// an exemplar.
+///
+/// A histogram tells you that some observations found a core at 95% usage.
+/// An exemplar tells you _which_ observation: each bucket carries the trace
+/// ID of the last sample that landed in it, and Grafana draws it as a dot on
+/// the histogram panel that links straight to the trace. From "something
+/// was busy at 14:02" to "here is the span tree of the observation that saw
+/// it" in one click.
+///
+/// Exemplars only exist in OpenMetrics. The Prometheus text format that
+/// [`PrometheusHandle::render`] produces has no room for them. Prometheus
+/// asks for OpenMetrics in its `Accept` header when started with
+/// `--enable-feature=exemplar-storage`, and the `RouterBuilder`'s `/metrics`
+/// route, behind the `axum` feature, serves this format when asked. Serve it
+/// with [`OPENMETRICS_CONTENT_TYPE`]. The listener installed by
+/// [`init_metrics`] only speaks the Prometheus text format, so it has no
+/// exemplars. Neither does the `otel-metrics` export, as the OTEL SDK doesn't
+/// record them yet.
+///
+/// Only sampled observations make exemplars. See [`TargetSampler`].
+///
+/// [OpenMetrics]: https://prometheus.io/docs/specs/om/open_metrics_spec/
+/// [`init_metrics`]: crate::init_metrics
+/// [`TargetSampler`]: crate::TargetSampler
+pub fn render_openmetrics(handle: &PrometheusHandle) -> String {
+    let text = handle.render();
+    let bucket_prefix = format!("{}_bucket{{", sanitize_metric_name(CPU_USAGE_HISTOGRAM));
+    let exemplars = CPU_USAGE_EXEMPLARS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    let mut out = String::with_capacity(text.len() + 64);
+    let mut counter: Option<&str> = None;
+    for line in text.lines() {
+        if let Some(family) = line.strip_prefix("# TYPE ") {
+            counter = family
+                .strip_suffix(" counter")
+                .filter(|name| !name.ends_with("_total"));
+        } else if let Some(name) = counter
+            && let Some(rest) = line.strip_prefix(name)
+            && (rest.starts_with('{') || rest.starts_with(' '))
+        {
+            // OpenMetrics counter samples must end in `_total`.
+            let _ = writeln!(out, "{name}_total{rest}");
+            continue;
+        }
+
+        out.push_str(line);
+        if let Some(labels) = line.strip_prefix(&bucket_prefix)
+            && let Some(exemplar) = exemplars
+                .as_ref()
+                .and_then(|exemplars| find_exemplar(exemplars, labels))
+        {
+            let timestamp = exemplar
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let _ = write!(
+                out,
+                " # {{trace_id=\"{}\"}} {} {}.{:03}",
+                exemplar.trace_id,
+                exemplar.value,
+                timestamp.as_secs(),
+                timestamp.subsec_millis(),
+            );
+        }
+        out.push('\n');
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// The exemplar for a bucket line, given its labels, e.g.
+/// `name="cpu0",le="10"} 3`.
+fn find_exemplar(exemplars: &HashMap<Box<str>, Buckets>, labels: &str) -> Option<Exemplar> {
+    let name = label(labels, "name")?;
+    let le: f64 = label(labels, "le")?.parse().ok()?;
+    let index = if le.is_infinite() {
+        CPU_USAGE_BUCKETS.len()
+    } else {
+        CPU_USAGE_BUCKETS.iter().position(|bound| *bound == le)?
+    };
+    exemplars.get(name)?[index]
+}
+
+/// The value of the label `key` in a rendered label set.
+fn label<'a>(labels: &'a str, key: &str) -> Option<&'a str> {
+    let labels = &labels[..labels.find('}')?];
+    labels.split(',').find_map(|pair| {
+        pair.strip_prefix(key)?
+            .strip_prefix("=\"")?
+            .strip_suffix('"')
+    })
+}
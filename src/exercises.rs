@@ -0,0 +1,181 @@
+//! Guided exercises. Fill in the `todo!()`s until the tests pass.
+//!
+//! This module is only built with the `exercises` feature. Each exercise has
+//! a skeleton, and tests that fail until you implement it. Run them with:
+//!
+//! ```sh
+//! cargo test --features exercises exercises
+//! ```
+//!
+//! Start with a single exercise, e.g. `cargo test --features exercises
+//! max_usage`, and work your way down. Each one builds on the crate's own
+//! code, so when you get stuck, go and read how the crate does something
+//! similar. The hints point you in the right direction.
+//!
+//! 1. [`max_usage`]: a warm-up. Plain Rust, no tracing.
+//! 2. [`MaxUsageSink`]: an actor that consumes observations from a channel.
+//!    Hint: read [`SysStats::spawn`].
+//! 3. [`EventCounter`]: a [`Layer`] that counts events by level. Hint: read
+//!    [`SpanCountLayer`].
+//!
+//! [`SysStats::spawn`]: crate::SysStats::spawn
+//! [`SpanCountLayer`]: crate::SpanCountLayer
+//! [`Layer`]: tracing_subscriber::Layer
+
+// The skeletons don't use their arguments or fields until you fill them in.
+#![allow(unused_variables, dead_code)]
+
+use crate::{CpuStats, Observation};
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::{Layer, layer::Context};
+
+/// Exercise 1: return the highest CPU usage in `cpus`, or `None` if there are
+/// no CPUs.
+pub fn max_usage(cpus: &[CpuStats]) -> Option<f32> {
+    todo!("exercise 1: find the highest usage")
+}
+
+/// Exercise 2: a sink actor that tracks the highest CPU usage seen across
+/// every observation it receives.
+///
+/// The task should:
+/// - receive observations until the channel closes,
+/// - process each one _inside its span_, emitting a `debug!` event with the
+///   observation's max usage,
+/// - return the highest usage seen, or `None` if it saw no observations.
+///
+/// Remember that dropping the observation closes its span. Don't keep them
+/// around!
+#[derive(Debug)]
+pub struct MaxUsageSink {
+    inbound: mpsc::Receiver<Observation>,
+}
+
+impl MaxUsageSink {
+    /// Create a new sink that consumes observations from `inbound`.
+    pub fn new(inbound: mpsc::Receiver<Observation>) -> Self {
+        Self { inbound }
+    }
+
+    /// Spawn the sink task.
+    pub fn spawn(self) -> JoinHandle<Option<f32>> {
+        todo!("exercise 2: spawn the sink task")
+    }
+}
+
+/// Exercise 3: a [`Layer`] that counts the events it sees at each level.
+///
+/// The counts are shared between clones, so a test (or your program) can
+/// keep a clone to read them after installing the layer.
+#[derive(Debug, Clone, Default)]
+pub struct EventCounter {
+    counts: Arc<[AtomicUsize; 5]>,
+}
+
+impl EventCounter {
+    /// Create a new counter, with every count at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of events seen at `level`.
+    pub fn count(&self, level: Level) -> usize {
+        self.counts[Self::index(level)].load(Ordering::Relaxed)
+    }
+
+    fn index(level: Level) -> usize {
+        match level {
+            Level::TRACE => 0,
+            Level::DEBUG => 1,
+            Level::INFO => 2,
+            Level::WARN => 3,
+            Level::ERROR => 4,
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for EventCounter {
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        todo!("exercise 3: count the event")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{debug, error, info, info_span, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn cpus(usages: &[f32]) -> Arc<[CpuStats]> {
+        usages
+            .iter()
+            .enumerate()
+            .map(|(i, &usage)| CpuStats {
+                name: format!("cpu{i}").into(),
+                usage,
+                frequency: 2_000,
+                times: None,
+                package: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn max_usage_finds_highest() {
+        assert_eq!(max_usage(&cpus(&[12.5, 80.0, 33.3])), Some(80.0));
+        assert_eq!(max_usage(&cpus(&[7.0])), Some(7.0));
+    }
+
+    #[test]
+    fn max_usage_of_nothing() {
+        assert_eq!(max_usage(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn sink_tracks_max_across_observations() {
+        let (tx, rx) = mpsc::channel(4);
+        let sink = MaxUsageSink::new(rx).spawn();
+
+        for usages in [[10.0, 20.0], [90.0, 5.0], [30.0, 40.0]] {
+            let span = info_span!("Observation");
+            tx.send(Observation::new(cpus(&usages), span))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+
+        assert_eq!(sink.await.unwrap(), Some(90.0));
+    }
+
+    #[tokio::test]
+    async fn sink_with_no_observations() {
+        let (tx, rx) = mpsc::channel(1);
+        drop(tx);
+        assert_eq!(MaxUsageSink::new(rx).spawn().await.unwrap(), None);
+    }
+
+    #[test]
+    fn counter_counts_by_level() {
+        let counter = EventCounter::new();
+        let subscriber = tracing_subscriber::registry().with(counter.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!("one");
+            info!("two");
+            warn!("three");
+            error!("four");
+            info_span!("a span").in_scope(|| debug!("five"));
+        });
+
+        assert_eq!(counter.count(Level::TRACE), 0);
+        assert_eq!(counter.count(Level::DEBUG), 1);
+        assert_eq!(counter.count(Level::INFO), 2);
+        assert_eq!(counter.count(Level::WARN), 1);
+        assert_eq!(counter.count(Level::ERROR), 1);
+    }
+}
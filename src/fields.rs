@@ -0,0 +1,129 @@
+//! Standard field names and value types for this crate's tracing events.
+//!
+//! ## Why standardize fields?
+//!
+//! Structured fields are only useful if you can query them. If one event
+//! records `usage = 31.8`, another records `cpu_usage = "31.8%"`, and a third
+//! records `usage_percent = 0.318`, then no single query in your backend
+//! finds all three. Each field name should mean one thing, with one type and
+//! one unit, everywhere it appears.
+//!
+//! The easiest way to get there is to not type field names by hand. This
+//! module provides a constant for each name, and a function for each value
+//! that converts it to the standard type. `tracing` accepts a constant as a
+//! field name, if it is wrapped in curly braces:
+//!
+//! ```
+//! use metrics_tracing_example::fields::{self, FREQ_MHZ, OBSERVATION_ID, USAGE_PCT};
+//!
+//! tracing::info!(
+//!     { OBSERVATION_ID } = fields::obs_id(42),
+//!     { USAGE_PCT } = fields::usage_pct(31.818182),
+//!     { FREQ_MHZ } = fields::freq_mhz(2100),
+//!     "sampled cpu"
+//! );
+//! ```
+//!
+//! Including the unit in the name (`_pct`, `_mhz`, `_ms`) means nobody has to
+//! guess whether `usage` is a percentage or a fraction, or whether `latency`
+//! is in seconds or milliseconds.
+
+/// The field name for an observation's ID. See [`obs_id`].
+pub const OBSERVATION_ID: &str = "observation_id";
+
+/// The field name for a CPU's name.
+pub const CPU: &str = "cpu";
+
+/// The field name for a CPU usage percentage. See [`usage_pct`].
+pub const USAGE_PCT: &str = "usage_pct";
+
+/// The field name for a CPU frequency in MHz. See [`freq_mhz`].
+pub const FREQ_MHZ: &str = "freq_mhz";
+
+/// The field name for the host an event is about, when it isn't this one.
+pub const HOST: &str = "host";
+
+/// The field names of the [`SysStats`] `finished cpu stats` event. Rules for
+/// the [`EventMetricsLayer`] depend on them, so they get constants, too.
+///
+/// [`SysStats`]: crate::SysStats
+/// [`EventMetricsLayer`]: crate::EventMetricsLayer
+pub mod stats {
+    /// The number of observations in the window.
+    pub const OBSERVATIONS: &str = "count";
+
+    /// The number of CPUs in the latest observation.
+    pub const CPUS: &str = "cpus";
+
+    /// The average usage percentage over the window.
+    pub const AVERAGE_USAGE: &str = "average_usage";
+
+    /// The standard deviation of the usage percentage over the window.
+    pub const USAGE_STDDEV: &str = "usage_stddev";
+
+    /// The average frequency in MHz over the window.
+    pub const AVERAGE_FREQ_MHZ: &str = "average_freq_mhz";
+
+    /// The correlation between usage and frequency over the window.
+    pub const USAGE_FREQ_CORRELATION: &str = "usage_freq_correlation";
+
+    /// How fast the usage percentage is changing, per minute.
+    pub const USAGE_TREND_PER_MIN: &str = "usage_trend_per_min";
+
+    /// The average usage percentage over the baseline window.
+    pub const BASELINE_USAGE: &str = "baseline_usage";
+
+    /// The ratio of the average usage to the baseline.
+    pub const USAGE_VS_BASELINE: &str = "usage_vs_baseline";
+
+    /// The number of samples in each tenth of the usage range, lowest
+    /// first.
+    pub const USAGE_BUCKETS: [&str; 10] = [
+        "usage_0_10",
+        "usage_10_20",
+        "usage_20_30",
+        "usage_30_40",
+        "usage_40_50",
+        "usage_50_60",
+        "usage_60_70",
+        "usage_70_80",
+        "usage_80_90",
+        "usage_90_100",
+    ];
+
+    /// The names of the busiest CPUs, busiest first.
+    pub const BUSIEST: [&str; 4] = ["busiest_1", "busiest_2", "busiest_3", "busiest_4"];
+
+    /// The average usage percentage of the busiest CPUs, busiest first.
+    pub const BUSIEST_USAGE: [&str; 4] = [
+        "busiest_1_usage",
+        "busiest_2_usage",
+        "busiest_3_usage",
+        "busiest_4_usage",
+    ];
+}
+
+/// An observation ID, as recorded in the [`OBSERVATION_ID`] field.
+///
+/// Observation IDs are assigned by the [`SysMonitor`] in order, starting at
+/// zero. They are only unique within a single run.
+///
+/// [`SysMonitor`]: crate::SysMonitor
+pub const fn obs_id(id: u64) -> u64 {
+    id
+}
+
+/// A CPU usage percentage, as recorded in the [`USAGE_PCT`] field.
+///
+/// `sysinfo` reports usage as an `f32`. Widening an `f32` to the `f64` that
+/// `tracing` records exposes its rounding error, so `31.818182` is recorded
+/// as `31.81818199157715`. Usage is only meaningful to a couple of decimal
+/// places anyway, so we round to two.
+pub fn usage_pct(usage: f32) -> f64 {
+    (usage as f64 * 100.0).round() / 100.0
+}
+
+/// A CPU frequency in MHz, as recorded in the [`FREQ_MHZ`] field.
+pub const fn freq_mhz(frequency: u64) -> u64 {
+    frequency
+}
@@ -0,0 +1,255 @@
+//! Liveness tracking for the actors, and a tiny `/healthz` endpoint. See
+//! [`Health`] and [`serve_health`].
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{Instrument, debug, debug_span, warn};
+
+#[derive(Debug)]
+struct HealthInner {
+    /// Reference point for the timestamps below.
+    start: Instant,
+    /// How long an actor may go without output before it is unhealthy.
+    max_age: Duration,
+    /// Millis since `start` at which the monitor last produced output.
+    monitor_last: AtomicU64,
+    /// Millis since `start` at which the stats processor last produced output.
+    stats_last: AtomicU64,
+}
+
+/// Shared liveness state for the [`SysMonitor`] and [`SysStats`] actors.
+///
+/// Each actor records a heartbeat whenever it produces output. The pipeline
+/// is healthy if _both_ actors have produced output within the last
+/// `max_age`. Typically `max_age` is a small multiple of the observation
+/// interval, e.g. `3 * interval`, so that a single slow tick does not flap
+/// the probe. The stats processor only produces output every
+/// [`SysStats::with_emit_every`] observations, so scale it by that too.
+///
+/// Note that a task that is alive but stuck is indistinguishable from a dead
+/// task from the outside. This is why we track output, rather than whether
+/// the task is running.
+///
+/// `Health` is cheap to clone. All clones share the same state.
+///
+/// [`SysMonitor`]: crate::SysMonitor
+/// [`SysStats`]: crate::SysStats
+/// [`SysStats::with_emit_every`]: crate::SysStats::with_emit_every
+#[derive(Debug, Clone)]
+pub struct Health {
+    inner: Arc<HealthInner>,
+}
+
+/// A point-in-time report produced by [`Health::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthStatus {
+    /// Time since the monitor last produced an observation.
+    pub monitor_age: Duration,
+    /// Time since the stats processor last computed stats.
+    pub stats_age: Duration,
+    /// The configured tolerance.
+    pub max_age: Duration,
+}
+
+impl HealthStatus {
+    /// True if both actors produced output within `max_age`.
+    pub const fn is_healthy(&self) -> bool {
+        self.monitor_age.as_millis() <= self.max_age.as_millis()
+            && self.stats_age.as_millis() <= self.max_age.as_millis()
+    }
+}
+
+impl Health {
+    /// Create a new health tracker. Actors that have not produced output
+    /// within `max_age` are considered unhealthy.
+    ///
+    /// Both actors are considered to have produced output at creation time,
+    /// which gives the pipeline `max_age` to start up.
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            inner: Arc::new(HealthInner {
+                start: Instant::now(),
+                max_age,
+                monitor_last: AtomicU64::new(0),
+                stats_last: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    fn now_millis(&self) -> u64 {
+        self.inner.start.elapsed().as_millis() as u64
+    }
+
+    fn age(&self, last: &AtomicU64) -> Duration {
+        let last = last.load(Ordering::Relaxed);
+        Duration::from_millis(self.now_millis().saturating_sub(last))
+    }
+
+    /// Record that the monitor produced an observation.
+    pub fn monitor_beat(&self) {
+        self.inner
+            .monitor_last
+            .store(self.now_millis(), Ordering::Relaxed);
+    }
+
+    /// Record that the stats processor computed stats.
+    pub fn stats_beat(&self) {
+        self.inner
+            .stats_last
+            .store(self.now_millis(), Ordering::Relaxed);
+    }
+
+    /// Check the current health of the pipeline.
+    pub fn check(&self) -> HealthStatus {
+        HealthStatus {
+            monitor_age: self.age(&self.inner.monitor_last),
+            stats_age: self.age(&self.inner.stats_last),
+            max_age: self.inner.max_age,
+        }
+    }
+}
+
+/// Serve `GET /healthz` on the given port, reporting the state of `health`.
+///
+/// The endpoint returns `200 OK` if the pipeline is healthy, and
+/// `503 Service Unavailable` otherwise. The body is a small JSON object with
+/// the age of each actor's last output, in milliseconds. Any other path
+/// returns `404 Not Found`. This is suitable for kubernetes readiness and
+/// liveness probes:
+///
+/// ```sh
+/// curl -i http://localhost:9001/healthz
+/// ```
+///
+/// This is deliberately separate from the metrics listener set up in
+/// [`init_metrics`]. Metrics are scraped, and a scrape succeeding tells you
+/// nothing about whether the actors are still producing data. The health
+/// endpoint answers exactly that question.
+///
+/// The listener is bound before this function returns, so that bind errors
+/// are reported to the caller. Connections are then served in a background
+/// task, which runs until the returned [`JoinHandle`] is aborted.
+///
+/// [`init_metrics`]: crate::init_metrics
+pub async fn serve_health(health: Health, port: u16) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(error) => {
+                    warn!(%error, "failed to accept health check connection");
+                    continue;
+                }
+            };
+
+            let health = health.clone();
+            let span = debug_span!("Health check", %peer);
+            tokio::spawn(
+                async move {
+                    if let Err(error) = handle_connection(stream, &health).await {
+                        debug!(%error, "health check connection failed");
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }))
+}
+
+/// The JSON body of a health check response, with the age of each actor's
+/// last output, in milliseconds.
+pub(crate) fn health_body(status: &HealthStatus) -> String {
+    format!(
+        r#"{{"healthy":{},"monitor_age_ms":{},"stats_age_ms":{},"max_age_ms":{}}}"#,
+        status.is_healthy(),
+        status.monitor_age.as_millis(),
+        status.stats_age.as_millis(),
+        status.max_age.as_millis(),
+    )
+}
+
+/// Handle a single HTTP/1.x request. We only need to read the request line,
+/// so this avoids pulling in a full HTTP server.
+async fn handle_connection(mut stream: TcpStream, health: &Health) -> std::io::Result<()> {
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let request_line = request.lines().next().unwrap_or_default();
+
+    let (status, body) = if request_line.starts_with("GET /healthz ") {
+        let status = health.check();
+        debug!(
+            healthy = status.is_healthy(),
+            monitor_age_ms = status.monitor_age.as_millis() as u64,
+            stats_age_ms = status.stats_age.as_millis() as u64,
+            "served health check"
+        );
+
+        let code = if status.is_healthy() {
+            "200 OK"
+        } else {
+            "503 Service Unavailable"
+        };
+        (code, health_body(&status))
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `GET /healthz` from the server on `port`, and return the status line.
+    async fn get_healthz(port: u16) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn stale_monitor_beats_are_unavailable() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let health = Health::new(Duration::from_millis(200));
+        let server = serve_health(health.clone(), port).await.unwrap();
+
+        health.monitor_beat();
+        health.stats_beat();
+        assert_eq!(get_healthz(port).await, "HTTP/1.1 200 OK");
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        health.stats_beat();
+        assert_eq!(get_healthz(port).await, "HTTP/1.1 503 Service Unavailable");
+
+        server.abort();
+    }
+}
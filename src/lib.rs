@@ -12,64 +12,362 @@
 //! tracing events with the computed statistics.
 //!
 //! The [`run_observations`] function starts the observation and stats
-//! processing tasks, and returns a [`JoinHandle`] that will resolve if the
-//! tasks panic or exit. The tasks will run indefinitely until the program
-//! exits or are cancelled using the [`JoinHandle`]. The [`run_observations`]
+//! processing tasks, and returns a [`PipelineHandle`] that will resolve if
+//! the tasks panic or exit. The tasks will run indefinitely until the program
+//! exits, or are shut down using the [`PipelineHandle`]. The [`run_observations`]
 //! function also takes an optional outbound channel, which can be used to
-//! add your own actors to further process the observations.
+//! add your own actors to further process the observations. If a channel is
+//! more than you need, [`run_observations_with`] calls an async closure for
+//! each observation instead. If it's less, an [`ObservationStream`] wraps the
+//! channel, for `StreamExt` combinators.
+//!
+//! The actors are building blocks too. [`SysMonitor::spawn`] and
+//! [`SysStats::spawn`] document what each does with its channels, so they
+//! can be wired into topologies of your own.
+//!
+//! The [`PipelineBuilder`] exposes optional pieces of the pipeline, such as
+//! [`Health`] tracking, which can be served for readiness and liveness probes
+//! with [`serve_health`], and a [`Watchdog`] that flags a stalled monitor.
 //!
 //! The library also provides sample code for initializing tracing subscribers
 //! in [`init_tracing`], and a metrics exporter in [`init_metrics`]. Typically
 //! these functions do not belong in library code, but are included here for
 //! education.
 //!
+//! Embedding just the pieces? The Prometheus exporter, OTLP span export, and
+//! the `sysinfo` monitor are behind the `prometheus`, `otlp`, and `sysinfo`
+//! features, which are on by default. Build with `--no-default-features
+//! --features minimal` for the data types and the actor plumbing alone.
+//!
 //! This crate is a teaching tool. Browse the source code, read the comments
 //! and documentation, check out the examples! File issues if you have
 //! questions, comments, concerns, worries, doubts, fears, or just need someone
 //! to talk to :)
 
+pub mod baggage;
+
+#[cfg(feature = "sysinfo")]
+mod cgroup;
+#[cfg(feature = "sysinfo")]
+pub use cgroup::CpuView;
+
+mod chaos;
+pub use chaos::{Chaos, Fault};
+
+mod collector;
+pub use collector::{
+    Collector, DEFAULT_HOST_TIMEOUT, DEFAULT_MAX_HOSTS, FLEET_HOST, FleetReport, HostReports,
+};
+
+#[cfg(feature = "sysinfo")]
+mod config;
+#[cfg(feature = "sysinfo")]
+pub use config::{
+    AlertingConfig, DEFAULT_INTERVAL, DEFAULT_METRICS_PORT, EmfConfig, MetricsConfig,
+    PipelineConfig, SinksConfig, TracingConfig, parse_duration,
+};
+
+#[cfg(feature = "sysinfo")]
+mod cpu_times;
+
+mod datadog;
+
+mod dispatch;
+pub use dispatch::{Dispatcher, Sample};
+
+#[cfg(feature = "sysinfo")]
+mod doctor;
+#[cfg(feature = "sysinfo")]
+pub use doctor::{Check, Diagnosis, doctor};
+
+mod emf;
+pub use emf::{DEFAULT_EMF_NAMESPACE, EmfSink};
+
+#[cfg(feature = "exercises")]
+pub mod exercises;
+
+#[cfg(feature = "eventlog")]
+mod event_log;
+#[cfg(feature = "eventlog")]
+pub use event_log::EventLogLayer;
+
+mod event_metrics;
+pub use event_metrics::EventMetricsLayer;
+
+#[cfg(feature = "prometheus")]
+mod exemplars;
+#[cfg(feature = "prometheus")]
+pub use exemplars::{OPENMETRICS_CONTENT_TYPE, render_openmetrics};
+
+pub mod fields;
+
+mod health;
+pub use health::{Health, HealthStatus, serve_health};
+
+mod memory;
+#[cfg(feature = "sysinfo")]
+pub use memory::MemoryMonitor;
+pub use memory::{MemoryObservation, MemoryReport, MemoryStats, MemoryUsage};
+
 pub(crate) mod metrics;
-pub use metrics::init_metrics;
+pub use metrics::{DEFAULT_LABEL_LIMIT, ObservationGuard, set_host_label, set_label_limit};
+#[cfg(feature = "prometheus")]
+pub use metrics::{
+    init_metrics, init_metrics_recorder, try_init_metrics, try_init_metrics_recorder,
+};
 
+#[cfg(feature = "prometheus")]
+mod live_gauge;
+#[cfg(feature = "prometheus")]
+pub use live_gauge::LiveGaugeCheck;
+
+mod long_span;
+pub use long_span::LongSpanLayer;
+
+#[cfg(feature = "mdns")]
+mod mdns;
+#[cfg(feature = "mdns")]
+pub use mdns::{
+    CollectorAdvertisement, DiscoveredCollector, MDNS_SERVICE_TYPE, advertise_collector,
+    discover_collector,
+};
+
+#[cfg(feature = "sysinfo")]
 mod monitor;
-pub use monitor::SysMonitor;
+#[cfg(feature = "sysinfo")]
+pub use monitor::{
+    AdaptiveInterval, DEFAULT_BUSY_THRESHOLD, DEFAULT_IDLE_THRESHOLD, MissedTicks, SysMonitor,
+    snapshot,
+};
 
 mod obs;
-pub use obs::{CpuStats, Observation};
+pub use obs::{CpuData, CpuStats, CpuTimes, Observation, ObservationData};
+
+#[cfg(feature = "otel-logs")]
+mod otel_logs;
+#[cfg(feature = "otel-logs")]
+pub use otel_logs::init_otel_logs;
+
+#[cfg(feature = "otel-metrics")]
+mod otel_metrics;
+#[cfg(feature = "otel-metrics")]
+pub use otel_metrics::init_otel_metrics;
+
+mod overhead;
+pub use overhead::CountingAllocator;
+#[cfg(feature = "sysinfo")]
+pub use overhead::{Cost, DEFAULT_OVERHEAD_ITERATIONS, Overhead, OverheadReport};
+
+mod pipeline;
+pub use pipeline::{ConfigError, DEFAULT_CHANNEL_CAPACITY, DEFAULT_WINDOW, MAX_WINDOW};
+#[cfg(feature = "sysinfo")]
+pub use pipeline::{Pipeline, PipelineBuilder, PipelineHandle};
+
+mod process;
+pub use process::{ProcessSelector, ProcessUsage};
+
+mod query;
+pub use query::{StatsQuerier, StatsQuery, WindowState};
+
+mod rate_limit;
+pub use rate_limit::{RateLimit, RateLimitPolicy};
+
+mod record;
+pub use record::{ObservationRecorder, ObservationReplayer};
+
+mod refresh;
+pub use refresh::{RefreshSpec, Temperature};
+
+mod report;
+pub use report::ShutdownReport;
+
+mod retry;
+pub use retry::{Backoff, retry, retry_blocking};
+
+mod rng;
+
+mod rollup;
+pub use rollup::{DEFAULT_ROLLUP_PERIOD, MinAvgMax, Rollup, RollupReport};
+
+#[cfg(feature = "axum")]
+mod router;
+#[cfg(feature = "axum")]
+pub use router::RouterBuilder;
+
+mod sampling;
+pub use sampling::TargetSampler;
+
+#[cfg(feature = "cron")]
+mod schedule;
+#[cfg(feature = "cron")]
+pub use schedule::CronSchedule;
+
+#[cfg(feature = "sentry")]
+mod sentry_hook;
+#[cfg(feature = "sentry")]
+pub use sentry_hook::init_sentry;
+
+mod shutdown;
+pub use shutdown::{DEFAULT_STAGE_TIMEOUT, ShutdownCoordinator};
+
+mod sink;
+pub use sink::{ObservationSink, SinkDriver};
+
+mod span_count;
+pub use span_count::SpanCountLayer;
+
+mod span_duration;
+pub use span_duration::{DEFAULT_TIMED_SPANS, SpanDurationLayer};
+
+mod span_tree;
+pub use span_tree::SpanTreeLayer;
 
 mod stats;
-pub use stats::SysStats;
+pub use stats::{
+    CoreUsage, DEFAULT_BASELINE_WINDOW, DEFAULT_BUSIEST_CORES, DEFAULT_IMBALANCE_THRESHOLD,
+    Eviction, MAX_BUSIEST_CORES, StatsReport, SysStats, USAGE_BUCKETS,
+};
+
+mod stream;
+pub use stream::ObservationStream;
+
+mod synthetic;
+pub use synthetic::{LoadPattern, SyntheticMonitor};
+
+#[cfg(feature = "systemd")]
+mod systemd;
+
+mod tcp;
+pub use tcp::{DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CONNECTIONS, TcpSink, TcpSource};
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[cfg(feature = "sysinfo")]
+mod topology;
 
 mod trace;
-pub use trace::init_tracing;
+pub use trace::{
+    DEFAULT_SHUTDOWN_TIMEOUT, LogFormat, OtlpProtocol, TracingBuilder, TracingHandle, init_tracing,
+};
+
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "tui")]
+pub use tui::TuiDashboard;
+
+mod udp;
+pub use udp::{UdpSink, UdpSource};
+
+mod watchdog;
+pub use watchdog::Watchdog;
+
+mod window;
+pub use window::Window;
+
+mod wire;
+pub use wire::{SharedSecret, WIRE_VERSION, WireCompression, WireEncoding};
 
+#[cfg(feature = "sysinfo")]
 use std::time::Duration;
-use tokio::{sync::mpsc, task::JoinHandle};
+#[cfg(feature = "sysinfo")]
+use tokio::{sync::mpsc, task::JoinSet};
+#[cfg(feature = "sysinfo")]
+use tracing::Instrument;
 
 /// Start taking observations repeatedly, with an interval of
 /// `duration`. If an outbound channel is provided, send observations to it
 /// after processing them.
+///
+/// This is shorthand for the [`PipelineBuilder`].
+///
+/// ## Panics
+///
+/// If `every` is zero. Use [`PipelineBuilder::spawn`] to handle invalid
+/// configuration without panicking.
+#[cfg(feature = "sysinfo")]
 pub fn run_observations(
     every: Duration,
     outbound: Option<mpsc::Sender<Observation>>,
-) -> JoinHandle<()> {
-    let (tx, rx) = mpsc::channel(2);
-
-    let monitor = SysMonitor::new(sysinfo::System::new_all(), every, tx);
-
-    let stats = SysStats::new(rx, outbound);
+) -> PipelineHandle {
+    let mut builder = PipelineBuilder::new(every);
+    if let Some(outbound) = outbound {
+        builder = builder.with_outbound(outbound);
+    }
+    builder
+        .spawn()
+        .unwrap_or_else(|error| panic!("invalid pipeline configuration: {error}"))
+}
 
-    let monitor_handle = monitor.spawn();
-    let stats_handle = stats.spawn();
+/// Start taking observations repeatedly, with an interval of `every`, and
+/// call `handler` with each one after processing it.
+///
+/// This is [`run_observations`], without the channel. Each call to `handler`
+/// is spawned as its own task, instrumented with the observation's span, so
+/// anything it logs, and any span it opens, is part of the observation's
+/// trace. A slow handler doesn't hold up the next observation, but it does
+/// mean handlers can run concurrently, and finish out of order. A handler
+/// that panics is logged, and the rest carry on.
+///
+/// [`PipelineHandle::shutdown`] waits for the handlers that are still
+/// running.
+///
+/// ```no_run
+/// use metrics_tracing_example::run_observations_with;
+/// use std::time::Duration;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let pipeline = run_observations_with(Duration::from_secs(5), |obs| async move {
+///     let busiest = obs.iter().map(|cpu| cpu.usage).fold(0.0, f32::max);
+///     tracing::info!(busiest, "handled observation");
+/// });
+///
+/// tokio::signal::ctrl_c().await?;
+/// pipeline.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## Panics
+///
+/// If `every` is zero, like [`run_observations`].
+#[cfg(feature = "sysinfo")]
+pub fn run_observations_with<F, Fut>(every: Duration, mut handler: F) -> PipelineHandle
+where
+    F: FnMut(Observation) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel::<Observation>(DEFAULT_CHANNEL_CAPACITY);
+    let handle = run_observations(every, Some(tx));
 
-    tokio::spawn(async move {
-        tokio::select! {
-            _ = monitor_handle => {
-                tracing::debug!("Monitor task exited");
-            }
-            _ = stats_handle => {
-                tracing::debug!("Stats task exited");
+    let consumer = tokio::spawn(async move {
+        let mut handlers = JoinSet::new();
+        loop {
+            tokio::select! {
+                obs = rx.recv() => {
+                    let Some(obs) = obs else { break };
+                    let span = obs.span().clone();
+                    handlers.spawn(handler(obs).instrument(span));
+                }
+                // Reap finished handlers as we go, so the set doesn't grow.
+                Some(result) = handlers.join_next(), if !handlers.is_empty() => {
+                    log_handler_panic(result);
+                }
             }
         }
-    })
+        while let Some(result) = handlers.join_next().await {
+            log_handler_panic(result);
+        }
+    });
+    handle.with_consumer(consumer)
+}
+
+/// Log the panic, if a handler spawned by [`run_observations_with`] panicked.
+#[cfg(feature = "sysinfo")]
+fn log_handler_panic(result: Result<(), tokio::task::JoinError>) {
+    if let Err(error) = result
+        && error.is_panic()
+    {
+        tracing::error!(%error, "observation handler panicked");
+    }
 }
@@ -0,0 +1,116 @@
+//! The [`LiveGaugeCheck`] actor catches the live observations gauge
+//! drifting.
+
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// Periodically checks the `my_cute_app.observations_live` gauge against
+/// the number of [`ObservationGuard`]s actually alive, and flags any drift.
+///
+/// The gauge is kept by [`Drop`]: up when a guard is created, down when
+/// it's dropped. That's hard to get wrong, but when it is wrong, nothing
+/// says so. The gauge just reads a plausible number that happens to be off.
+/// For example:
+///
+/// - A guard's handle was created before the recorder was installed, so it
+///   is a no-op. The observations are alive, and the gauge reads 0.
+/// - A guard was created under a different recorder, e.g. a local one in a
+///   test, so its increments land somewhere else.
+/// - Something else sets the gauge, by name.
+///
+/// Each guard is counted on an atomic too, which never touches the
+/// recorder. This reads the gauge back from the Prometheus recorder, and
+/// compares. An observation made or dropped mid-check can make the two
+/// disagree for a moment, so a check is only trusted if the count didn't
+/// change while the gauge was read, and drift is only flagged once two
+/// checks in a row have seen it:
+///
+/// - An `error!` event when the gauge drifts, and an `info!` event when it
+///   agrees again. Like the [`Watchdog`], these are emitted once per
+///   transition, rather than on every check.
+///
+/// Rendering the metrics isn't free, so check every minute or so, not every
+/// observation. The recorder must be the one the guards record to, e.g.
+/// the handle from [`init_metrics_recorder`].
+///
+/// [`ObservationGuard`]: crate::ObservationGuard
+/// [`Watchdog`]: crate::Watchdog
+/// [`init_metrics_recorder`]: crate::init_metrics_recorder
+#[derive(Debug, Clone)]
+pub struct LiveGaugeCheck {
+    metrics: PrometheusHandle,
+    every: Duration,
+}
+
+impl LiveGaugeCheck {
+    /// Create a check that reads the gauge from `metrics` every `every`.
+    pub const fn new(metrics: PrometheusHandle, every: Duration) -> Self {
+        Self { metrics, every }
+    }
+
+    /// How far the gauge is from the number of live guards, right now.
+    /// Positive if the gauge reads too high. `None` if a guard was created
+    /// or dropped while the gauge was being read, so the two can't be
+    /// compared.
+    pub fn drift(&self) -> Option<i64> {
+        let before = crate::metrics::live_guards();
+        let gauge = crate::metrics::rendered_observations_live(&self.metrics.render());
+        let after = crate::metrics::live_guards();
+        (before == after).then(|| gauge as i64 - after)
+    }
+
+    /// Spawn the check task. It checks every `every`, and runs until
+    /// aborted.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.every.max(Duration::from_millis(1)));
+            let mut seen = false;
+            let mut drifted = false;
+
+            loop {
+                interval.tick().await;
+
+                let Some(drift) = self.drift() else {
+                    continue;
+                };
+                let now_seen = drift != 0;
+                let now_drifted = now_seen && seen;
+                seen = now_seen;
+
+                match (drifted, now_drifted) {
+                    (false, true) => error!(
+                        drift,
+                        live = crate::metrics::live_guards(),
+                        "observations_live gauge drifted from the live observation count"
+                    ),
+                    (true, false) => {
+                        info!("observations_live gauge agrees with the live observation count")
+                    }
+                    _ => continue,
+                }
+                drifted = now_drifted;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::rendered_observations_live;
+
+    #[test]
+    fn reads_the_gauge_across_labels() {
+        let rendered = "\
+# TYPE my_cute_app_observations_live gauge
+my_cute_app_observations_live{host=\"a\"} 3
+my_cute_app_observations_live{host=\"b\"} 2
+# TYPE my_cute_app_observations_live_total counter
+my_cute_app_observations_live_total 100
+my_cute_app_observations_made 9
+";
+        assert_eq!(rendered_observations_live(rendered), 5.0);
+        assert_eq!(rendered_observations_live(""), 0.0);
+    }
+}
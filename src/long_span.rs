@@ -0,0 +1,312 @@
+//! A diagnostic [`Layer`] that flags spans held open for too long. See
+//! [`LongSpanLayer`].
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+    time::{Duration, Instant},
+};
+use tracing::{Level, Subscriber, span, warn};
+use tracing_subscriber::{Layer, filter::LevelFilter, layer::Context, registry::LookupSpan};
+
+/// The shortest interval between checks, however small the threshold.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A span that is, or was recently, open.
+#[derive(Debug)]
+struct OpenSpan {
+    id: span::Id,
+    name: &'static str,
+    target: &'static str,
+    opened_at: Instant,
+    /// Set when the span closes, if it has not been flagged yet.
+    closed_at: Option<Instant>,
+    flagged: bool,
+}
+
+impl OpenSpan {
+    fn open_for(&self, now: Instant) -> Duration {
+        self.closed_at.unwrap_or(now).duration_since(self.opened_at)
+    }
+}
+
+/// The spans being tracked, and a map from span IDs to their keys.
+///
+/// The registry reuses the IDs of closed spans, and a span that closed after
+/// the threshold is kept until it is flagged. So spans are keyed by the
+/// order they were created in, rather than by ID, like in the
+/// [`SpanTreeLayer`], or the next span with the same ID would replace it
+/// before it's flagged.
+///
+/// [`SpanTreeLayer`]: crate::SpanTreeLayer
+#[derive(Debug, Default)]
+struct State {
+    spans: HashMap<u64, OpenSpan>,
+    open: HashMap<span::Id, u64>,
+    next_key: u64,
+}
+
+/// A span that was open for longer than the threshold.
+#[derive(Debug)]
+struct LongOpen {
+    id: span::Id,
+    name: &'static str,
+    target: &'static str,
+    open_for: Duration,
+}
+
+impl State {
+    /// Track the span `id`, opened at `now`.
+    fn open(&mut self, id: &span::Id, metadata: &'static tracing::Metadata<'static>, now: Instant) {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.open.insert(id.clone(), key);
+        self.spans.insert(
+            key,
+            OpenSpan {
+                id: id.clone(),
+                name: metadata.name(),
+                target: metadata.target(),
+                opened_at: now,
+                closed_at: None,
+                flagged: false,
+            },
+        );
+    }
+
+    /// The span `id` closed at `now`. Its ID is free for the next span.
+    fn close(&mut self, id: &span::Id, threshold: Duration, now: Instant) {
+        let Some(key) = self.open.remove(id) else {
+            return;
+        };
+        let Some(span) = self.spans.get_mut(&key) else {
+            return;
+        };
+
+        // Short-lived spans, which is nearly all of them, are forgotten
+        // right away. A span that closed after the threshold, but before the
+        // check thread saw it, is kept until the next check flags it.
+        if span.flagged || span.open_for(now) < threshold {
+            self.spans.remove(&key);
+        } else {
+            span.closed_at = Some(now);
+        }
+    }
+
+    /// Flag the spans that have been open for `threshold` at `now`, and
+    /// forget the closed ones.
+    fn check(&mut self, threshold: Duration, now: Instant) -> Vec<LongOpen> {
+        let mut long_open = Vec::new();
+        self.spans.retain(|_, span| {
+            let open_for = span.open_for(now);
+            if !span.flagged && open_for >= threshold {
+                span.flagged = true;
+                long_open.push(LongOpen {
+                    id: span.id.clone(),
+                    name: span.name,
+                    target: span.target,
+                    open_for,
+                });
+            }
+            // Closed spans are only kept around until they are checked.
+            span.closed_at.is_none()
+        });
+        long_open
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A [`Layer`] that emits a warning when a span stays open for longer than a
+/// threshold.
+///
+/// ## Catching bad span hygiene
+///
+/// A span should live exactly as long as the work it describes. Holding on
+/// to one, e.g. by keeping [`Observation`]s in a collection, or entering a
+/// span at the top of `main` and never leaving it, keeps it open. Open
+/// spans are not exported, so the trace shows up late, or not at all. The
+/// `bad_holding_span` and `bad_program_span` examples both make this
+/// mistake, and both install this layer, so that they tell on themselves.
+///
+/// ## How it works
+///
+/// A layer only hears about a span when something happens to it. A span
+/// that is being held, and not used, produces no callbacks at all. So the
+/// layer keeps its own table of open spans, and a background thread checks
+/// it every quarter of the threshold. Each long-open span is flagged once,
+/// with a `warn!` event naming the offending span.
+///
+/// Why a thread, rather than checking from the layer callbacks? Emitting an
+/// event from inside a layer callback re-enters the subscriber in the middle
+/// of dispatching something else. With per-layer filters, that confuses
+/// the other layers about which spans they have enabled, and the `fmt`
+/// layer panics. The background thread emits its events from outside of any
+/// callback, and outside of any span.
+///
+/// Only spans at `INFO` level or above are tracked by default, regardless of
+/// the log filter. Otherwise, turning logging down would hide exactly the
+/// spans we're trying to catch. See [`LongSpanLayer::with_max_level`].
+///
+/// ```no_run
+/// use metrics_tracing_example::{LongSpanLayer, TracingBuilder};
+/// use std::time::Duration;
+///
+/// # async fn _main() {
+/// let long_spans = LongSpanLayer::new(Duration::from_secs(30));
+/// let _provider = TracingBuilder::new().with_long_spans(long_spans).init();
+/// # }
+/// ```
+///
+/// [`Observation`]: crate::Observation
+#[derive(Debug, Clone)]
+pub struct LongSpanLayer {
+    threshold: Duration,
+    max_level: Level,
+    state: Arc<Mutex<State>>,
+}
+
+impl LongSpanLayer {
+    /// Create a new layer that warns about spans open for longer than
+    /// `threshold`.
+    ///
+    /// This starts the background thread that checks for long-open spans.
+    /// The thread exits once the layer, and all of its clones, are dropped.
+    pub fn new(threshold: Duration) -> Self {
+        let layer = Self::unchecked(threshold);
+        let check_every = (threshold / 4).max(MIN_CHECK_INTERVAL);
+        let weak = Arc::downgrade(&layer.state);
+        std::thread::Builder::new()
+            .name("long-span-check".into())
+            .spawn(move || check_loop(weak, threshold, check_every))
+            .expect("failed to spawn long span check thread");
+        layer
+    }
+
+    /// The layer, without the background thread to check on it.
+    fn unchecked(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            max_level: Level::INFO,
+            state: Arc::default(),
+        }
+    }
+
+    /// Track spans up to `level`, instead of the default of `INFO`. E.g.
+    /// `Level::TRACE` tracks every span.
+    pub const fn with_max_level(mut self, level: Level) -> Self {
+        self.max_level = level;
+        self
+    }
+
+    /// A per-layer filter that enables the tracked spans.
+    pub(crate) fn filter(&self) -> LevelFilter {
+        LevelFilter::from_level(self.max_level)
+    }
+}
+
+/// Periodically flag spans that have been open longer than `threshold`.
+fn check_loop(state: Weak<Mutex<State>>, threshold: Duration, check_every: Duration) {
+    loop {
+        std::thread::sleep(check_every);
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+
+        let long_open = lock(&state).check(threshold, Instant::now());
+        drop(state);
+
+        for span in long_open {
+            warn!(
+                span_id = span.id.into_u64(),
+                span_name = span.name,
+                span_target = span.target,
+                open_for_ms = span.open_for.as_millis() as u64,
+                threshold_ms = threshold.as_millis() as u64,
+                "span open longer than the threshold"
+            );
+        }
+    }
+}
+
+impl<S> Layer<S> for LongSpanLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, _ctx: Context<'_, S>) {
+        lock(&self.state).open(id, attrs.metadata(), Instant::now());
+    }
+
+    fn on_close(&self, id: span::Id, _ctx: Context<'_, S>) {
+        lock(&self.state).close(&id, self.threshold, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::info_span;
+    use tracing_subscriber::{Registry, layer::SubscriberExt};
+
+    const THRESHOLD: Duration = Duration::from_millis(20);
+
+    /// Run `f` with the layer installed, without its background thread, so
+    /// that the test decides when to check. `f` gets the layer's state.
+    fn with_layer(f: impl FnOnce(&Mutex<State>)) {
+        let layer = LongSpanLayer::unchecked(THRESHOLD);
+        let state = layer.state.clone();
+        tracing::subscriber::with_default(Registry::default().with(layer), || f(&state));
+    }
+
+    fn check(state: &Mutex<State>) -> Vec<&'static str> {
+        let long_open = lock(state).check(THRESHOLD, Instant::now());
+        long_open.iter().map(|span| span.name).collect()
+    }
+
+    #[test]
+    fn long_spans_are_flagged_once() {
+        with_layer(|state| {
+            let _held = info_span!("held").entered();
+            std::thread::sleep(THRESHOLD * 2);
+            assert_eq!(check(state), ["held"]);
+            assert!(check(state).is_empty());
+        });
+    }
+
+    #[test]
+    fn short_spans_are_not_flagged() {
+        with_layer(|state| {
+            info_span!("short").in_scope(|| {});
+            assert!(lock(state).spans.is_empty());
+            assert!(check(state).is_empty());
+        });
+    }
+
+    /// The registry's IDs carry a generation, so this drives the state by
+    /// hand, with the same ID twice, like a subscriber that reuses them.
+    #[test]
+    fn reused_ids_do_not_hide_closed_spans() {
+        let (mut first, mut second) = (None, None);
+        with_layer(|_| {
+            first = info_span!("first").metadata();
+            second = info_span!("second").metadata();
+        });
+        let id = span::Id::from_u64(1);
+        let state = Mutex::new(State::default());
+        let start = Instant::now();
+
+        let mut locked = lock(&state);
+        locked.open(&id, first.unwrap(), start);
+        // Closed past the threshold, before any check saw it.
+        locked.close(&id, THRESHOLD, start + THRESHOLD * 2);
+        locked.open(&id, second.unwrap(), start + THRESHOLD * 2);
+        drop(locked);
+
+        assert_eq!(check(&state), ["first"]);
+        // The second span is still tracked under the reused ID.
+        lock(&state).close(&id, THRESHOLD, start + THRESHOLD * 5);
+        assert_eq!(check(&state), ["second"]);
+    }
+}
@@ -0,0 +1,177 @@
+//! Finding the collector on the local network. See [`discover_collector`].
+
+use mdns_sd::{ScopedIp, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// The mDNS service type collectors are advertised as.
+pub const MDNS_SERVICE_TYPE: &str = "_sysmon._tcp.local.";
+
+/// The TXT record property holding the collector's UDP port, if it listens
+/// for [`UdpSink`]s too.
+///
+/// [`UdpSink`]: crate::UdpSink
+const UDP_PORT: &str = "udp";
+
+fn mdns_error(error: mdns_sd::Error) -> io::Error {
+    io::Error::other(error)
+}
+
+/// A collector advertised on the local network. Stops advertising when
+/// dropped. See [`advertise_collector`].
+pub struct CollectorAdvertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl std::fmt::Debug for CollectorAdvertisement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CollectorAdvertisement")
+            .field("fullname", &self.fullname)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for CollectorAdvertisement {
+    fn drop(&mut self) {
+        // Unregistering tells agents that are browsing that the collector is
+        // gone, rather than leaving them to time it out. Both are best
+        // effort: the daemon runs on its own thread, and we don't wait.
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Advertise a collector listening for [`TcpSink`]s on `port`, and for
+/// [`UdpSink`]s on `udp_port`, if any, on every interface, under this host's
+/// name.
+///
+/// Agents find it with [`discover_collector`]. The collector is advertised
+/// until the returned guard is dropped.
+///
+/// ## Errors
+///
+/// If the mDNS daemon can't start, e.g. because multicast isn't available.
+///
+/// [`TcpSink`]: crate::TcpSink
+/// [`UdpSink`]: crate::UdpSink
+pub fn advertise_collector(port: u16, udp_port: Option<u16>) -> io::Result<CollectorAdvertisement> {
+    let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+    let host = crate::wire::local_host();
+    let properties: Vec<_> = udp_port
+        .map(|udp_port| (UDP_PORT, udp_port.to_string()))
+        .into_iter()
+        .collect();
+    // No addresses: the daemon fills them in from the interfaces, and keeps
+    // them up to date as they change.
+    let service = ServiceInfo::new(
+        MDNS_SERVICE_TYPE,
+        &host,
+        &format!("{host}.local."),
+        (),
+        port,
+        &properties[..],
+    )
+    .map_err(mdns_error)?
+    .enable_addr_auto();
+    let fullname = service.get_fullname().to_owned();
+    daemon.register(service).map_err(mdns_error)?;
+    info!(name = %fullname, port, udp_port, "advertising collector with mDNS");
+    Ok(CollectorAdvertisement { daemon, fullname })
+}
+
+/// A collector found by [`discover_collector`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredCollector {
+    /// The collector's mDNS instance name, e.g.
+    /// `box-1._sysmon._tcp.local.`.
+    pub name: String,
+    /// The address to point a [`TcpSink`] at.
+    ///
+    /// [`TcpSink`]: crate::TcpSink
+    pub tcp: SocketAddr,
+    /// The address to point a [`UdpSink`] at, if the collector listens for
+    /// them.
+    ///
+    /// [`UdpSink`]: crate::UdpSink
+    pub udp: Option<SocketAddr>,
+}
+
+/// Find a collector advertised with [`advertise_collector`] on the local
+/// network, waiting up to `timeout` for one to answer.
+///
+/// Typing the collector's address into every agent is fine for one or two
+/// machines. For a classroom of laptops, or a demo on a network you've never
+/// seen before, it isn't. mDNS (the protocol behind Bonjour and Avahi) lets
+/// the collector announce itself with a multicast packet, and the agents ask
+/// for it the same way. No DNS server, and no configuration.
+///
+/// Multicast doesn't cross routers, so this only finds collectors on the
+/// same network segment. If there are several, the first to answer wins.
+/// IPv4 addresses are preferred over IPv6 ones, which may need a scope.
+///
+/// ```no_run
+/// use metrics_tracing_example::{TcpSink, discover_collector};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let collector = discover_collector(Duration::from_secs(5)).await?;
+/// let (_tx, rx) = mpsc::channel(16);
+/// let _sink = TcpSink::new(rx, collector.tcp.to_string()).spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## Errors
+///
+/// If the mDNS daemon can't start, or no collector answers in time.
+pub async fn discover_collector(timeout: Duration) -> io::Result<DiscoveredCollector> {
+    let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+    let events = daemon.browse(MDNS_SERVICE_TYPE).map_err(mdns_error)?;
+    let deadline = Instant::now() + timeout;
+
+    let found = loop {
+        let event = match tokio::time::timeout_at(deadline, events.recv_async()).await {
+            Ok(Ok(event)) => event,
+            // Timed out, or the daemon died.
+            Ok(Err(_)) | Err(_) => break None,
+        };
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        let mut addresses: Vec<IpAddr> =
+            service.addresses.iter().map(ScopedIp::to_ip_addr).collect();
+        // `false` sorts first.
+        addresses.sort_by_key(|ip| !ip.is_ipv4());
+        let Some(&ip) = addresses.first() else {
+            debug!(name = %service.fullname, "skipping collector without addresses");
+            continue;
+        };
+        let udp = service
+            .txt_properties
+            .get_property_val_str(UDP_PORT)
+            .and_then(|port| port.parse().ok())
+            .map(|port| SocketAddr::new(ip, port));
+        break Some(DiscoveredCollector {
+            name: service.fullname.clone(),
+            tcp: SocketAddr::new(ip, service.port),
+            udp,
+        });
+    };
+    let _ = daemon.shutdown();
+
+    let found = found.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no collector answered on mDNS within {timeout:?}"),
+        )
+    })?;
+    info!(name = %found.name, tcp = %found.tcp, udp = ?found.udp, "discovered collector");
+    Ok(found)
+}
@@ -0,0 +1,245 @@
+//! Memory monitoring, at its own pace. See [`MemoryMonitor`] and
+//! [`MemoryStats`].
+
+use crate::{DEFAULT_WINDOW, Observation, ObservationData, Window};
+#[cfg(feature = "sysinfo")]
+use std::time::Duration;
+#[cfg(feature = "sysinfo")]
+use sysinfo::{MemoryRefreshKind, RefreshKind, System};
+use tokio::sync::{mpsc, watch};
+#[cfg(feature = "sysinfo")]
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span};
+#[cfg(feature = "sysinfo")]
+use tracing::{instrument, trace};
+
+/// The memory of the host at a point in time, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MemoryUsage {
+    /// The total amount of RAM.
+    pub total: u64,
+    /// The amount of RAM in use.
+    pub used: u64,
+    /// The amount of RAM that can be handed out without swapping, including
+    /// caches the kernel would drop.
+    pub available: u64,
+    /// The total amount of swap.
+    pub swap_total: u64,
+    /// The amount of swap in use.
+    pub swap_used: u64,
+}
+
+impl MemoryUsage {
+    /// Read the memory from `system`, as of its last memory refresh.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn read(system: &System) -> Self {
+        Self {
+            total: system.total_memory(),
+            used: system.used_memory(),
+            available: system.available_memory(),
+            swap_total: system.total_swap(),
+            swap_used: system.used_swap(),
+        }
+    }
+
+    /// The percentage of RAM in use. Zero if there is no RAM to speak of.
+    pub fn used_pct(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.used as f64 / self.total as f64 * 100.0
+    }
+}
+
+impl ObservationData for MemoryUsage {
+    const KIND: &'static str = "memory";
+}
+
+/// A single reading of a host's memory, and the span it was taken in. The
+/// memory counterpart of a CPU [`Observation`], and the same type, with
+/// different data.
+pub type MemoryObservation = Observation<MemoryUsage>;
+
+/// A monitor that reads the host's memory at a fixed interval, and sends
+/// [`MemoryObservation`]s to a channel.
+///
+/// Memory moves slower than CPU usage, and nobody needs it every second. So
+/// it gets a monitor of its own, with its own interval, instead of riding
+/// along with the [`SysMonitor`]'s. The two can feed one [`Dispatcher`],
+/// which sends each kind of observation where it belongs.
+///
+/// [`SysMonitor`]: crate::SysMonitor
+/// [`Dispatcher`]: crate::Dispatcher
+#[cfg(feature = "sysinfo")]
+pub struct MemoryMonitor {
+    system: System,
+    interval: Duration,
+    outbound: mpsc::Sender<MemoryObservation>,
+    shutdown: CancellationToken,
+}
+
+#[cfg(feature = "sysinfo")]
+impl std::fmt::Debug for MemoryMonitor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryMonitor")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "sysinfo")]
+impl MemoryMonitor {
+    /// Create a new memory monitor that reads the memory every `interval`.
+    pub fn new(interval: Duration, outbound: mpsc::Sender<MemoryObservation>) -> Self {
+        let refresh = RefreshKind::nothing().with_memory(MemoryRefreshKind::everything());
+        Self {
+            system: System::new_with_specifics(refresh),
+            interval,
+            outbound,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Stop reading the memory when `shutdown` is cancelled. See
+    /// [`SysMonitor::with_shutdown`].
+    ///
+    /// [`SysMonitor::with_shutdown`]: crate::SysMonitor::with_shutdown
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Read the memory, and record it on the `my_cute_app.memory_bytes`
+    /// gauges.
+    ///
+    /// Unlike a CPU refresh, this reads a single small file, so it runs
+    /// right here, rather than on the blocking thread pool.
+    #[instrument(skip(self), name = "Reading memory")]
+    fn read(&mut self) -> MemoryUsage {
+        self.system.refresh_memory();
+        let usage = MemoryUsage::read(&self.system);
+        trace!(used = usage.used, total = usage.total, "Read memory");
+        crate::metrics::record_memory(&usage);
+        usage
+    }
+
+    /// Spawn the memory monitor in a new task. It runs until `shutdown` is
+    /// cancelled, or the outbound channel is closed.
+    pub fn spawn(mut self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                tokio::select! {
+                    biased;
+                    _ = self.shutdown.cancelled() => {
+                        debug!("Shutdown requested, memory monitor exiting");
+                        break;
+                    }
+                    _ = interval.tick() => {}
+                }
+
+                let span = info_span!("Memory observation");
+                let usage = span.in_scope(|| self.read());
+                let obs = MemoryObservation::from_data(usage, span);
+                if self.outbound.send(obs).await.is_err() {
+                    trace!("Memory receiver dropped, exiting");
+                    break;
+                }
+            }
+        })
+    }
+}
+
+/// Stats over a window of [`MemoryObservation`]s. See [`MemoryStats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct MemoryReport {
+    /// The number of observations in the window.
+    pub observations: usize,
+    /// The average percentage of RAM in use over the window.
+    pub average_used_pct: f64,
+    /// The highest percentage of RAM in use over the window.
+    pub peak_used_pct: f64,
+    /// The latest observation's memory usage.
+    pub latest: MemoryUsage,
+}
+
+/// A stats processor for [`MemoryObservation`]s: the [`SysStats`] of
+/// memory, in miniature.
+///
+/// For each observation, it computes a [`MemoryReport`] over the last
+/// `window` of them, emits it as a `finished memory stats` event in the
+/// observation's span, and publishes it to subscribers.
+///
+/// [`SysStats`]: crate::SysStats
+#[derive(Debug)]
+pub struct MemoryStats {
+    inbound: mpsc::Receiver<MemoryObservation>,
+    window: Window<MemoryUsage>,
+    reports: watch::Sender<MemoryReport>,
+}
+
+impl MemoryStats {
+    /// Create a new memory stats processor, over the last
+    /// [`DEFAULT_WINDOW`] observations.
+    pub fn new(inbound: mpsc::Receiver<MemoryObservation>) -> Self {
+        Self {
+            inbound,
+            window: Window::new(DEFAULT_WINDOW),
+            reports: watch::Sender::default(),
+        }
+    }
+
+    /// Compute stats over the last `window` observations.
+    ///
+    /// ## Panics
+    ///
+    /// If `window` is zero.
+    pub fn with_window(mut self, window: usize) -> Self {
+        assert!(window > 0, "stats window must be non-zero");
+        self.window = Window::new(window);
+        self
+    }
+
+    /// Subscribe to the [`MemoryReport`]s computed by this processor.
+    pub fn subscribe(&self) -> watch::Receiver<MemoryReport> {
+        self.reports.subscribe()
+    }
+
+    /// Add an observation to the window, and compute and publish the stats.
+    fn process(&mut self, obs: &MemoryObservation) {
+        self.window.push(*obs.data());
+        let (sum, peak) = self
+            .window
+            .iter()
+            .map(MemoryUsage::used_pct)
+            .fold((0.0, 0.0_f64), |(sum, peak), pct| {
+                (sum + pct, peak.max(pct))
+            });
+        let report = MemoryReport {
+            observations: self.window.len(),
+            average_used_pct: sum / self.window.len() as f64,
+            peak_used_pct: peak,
+            latest: *obs.data(),
+        };
+        info!(
+            count = report.observations,
+            average_used_pct = report.average_used_pct,
+            peak_used_pct = report.peak_used_pct,
+            available = report.latest.available,
+            "finished memory stats"
+        );
+        self.reports.send_replace(report);
+    }
+
+    /// Spawn the memory stats processor. It runs until the inbound channel
+    /// is closed.
+    pub fn spawn(mut self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(obs) = self.inbound.recv().await {
+                info_span!(parent: obs.span(), "Computing memory stats")
+                    .in_scope(|| self.process(&obs));
+            }
+            debug!("Inbound channel closed, closing memory stats");
+        })
+    }
+}
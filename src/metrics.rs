@@ -1,22 +1,321 @@
 //! Metrics collection and exporting. Check the docs for out [`init_metrics`].
 
-use crate::CpuStats;
-use metrics::{counter, gauge, histogram};
-use metrics_exporter_prometheus::PrometheusBuilder;
-use std::sync::LazyLock;
+#[cfg(feature = "prometheus")]
+use crate::exemplars::CPU_USAGE_BUCKETS;
+use crate::{
+    CoreUsage, Fault, FleetReport, MAX_BUSIEST_CORES, MinAvgMax, RollupReport,
+    collector::GapCause,
+    stats::SocketUsage,
+    wire::{Rejection, WireFormat},
+};
+#[cfg(feature = "sysinfo")]
+use crate::{
+    CpuStats, CpuTimes, MemoryUsage, MissedTicks, OverheadReport, ProcessUsage, Temperature,
+};
+#[cfg(feature = "sysinfo")]
+use metrics::{Counter, Histogram};
+use metrics::{Gauge, Key, Label, SharedString, counter, gauge, histogram};
+#[cfg(feature = "prometheus")]
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        Arc, LazyLock, Mutex, PoisonError,
+        atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tracing::warn;
 
-const OBSERVATIONS_MADE: &str = "my_cute_app.observations_made";
-const OBSERVATIONS_MADE_DESC: &str = "The total number of observations made";
+pub(crate) const OBSERVATIONS_MADE: &str = "my_cute_app.observations_made";
+pub(crate) const OBSERVATIONS_MADE_DESC: &str = "The total number of observations made";
 
 const OBSERVATIONS_LIVE: &str = "my_cute_app.observations_live";
 const OBSERVATIONS_LIVE_DESC: &str = "The number of observations currently held in memory";
 
-const CPU_USAGE_HISTOGRAM: &str = "my_cute_app.cpu_usage";
-const CPU_USAGE_HISTOGRAM_DESC: &str = "The CPU usage percentage";
+pub(crate) const CPU_USAGE_HISTOGRAM: &str = "my_cute_app.cpu_usage";
+pub(crate) const CPU_USAGE_HISTOGRAM_DESC: &str = "The CPU usage percentage";
 
-const CPU_FREQUENCY_HISTOGRAM: &str = "my_cute_app.cpu_frequency_mhz";
-const CPU_FREQUENCY_HISTOGRAM_DESC: &str = "The CPU frequency in MHz";
+pub(crate) const CPU_FREQUENCY_HISTOGRAM: &str = "my_cute_app.cpu_frequency_mhz";
+pub(crate) const CPU_FREQUENCY_HISTOGRAM_DESC: &str = "The CPU frequency in MHz";
 
+pub(crate) const CPU_TIME_HISTOGRAM: &str = "my_cute_app.cpu_time_fraction";
+pub(crate) const CPU_TIME_HISTOGRAM_DESC: &str =
+    "The fraction of CPU time spent in each mode, labeled by CPU name and mode. Linux only";
+
+const BUSIEST_CORE_USAGE: &str = "my_cute_app.busiest_core_usage";
+const BUSIEST_CORE_USAGE_DESC: &str = "The average usage percentage of the busiest CPUs over the stats window, labeled by rank and CPU name";
+
+const SOCKET_USAGE: &str = "my_cute_app.socket_usage";
+const SOCKET_USAGE_DESC: &str = "The average usage percentage of each physical package over the stats window, labeled by socket";
+
+const ROLLUP_USAGE: &str = "my_cute_app.rollup_usage";
+const ROLLUP_USAGE_DESC: &str =
+    "The min, avg, and max CPU usage percentage over the last rollup period, labeled by stat";
+
+const ROLLUP_FREQ: &str = "my_cute_app.rollup_freq_mhz";
+const ROLLUP_FREQ_DESC: &str =
+    "The min, avg, and max CPU frequency in MHz over the last rollup period, labeled by stat";
+
+const SAMPLE_INTERVAL: &str = "my_cute_app.sample_interval_seconds";
+const SAMPLE_INTERVAL_DESC: &str = "The time between observations, as adapted to the load";
+
+const MISSED_TICKS: &str = "my_cute_app.missed_ticks";
+const MISSED_TICKS_DESC: &str = "The total number of observation ticks that were due while the monitor was busy, labeled by what it did about them: burst, delay, or skip";
+
+const MONITOR_STALLED: &str = "my_cute_app.monitor_stalled";
+const MONITOR_STALLED_DESC: &str =
+    "1 if the monitor has stopped producing observations, 0 otherwise";
+
+const MEMORY_BYTES: &str = "my_cute_app.memory_bytes";
+const MEMORY_BYTES_DESC: &str = "The host's memory in bytes, labeled by state: total, used, available, swap_total, or swap_used";
+
+const SAMPLES_DISPATCHED: &str = "my_cute_app.samples_dispatched";
+const SAMPLES_DISPATCHED_DESC: &str =
+    "The total number of samples a dispatcher sent on to their route, labeled by kind";
+
+const SAMPLES_UNROUTED: &str = "my_cute_app.samples_unrouted";
+const SAMPLES_UNROUTED_DESC: &str =
+    "The total number of samples a dispatcher dropped for want of a route, labeled by kind";
+
+const PROCESS_CPU_USAGE: &str = "my_cute_app.process_cpu_usage";
+const PROCESS_CPU_USAGE_DESC: &str = "The watched process's CPU usage percentage, above 100 when it keeps more than one CPU busy, labeled by process";
+
+const PROCESS_MEMORY_BYTES: &str = "my_cute_app.process_memory_bytes";
+const PROCESS_MEMORY_BYTES_DESC: &str =
+    "The watched process's resident memory in bytes, labeled by process";
+
+const CPU_LIMIT_CORES: &str = "my_cute_app.cpu_limit_cores";
+const CPU_LIMIT_CORES_DESC: &str =
+    "The monitor's cgroup's CPU quota in cores, or the number of CPUs it may run on without one";
+
+const PROCESSES: &str = "my_cute_app.processes";
+const PROCESSES_DESC: &str = "The number of processes on the host";
+
+const COMPONENT_TEMPERATURE: &str = "my_cute_app.component_temperature_celsius";
+const COMPONENT_TEMPERATURE_DESC: &str =
+    "The temperature of each sensor on the host, in degrees Celsius, labeled by component";
+
+const SINK_OBSERVATIONS: &str = "my_cute_app.sink_observations";
+const SINK_OBSERVATIONS_DESC: &str =
+    "The total number of observations handled by sinks, labeled by sink and outcome: ok, or error";
+
+const RETRIES: &str = "my_cute_app.retries";
+const RETRIES_DESC: &str = "The total number of retried operations, labeled by operation";
+
+const SPANS_OPEN: &str = "my_cute_app.spans_open";
+const SPANS_OPEN_DESC: &str = "The number of open tracing spans, labeled by target";
+
+const FLEET_HOSTS: &str = "my_cute_app.fleet_hosts";
+const FLEET_HOSTS_DESC: &str =
+    "The number of hosts known to the collector, labeled by state: reporting, or silent";
+
+const FLEET_AVERAGE_USAGE: &str = "my_cute_app.fleet_average_usage";
+const FLEET_AVERAGE_USAGE_DESC: &str =
+    "The mean of the average CPU usage percentage of each reporting host";
+
+const FLEET_HOTTEST_USAGE: &str = "my_cute_app.fleet_hottest_usage";
+const FLEET_HOTTEST_USAGE_DESC: &str =
+    "The average CPU usage percentage of the busiest reporting host";
+
+const REMOTE_SENT: &str = "my_cute_app.remote_sent";
+const REMOTE_SENT_DESC: &str =
+    "The total number of observations sent to a remote collector, labeled by transport";
+
+const REMOTE_DROPPED: &str = "my_cute_app.remote_dropped";
+const REMOTE_DROPPED_DESC: &str = "The total number of observations that could not be sent to a remote collector, labeled by transport";
+
+const REMOTE_RECEIVED: &str = "my_cute_app.remote_received";
+const REMOTE_RECEIVED_DESC: &str =
+    "The total number of observations received from remote agents, labeled by transport";
+
+const REMOTE_REJECTED: &str = "my_cute_app.remote_rejected";
+const REMOTE_REJECTED_DESC: &str = "The total number of observations received from remote agents and rejected, labeled by transport and reason";
+
+const OBSERVATIONS_MISSED: &str = "my_cute_app.observations_missed";
+const OBSERVATIONS_MISSED_DESC: &str = "The total number of observations a collector missed from remote agents, labeled by host and cause: network, or stall";
+
+const REMOTE_BYTES_ENCODED: &str = "my_cute_app.remote_bytes_encoded";
+const REMOTE_BYTES_ENCODED_DESC: &str = "The total size of the observations sent to a remote collector, encoded but not yet compressed, labeled by transport, encoding, and compression";
+
+const REMOTE_BYTES_SENT: &str = "my_cute_app.remote_bytes_sent";
+const REMOTE_BYTES_SENT_DESC: &str = "The total number of bytes sent to a remote collector, labeled by transport, encoding, and compression";
+
+const SPAN_DURATION_HISTOGRAM: &str = "my_cute_app.span_duration_seconds";
+const SPAN_DURATION_HISTOGRAM_DESC: &str = "The time tracing spans were open, labeled by span";
+
+const OVERHEAD_NANOS: &str = "my_cute_app.overhead_nanos";
+const OVERHEAD_NANOS_DESC: &str =
+    "The measured time per operation the monitoring itself takes, in nanoseconds, labeled by op";
+
+const OVERHEAD_ALLOCATIONS: &str = "my_cute_app.overhead_allocations";
+const OVERHEAD_ALLOCATIONS_DESC: &str =
+    "The measured heap allocations per operation the monitoring itself makes, labeled by op";
+
+const RATE_LIMIT_OBSERVATIONS: &str = "my_cute_app.rate_limit_observations";
+const RATE_LIMIT_OBSERVATIONS_DESC: &str = "The total number of observations handled by rate limits, labeled by outcome: forwarded, dropped, or coalesced";
+
+const CHAOS_FAULTS: &str = "my_cute_app.chaos_faults";
+const CHAOS_FAULTS_DESC: &str =
+    "The total number of faults injected by chaos actors, labeled by fault: delay, drop, or panic";
+
+/// The default maximum number of distinct values per metric label. See
+/// [`set_label_limit`].
+pub const DEFAULT_LABEL_LIMIT: usize = 512;
+
+/// The label value that values beyond the limit are folded into.
+pub(crate) const OVERFLOW_LABEL: &str = "other";
+
+static LABEL_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_LABEL_LIMIT);
+
+/// Set the maximum number of distinct values recorded for each metric label,
+/// instead of the default of [`DEFAULT_LABEL_LIMIT`].
+///
+/// Every distinct combination of label values is a separate time series in
+/// Prometheus, with its own memory and storage cost. Labeling by something
+/// unbounded, like a process name, can create an unbounded number of series,
+/// and take down your metrics backend. This is known as a _cardinality
+/// explosion_.
+///
+/// Once a label has seen `limit` distinct values, any new values are
+/// recorded as `other` instead, and a warning is emitted. Values seen before
+/// the limit was reached are unaffected. The limit applies to metric handles
+/// created after this is called, so call it before [`init_metrics`].
+pub fn set_label_limit(limit: usize) {
+    LABEL_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// The value of the `host` label on every metric, if any. See
+/// [`set_host_label`].
+static HOST_LABEL: Mutex<Option<String>> = Mutex::new(None);
+
+/// Label every metric with `host`, e.g. `host="box-1"`.
+///
+/// On a single host, a label that never changes is noise. Once agents on
+/// several hosts, and their collector, are scraped into the same Prometheus,
+/// it isn't: without it, `my_cute_app_observations_made` from ten hosts is
+/// ten series with identical names and labels, and Prometheus can't tell
+/// them apart. Metrics that are labeled with a host already, like the
+/// [`Collector`]'s per-host stats, keep their own. The label applies to
+/// recorders installed after this is called, so call it before
+/// [`init_metrics`].
+///
+/// Metrics pushed with the OTEL SDK don't need it: their resource carries
+/// the `host.name` attribute already.
+///
+/// [`Collector`]: crate::Collector
+pub fn set_host_label(host: impl Into<String>) {
+    *HOST_LABEL.lock().unwrap_or_else(PoisonError::into_inner) = Some(host.into());
+}
+
+/// Tracks the distinct values of a single metric label, folding values
+/// beyond the [label limit] into [`OVERFLOW_LABEL`].
+///
+/// [label limit]: set_label_limit
+#[derive(Debug)]
+pub(crate) struct LabelGuard {
+    label: &'static str,
+    seen: Mutex<BTreeSet<Arc<str>>>,
+    warned: AtomicBool,
+}
+
+impl LabelGuard {
+    const fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            seen: Mutex::new(BTreeSet::new()),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the label value to record for `value`.
+    fn admit(&self, value: &Arc<str>) -> SharedString {
+        if self.is_admitted(value) {
+            value.clone().into()
+        } else {
+            SharedString::const_str(OVERFLOW_LABEL)
+        }
+    }
+
+    /// Whether `value` may be recorded as is, rather than as
+    /// [`OVERFLOW_LABEL`].
+    pub(crate) fn is_admitted(&self, value: &Arc<str>) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if seen.contains(value) {
+            return true;
+        }
+
+        let limit = LABEL_LIMIT.load(Ordering::Relaxed);
+        if seen.len() < limit {
+            seen.insert(value.clone());
+            return true;
+        }
+
+        // Warn once, rather than once per value. If the label is exploding,
+        // the logs would explode right along with it.
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                label = self.label,
+                limit,
+                value = &**value,
+                recorded_as = OVERFLOW_LABEL,
+                "metric label limit reached"
+            );
+        }
+        false
+    }
+}
+
+/// The CPU names used to label the CPU histograms.
+pub(crate) static CPU_NAMES: LabelGuard = LabelGuard::new("name");
+
+/// The host names used to label the [`Collector`]'s per-host metrics. They
+/// come from the agents, so they're as unbounded as anything.
+///
+/// [`Collector`]: crate::Collector
+static HOST_NAMES: LabelGuard = LabelGuard::new("host");
+
+/// The metrics of the [`EventMetricsLayer`]s: name, description, and
+/// whether it's a histogram. Their names are only known at runtime, so
+/// they're described when their rule is added, and again by [`DESCRIBE`],
+/// in case the recorder wasn't installed yet.
+///
+/// [`EventMetricsLayer`]: crate::EventMetricsLayer
+static EVENT_METRICS: Mutex<Vec<(&'static str, SharedString, bool)>> = Mutex::new(Vec::new());
+
+/// Describe the metric of an [`EventMetricsLayer`] rule, which counts the
+/// events with `message`, or records their `field`.
+///
+/// [`EventMetricsLayer`]: crate::EventMetricsLayer
+pub(crate) fn describe_event_metric(
+    metric: &'static str,
+    message: &'static str,
+    field: Option<&'static str>,
+) {
+    let description = match field {
+        None => format!("The total number of `{message}` events"),
+        Some(field) => format!("The `{field}` field of `{message}` events"),
+    };
+    let histogram = field.is_some();
+    describe_event(metric, description.clone().into(), histogram);
+
+    let mut metrics = EVENT_METRICS.lock().unwrap_or_else(PoisonError::into_inner);
+    if !metrics.iter().any(|(name, ..)| *name == metric) {
+        metrics.push((metric, description.into(), histogram));
+    }
+}
+
+fn describe_event(metric: &'static str, description: SharedString, histogram: bool) {
+    if histogram {
+        metrics::describe_histogram!(metric, description);
+    } else {
+        metrics::describe_counter!(metric, description);
+    }
+}
+
+// Only the Prometheus recorders are installed by this crate, so only they
+// are described.
+#[cfg_attr(not(feature = "prometheus"), allow(dead_code))]
 static DESCRIBE: LazyLock<()> = LazyLock::new(|| {
     metrics::describe_counter!(OBSERVATIONS_MADE, OBSERVATIONS_MADE_DESC);
     metrics::describe_gauge!(OBSERVATIONS_LIVE, OBSERVATIONS_LIVE_DESC);
@@ -26,17 +325,520 @@ static DESCRIBE: LazyLock<()> = LazyLock::new(|| {
         CPU_USAGE_HISTOGRAM_DESC
     );
     metrics::describe_histogram!(CPU_FREQUENCY_HISTOGRAM, CPU_FREQUENCY_HISTOGRAM_DESC);
+    metrics::describe_histogram!(CPU_TIME_HISTOGRAM, CPU_TIME_HISTOGRAM_DESC);
+    metrics::describe_gauge!(
+        BUSIEST_CORE_USAGE,
+        metrics::Unit::Percent,
+        BUSIEST_CORE_USAGE_DESC
+    );
+    metrics::describe_gauge!(SOCKET_USAGE, metrics::Unit::Percent, SOCKET_USAGE_DESC);
+    metrics::describe_gauge!(ROLLUP_USAGE, metrics::Unit::Percent, ROLLUP_USAGE_DESC);
+    metrics::describe_gauge!(ROLLUP_FREQ, ROLLUP_FREQ_DESC);
+    metrics::describe_gauge!(SAMPLE_INTERVAL, SAMPLE_INTERVAL_DESC);
+    metrics::describe_counter!(MISSED_TICKS, MISSED_TICKS_DESC);
+    metrics::describe_gauge!(MEMORY_BYTES, MEMORY_BYTES_DESC);
+    metrics::describe_gauge!(CPU_LIMIT_CORES, CPU_LIMIT_CORES_DESC);
+    metrics::describe_gauge!(PROCESSES, PROCESSES_DESC);
+    metrics::describe_counter!(SINK_OBSERVATIONS, SINK_OBSERVATIONS_DESC);
+    metrics::describe_gauge!(COMPONENT_TEMPERATURE, COMPONENT_TEMPERATURE_DESC);
+    metrics::describe_gauge!(PROCESS_CPU_USAGE, PROCESS_CPU_USAGE_DESC);
+    metrics::describe_gauge!(PROCESS_MEMORY_BYTES, PROCESS_MEMORY_BYTES_DESC);
+    metrics::describe_counter!(SAMPLES_DISPATCHED, SAMPLES_DISPATCHED_DESC);
+    metrics::describe_counter!(SAMPLES_UNROUTED, SAMPLES_UNROUTED_DESC);
+    metrics::describe_gauge!(MONITOR_STALLED, MONITOR_STALLED_DESC);
+    metrics::describe_counter!(RETRIES, RETRIES_DESC);
+    metrics::describe_gauge!(SPANS_OPEN, SPANS_OPEN_DESC);
+    metrics::describe_gauge!(FLEET_HOSTS, FLEET_HOSTS_DESC);
+    metrics::describe_gauge!(FLEET_AVERAGE_USAGE, FLEET_AVERAGE_USAGE_DESC);
+    metrics::describe_gauge!(FLEET_HOTTEST_USAGE, FLEET_HOTTEST_USAGE_DESC);
+    metrics::describe_counter!(REMOTE_SENT, REMOTE_SENT_DESC);
+    metrics::describe_counter!(REMOTE_DROPPED, REMOTE_DROPPED_DESC);
+    metrics::describe_counter!(REMOTE_RECEIVED, REMOTE_RECEIVED_DESC);
+    metrics::describe_counter!(REMOTE_REJECTED, REMOTE_REJECTED_DESC);
+    metrics::describe_counter!(OBSERVATIONS_MISSED, OBSERVATIONS_MISSED_DESC);
+    metrics::describe_counter!(REMOTE_BYTES_ENCODED, REMOTE_BYTES_ENCODED_DESC);
+    metrics::describe_counter!(REMOTE_BYTES_SENT, REMOTE_BYTES_SENT_DESC);
+    metrics::describe_histogram!(
+        SPAN_DURATION_HISTOGRAM,
+        metrics::Unit::Seconds,
+        SPAN_DURATION_HISTOGRAM_DESC
+    );
+    metrics::describe_gauge!(OVERHEAD_NANOS, OVERHEAD_NANOS_DESC);
+    metrics::describe_gauge!(OVERHEAD_ALLOCATIONS, OVERHEAD_ALLOCATIONS_DESC);
+    metrics::describe_counter!(RATE_LIMIT_OBSERVATIONS, RATE_LIMIT_OBSERVATIONS_DESC);
+    metrics::describe_counter!(CHAOS_FAULTS, CHAOS_FAULTS_DESC);
+    for (metric, description, histogram) in EVENT_METRICS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+    {
+        describe_event(metric, description.clone(), *histogram);
+    }
 });
 
-pub(crate) fn record_observation(obs: &[CpuStats]) {
-    counter!(OBSERVATIONS_MADE).increment(1);
-    gauge!(OBSERVATIONS_LIVE).increment(1);
+/// Cached metric handles for the histograms of a single CPU.
+#[cfg(feature = "sysinfo")]
+#[derive(Debug)]
+struct CpuHandles {
+    name: Arc<str>,
+    /// The `name` label, which is `name`, or [`OVERFLOW_LABEL`].
+    #[cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+    label: SharedString,
+    usage: Histogram,
+    frequency: Histogram,
+    /// One per mode, in the order of [`CpuTimes::MODES`].
+    times: [Histogram; 4],
+}
+
+#[cfg(feature = "sysinfo")]
+impl CpuHandles {
+    fn new(name: &Arc<str>) -> Self {
+        let label = CPU_NAMES.admit(name);
+        Self {
+            name: name.clone(),
+            label: label.clone(),
+            usage: histogram!(CPU_USAGE_HISTOGRAM, "name" => label.clone()),
+            frequency: histogram!(CPU_FREQUENCY_HISTOGRAM, "name" => label.clone()),
+            times: CpuTimes::MODES.map(
+                |mode| histogram!(CPU_TIME_HISTOGRAM, "name" => label.clone(), "mode" => mode),
+            ),
+        }
+    }
+}
+
+/// Records the metrics of each observation the [`SysMonitor`] takes, and
+/// caches metric handles between observations.
+///
+/// Each `histogram!` call builds a key from the metric name and labels,
+/// hashes it, and looks it up in the recorder's registry. With two
+/// histograms per core, that's a lot of hashing for values that never
+/// change. Instead, we look the handles up once, and keep
+/// them. CPUs are reported in a fixed order, so the handles are cached by
+/// position, and only rebuilt if the name at a position changes.
+///
+/// Handles are bound to the recorder that was installed when they were
+/// created. Install the recorder (e.g. with [`init_metrics`]) before the
+/// first observation is recorded, or the cached handles will be no-ops.
+///
+/// [`SysMonitor`]: crate::SysMonitor
+#[cfg(feature = "sysinfo")]
+#[derive(Debug, Default)]
+pub(crate) struct ObservationMetrics {
+    made: Option<(Counter, Gauge)>,
+    cpus: Vec<CpuHandles>,
+}
+
+/// Counts one observation on the `my_cute_app.observations_live` gauge, for
+/// as long as it's held. See [`Observation::with_guard`].
+///
+/// The gauge goes up when the guard is created, and down when it's dropped.
+/// Attached to an observation, it's dropped with the observation, so the
+/// gauge counts the observations still in memory. Observations without one
+/// don't count: the observation itself knows nothing about the gauge. The
+/// [`SysMonitor`] attaches one to every observation it takes. A replay, or a
+/// test, can attach them or not, as it pleases.
+///
+/// Guards are also counted on an atomic, which never touches the recorder.
+/// The [`LiveGaugeCheck`] compares the two, to catch the gauge drifting.
+///
+/// [`Observation::with_guard`]: crate::Observation::with_guard
+/// [`SysMonitor`]: crate::SysMonitor
+/// [`LiveGaugeCheck`]: crate::LiveGaugeCheck
+#[derive(Debug)]
+#[must_use = "the observation is uncounted as soon as the guard is dropped"]
+pub struct ObservationGuard {
+    live: Gauge,
+}
+
+impl ObservationGuard {
+    /// Count one more live observation, until the guard is dropped.
+    pub fn new() -> Self {
+        Self::with_handle(gauge!(OBSERVATIONS_LIVE))
+    }
+
+    /// Count one more live observation on a cached handle to the gauge.
+    fn with_handle(live: Gauge) -> Self {
+        live.increment(1);
+        LIVE_GUARDS.fetch_add(1, Ordering::Relaxed);
+        Self { live }
+    }
+}
+
+/// The number of [`ObservationGuard`]s alive, counted independently of the
+/// `my_cute_app.observations_live` gauge.
+static LIVE_GUARDS: AtomicI64 = AtomicI64::new(0);
+
+/// The number of [`ObservationGuard`]s alive right now.
+#[cfg(feature = "prometheus")]
+pub(crate) fn live_guards() -> i64 {
+    LIVE_GUARDS.load(Ordering::Relaxed)
+}
+
+/// The value of the `my_cute_app.observations_live` gauge in `rendered`
+/// Prometheus text, summed over its label sets, or 0 if it isn't there.
+#[cfg(feature = "prometheus")]
+pub(crate) fn rendered_observations_live(rendered: &str) -> f64 {
+    let name = OBSERVATIONS_LIVE.replace('.', "_");
+    rendered
+        .lines()
+        .filter_map(|line| line.strip_prefix(name.as_str()))
+        // Skip longer names that share the prefix.
+        .filter(|rest| rest.starts_with(['{', ' ']))
+        .filter_map(|rest| rest.rsplit(' ').next()?.parse::<f64>().ok())
+        .sum()
+}
+
+impl Default for ObservationGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ObservationGuard {
+    fn drop(&mut self) {
+        LIVE_GUARDS.fetch_sub(1, Ordering::Relaxed);
+        self.live.decrement(1);
+    }
+}
+
+#[cfg(feature = "sysinfo")]
+impl ObservationMetrics {
+    /// Record `obs`, and return a guard that counts it as live.
+    pub(crate) fn record(&mut self, obs: &[CpuStats], span: &tracing::Span) -> ObservationGuard {
+        let guard = self.record_in_recorder(obs, span);
+
+        #[cfg(feature = "otel-metrics")]
+        crate::otel_metrics::record_observation(obs);
+
+        guard
+    }
+
+    /// Record `obs` in the installed `metrics` recorder only, skipping the
+    /// OTEL instruments, which can't be redirected. Used by [`Overhead`] to
+    /// measure recording into a private recorder.
+    ///
+    /// [`Overhead`]: crate::Overhead
+    pub(crate) fn record_in_recorder(
+        &mut self,
+        obs: &[CpuStats],
+        #[cfg_attr(not(feature = "prometheus"), allow(unused_variables))] span: &tracing::Span,
+    ) -> ObservationGuard {
+        let (made, live) = self
+            .made
+            .get_or_insert_with(|| (counter!(OBSERVATIONS_MADE), gauge!(OBSERVATIONS_LIVE)));
+        made.increment(1);
+        let guard = ObservationGuard::with_handle(live.clone());
+
+        self.cpus.truncate(obs.len());
+        for (i, cpu) in obs.iter().enumerate() {
+            match self.cpus.get(i) {
+                Some(handles) if handles.name == cpu.name => {}
+                Some(_) => self.cpus[i] = CpuHandles::new(&cpu.name),
+                None => self.cpus.push(CpuHandles::new(&cpu.name)),
+            }
+
+            let handles = &self.cpus[i];
+            handles.usage.record(cpu.usage as f64);
+            handles.frequency.record(cpu.frequency as f64);
+            if let Some(times) = &cpu.times {
+                for (histogram, (_, share)) in handles.times.iter().zip(times.modes()) {
+                    histogram.record(share as f64);
+                }
+            }
+        }
+        #[cfg(feature = "prometheus")]
+        crate::exemplars::record_cpu_usage(
+            span,
+            self.cpus
+                .iter()
+                .zip(obs)
+                .map(|(handles, cpu)| (&*handles.label, cpu.usage as f64)),
+        );
+
+        guard
+    }
+}
+
+/// The `rank` label values, one per busiest core.
+const RANKS: [&str; MAX_BUSIEST_CORES] = ["1", "2", "3", "4"];
+
+/// Cached gauge handles for the busiest cores, by CPU position and rank.
+///
+/// A gauge keeps its last value until it is set again. When a CPU drops out
+/// of a rank, its series for that rank would keep reporting it as busy
+/// forever. So we remember which CPU holds each rank, and zero the previous
+/// holder's series when it changes. At any time, each rank has at most one
+/// nonzero series.
+///
+/// Handles are created the first time a CPU reaches a rank, and cached like
+/// in [`ObservationMetrics`], so the steady state doesn't allocate.
+#[derive(Debug, Default)]
+pub(crate) struct BusiestCoreMetrics {
+    gauges: Vec<[Option<Gauge>; MAX_BUSIEST_CORES]>,
+    holders: [Option<usize>; MAX_BUSIEST_CORES],
+    /// The `host` label of the gauges, if any.
+    host: Option<SharedString>,
+}
+
+impl BusiestCoreMetrics {
+    /// Gauges labeled with `host`.
+    pub(crate) fn with_host(host: &Arc<str>) -> Self {
+        Self {
+            host: Some(HOST_NAMES.admit(host)),
+            ..Default::default()
+        }
+    }
 
-    for cpu in obs.iter() {
-        histogram!(CPU_USAGE_HISTOGRAM, "name" => cpu.name.clone()).record(cpu.usage as f64);
-        histogram!(CPU_FREQUENCY_HISTOGRAM, "name" => cpu.name.clone())
-            .record(cpu.frequency as f64);
+    /// Record the busiest cores, with the CPU `positions` they were found
+    /// at.
+    pub(crate) fn record(
+        &mut self,
+        positions: &[Option<usize>; MAX_BUSIEST_CORES],
+        cores: &[Option<CoreUsage>; MAX_BUSIEST_CORES],
+    ) {
+        for (rank, (position, core)) in positions.iter().zip(cores).enumerate() {
+            let current = position.zip(core.as_ref());
+            if let Some(previous) = self.holders[rank]
+                && current.is_none_or(|(position, _)| position != previous)
+                && let Some(gauge) = &self.gauges[previous][rank]
+            {
+                gauge.set(0.0);
+            }
+            self.holders[rank] = current.map(|(position, _)| position);
+
+            let Some((position, core)) = current else {
+                continue;
+            };
+            if self.gauges.len() <= position {
+                self.gauges.resize_with(position + 1, Default::default);
+            }
+            self.gauges[position][rank]
+                .get_or_insert_with(|| {
+                    let name = CPU_NAMES.admit(&core.name);
+                    let mut labels =
+                        vec![Label::new("rank", RANKS[rank]), Label::new("name", name)];
+                    labels.extend(self.host.clone().map(|host| Label::new("host", host)));
+                    gauge!(BUSIEST_CORE_USAGE, labels)
+                })
+                .set(core.usage);
+        }
+    }
+}
+
+/// Cached gauge handles for the socket usage, by physical package ID.
+///
+/// Sockets never come and go, so unlike the busiest cores, there's nothing
+/// to clean up. Most machines have one or two, so a linear search beats
+/// hashing.
+#[derive(Debug, Default)]
+pub(crate) struct SocketMetrics {
+    gauges: Vec<(u32, Gauge)>,
+    /// The `host` label of the gauges, if any.
+    host: Option<SharedString>,
+}
+
+impl SocketMetrics {
+    /// Gauges labeled with `host`.
+    pub(crate) fn with_host(host: &Arc<str>) -> Self {
+        Self {
+            host: Some(HOST_NAMES.admit(host)),
+            ..Default::default()
+        }
     }
+
+    pub(crate) fn record(&mut self, sockets: &[SocketUsage]) {
+        for socket in sockets {
+            let i = match self
+                .gauges
+                .iter()
+                .position(|(package, _)| *package == socket.package)
+            {
+                Some(i) => i,
+                None => {
+                    let mut labels = vec![Label::new("socket", socket.package.to_string())];
+                    labels.extend(self.host.clone().map(|host| Label::new("host", host)));
+                    let gauge = gauge!(SOCKET_USAGE, labels);
+                    self.gauges.push((socket.package, gauge));
+                    self.gauges.len() - 1
+                }
+            };
+            self.gauges[i].1.set(socket.usage);
+        }
+    }
+}
+
+pub(crate) fn record_rollup(report: &RollupReport) {
+    let record = |metric: &'static str, values: MinAvgMax| {
+        gauge!(metric, "stat" => "min").set(values.min);
+        gauge!(metric, "stat" => "avg").set(values.avg);
+        gauge!(metric, "stat" => "max").set(values.max);
+    };
+    record(ROLLUP_USAGE, report.usage);
+    record(ROLLUP_FREQ, report.freq_mhz);
+}
+
+#[cfg(feature = "sysinfo")]
+pub(crate) fn record_sample_interval(interval: Duration) {
+    gauge!(SAMPLE_INTERVAL).set(interval.as_secs_f64());
+}
+
+#[cfg(feature = "sysinfo")]
+pub(crate) fn record_missed_ticks(behavior: MissedTicks, missed: u64) {
+    counter!(MISSED_TICKS, "behavior" => behavior.as_str()).increment(missed);
+}
+
+#[cfg(feature = "sysinfo")]
+pub(crate) fn record_memory(usage: &MemoryUsage) {
+    gauge!(MEMORY_BYTES, "state" => "total").set(usage.total as f64);
+    gauge!(MEMORY_BYTES, "state" => "used").set(usage.used as f64);
+    gauge!(MEMORY_BYTES, "state" => "available").set(usage.available as f64);
+    gauge!(MEMORY_BYTES, "state" => "swap_total").set(usage.swap_total as f64);
+    gauge!(MEMORY_BYTES, "state" => "swap_used").set(usage.swap_used as f64);
+}
+
+pub(crate) fn record_sink_observation(sink: &'static str, ok: bool) {
+    let outcome = if ok { "ok" } else { "error" };
+    counter!(SINK_OBSERVATIONS, "sink" => sink, "outcome" => outcome).increment(1);
+}
+
+#[cfg(feature = "sysinfo")]
+pub(crate) fn record_overhead(report: &OverheadReport) {
+    for (op, cost) in report.costs() {
+        gauge!(OVERHEAD_NANOS, "op" => op).set(cost.nanos);
+        if let Some(allocations) = cost.allocations {
+            gauge!(OVERHEAD_ALLOCATIONS, "op" => op).set(allocations);
+        }
+    }
+}
+
+/// Run `f` with a fresh Prometheus recorder installed on this thread, so
+/// that what it records never reaches the real one. The recorder is the
+/// same kind as the real one, so it costs the same to record into.
+#[cfg(all(feature = "sysinfo", feature = "prometheus"))]
+pub(crate) fn with_private_recorder<T>(f: impl FnOnce() -> T) -> T {
+    let recorder = prometheus_builder().build_recorder();
+    metrics::with_local_recorder(&recorder, f)
+}
+
+#[cfg(feature = "sysinfo")]
+pub(crate) fn record_processes(count: usize) {
+    gauge!(PROCESSES).set(count as f64);
+}
+
+#[cfg(feature = "sysinfo")]
+pub(crate) fn record_temperatures(temperatures: &[Temperature]) {
+    for temperature in temperatures {
+        let component = SharedString::from(temperature.label.clone());
+        gauge!(COMPONENT_TEMPERATURE, "component" => component).set(f64::from(temperature.celsius));
+    }
+}
+
+#[cfg(feature = "sysinfo")]
+pub(crate) fn record_cpu_limit(cores: f64) {
+    gauge!(CPU_LIMIT_CORES).set(cores);
+}
+
+#[cfg(feature = "sysinfo")]
+pub(crate) fn record_process(usage: &ProcessUsage) {
+    let process = SharedString::from(usage.name.clone());
+    gauge!(PROCESS_CPU_USAGE, "process" => process.clone()).set(f64::from(usage.cpu_usage));
+    gauge!(PROCESS_MEMORY_BYTES, "process" => process).set(usage.memory as f64);
+}
+
+pub(crate) fn record_rate_limited(outcome: &'static str) {
+    counter!(RATE_LIMIT_OBSERVATIONS, "outcome" => outcome).increment(1);
+}
+
+pub(crate) fn record_chaos_fault(fault: Fault) {
+    counter!(CHAOS_FAULTS, "fault" => fault.as_str()).increment(1);
+}
+
+pub(crate) fn record_sample_dispatched(kind: &'static str) {
+    counter!(SAMPLES_DISPATCHED, "kind" => kind).increment(1);
+}
+
+pub(crate) fn record_sample_unrouted(kind: &'static str) {
+    counter!(SAMPLES_UNROUTED, "kind" => kind).increment(1);
+}
+
+pub(crate) fn record_monitor_stalled(stalled: bool) {
+    gauge!(MONITOR_STALLED).set(if stalled { 1.0 } else { 0.0 });
+}
+
+pub(crate) fn record_retry(operation: &'static str) {
+    counter!(RETRIES, "operation" => operation).increment(1);
+}
+
+/// Record the fleet aggregates. The hottest host's name isn't a label: a
+/// gauge per host that was ever the hottest would keep its last value after
+/// it cooled down. It is in the `finished fleet stats` event instead.
+pub(crate) fn record_fleet(report: &FleetReport) {
+    gauge!(FLEET_HOSTS, "state" => "reporting").set(report.reporting_hosts as f64);
+    gauge!(FLEET_HOSTS, "state" => "silent").set(report.silent_hosts as f64);
+    gauge!(FLEET_AVERAGE_USAGE).set(report.average_usage);
+    gauge!(FLEET_HOTTEST_USAGE).set(report.hottest_usage);
+}
+
+pub(crate) fn record_remote_sent(transport: &'static str) {
+    counter!(REMOTE_SENT, "transport" => transport).increment(1);
+}
+
+/// Record the size of a sent observation, `encoded` bytes before
+/// compression, and `sent` bytes on the wire.
+pub(crate) fn record_remote_bytes(
+    transport: &'static str,
+    format: WireFormat,
+    encoded: usize,
+    sent: usize,
+) {
+    let labels = [
+        ("transport", transport),
+        ("encoding", format.encoding.as_str()),
+        ("compression", format.compression.as_str()),
+    ];
+    counter!(REMOTE_BYTES_ENCODED, &labels).increment(encoded as u64);
+    counter!(REMOTE_BYTES_SENT, &labels).increment(sent as u64);
+}
+
+pub(crate) fn record_remote_dropped(transport: &'static str) {
+    counter!(REMOTE_DROPPED, "transport" => transport).increment(1);
+}
+
+pub(crate) fn record_remote_received(transport: &'static str) {
+    counter!(REMOTE_RECEIVED, "transport" => transport).increment(1);
+}
+
+pub(crate) fn record_remote_rejected(transport: &'static str, rejection: Rejection) {
+    counter!(REMOTE_REJECTED, "transport" => transport, "reason" => rejection.as_str())
+        .increment(1);
+}
+
+pub(crate) fn record_observations_missed(host: &Arc<str>, cause: GapCause, missed: u64) {
+    counter!(OBSERVATIONS_MISSED, "host" => HOST_NAMES.admit(host), "cause" => cause.as_str())
+        .increment(missed);
+}
+
+pub(crate) fn record_spans_open(target: &'static str, open: usize) {
+    gauge!(SPANS_OPEN, "target" => target).set(open as f64);
+}
+
+pub(crate) fn record_span_duration(span: &'static str, duration: Duration) {
+    histogram!(SPAN_DURATION_HISTOGRAM, "span" => span).record(duration.as_secs_f64());
+}
+
+/// The metadata of the metrics the [`EventMetricsLayer`] records.
+///
+/// [`EventMetricsLayer`]: crate::EventMetricsLayer
+static EVENT_METADATA: metrics::Metadata<'static> =
+    metrics::Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
+
+pub(crate) fn record_event_count(key: &Key) {
+    metrics::with_recorder(|recorder| recorder.register_counter(key, &EVENT_METADATA)).increment(1);
+}
+
+pub(crate) fn record_event_value(key: &Key, value: f64) {
+    metrics::with_recorder(|recorder| recorder.register_histogram(key, &EVENT_METADATA))
+        .record(value);
 }
 
 /// Initialize a prometheus metrics exporter on the given port, or 9000 if
@@ -78,13 +880,116 @@ pub(crate) fn record_observation(obs: &[CpuStats]) {
 ///
 /// This program records the following metrics:
 /// - `my_cute_app.observations_made` (counter): The total number of
-///   observations made while the program has been running.
+///   observations the [`SysMonitor`] took while the program has been
+///   running. Observations created any other way, e.g. received from
+///   another host, or replayed, aren't counted, and aren't recorded in the
+///   CPU histograms below either.
 /// - `my_cute_app.observations_live` (gauge): The number of observations
-///   currently held in memory.
+///   taken by the [`SysMonitor`] that are still held in memory, or, more
+///   precisely, the number of live [`ObservationGuard`]s.
 /// - `my_cute_app.cpu_usage` (histogram): The CPU usage percentage,
-///   labeled by CPU name.
+///   labeled by CPU name, in buckets of 10%. Each bucket links to the trace
+///   of its latest observation. See [`render_openmetrics`].
 /// - `my_cute_app.cpu_frequency_mhz` (histogram): The CPU frequency in MHz,
 ///   labeled by CPU name.
+/// - `my_cute_app.cpu_time_fraction` (histogram): The fraction of CPU time
+///   spent in user, system, iowait, and steal mode, labeled by CPU name and
+///   mode. Linux only. See [`CpuTimes`].
+/// - `my_cute_app.busiest_core_usage` (gauge): The average usage percentage
+///   of the busiest CPUs over the stats window, labeled by rank and CPU name,
+///   and host, if the [`SysStats`] has one. See
+///   [`SysStats::with_busiest_cores`].
+/// - `my_cute_app.socket_usage` (gauge): The average usage percentage of
+///   each physical package over the stats window, labeled by socket, and
+///   host, like `busiest_core_usage`. Linux only. This is a level up the
+///   hierarchy from the per-CPU metrics: one series per socket, rather than
+///   one per CPU. Recording it here, rather than summing the per-CPU series
+///   in a query, keeps dashboards cheap on machines with hundreds of CPUs.
+/// - `my_cute_app.rollup_usage` and `my_cute_app.rollup_freq_mhz` (gauges):
+///   The min, avg, and max CPU usage percentage and frequency in MHz over
+///   the last rollup period, labeled by stat. Only recorded if a [`Rollup`]
+///   is running.
+/// - `my_cute_app.sample_interval_seconds` (gauge): The time between
+///   observations. Constant, unless the [`SysMonitor`] has an
+///   [`AdaptiveInterval`].
+/// - `my_cute_app.missed_ticks` (counter): The number of observations that
+///   were due while the monitor was busy, or stalled, labeled by behavior,
+///   the [`MissedTicks`] that decided what to do about them.
+/// - `my_cute_app.memory_bytes` (gauge): The host's memory, labeled by
+///   state: `total`, `used`, `available`, `swap_total`, or `swap_used`. Only
+///   recorded if a [`MemoryMonitor`] is running, or the [`SysMonitor`]
+///   refreshes memory. See [`RefreshSpec`].
+/// - `my_cute_app.sink_observations` (counter): The number of observations
+///   handled by each [`ObservationSink`] run by a [`SinkDriver`], labeled by
+///   sink, and outcome: `ok`, or `error`.
+/// - `my_cute_app.processes` (gauge): The number of processes on the host.
+///   Only recorded if the [`SysMonitor`] refreshes processes.
+/// - `my_cute_app.component_temperature_celsius` (gauge): The temperature of
+///   each sensor, labeled by component. Only recorded if the [`SysMonitor`]
+///   refreshes components.
+/// - `my_cute_app.cpu_limit_cores` (gauge): The CPU quota of the monitor's
+///   cgroup, in cores. Only recorded with [`CpuView::Cgroup`].
+/// - `my_cute_app.process_cpu_usage` and `my_cute_app.process_memory_bytes`
+///   (gauges): The CPU usage and resident memory of the process a
+///   [`SysMonitor`] is watching, labeled by process name. See
+///   [`SysMonitor::for_process`].
+/// - `my_cute_app.samples_dispatched` and `my_cute_app.samples_unrouted`
+///   (counters): The number of samples a [`Dispatcher`] sent on, and dropped
+///   for want of a route, labeled by kind: `cpu`, or `memory`.
+/// - `my_cute_app.monitor_stalled` (gauge): `1` if the [`Watchdog`] has
+///   flagged the monitor as stalled, `0` otherwise.
+/// - `my_cute_app.retries` (counter): The number of retried operations,
+///   labeled by operation. See [`retry`].
+/// - `my_cute_app.fleet_hosts` (gauge): The number of hosts a [`Collector`]
+///   has heard from, labeled by state: `reporting`, or `silent`.
+/// - `my_cute_app.fleet_average_usage` and `my_cute_app.fleet_hottest_usage`
+///   (gauges): The mean of the reporting hosts' average usage percentages,
+///   and the highest of them. See [`FleetReport`].
+/// - `my_cute_app.remote_sent`, `my_cute_app.remote_dropped`, and
+///   `my_cute_app.remote_received` (counters): The number of observations
+///   sent to, failed to send to, and received from other hosts, labeled by
+///   transport. Only recorded by the [`TcpSink`], [`TcpSource`], [`UdpSink`],
+///   and [`UdpSource`].
+/// - `my_cute_app.remote_rejected` (counter): The number of observations
+///   received from other hosts, and rejected, labeled by transport and
+///   reason: `unsigned`, or `bad_signature`, for not being signed with the
+///   [`SharedSecret`], or `version`, for being newer than the
+///   [`WIRE_VERSION`].
+/// - `my_cute_app.observations_missed` (counter): The number of
+///   observations a [`Collector`] didn't receive from other hosts, labeled
+///   by host, and cause: `network`, for observations that were sent but lost,
+///   or `stall`, for ones the agent never took.
+/// - `my_cute_app.remote_bytes_encoded` and `my_cute_app.remote_bytes_sent`
+///   (counters): The size of the observations sent to other hosts, encoded
+///   but not yet compressed, and on the wire, labeled by transport, encoding,
+///   and compression. Their ratio is what the [`WireCompression`] buys.
+/// - `my_cute_app.spans_open` (gauge): The number of open tracing spans,
+///   labeled by target. Only recorded if the [`SpanCountLayer`] is
+///   installed.
+/// - `my_cute_app.span_duration_seconds` (histogram): The time selected
+///   tracing spans were open, labeled by span name. Only recorded if the
+///   [`SpanDurationLayer`] is installed.
+/// - `my_cute_app.overhead_nanos` and `my_cute_app.overhead_allocations`
+///   (gauges): What the monitoring itself costs per operation, in time and
+///   heap allocations, labeled by op: `observation`, `metrics`, or `span`.
+///   Only recorded when an [`Overhead`] measurement runs. Allocations are
+///   only recorded with the [`CountingAllocator`] installed.
+/// - `my_cute_app.rate_limit_observations` (counter): The number of
+///   observations handled by each [`RateLimit`], labeled by outcome:
+///   `forwarded`, `dropped`, or `coalesced`.
+/// - `my_cute_app.chaos_faults` (counter): The number of faults injected by
+///   [`Chaos`] actors, labeled by fault: `delay`, `drop`, or `panic`.
+///
+/// With [`set_host_label`], every metric is labeled with the host, too.
+///
+/// The [`EventMetricsLayer`] records additional metrics, with names of your
+/// choosing, derived from tracing events. They're described after the rule
+/// that records them.
+///
+/// Labels with values we don't control, like CPU names, and the host names
+/// agents send a [`Collector`], are capped at a fixed number of distinct
+/// values, to protect the metrics backend from cardinality explosions. See
+/// [`set_label_limit`].
 ///
 /// Collecting usage and frequency allows metrics aggregators to monitor the
 /// CPU over time, and to alert if the CPU usage is too high or the frequency
@@ -110,13 +1015,127 @@ pub(crate) fn record_observation(obs: &[CpuStats]) {
 /// This will return a plaintext response with the metrics in the
 /// [Prometheus exposition format].
 ///
+/// ## Panics
+///
+/// If a recorder is already installed, or the port can't be bound. See
+/// [`try_init_metrics`] to handle those instead.
+///
+/// [`SysStats`]: crate::SysStats
+/// [`SysStats::with_busiest_cores`]: crate::SysStats::with_busiest_cores
+/// [`render_openmetrics`]: crate::render_openmetrics
+/// [`Watchdog`]: crate::Watchdog
+/// [`SysMonitor`]: crate::SysMonitor
+/// [`AdaptiveInterval`]: crate::AdaptiveInterval
+/// [`MissedTicks`]: crate::MissedTicks
+/// [`MemoryMonitor`]: crate::MemoryMonitor
+/// [`Dispatcher`]: crate::Dispatcher
+/// [`SysMonitor::for_process`]: crate::SysMonitor::for_process
+/// [`CpuView::Cgroup`]: crate::CpuView::Cgroup
+/// [`RefreshSpec`]: crate::RefreshSpec
+/// [`ObservationSink`]: crate::ObservationSink
+/// [`SinkDriver`]: crate::SinkDriver
+/// [`Rollup`]: crate::Rollup
+/// [`retry`]: crate::retry
+/// [`SpanCountLayer`]: crate::SpanCountLayer
+/// [`Collector`]: crate::Collector
+/// [`FleetReport`]: crate::FleetReport
+/// [`TcpSink`]: crate::TcpSink
+/// [`TcpSource`]: crate::TcpSource
+/// [`UdpSink`]: crate::UdpSink
+/// [`UdpSource`]: crate::UdpSource
+/// [`SharedSecret`]: crate::SharedSecret
+/// [`WIRE_VERSION`]: crate::WIRE_VERSION
+/// [`WireCompression`]: crate::WireCompression
+/// [`SpanDurationLayer`]: crate::SpanDurationLayer
+/// [`EventMetricsLayer`]: crate::EventMetricsLayer
+/// [`Overhead`]: crate::Overhead
+/// [`CountingAllocator`]: crate::CountingAllocator
+/// [`RateLimit`]: crate::RateLimit
+/// [`Chaos`]: crate::Chaos
 /// [Prometheus exposition format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+#[cfg(feature = "prometheus")]
 pub fn init_metrics(port: Option<u16>) -> u16 {
+    try_init_metrics(port).expect("failed to install prometheus exporter")
+}
+
+/// Like [`init_metrics`], but returns an error instead of panicking.
+///
+/// ## Errors
+///
+/// If a recorder is already installed, or the port can't be bound.
+#[cfg(feature = "prometheus")]
+pub fn try_init_metrics(port: Option<u16>) -> Result<u16, BuildError> {
     LazyLock::force(&DESCRIBE);
     let port = port.unwrap_or(9000);
-    PrometheusBuilder::new()
+    prometheus_builder()
         .with_http_listener(([0, 0, 0, 0], port))
-        .install()
-        .expect("failed to install prometheus exporter");
-    port
+        .install()?;
+    Ok(port)
+}
+
+/// The Prometheus exporter, with buckets for `cpu_usage`. Other histograms
+/// are rendered as summaries.
+#[cfg(feature = "prometheus")]
+fn prometheus_builder() -> PrometheusBuilder {
+    let builder = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(CPU_USAGE_HISTOGRAM.into()),
+            &CPU_USAGE_BUCKETS,
+        )
+        .expect("buckets are not empty");
+    match &*HOST_LABEL.lock().unwrap_or_else(PoisonError::into_inner) {
+        Some(host) => builder.add_global_label("host", host),
+        None => builder,
+    }
+}
+
+/// How often [`init_metrics_recorder`] runs the recorder's upkeep. This is
+/// the same as the exporter's own default.
+#[cfg(feature = "prometheus")]
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Install the Prometheus recorder without binding a listener, and return a
+/// handle that renders the metrics. Use this instead of [`init_metrics`]
+/// when your application serves the metrics from its own HTTP server, e.g.
+/// with the `RouterBuilder`, behind the `axum` feature.
+///
+/// The listener installed by [`init_metrics`] also runs the recorder's
+/// upkeep, which drains histogram samples into their summaries. Without it,
+/// samples pile up between scrapes, so this spawns a thread to run it.
+///
+/// ## Panics
+///
+/// If a recorder is already installed. See [`try_init_metrics_recorder`] to
+/// handle that instead.
+#[cfg(feature = "prometheus")]
+pub fn init_metrics_recorder() -> PrometheusHandle {
+    try_init_metrics_recorder().expect("failed to install prometheus recorder")
+}
+
+/// Like [`init_metrics_recorder`], but returns an error instead of
+/// panicking.
+///
+/// ## Errors
+///
+/// If a recorder is already installed.
+///
+/// ## Panics
+///
+/// If the upkeep thread can't be spawned.
+#[cfg(feature = "prometheus")]
+pub fn try_init_metrics_recorder() -> Result<PrometheusHandle, BuildError> {
+    LazyLock::force(&DESCRIBE);
+    let handle = prometheus_builder().install_recorder()?;
+
+    let upkeep = handle.clone();
+    std::thread::Builder::new()
+        .name("metrics-upkeep".into())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(UPKEEP_INTERVAL);
+                upkeep.run_upkeep();
+            }
+        })
+        .expect("failed to spawn metrics upkeep thread");
+    Ok(handle)
 }
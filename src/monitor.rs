@@ -1,36 +1,597 @@
 //! System monitoring code. This module contains the [`SysMonitor`] struct.
 
-use crate::{CpuStats, Observation};
-use sysinfo::System;
-use tokio::spawn;
-use tracing::{info_span, instrument, trace};
+#[cfg(feature = "cron")]
+use crate::CronSchedule;
+#[cfg(feature = "systemd")]
+use crate::systemd::SystemdNotifier;
+use crate::{
+    CpuStats, CpuView, Health, MemoryUsage, Observation, ProcessSelector, RefreshSpec, Temperature,
+    baggage::attach_baggage,
+    cgroup::CgroupReader,
+    cpu_times::CpuTimesReader,
+    fields::{self, CPU, FREQ_MHZ, OBSERVATION_ID, USAGE_PCT},
+    metrics::ObservationMetrics,
+    process::ProcessTracker,
+    report::PipelineCounters,
+    topology::physical_package,
+};
+use opentelemetry::trace::{SpanContext, TraceContextExt};
+use std::{
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime},
+};
+use sysinfo::{Components, MINIMUM_CPU_UPDATE_INTERVAL, RefreshKind, System};
+use tokio::{
+    spawn,
+    task::JoinError,
+    time::{Instant, Interval, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{
+    Instrument, Level, debug, debug_span, error, info, info_span, instrument, trace, warn,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The maximum number of CPU stats buffers the monitor keeps for reuse. This
+/// should comfortably exceed the number of observations alive at once, i.e.
+/// the stats window plus whatever is queued in channels.
+const MAX_RECYCLED_BUFFERS: usize = 32;
+
+/// The name of the one "CPU" in an observation with the cgroup CPU view.
+const CGROUP_CPU: &str = "cgroup";
+
+/// Intern the CPU names of a refreshed [`System`], so that each observation
+/// can share them instead of allocating its own copies.
+pub(crate) fn intern_names(system: &System) -> Vec<Arc<str>> {
+    system
+        .cpus()
+        .iter()
+        .map(|cpu| Arc::from(cpu.name()))
+        .collect()
+}
+
+/// Look up the physical package of each CPU. Like the names, these never
+/// change, so this runs once.
+pub(crate) fn read_packages(names: &[Arc<str>]) -> Vec<Option<u32>> {
+    names.iter().map(|name| physical_package(name)).collect()
+}
+
+/// Read the per-CPU stats from a refreshed [`System`] and [`CpuTimesReader`],
+/// using the interned `names`, and `packages`.
+pub(crate) fn collect_cpus(
+    system: &System,
+    times: &CpuTimesReader,
+    names: &[Arc<str>],
+    packages: &[Option<u32>],
+) -> Vec<CpuStats> {
+    system
+        .cpus()
+        .iter()
+        .zip(names.iter().zip(packages))
+        .enumerate()
+        .map(|(i, (cpu, (name, &package)))| CpuStats {
+            name: name.clone(),
+            usage: cpu.cpu_usage(),
+            frequency: cpu.frequency(),
+            times: times.times(i),
+            package,
+        })
+        .collect()
+}
+
+/// Refill `buf`, a buffer from [`collect_cpus`] with the same CPUs, in place.
+/// Names are only replaced if they were interned again since.
+pub(crate) fn fill_cpus(
+    buf: &mut [CpuStats],
+    system: &System,
+    times: &CpuTimesReader,
+    names: &[Arc<str>],
+    packages: &[Option<u32>],
+) {
+    let identities = names.iter().zip(packages);
+    for (i, ((slot, cpu), (name, &package))) in buf
+        .iter_mut()
+        .zip(system.cpus())
+        .zip(identities)
+        .enumerate()
+    {
+        if !Arc::ptr_eq(&slot.name, name) {
+            slot.name = name.clone();
+        }
+        slot.usage = cpu.cpu_usage();
+        slot.frequency = cpu.frequency();
+        slot.times = times.times(i);
+        slot.package = package;
+    }
+}
+
+/// Take a single observation of the system, without starting a pipeline.
+///
+/// CPU usage is computed by `sysinfo` as the difference between two
+/// refreshes, so the first refresh always reports 0% usage. This function
+/// refreshes twice, [`MINIMUM_CPU_UPDATE_INTERVAL`] apart, so the returned
+/// usage is meaningful. This is handy for scripting, and for checking that
+/// `sysinfo` works on your platform.
+///
+/// ```no_run
+/// # async fn _main() -> eyre::Result<()> {
+/// let obs = metrics_tracing_example::snapshot().await;
+/// println!("{}", serde_json::to_string_pretty(&*obs)?);
+/// # Ok(())
+/// # }
+/// ```
+#[instrument(name = "Snapshot")]
+pub async fn snapshot() -> Observation {
+    let mut system = System::new();
+    let mut times = CpuTimesReader::default();
+    system.refresh_cpu_all();
+    let _ = times.refresh();
+    tokio::time::sleep(MINIMUM_CPU_UPDATE_INTERVAL).await;
+    system.refresh_cpu_all();
+    if let Err(error) = times.refresh() {
+        debug!(%error, "Failed to read CPU times");
+    }
+
+    let names = intern_names(&system);
+    let packages = read_packages(&names);
+    Observation::new(
+        collect_cpus(&system, &times, &names, &packages),
+        tracing::Span::current(),
+    )
+}
+
+/// The number of intervals that may pass between two ticks, by the wall
+/// clock, before the monitor counts it as a gap. See
+/// [`Observation::gap_before`].
+const GAP_INTERVALS: u32 = 3;
+
+/// What the monitor does about ticks it missed, because an observation, or
+/// the whole process, took longer than the interval. See
+/// [`SysMonitor::with_missed_ticks`].
+///
+/// Tokio's default is [`Burst`], which surprises people: after a one minute
+/// stall, a monitor with a one second interval takes 60 observations as fast
+/// as it can, all of the same moment, then carries on. Each one is counted,
+/// and recorded, and fills the stats window with copies of the present.
+///
+/// [`Burst`]: MissedTicks::Burst
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum MissedTicks {
+    /// Take the missed observations right away, one after another, until
+    /// caught up with the original schedule.
+    #[default]
+    Burst,
+    /// Take one observation right away, then carry on every interval from
+    /// there. The schedule shifts by however late it was.
+    Delay,
+    /// Take one observation right away, then carry on at the next tick of
+    /// the original schedule.
+    Skip,
+}
+
+impl MissedTicks {
+    /// The name of the behavior, as used in flags and config files.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Burst => "burst",
+            Self::Delay => "delay",
+            Self::Skip => "skip",
+        }
+    }
+}
+
+impl From<MissedTicks> for MissedTickBehavior {
+    fn from(missed: MissedTicks) -> Self {
+        match missed {
+            MissedTicks::Burst => Self::Burst,
+            MissedTicks::Delay => Self::Delay,
+            MissedTicks::Skip => Self::Skip,
+        }
+    }
+}
+
+/// The average usage percentage above which an [`AdaptiveInterval`] samples
+/// faster, by default.
+pub const DEFAULT_BUSY_THRESHOLD: f64 = 75.0;
+
+/// The average usage percentage below which an [`AdaptiveInterval`] samples
+/// slower, by default.
+pub const DEFAULT_IDLE_THRESHOLD: f64 = 10.0;
+
+/// A sampling interval that follows the load. See
+/// [`SysMonitor::with_adaptive_interval`].
+///
+/// An idle machine is boring. Sampling it every second costs as much as
+/// sampling a busy one, and tells us nothing new. A busy machine is where
+/// the questions are, and a 30 second interval averages away the spikes we
+/// want to see. So after each observation, the interval is halved when the
+/// average usage is above the busy threshold, and doubled when it is below
+/// the idle threshold, within `min` and `max`. In between, it stays put.
+///
+/// Halving and doubling, rather than jumping straight to `min` or `max`,
+/// keeps one odd observation from swinging the interval across its range.
+///
+/// ```
+/// use metrics_tracing_example::AdaptiveInterval;
+/// use std::time::Duration;
+///
+/// let adaptive = AdaptiveInterval::new(Duration::from_secs(1), Duration::from_secs(30));
+/// let every = Duration::from_secs(8);
+/// assert_eq!(adaptive.next(every, 90.0), Duration::from_secs(4));
+/// assert_eq!(adaptive.next(every, 50.0), every);
+/// assert_eq!(adaptive.next(every, 2.0), Duration::from_secs(16));
+/// assert_eq!(adaptive.next(Duration::from_secs(16), 2.0), Duration::from_secs(30));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveInterval {
+    min: Duration,
+    max: Duration,
+    busy_threshold: f64,
+    idle_threshold: f64,
+}
+
+impl AdaptiveInterval {
+    /// Create an adaptive interval between `min` and `max`, with the default
+    /// [`DEFAULT_BUSY_THRESHOLD`] and [`DEFAULT_IDLE_THRESHOLD`].
+    pub const fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            busy_threshold: DEFAULT_BUSY_THRESHOLD,
+            idle_threshold: DEFAULT_IDLE_THRESHOLD,
+        }
+    }
+
+    /// Sample faster when the average usage is above `threshold` percent.
+    pub const fn with_busy_threshold(mut self, threshold: f64) -> Self {
+        self.busy_threshold = threshold;
+        self
+    }
+
+    /// Sample slower when the average usage is below `threshold` percent.
+    pub const fn with_idle_threshold(mut self, threshold: f64) -> Self {
+        self.idle_threshold = threshold;
+        self
+    }
+
+    /// The shortest interval.
+    pub const fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// The longest interval.
+    pub const fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Whether the bounds make sense: `min` is non-zero, and no longer than
+    /// `max`.
+    pub const fn is_valid(&self) -> bool {
+        !self.min.is_zero() && self.min.as_nanos() <= self.max.as_nanos()
+    }
+
+    /// The interval to wait after an observation with an average usage of
+    /// `usage` percent, taken `current` after the one before it.
+    pub fn next(&self, current: Duration, usage: f64) -> Duration {
+        let next = if usage > self.busy_threshold {
+            current / 2
+        } else if usage < self.idle_threshold {
+            current.saturating_mul(2)
+        } else {
+            current
+        };
+        next.clamp(self.min, self.max)
+    }
+}
 
 /// System monitor that takes observations at a fixed interval, and sends them
 /// to a channel.
 pub struct SysMonitor {
-    system: System,
+    /// Shared with the blocking thread that refreshes it. See
+    /// [`SysMonitor::take_observation`].
+    system: Arc<Mutex<System>>,
+    /// Refreshed right after `system`, on the same blocking thread.
+    cpu_times: Arc<Mutex<CpuTimesReader>>,
+    /// Refreshed right after `system` too, with the cgroup CPU view. See
+    /// [`SysMonitor::with_cpu_view`].
+    cgroup: Option<Arc<Mutex<CgroupReader>>>,
+    /// Refreshed right after `system` too, if the [`RefreshSpec`] asks for
+    /// them.
+    components: Option<Arc<Mutex<Components>>>,
+    /// Refreshed right after `system` too, if watching a process.
+    process: Option<Arc<Mutex<ProcessTracker>>>,
+    refresh: RefreshKind,
     interval: tokio::time::Duration,
+    adaptive: Option<AdaptiveInterval>,
+    missed_ticks: MissedTicks,
+    #[cfg(feature = "cron")]
+    cron: Option<CronSchedule>,
     counter: u64,
 
     outbound: tokio::sync::mpsc::Sender<Observation>,
+
+    health: Option<Health>,
+
+    shutdown: CancellationToken,
+
+    counters: Option<Arc<PipelineCounters>>,
+
+    /// Buffers handed out in previous observations. Once every other holder
+    /// has dropped a buffer, we overwrite it in place for a new observation.
+    buffers: Vec<Arc<[CpuStats]>>,
+
+    /// Interned CPU names, shared by every observation.
+    names: Vec<Arc<str>>,
+    /// The physical package of each CPU, in the same order as `names`.
+    packages: Vec<Option<u32>>,
+
+    metrics: ObservationMetrics,
+
+    baggage: Option<opentelemetry::Context>,
+
+    /// Link each observation's span to the previous one's. See
+    /// [`SysMonitor::with_span_links`].
+    link_previous: bool,
+    previous: Option<SpanContext>,
+
+    #[cfg(feature = "systemd")]
+    systemd: Option<SystemdNotifier>,
 }
 
 impl SysMonitor {
+    /// The resources refreshed on each observation by default: CPU usage and
+    /// frequency, and nothing else. See [`RefreshSpec::cpu`].
+    pub fn default_refresh_kind() -> RefreshKind {
+        RefreshSpec::cpu().refresh_kind()
+    }
+
     /// Create a new system monitor that takes observations at the given
     /// interval.
+    ///
+    /// Only CPU information is refreshed on each observation, regardless of
+    /// what `system` was initialized with.
     pub fn new(
         system: System,
         interval: tokio::time::Duration,
         outbound: tokio::sync::mpsc::Sender<Observation>,
     ) -> Self {
         Self {
-            system,
+            system: Arc::new(Mutex::new(system)),
+            cpu_times: Arc::default(),
+            cgroup: None,
+            components: None,
+            process: None,
+            refresh: Self::default_refresh_kind(),
             interval,
+            adaptive: None,
+            missed_ticks: MissedTicks::Burst,
+            #[cfg(feature = "cron")]
+            cron: None,
             counter: 0,
             outbound,
+            health: None,
+            shutdown: CancellationToken::new(),
+            counters: None,
+            buffers: Vec::with_capacity(MAX_RECYCLED_BUFFERS),
+            names: Vec::new(),
+            packages: Vec::new(),
+            metrics: ObservationMetrics::default(),
+            baggage: None,
+            link_previous: false,
+            previous: None,
+            #[cfg(feature = "systemd")]
+            systemd: None,
         }
     }
 
+    /// Create a new system monitor that initializes and refreshes only the
+    /// resources in `refresh`. See [`SysMonitor::with_refresh`] for the
+    /// friendlier version.
+    ///
+    /// [`System::new_all`] loads _everything_: every process, disk, network
+    /// interface, and sensor on the machine. That's a lot of syscalls for a
+    /// program that only looks at CPUs. Asking for only what we need makes
+    /// both startup and each observation cheaper.
+    ///
+    /// `refresh` should include CPU usage and frequency, otherwise the
+    /// observations will be all zeros.
+    pub fn new_with_specifics(
+        refresh: RefreshKind,
+        interval: tokio::time::Duration,
+        outbound: tokio::sync::mpsc::Sender<Observation>,
+    ) -> Self {
+        let mut monitor = Self::new(System::new_with_specifics(refresh), interval, outbound);
+        monitor.refresh = refresh;
+        monitor
+    }
+
+    /// Stop taking observations when `shutdown` is cancelled.
+    ///
+    /// When the monitor stops, it drops its outbound sender. This closes the
+    /// channel, which tells the [`SysStats`] processor to drain what remains
+    /// and exit.
+    ///
+    /// [`SysStats`]: crate::SysStats
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Record a heartbeat in `health` each time an observation is sent.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Attach the baggage in `cx` to each observation's span. See
+    /// [`baggage_context`].
+    ///
+    /// [`baggage_context`]: crate::baggage::baggage_context
+    pub fn with_baggage(mut self, cx: opentelemetry::Context) -> Self {
+        self.baggage = Some(cx);
+        self
+    }
+
+    /// Add a span link from each observation's span to the previous
+    /// observation's span.
+    ///
+    /// Each observation gets its own trace, so trace viewers show them as
+    /// unrelated. But they aren't: they are a series of samples, and the
+    /// stats computed for one depend on the ones before it. A _span link_
+    /// records a relationship between spans without making one the parent of
+    /// the other. Viewers like Jaeger show links as references, so you can
+    /// walk from any observation back along the chain.
+    ///
+    /// Links are an OTEL concept, and `tracing` has no notion of them. They
+    /// are added with [`OpenTelemetrySpanExt::add_link`], and only exist when
+    /// the OTEL layer is installed.
+    pub const fn with_span_links(mut self) -> Self {
+        self.link_previous = true;
+        self
+    }
+
+    /// Adapt the interval to the load, within the bounds of `adaptive`. The
+    /// interval the monitor was created with is where it starts. See
+    /// [`AdaptiveInterval`].
+    ///
+    /// The interval in effect is recorded on the
+    /// `my_cute_app.sample_interval_seconds` gauge, and each change is logged.
+    /// Anything that expects an observation every interval, like a
+    /// [`Health`] timeout, or a [`Watchdog`], should allow for the longest.
+    ///
+    /// ## Panics
+    ///
+    /// If `adaptive`'s bounds are invalid. See [`AdaptiveInterval::is_valid`].
+    ///
+    /// [`Watchdog`]: crate::Watchdog
+    pub fn with_adaptive_interval(mut self, adaptive: AdaptiveInterval) -> Self {
+        assert!(adaptive.is_valid(), "adaptive interval bounds are invalid");
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Catch up on missed ticks as `missed` says, instead of the default of
+    /// [`MissedTicks::Burst`].
+    ///
+    /// Whatever the behavior, the ticks that were due while the monitor was
+    /// busy are counted on the `my_cute_app.missed_ticks` counter. Ticks the
+    /// host slept through aren't: tokio's clock stops while it's suspended.
+    /// See [`Observation::gap_before`] for those.
+    pub const fn with_missed_ticks(mut self, missed: MissedTicks) -> Self {
+        self.missed_ticks = missed;
+        self
+    }
+
+    /// Take observations when `schedule` fires, instead of every interval.
+    /// See [`CronSchedule`].
+    ///
+    /// The schedule replaces the interval, and an adaptive interval too.
+    /// When it never fires again, the monitor stops, as if shut down.
+    ///
+    /// Between firings, the monitor is quiet, so a [`Health`] timeout, or a
+    /// [`Watchdog`], shorter than the longest gap between them flags it as
+    /// stalled. A schedule restricted to business hours has a gap of a whole
+    /// weekend.
+    ///
+    /// [`Watchdog`]: crate::Watchdog
+    #[cfg(feature = "cron")]
+    pub fn with_cron(mut self, schedule: CronSchedule) -> Self {
+        self.cron = Some(schedule);
+        self
+    }
+
+    /// Refresh the subsystems in `spec` on each observation, instead of the
+    /// default of [`RefreshSpec::cpu`], and attach what they read to each
+    /// observation. See [`RefreshSpec`].
+    pub fn with_refresh(mut self, spec: RefreshSpec) -> Self {
+        self.refresh = spec.refresh_kind();
+        self.components = spec
+            .components()
+            .then(|| Arc::new(Mutex::new(Components::new_with_refreshed_list())));
+        self
+    }
+
+    /// Report CPU usage as `view` says, instead of the default of
+    /// [`CpuView::Host`].
+    ///
+    /// With [`CpuView::Cgroup`], each observation has a single "CPU", named
+    /// `cgroup`, whose usage is the monitor's cgroup's, as a percentage of
+    /// its quota. Everything downstream, from the stats window to the
+    /// alerts, then sees the container's usage. Its frequency is the
+    /// average of the host's CPUs, and it has no time breakdown. The quota,
+    /// in cores, is recorded on the `my_cute_app.cpu_limit_cores` gauge.
+    ///
+    /// If the monitor isn't in a cgroup v2 hierarchy, a warning is logged,
+    /// and it reports the host's CPUs after all.
+    pub fn with_cpu_view(mut self, view: CpuView) -> Self {
+        self.cgroup = match view {
+            CpuView::Host => None,
+            CpuView::Cgroup => match CgroupReader::detect() {
+                Ok(reader) => {
+                    info!(
+                        limit_cores = reader.limit(),
+                        "accounting CPU against the cgroup"
+                    );
+                    Some(Arc::new(Mutex::new(reader)))
+                }
+                Err(error) => {
+                    warn!(%error, "no cgroup to account CPU against, reporting the host's CPUs");
+                    None
+                }
+            },
+        };
+        self
+    }
+
+    /// Also watch the process picked out by `selector`, and attach its CPU
+    /// and memory usage to each observation. See [`Observation::process`].
+    ///
+    /// Host-wide CPU usage says the machine is busy, but not who is keeping
+    /// it busy. When the point of monitoring is one application, watch that
+    /// application. Selected by name, the monitor follows the process across
+    /// restarts: when it exits, the next observation looks for another
+    /// process of the same name, and logs the new PID. Selected by PID,
+    /// there is nothing to follow, and observations go without process
+    /// usage once it exits.
+    ///
+    /// The usage is recorded on the `my_cute_app.process_cpu_usage` and
+    /// `my_cute_app.process_memory_bytes` gauges, labeled by process name.
+    pub fn for_process(mut self, selector: ProcessSelector) -> Self {
+        self.process = Some(Arc::new(Mutex::new(ProcessTracker::new(selector))));
+        self
+    }
+
+    /// Notify systemd when running as a service: `READY=1` after the first
+    /// successful observation, and `WATCHDOG=1` after every one. When the
+    /// monitor stalls, the pings stop, and a unit with `WatchdogSec=` set is
+    /// restarted by systemd. `STOPPING=1` is sent on shutdown.
+    ///
+    /// Use this with `Type=notify` in the unit file. Set `WatchdogSec=` to
+    /// at least twice the observation interval:
+    ///
+    /// ```ini
+    /// [Service]
+    /// Type=notify
+    /// ExecStart=/usr/local/bin/sysmon --systemd --interval 5s
+    /// WatchdogSec=15s
+    /// Restart=on-watchdog
+    /// ```
+    ///
+    /// Outside of systemd, this does nothing.
+    #[cfg(feature = "systemd")]
+    pub fn with_systemd(mut self) -> Self {
+        self.systemd = Some(SystemdNotifier::default());
+        self
+    }
+
+    /// Count observations taken in `counters`.
+    pub(crate) fn with_counters(mut self, counters: Arc<PipelineCounters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
     /// Take a single observation of the system state.
     ///
     /// This is instrumented so that we can see when observations are taken.
@@ -43,66 +604,518 @@ impl SysMonitor {
     ///
     /// See the tracing crate documentation for more details:
     /// <https://docs.rs/tracing/latest/tracing/attr.instrument.html>
+    ///
+    /// Refreshing the system reads a pile of files under `/proc`, and blocks
+    /// the thread while it does so. At high sampling rates, doing that on the
+    /// async runtime would stall every other task scheduled on the same
+    /// worker. Instead, we hand the refresh off to tokio's blocking thread
+    /// pool, and wait for it asynchronously.
+    ///
+    /// Spans do NOT follow work onto other threads by themselves. The
+    /// blocking closure runs on a different thread, where the current span
+    /// is whatever that thread happens to have entered (i.e. nothing). To keep
+    /// the refresh inside the observation's trace, we capture the current
+    /// span before the handoff, and enter it again on the other side.
     #[instrument(skip(self), name = "Taking observation")]
-    fn take_observation(&mut self) -> Vec<CpuStats> {
-        // We're going to emit an event when we create the observation
-        self.system.refresh_cpu_all();
-
-        trace!("Refreshed CPU information");
-
-        let cpus = self
-            .system
-            .cpus()
-            .iter()
-            .map(|cpu| {
-                let name = cpu.name().to_owned();
-                CpuStats {
-                    name,
-                    usage: cpu.cpu_usage(),
-                    frequency: cpu.frequency(),
+    async fn take_observation(&mut self) -> Result<Arc<[CpuStats]>, JoinError> {
+        self.refresh().await?;
+
+        let cpus = match self.cgroup.clone() {
+            Some(cgroup) => self.fill_cgroup_buffer(&cgroup),
+            None => self.fill_buffer(),
+        };
+
+        // Checking once up front saves checking once per CPU.
+        if tracing::enabled!(Level::TRACE) {
+            for cpu in cpus.iter() {
+                trace!(
+                    { CPU } = %cpu.name,
+                    { USAGE_PCT } = fields::usage_pct(cpu.usage),
+                    { FREQ_MHZ } = fields::freq_mhz(cpu.frequency),
+                    "Sampled CPU"
+                );
+            }
+        }
+
+        self.counter = self.counter.wrapping_add(1);
+
+        Ok(cpus)
+    }
+
+    /// Refresh the system and the CPU times on the blocking thread pool,
+    /// within the current span. See [`SysMonitor::take_observation`].
+    async fn refresh(&self) -> Result<(), JoinError> {
+        let system = self.system.clone();
+        let cpu_times = self.cpu_times.clone();
+        let cgroup = self.cgroup.clone();
+        let components = self.components.clone();
+        let process = self.process.clone();
+        let refresh = self.refresh;
+        let span = tracing::Span::current();
+
+        tokio::task::spawn_blocking(move || {
+            span.in_scope(|| {
+                let _refresh = debug_span!("Refreshing system").entered();
+                let mut system = lock(&system);
+                system.refresh_specifics(refresh);
+                // Not fatal, the observation just goes without a breakdown.
+                if let Err(error) = lock(&cpu_times).refresh() {
+                    debug!(%error, "Failed to read CPU times");
+                }
+                if let Some(cgroup) = &cgroup
+                    && let Err(error) = lock(cgroup).refresh()
+                {
+                    debug!(%error, "Failed to read cgroup CPU usage");
+                }
+                if let Some(components) = &components {
+                    lock(components).refresh(false);
+                }
+                if let Some(process) = &process {
+                    lock(process).refresh(&mut system);
                 }
+                trace!("Refreshed CPU information");
             })
-            .collect();
+        })
+        .await
+    }
 
-        self.counter = self.counter.wrapping_add(1);
+    /// Refresh once, and throw the result away.
+    ///
+    /// `sysinfo` computes CPU usage as the difference between two refreshes.
+    /// The first refresh has nothing to compare against, so every CPU reads
+    /// 0%. As an observation, that drags the first window's average down,
+    /// and looks like the machine was idle at startup. Instead, we take that
+    /// refresh before the first observation, and don't send it anywhere.
+    ///
+    /// The next refresh must be at least [`MINIMUM_CPU_UPDATE_INTERVAL`]
+    /// later, or its usage is just as meaningless, so the caller waits that
+    /// long before the first tick.
+    #[instrument(skip(self), name = "Warming up")]
+    async fn warm_up(&self) -> Result<(), JoinError> {
+        self.refresh().await?;
+        info!(
+            wait_ms = MINIMUM_CPU_UPDATE_INTERVAL.as_millis() as u64,
+            "discarded the first CPU refresh, its usage is always 0%"
+        );
+        Ok(())
+    }
+
+    /// An interval of `every`, starting at `start`, that catches up on
+    /// missed ticks as configured.
+    fn interval_at(&self, start: Instant, every: Duration) -> Interval {
+        let mut interval = tokio::time::interval_at(start, every);
+        interval.set_missed_tick_behavior(self.missed_ticks.into());
+        interval
+    }
+
+    /// Whether observations are taken on a cron schedule, rather than every
+    /// interval.
+    const fn on_schedule(&self) -> bool {
+        #[cfg(feature = "cron")]
+        return self.cron.is_some();
+        #[cfg(not(feature = "cron"))]
+        false
+    }
+
+    /// Wait until the next observation is due: the next tick of `interval`,
+    /// or the next time the cron schedule fires, if there is one. `false`
+    /// if it never fires again.
+    async fn next_tick(&self, interval: &mut Interval) -> bool {
+        #[cfg(feature = "cron")]
+        if let Some(schedule) = &self.cron {
+            let Some(wait) = schedule.until_next() else {
+                return false;
+            };
+            tokio::time::sleep(wait).await;
+            return true;
+        }
+        interval.tick().await;
+        true
+    }
+
+    /// Link `span` to the previous observation's span, and remember `span`'s
+    /// context for the next one.
+    ///
+    /// Links must be added before the OTEL span is started, so this is
+    /// called right after `span` is created. Reading the span's context
+    /// starts it, so we do that last.
+    fn link_to_previous(&mut self, span: &tracing::Span) {
+        if let Some(previous) = self.previous.take() {
+            span.add_link(previous);
+        }
+        let cx = span.context().span().span_context().clone();
+        // Without the OTEL layer, the context is invalid, and there is
+        // nothing to link to.
+        self.previous = cx.is_valid().then_some(cx);
+    }
+
+    /// Write the current CPU stats into a recycled buffer, or allocate a new
+    /// one if none is free.
+    ///
+    /// The number of CPUs is fixed at runtime, so every observation needs a
+    /// buffer of the same size. Rather than allocating a fresh one each tick,
+    /// we keep the buffers we've handed out. When the stats processor evicts
+    /// an observation from its window, and everyone else has dropped it too,
+    /// [`Arc::get_mut`] succeeds and we can reuse the buffer. In the steady
+    /// state, this means no allocations at all.
+    ///
+    /// CPU names don't change either, so they are interned the first time we
+    /// see them, and every buffer shares the same [`Arc<str>`]s.
+    fn fill_buffer(&mut self) -> Arc<[CpuStats]> {
+        let system = lock(&self.system);
+        let times = lock(&self.cpu_times);
+        let cpus = system.cpus();
+
+        if self.names.len() != cpus.len() {
+            trace!(cpus = cpus.len(), "Interning CPU names");
+            self.names = intern_names(&system);
+            self.packages = read_packages(&self.names);
+        }
+
+        let free = self
+            .buffers
+            .iter_mut()
+            .position(|buf| Arc::get_mut(buf).is_some_and(|buf| buf.len() == cpus.len()));
+
+        let Some(idx) = free else {
+            trace!("No free buffer, allocating");
+            let buf: Arc<[CpuStats]> =
+                collect_cpus(&system, &times, &self.names, &self.packages).into();
+            if self.buffers.len() < MAX_RECYCLED_BUFFERS {
+                self.buffers.push(buf.clone());
+            }
+            return buf;
+        };
 
-        cpus
+        let buf = Arc::get_mut(&mut self.buffers[idx]).expect("checked above");
+        fill_cpus(buf, &system, &times, &self.names, &self.packages);
+        self.buffers[idx].clone()
+    }
+
+    /// Attach whatever else was refreshed, besides the CPUs, to `obs`, and
+    /// record it. See [`SysMonitor::with_refresh`].
+    fn attach_extras(&self, mut obs: Observation) -> Observation {
+        let system = lock(&self.system);
+        if self.refresh.memory().is_some() {
+            let usage = MemoryUsage::read(&system);
+            crate::metrics::record_memory(&usage);
+            obs = obs.with_memory(usage);
+        }
+        if self.refresh.processes().is_some() {
+            let count = system.processes().len();
+            crate::metrics::record_processes(count);
+            obs = obs.with_process_count(count);
+        }
+        if let Some(components) = &self.components {
+            let temperatures: Arc<[Temperature]> = lock(components)
+                .iter()
+                .filter_map(|component| {
+                    Some(Temperature {
+                        label: component.label().into(),
+                        celsius: component.temperature()?,
+                    })
+                })
+                .collect();
+            crate::metrics::record_temperatures(&temperatures);
+            obs = obs.with_temperatures(temperatures);
+        }
+        obs
+    }
+
+    /// Write the cgroup's usage into a recycled buffer of one, or allocate a
+    /// new one if none is free. See [`SysMonitor::with_cpu_view`].
+    fn fill_cgroup_buffer(&mut self, cgroup: &Mutex<CgroupReader>) -> Arc<[CpuStats]> {
+        let (usage, limit) = {
+            let cgroup = lock(cgroup);
+            (cgroup.usage().unwrap_or_default(), cgroup.limit())
+        };
+        crate::metrics::record_cpu_limit(limit);
+
+        let frequency = {
+            let system = lock(&self.system);
+            let cpus = system.cpus();
+            cpus.iter().map(|cpu| cpu.frequency()).sum::<u64>() / (cpus.len() as u64).max(1)
+        };
+        let name = self.names.first().cloned().unwrap_or_else(|| {
+            self.names = vec![Arc::from(CGROUP_CPU)];
+            self.names[0].clone()
+        });
+        let stats = CpuStats {
+            name,
+            usage,
+            frequency,
+            times: None,
+            package: None,
+        };
+
+        let free = self
+            .buffers
+            .iter_mut()
+            .position(|buf| Arc::get_mut(buf).is_some_and(|buf| buf.len() == 1));
+        let Some(idx) = free else {
+            trace!("No free buffer, allocating");
+            let buf: Arc<[CpuStats]> = Arc::new([stats]);
+            if self.buffers.len() < MAX_RECYCLED_BUFFERS {
+                self.buffers.push(buf.clone());
+            }
+            return buf;
+        };
+        Arc::get_mut(&mut self.buffers[idx]).expect("checked above")[0] = stats;
+        self.buffers[idx].clone()
     }
 
     /// Spawn the system monitor in a new task. This is the core task loop,
     /// which takes observations at the configured interval, and sends them to
     /// the outbound channel.
-    pub(crate) fn spawn(mut self) -> tokio::task::JoinHandle<()> {
+    ///
+    /// ## Channels
+    ///
+    /// The monitor is the only sender on its outbound channel, unless you
+    /// clone the sender before handing it over. It sends one [`Observation`]
+    /// per tick, and waits for room in the channel, so a slow receiver holds
+    /// up the monitor, and it misses ticks. Nothing is dropped. See
+    /// [`SysMonitor::with_missed_ticks`].
+    ///
+    /// The task exits, and drops its sender, when the [shutdown token] is
+    /// cancelled, when the receiver is dropped, or when a refresh fails. With
+    /// no other senders, the channel then closes, and the receiver sees every
+    /// observation sent before the close. That's how the [`SysStats`]
+    /// processor knows to compute its final stats and exit.
+    ///
+    /// Together, the two actors are the pipeline that [`run_observations`]
+    /// and the [`PipelineBuilder`] assemble. Wired by hand, anything can go
+    /// between them, or after them:
+    ///
+    /// ```no_run
+    /// use metrics_tracing_example::{SysMonitor, SysStats};
+    /// use std::time::Duration;
+    /// use tokio::sync::mpsc;
+    /// use tokio_util::sync::CancellationToken;
+    ///
+    /// # async fn _main() -> eyre::Result<()> {
+    /// let shutdown = CancellationToken::new();
+    /// let (monitor_tx, mut monitor_rx) = mpsc::channel(2);
+    /// let (stats_tx, stats_rx) = mpsc::channel(2);
+    ///
+    /// let monitor = SysMonitor::new_with_specifics(
+    ///     SysMonitor::default_refresh_kind(),
+    ///     Duration::from_secs(1),
+    ///     monitor_tx,
+    /// )
+    /// .with_shutdown(shutdown.clone())
+    /// .spawn();
+    /// let stats = SysStats::new(stats_rx, None).spawn();
+    ///
+    /// // Only busy observations reach the stats processor.
+    /// let filter = tokio::spawn(async move {
+    ///     while let Some(obs) = monitor_rx.recv().await {
+    ///         if obs.iter().any(|cpu| cpu.usage > 50.0) && stats_tx.send(obs).await.is_err() {
+    ///             break;
+    ///         }
+    ///     }
+    /// });
+    ///
+    /// tokio::signal::ctrl_c().await?;
+    /// // Each actor exits once the one before it has, and its channel closed.
+    /// shutdown.cancel();
+    /// monitor.await?;
+    /// filter.await?;
+    /// stats.await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// The [`ShutdownCoordinator`] does the waiting in this order for you,
+    /// and shuts tracing down after it.
+    ///
+    /// [shutdown token]: SysMonitor::with_shutdown
+    /// [`ShutdownCoordinator`]: crate::ShutdownCoordinator
+    /// [`SysStats`]: crate::SysStats
+    /// [`run_observations`]: crate::run_observations
+    /// [`PipelineBuilder`]: crate::PipelineBuilder
+    pub fn spawn(mut self) -> tokio::task::JoinHandle<()> {
         spawn(async move {
-            let mut interval = tokio::time::interval(self.interval);
+            if let Err(error) = self.warm_up().await {
+                error!(%error, "System refresh failed, monitor exiting");
+                return;
+            }
+            tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => {
+                    debug!("Shutdown requested during warm-up, monitor exiting");
+                    return;
+                }
+                _ = tokio::time::sleep(MINIMUM_CPU_UPDATE_INTERVAL) => {}
+            }
+
+            #[cfg(feature = "cron")]
+            if let Some(schedule) = &self.cron {
+                info!(%schedule, "sampling on a cron schedule");
+                self.adaptive = None;
+            }
+            let mut every = match self.adaptive {
+                Some(adaptive) => self.interval.clamp(adaptive.min, adaptive.max),
+                None => self.interval,
+            };
+            let mut interval = self.interval_at(Instant::now(), every);
+            // On a schedule, the time between observations isn't fixed.
+            if !self.on_schedule() {
+                crate::metrics::record_sample_interval(every);
+            }
+            #[cfg(feature = "systemd")]
+            if let Some(systemd) = &self.systemd {
+                let longest = self.adaptive.map_or(every, |adaptive| adaptive.max);
+                systemd.check_interval(longest);
+            }
+
+            let mut last_tick = None;
+            let mut last_instant = None;
 
             loop {
-                interval.tick().await;
+                tokio::select! {
+                    biased;
+                    _ = self.shutdown.cancelled() => {
+                        debug!("Shutdown requested, monitor exiting");
+                        break;
+                    }
+                    tick = self.next_tick(&mut interval) => if !tick {
+                        debug!("Cron schedule never fires again, monitor exiting");
+                        break;
+                    }
+                }
+
+                // Tokio's clock is monotonic, and on most platforms, it stops
+                // while the host is suspended. After a laptop wakes up, the
+                // next tick comes right on time, as far as tokio knows. The
+                // wall clock knows better. It also catches the monitor
+                // stalling, and bursting through the ticks it missed.
+                let instant = Instant::now();
+                if let Some(last) = last_instant.replace(instant)
+                    && !self.on_schedule()
+                {
+                    let due = (instant - last).as_nanos() / every.as_nanos().max(1);
+                    let missed = due.saturating_sub(1) as u64;
+                    if missed > 0 {
+                        crate::metrics::record_missed_ticks(self.missed_ticks, missed);
+                        trace!(missed, "Missed ticks");
+                    }
+                }
+
+                let now = SystemTime::now();
+                let gap = last_tick
+                    .replace(now)
+                    .and_then(|last| now.duration_since(last).ok())
+                    .filter(|elapsed| !self.on_schedule() && *elapsed > every * GAP_INTERVALS)
+                    .map(|elapsed| elapsed - every);
 
                 // We create a new span for each observation, so that we can see
                 // when observations are taken, and how long they take.
                 //
                 // The observation ID is included as a field in the span, so
                 // that we can correlate logs and traces.
-                let span = info_span!("Observation", observation_id = self.counter);
+                let span = info_span!(
+                    "Observation",
+                    { OBSERVATION_ID } = fields::obs_id(self.counter)
+                );
+                if let Some(baggage) = &self.baggage {
+                    attach_baggage(&span, baggage);
+                }
+                if self.link_previous {
+                    self.link_to_previous(&span);
+                }
 
-                // In-scope runs the closure within the context of the
+                // Instrument runs the future within the context of the
                 // span. This ensures that the observation span is the
-                // parent of any spans created within the closure, as well
+                // parent of any spans created within the future, as well
                 // as that the observation span is Entered and Exited
-                // correctly.
-                let stats = span.in_scope(|| {
-                    trace!("Taking observation");
-                    self.take_observation()
-                });
+                // correctly each time the future is polled.
+                let stats = match self.take_observation().instrument(span.clone()).await {
+                    Ok(stats) => stats,
+                    Err(error) => {
+                        span.in_scope(|| error!(%error, "System refresh failed, monitor exiting"));
+                        break;
+                    }
+                };
+
+                if let Some(adaptive) = self.adaptive {
+                    let next = adaptive.next(every, average_usage(&stats));
+                    if next != every {
+                        span.in_scope(|| {
+                            info!(
+                                from_ms = every.as_millis() as u64,
+                                to_ms = next.as_millis() as u64,
+                                "sampling interval changed"
+                            );
+                        });
+                        every = next;
+                        interval = self.interval_at(Instant::now() + every, every);
+                        crate::metrics::record_sample_interval(every);
+                    }
+                }
 
-                let obs = Observation::new(stats, span);
+                let mut obs = Observation::new_with_metrics(stats, span, &mut self.metrics);
+                obs = self.attach_extras(obs);
+                if let Some(usage) = self.process.as_deref().and_then(|p| lock(p).latest()) {
+                    obs.in_scope(|_| {
+                        trace!(
+                            pid = usage.pid,
+                            { USAGE_PCT } = fields::usage_pct(usage.cpu_usage),
+                            memory = usage.memory,
+                            "Sampled process"
+                        );
+                    });
+                    crate::metrics::record_process(&usage);
+                    obs = obs.with_process(usage);
+                }
+                if let Some(gap) = gap {
+                    obs.in_scope(|_| {
+                        warn!(
+                            gap_ms = gap.as_millis() as u64,
+                            interval_ms = every.as_millis() as u64,
+                            "gap detected, the host was suspended, or the monitor stalled"
+                        );
+                    });
+                    obs = obs.with_gap_before(gap);
+                }
+
+                if let Some(counters) = &self.counters {
+                    counters.record_taken();
+                }
 
                 if self.outbound.send(obs).await.is_err() {
                     trace!("SysStats receiver dropped, exiting");
                     break;
                 }
+
+                if let Some(health) = &self.health {
+                    health.monitor_beat();
+                }
+                #[cfg(feature = "systemd")]
+                if let Some(systemd) = &mut self.systemd {
+                    systemd.observed();
+                }
+            }
+
+            #[cfg(feature = "systemd")]
+            if let Some(systemd) = &self.systemd {
+                systemd.stopping();
             }
         })
     }
 }
+
+/// The average usage percentage over every CPU.
+fn average_usage(cpus: &[CpuStats]) -> f64 {
+    if cpus.is_empty() {
+        return 0.0;
+    }
+    cpus.iter().map(|cpu| f64::from(cpu.usage)).sum::<f64>() / cpus.len() as f64
+}
+
+/// Lock the shared [`System`], [`CpuTimesReader`], [`CgroupReader`],
+/// [`Components`], or [`ProcessTracker`]. A panic during a
+/// refresh poisons the mutex, but leaves the value itself perfectly usable,
+/// so we ignore the poison.
+fn lock<T>(shared: &Mutex<T>) -> MutexGuard<'_, T> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
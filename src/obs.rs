@@ -1,20 +1,201 @@
 //! Just the [`Observation`] struct.
 
-use metrics::gauge;
-use std::ops::{Deref, DerefMut};
+#[cfg(feature = "sysinfo")]
+use crate::metrics::ObservationMetrics;
+use crate::{MemoryUsage, ObservationGuard, ProcessUsage, Temperature};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 use tracing::trace;
 
 /// CPU statistics at a point in time.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct CpuStats {
-    /// CPU name
-    pub name: String,
+    /// CPU name. Names never change at runtime, so they are interned and
+    /// shared between observations, rather than cloned for each one.
+    pub name: Arc<str>,
 
     /// CPU usage percentage
     pub usage: f32,
 
     /// CPU frequency in MHz
     pub frequency: u64,
+
+    /// Where the CPU's time went since the previous observation. Only
+    /// available on Linux, and only from the second read of the CPU times,
+    /// which the [`SysMonitor`]'s warm-up takes care of.
+    ///
+    /// [`SysMonitor`]: crate::SysMonitor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub times: Option<CpuTimes>,
+
+    /// The physical package, i.e. the socket, the CPU belongs to. Only
+    /// available on Linux.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<u32>,
+}
+
+/// The share of a CPU's time spent in each mode, between 0 and 1.
+///
+/// Usage alone can't tell a CPU crunching numbers from one stuck in the
+/// kernel, or one that is busy on paper but stolen by the hypervisor. The
+/// modes below can. They don't add up to 1: the rest of the time is idle.
+///
+/// - High `user` time is your code, and the libraries it calls, doing work.
+/// - High `system` time is the kernel doing work on your behalf, e.g.
+///   syscalls, page faults, or interrupts. Too many small reads or writes
+///   show up here.
+/// - High `iowait` means the CPU was idle, with I/O outstanding. The CPU
+///   isn't the bottleneck, the disk or network is.
+/// - High `steal` means a virtual machine wanted the CPU, but the hypervisor
+///   gave it to another guest. Your cloud instance is oversubscribed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CpuTimes {
+    /// Running user code, including `nice`d processes.
+    pub user: f32,
+    /// Running kernel code, including interrupt handlers.
+    pub system: f32,
+    /// Idle, while waiting for I/O to complete.
+    pub iowait: f32,
+    /// Waiting for the hypervisor to run this virtual CPU.
+    pub steal: f32,
+}
+
+impl CpuTimes {
+    /// The names of the modes, in the order [`CpuTimes::modes`] returns them.
+    pub const MODES: [&'static str; 4] = ["user", "system", "iowait", "steal"];
+
+    /// The modes, as `(name, share)` pairs, e.g. for labeling metrics.
+    pub const fn modes(&self) -> [(&'static str, f32); 4] {
+        let [user, system, iowait, steal] = Self::MODES;
+        [
+            (user, self.user),
+            (system, self.system),
+            (iowait, self.iowait),
+            (steal, self.steal),
+        ]
+    }
+}
+
+/// The data carried by an [`Observation`].
+///
+/// The span, the timestamps, and the channels between actors have nothing
+/// to do with CPUs. Implement this for your own data, e.g. request latencies
+/// or queue depths, and an `Observation` of it travels through channels,
+/// [`ObservationStream`]s, and [`ObservationSink`]s, with its span, just like
+/// the CPU stats do. A [`Window`] keeps a sliding window of it.
+///
+/// ```
+/// use metrics_tracing_example::{Observation, ObservationData};
+///
+/// /// The depth of a work queue.
+/// #[derive(Debug)]
+/// struct QueueDepth(usize);
+///
+/// impl ObservationData for QueueDepth {
+///     const KIND: &'static str = "queue_depth";
+/// }
+///
+/// let span = tracing::info_span!("Observation", kind = QueueDepth::KIND);
+/// let obs = Observation::from_data(QueueDepth(12), span);
+/// assert_eq!(obs.data().0, 12);
+/// ```
+///
+/// [`ObservationStream`]: crate::ObservationStream
+/// [`ObservationSink`]: crate::ObservationSink
+/// [`Window`]: crate::Window
+pub trait ObservationData: Send + 'static {
+    /// What kind of data this is, for logs and metric labels, e.g. `cpu`.
+    const KIND: &'static str;
+}
+
+/// What the [`SysMonitor`] took: the CPU stats, and whatever else it was
+/// asked to refresh along with them. This is the data of a plain
+/// [`Observation`].
+///
+/// The stats are a shared handle, so that the [`SysStats`] window can hold
+/// on to them without copying them. It derefs to them, so most code never
+/// needs to know about the rest.
+///
+/// [`SysMonitor`]: crate::SysMonitor
+/// [`SysStats`]: crate::SysStats
+#[derive(Debug, Clone, Default)]
+pub struct CpuData {
+    cpus: Arc<[CpuStats]>,
+    /// The watched process's usage, if the monitor is watching one. See
+    /// [`CpuData::process`].
+    process: Option<ProcessUsage>,
+    /// What else the monitor refreshed, if it was asked to. See
+    /// [`RefreshSpec`].
+    ///
+    /// [`RefreshSpec`]: crate::RefreshSpec
+    memory: Option<MemoryUsage>,
+    process_count: Option<usize>,
+    temperatures: Option<Arc<[Temperature]>>,
+}
+
+impl ObservationData for CpuData {
+    const KIND: &'static str = "cpu";
+}
+
+impl From<Arc<[CpuStats]>> for CpuData {
+    fn from(cpus: Arc<[CpuStats]>) -> Self {
+        Self {
+            cpus,
+            ..Self::default()
+        }
+    }
+}
+
+impl Deref for CpuData {
+    type Target = [CpuStats];
+
+    fn deref(&self) -> &Self::Target {
+        &self.cpus
+    }
+}
+
+impl CpuData {
+    /// Get a shared handle to the CPU stats.
+    pub const fn cpus(&self) -> &Arc<[CpuStats]> {
+        &self.cpus
+    }
+
+    /// The usage of the process the [`SysMonitor`] is watching, if it is
+    /// watching one, and the process was running when this was taken. The
+    /// CPU stats are still the whole host's, so the two can be compared:
+    /// is the host busy because of this process, or despite it?
+    ///
+    /// [`SysMonitor`]: crate::SysMonitor
+    pub const fn process(&self) -> Option<&ProcessUsage> {
+        self.process.as_ref()
+    }
+
+    /// The host's memory usage, if the monitor refreshes it. See
+    /// [`RefreshSpec::with_memory`].
+    ///
+    /// [`RefreshSpec::with_memory`]: crate::RefreshSpec::with_memory
+    pub const fn memory(&self) -> Option<&MemoryUsage> {
+        self.memory.as_ref()
+    }
+
+    /// The number of processes on the host, if the monitor refreshes them.
+    /// See [`RefreshSpec::with_processes`].
+    ///
+    /// [`RefreshSpec::with_processes`]: crate::RefreshSpec::with_processes
+    pub const fn process_count(&self) -> Option<usize> {
+        self.process_count
+    }
+
+    /// The temperature sensors' readings, if the monitor refreshes them. See
+    /// [`RefreshSpec::with_components`].
+    ///
+    /// [`RefreshSpec::with_components`]: crate::RefreshSpec::with_components
+    pub fn temperatures(&self) -> Option<&[Temperature]> {
+        self.temperatures.as_deref()
+    }
 }
 
 /// An observation of CPU stats at a point in time, along with the tracing span
@@ -59,25 +240,49 @@ pub struct CpuStats {
 /// }
 /// ```
 ///
+/// Observations carry CPU stats by default, but the payload can be anything
+/// that implements [`ObservationData`]. Methods that only make sense for CPU
+/// stats, like [`Observation::cpus`], are only available on `Observation`,
+/// which is `Observation<CpuData>`.
+///
 /// [`Span`]: tracing::Span
 #[derive(Debug)]
-pub struct Observation {
-    cpus: Vec<CpuStats>,
+pub struct Observation<T: ObservationData = CpuData> {
+    data: T,
 
     span: tracing::Span,
+
+    taken_at: Instant,
+
+    host: Option<Arc<str>>,
+
+    /// The sender's sequence number, and when the sender took it, for
+    /// observations received from another host.
+    sequence: Option<(u64, SystemTime)>,
+    /// How long the monitor was gone before this observation, if it was
+    /// gone long enough to count. See [`Observation::gap_before`].
+    gap_before: Option<Duration>,
+    /// Counts the observation on the `observations_live` gauge, until it's
+    /// dropped. See [`Observation::with_guard`].
+    guard: Option<ObservationGuard>,
 }
 
 impl Deref for Observation {
-    type Target = Vec<CpuStats>;
+    type Target = [CpuStats];
 
     fn deref(&self) -> &Self::Target {
-        &self.cpus
+        &self.data.cpus
     }
 }
 
+/// Mutable access is copy-on-write. If the stats are shared, e.g. with the
+/// [`SysStats`] window, they are cloned first, so that the window is not
+/// modified behind the stats processor's back.
+///
+/// [`SysStats`]: crate::SysStats
 impl DerefMut for Observation {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.cpus
+        Arc::make_mut(&mut self.data.cpus)
     }
 }
 
@@ -87,30 +292,203 @@ impl Observation {
     /// well as a span for use when accessing the observation.
     ///
     /// The `span` here is the tracing span associated with this Observation.
-    pub fn new(cpus: Vec<CpuStats>, span: tracing::Span) -> Self {
-        crate::metrics::record_observation(&cpus);
-        Self { cpus, span }
+    ///
+    /// Creating an observation records no metrics, and neither does
+    /// dropping it. The [`SysMonitor`] records its own observations, as it
+    /// takes them. Observations created anywhere else, like tests, replays,
+    /// or observations received from another host, aren't something this
+    /// host measured, and counting them as if they were would skew its
+    /// metrics.
+    ///
+    /// [`SysMonitor`]: crate::SysMonitor
+    pub fn new(cpus: impl Into<Arc<[CpuStats]>>, span: tracing::Span) -> Self {
+        Self::from_data(CpuData::from(cpus.into()), span)
+    }
+
+    /// Create a new Observation, recording metrics via the cached handles in
+    /// `metrics`, and counting it as live until it's dropped. Used by the
+    /// [`SysMonitor`].
+    ///
+    /// [`SysMonitor`]: crate::SysMonitor
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn new_with_metrics(
+        cpus: Arc<[CpuStats]>,
+        span: tracing::Span,
+        metrics: &mut ObservationMetrics,
+    ) -> Self {
+        let guard = metrics.record(&cpus, &span);
+        Self::new(cpus, span).with_guard(guard)
+    }
+
+    /// Get a shared handle to the CPU stats. Cloning the [`Arc`] is cheap,
+    /// and lets the stats outlive the observation without copying them, or
+    /// holding on to the observation's span.
+    pub const fn cpus(&self) -> &Arc<[CpuStats]> {
+        self.data.cpus()
+    }
+
+    /// Attach the watched process's usage.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn with_process(mut self, usage: ProcessUsage) -> Self {
+        self.data.process = Some(usage);
+        self
+    }
+
+    /// Attach the host's memory usage.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) const fn with_memory(mut self, memory: MemoryUsage) -> Self {
+        self.data.memory = Some(memory);
+        self
+    }
+
+    /// Attach the number of processes on the host.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) const fn with_process_count(mut self, count: usize) -> Self {
+        self.data.process_count = Some(count);
+        self
+    }
+
+    /// Attach the temperature sensors' readings.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn with_temperatures(mut self, temperatures: Arc<[Temperature]>) -> Self {
+        self.data.temperatures = Some(temperatures);
+        self
+    }
+
+    /// The watched process's usage. See [`CpuData::process`].
+    pub const fn process(&self) -> Option<&ProcessUsage> {
+        self.data.process()
+    }
+
+    /// The host's memory usage. See [`CpuData::memory`].
+    pub const fn memory(&self) -> Option<&MemoryUsage> {
+        self.data.memory()
+    }
+
+    /// The number of processes on the host. See [`CpuData::process_count`].
+    pub const fn process_count(&self) -> Option<usize> {
+        self.data.process_count()
+    }
+
+    /// The temperature sensors' readings. See [`CpuData::temperatures`].
+    pub fn temperatures(&self) -> Option<&[Temperature]> {
+        self.data.temperatures()
+    }
+}
+
+impl<T: ObservationData> Observation<T> {
+    /// Create a new Observation of any [`ObservationData`], and the span
+    /// associated with it. Like [`Observation::new`], it records no metrics.
+    pub fn from_data(data: T, span: tracing::Span) -> Self {
+        Self {
+            data,
+            span,
+            taken_at: Instant::now(),
+            host: None,
+            sequence: None,
+            gap_before: None,
+            guard: None,
+        }
+    }
+
+    /// Count the observation on the `my_cute_app.observations_live` gauge
+    /// until it's dropped, by holding `guard`. Replaces any guard it already
+    /// held, which uncounts it once, so it's never counted twice.
+    pub fn with_guard(mut self, guard: ObservationGuard) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// Whether the observation is counted on the
+    /// `my_cute_app.observations_live` gauge. See [`Observation::with_guard`].
+    pub const fn is_live(&self) -> bool {
+        self.guard.is_some()
+    }
+
+    /// Tag the observation with the name of the host it was taken on. The
+    /// [`TcpSource`] and [`UdpSource`] tag every observation they receive,
+    /// so that the [`Collector`] can tell hosts apart.
+    ///
+    /// [`TcpSource`]: crate::TcpSource
+    /// [`UdpSource`]: crate::UdpSource
+    /// [`Collector`]: crate::Collector
+    pub fn with_host(mut self, host: impl Into<Arc<str>>) -> Self {
+        self.host = Some(host.into());
+        self
     }
 
     /// Run a function within the scope of this observation's span.
     pub fn in_scope<F, R>(&self, f: F) -> R
     where
-        F: FnOnce(&[CpuStats]) -> R,
+        F: FnOnce(&T) -> R,
     {
-        self.span().in_scope(|| f(&self.cpus))
+        self.span().in_scope(|| f(&self.data))
+    }
+
+    /// Get the observation's data.
+    pub const fn data(&self) -> &T {
+        &self.data
+    }
+
+    /// Get mutable access to the observation's data.
+    pub const fn data_mut(&mut self) -> &mut T {
+        &mut self.data
     }
 
     /// Get the tracing span associated with this observation
     pub fn span(&self) -> &tracing::Span {
         &self.span
     }
+
+    /// Get the time at which this observation was created.
+    pub const fn taken_at(&self) -> Instant {
+        self.taken_at
+    }
+
+    /// Get the name of the host the observation was taken on, if it was
+    /// tagged with one. Observations taken locally are not.
+    pub const fn host(&self) -> Option<&Arc<str>> {
+        self.host.as_ref()
+    }
+
+    /// Mark the observation as the first after a gap of `gap`.
+    #[cfg(any(test, feature = "sysinfo"))]
+    pub(crate) const fn with_gap_before(mut self, gap: Duration) -> Self {
+        self.gap_before = Some(gap);
+        self
+    }
+
+    /// How much longer than usual the wait for this observation was, if the
+    /// [`SysMonitor`] noticed a gap before it: the host was suspended, or the
+    /// monitor stalled. The previous observation describes a different
+    /// moment, and averaging across the two blends them into something that
+    /// never happened. The [`SysStats`] processor empties its window when it
+    /// sees one, and so should anything else that keeps a window.
+    ///
+    /// [`SysMonitor`]: crate::SysMonitor
+    /// [`SysStats`]: crate::SysStats
+    pub const fn gap_before(&self) -> Option<Duration> {
+        self.gap_before
+    }
+
+    /// Attach the sender's sequence number of a received observation, and
+    /// the time the sender took it, by its clock.
+    pub(crate) const fn with_sequence(mut self, seq: u64, taken_at: SystemTime) -> Self {
+        self.sequence = Some((seq, taken_at));
+        self
+    }
+
+    /// The sender's sequence number, and the time the sender took it, if
+    /// the observation was received from a sender that numbers them.
+    pub(crate) const fn sequence(&self) -> Option<(u64, SystemTime)> {
+        self.sequence
+    }
 }
 
-impl Drop for Observation {
+impl<T: ObservationData> Drop for Observation<T> {
     fn drop(&mut self) {
         self.span().in_scope(|| {
-            trace!("Dropping observation");
+            trace!(kind = T::KIND, "Dropping observation");
         });
-        gauge!("my_cute_app.observations_live").decrement(1);
     }
 }
@@ -0,0 +1,101 @@
+//! Tracing events, exported as OTEL log records. See [`init_otel_logs`].
+
+use crate::{
+    OtlpProtocol,
+    trace::{OtlpTransport, create_otel_resource},
+};
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_otlp::{ExporterBuildError, LogExporter};
+use opentelemetry_sdk::logs::SdkLoggerProvider;
+use tracing_subscriber::{Layer, Registry, filter::EnvFilter};
+
+/// Targets whose events are never exported as logs. These crates do the
+/// exporting. Exporting their logs would produce more of their logs, and so
+/// on, forever.
+const EXPORTER_TARGETS: &[&str] = &["hyper", "h2", "tonic", "reqwest", "opentelemetry"];
+
+/// Build an OTEL logger provider that exports log records over OTLP.
+///
+/// Pass the provider to [`TracingBuilder::with_otel_logs`], and every event
+/// that passes the OTEL filter is also exported as a log record. With spans
+/// and metrics already going to the collector, that completes the set:
+/// traces, metrics, and logs, all in one place.
+///
+/// ## Why export logs?
+///
+/// Span events are exported with their span, as part of the trace. But
+/// events outside of any span, like `serving metrics` at startup, have no
+/// trace to belong to, and are only ever printed to the console. Log
+/// records stand on their own. Each one carries the trace and span IDs of
+/// the span it was emitted in, if any, so backends can still jump from a log
+/// line to its trace.
+///
+/// OTEL doesn't have its own logging API for applications. Instead, it
+/// bridges existing ones. The bridge for `tracing` is a [`Layer`], from the
+/// `opentelemetry-appender-tracing` crate, that turns each event into a log
+/// record: the level becomes the severity, the message becomes the body,
+/// and the other fields become attributes.
+///
+/// ## Usage
+///
+/// The exporter is configured with the standard env vars, like the span
+/// exporter, including the protocol. See [`OtlpProtocol`]. Shut the
+/// provider down before exiting, to flush the last batch.
+///
+/// ```no_run
+/// use metrics_tracing_example::{TracingBuilder, init_otel_logs};
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let logger_provider = init_otel_logs()?;
+/// let provider = TracingBuilder::new()
+///     .with_otel_logs(&logger_provider)
+///     .init();
+/// // ... run the pipeline ...
+/// logger_provider.shutdown()?;
+/// provider.shutdown()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## Errors
+///
+/// If the exporter can't be built, which means it's misconfigured. Building
+/// it doesn't connect to anything, so there's nothing to retry. This is
+/// usually called before tracing is initialized, so it returns the error
+/// instead of logging it.
+///
+/// [`TracingBuilder::with_otel_logs`]: crate::TracingBuilder::with_otel_logs
+/// [`Layer`]: tracing_subscriber::Layer
+pub fn init_otel_logs() -> Result<SdkLoggerProvider, ExporterBuildError> {
+    let protocol = OtlpProtocol::from_env();
+    let transport = OtlpTransport::default().or_env()?;
+    let exporter = build_log_exporter(protocol, &transport)?;
+
+    Ok(SdkLoggerProvider::builder()
+        .with_resource(create_otel_resource())
+        .with_batch_exporter(exporter)
+        .build())
+}
+
+/// Build an OTLP log exporter for `protocol`, connecting with `transport`.
+fn build_log_exporter(
+    protocol: OtlpProtocol,
+    transport: &OtlpTransport,
+) -> Result<LogExporter, ExporterBuildError> {
+    match protocol {
+        OtlpProtocol::HttpProtobuf => transport.http(LogExporter::builder().with_http())?.build(),
+        OtlpProtocol::Grpc => transport.grpc(LogExporter::builder().with_tonic())?.build(),
+    }
+}
+
+/// The bridge [`Layer`] that exports events to `provider`, filtered by
+/// `filter`, minus the [`EXPORTER_TARGETS`].
+pub(crate) fn bridge_layer(
+    provider: &SdkLoggerProvider,
+    filter: EnvFilter,
+) -> impl Layer<Registry> + Send + Sync + use<> {
+    let filter = EXPORTER_TARGETS.iter().fold(filter, |filter, target| {
+        filter.add_directive(format!("{target}=off").parse().expect("valid directive"))
+    });
+    OpenTelemetryTracingBridge::new(provider).with_filter(filter)
+}
@@ -0,0 +1,156 @@
+//! The same observation metrics, recorded with the OTEL metrics SDK. See
+//! [`init_otel_metrics`].
+
+use crate::{
+    CpuStats, CpuTimes, OtlpProtocol,
+    metrics::{
+        CPU_FREQUENCY_HISTOGRAM, CPU_FREQUENCY_HISTOGRAM_DESC, CPU_NAMES, CPU_TIME_HISTOGRAM,
+        CPU_TIME_HISTOGRAM_DESC, CPU_USAGE_HISTOGRAM, CPU_USAGE_HISTOGRAM_DESC, OBSERVATIONS_MADE,
+        OBSERVATIONS_MADE_DESC, OVERFLOW_LABEL,
+    },
+    trace::{OtlpTransport, create_otel_resource},
+};
+use opentelemetry::{
+    KeyValue, Value, global,
+    metrics::{Counter, Histogram, MeterProvider},
+};
+use opentelemetry_otlp::{ExporterBuildError, MetricExporter};
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use std::sync::OnceLock;
+
+/// The instruments, created by [`init_otel_metrics`].
+#[derive(Debug)]
+struct Instruments {
+    made: Counter<u64>,
+    usage: Histogram<f64>,
+    frequency: Histogram<u64>,
+    times: Histogram<f64>,
+}
+
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+/// Initialize the OTEL metrics SDK, exporting over OTLP, and return the
+/// meter provider.
+///
+/// With the `otel-metrics` feature, the observation metrics are recorded
+/// twice: once with the [`metrics`] crate, for the Prometheus endpoint set up
+/// by [`init_metrics`], and once with the OTEL instruments created here. The
+/// same names, on the same data, so you can compare the two side by side.
+///
+/// ## Two ecosystems
+///
+/// The [`metrics`] crate is a _facade_, like `log`. Libraries record with
+/// macros like `histogram!`, which look the metric up by name in whatever
+/// global recorder the binary installed. Metadata is attached separately,
+/// with `describe_histogram!`. The Prometheus exporter is _pulled_: it keeps
+/// the aggregated values in memory, and a Prometheus server scrapes them.
+///
+/// The OTEL SDK is object-based. A [`MeterProvider`] hands out a [`Meter`],
+/// which creates typed _instruments_, with their description and unit, up
+/// front. Recording on an instrument takes a slice of attributes (OTEL's
+/// word for labels). Metrics are _pushed_: a periodic reader collects them,
+/// every 60 seconds by default, or `OTEL_METRIC_EXPORT_INTERVAL`
+/// milliseconds, and sends them to the same OTLP collector as the spans.
+///
+/// Neither is better in general. Prometheus is everywhere, and pulling
+/// suits long-lived servers. Pushing suits short-lived jobs, and keeps all
+/// of your telemetry going to one place.
+///
+/// ## Usage
+///
+/// The exporter is configured with the standard env vars, like the span
+/// exporter, including the protocol. See [`OtlpProtocol`]. Call this before
+/// starting the pipeline. Observations recorded before it are not recorded
+/// here. Shut the provider down before exiting, to flush the last
+/// collection.
+///
+/// ```no_run
+/// # async fn _main() -> eyre::Result<()> {
+/// let meter_provider = metrics_tracing_example::init_otel_metrics();
+/// // ... run the pipeline ...
+/// meter_provider.shutdown()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`init_metrics`]: crate::init_metrics
+/// [`Meter`]: opentelemetry::metrics::Meter
+pub fn init_otel_metrics() -> SdkMeterProvider {
+    let protocol = OtlpProtocol::from_env();
+    let exporter = OtlpTransport::default()
+        .or_env()
+        .and_then(|transport| build_metric_exporter(protocol, &transport));
+
+    let builder = SdkMeterProvider::builder().with_resource(create_otel_resource());
+    let provider = match exporter {
+        Ok(exporter) => builder.with_periodic_exporter(exporter).build(),
+        Err(error) => {
+            tracing::error!(%error, "failed to build OTLP metric exporter, metrics will not be exported");
+            builder.build()
+        }
+    };
+    global::set_meter_provider(provider.clone());
+
+    let meter = provider.meter(env!("CARGO_PKG_NAME"));
+    let _ = INSTRUMENTS.set(Instruments {
+        made: meter
+            .u64_counter(OBSERVATIONS_MADE)
+            .with_description(OBSERVATIONS_MADE_DESC)
+            .build(),
+        usage: meter
+            .f64_histogram(CPU_USAGE_HISTOGRAM)
+            .with_description(CPU_USAGE_HISTOGRAM_DESC)
+            .with_unit("%")
+            .build(),
+        frequency: meter
+            .u64_histogram(CPU_FREQUENCY_HISTOGRAM)
+            .with_description(CPU_FREQUENCY_HISTOGRAM_DESC)
+            .with_unit("MHz")
+            .build(),
+        times: meter
+            .f64_histogram(CPU_TIME_HISTOGRAM)
+            .with_description(CPU_TIME_HISTOGRAM_DESC)
+            .with_unit("1")
+            .build(),
+    });
+
+    provider
+}
+
+/// Build an OTLP metric exporter for `protocol`, connecting with `transport`.
+fn build_metric_exporter(
+    protocol: OtlpProtocol,
+    transport: &OtlpTransport,
+) -> Result<MetricExporter, ExporterBuildError> {
+    match protocol {
+        OtlpProtocol::HttpProtobuf => transport
+            .http(MetricExporter::builder().with_http())?
+            .build(),
+        OtlpProtocol::Grpc => transport
+            .grpc(MetricExporter::builder().with_tonic())?
+            .build(),
+    }
+}
+
+/// Record an observation on the OTEL instruments, if they have been created.
+pub(crate) fn record_observation(obs: &[CpuStats]) {
+    let Some(instruments) = INSTRUMENTS.get() else {
+        return;
+    };
+
+    instruments.made.add(1, &[]);
+    for cpu in obs.iter() {
+        let name = if CPU_NAMES.is_admitted(&cpu.name) {
+            Value::from(cpu.name.clone())
+        } else {
+            Value::from(OVERFLOW_LABEL)
+        };
+        let attributes = [KeyValue::new("name", name)];
+        instruments.usage.record(cpu.usage as f64, &attributes);
+        instruments.frequency.record(cpu.frequency, &attributes);
+        for (mode, share) in cpu.times.iter().flat_map(CpuTimes::modes) {
+            let attributes = [attributes[0].clone(), KeyValue::new("mode", mode)];
+            instruments.times.record(share as f64, &attributes);
+        }
+    }
+}
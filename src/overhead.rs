@@ -0,0 +1,346 @@
+//! What the monitoring itself costs. See [`Overhead`].
+
+#[cfg(feature = "sysinfo")]
+use crate::{
+    CpuStats, Observation, RefreshSpec,
+    cpu_times::CpuTimesReader,
+    fields::{self, OBSERVATION_ID},
+    monitor::{collect_cpus, fill_cpus, intern_names, read_packages},
+};
+#[cfg(feature = "sysinfo")]
+use serde::Serialize;
+use std::{
+    alloc::{GlobalAlloc, Layout, System as SystemAlloc},
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+#[cfg(feature = "sysinfo")]
+use std::{hint::black_box, ops::Add, sync::Arc, time::Instant};
+#[cfg(feature = "sysinfo")]
+use sysinfo::System;
+#[cfg(feature = "sysinfo")]
+use tracing::{info, info_span, instrument};
+
+/// The number of heap allocations made by the whole process.
+static TOTAL_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The number of heap allocations made by the current thread.
+    static THREAD_ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A global allocator that counts allocations, and otherwise defers to the
+/// system allocator.
+///
+/// Allocations are counted for the whole process, and for each thread, so
+/// that a measurement on one thread isn't thrown off by whatever the others
+/// are doing. Counting is a relaxed atomic increment and a thread-local one,
+/// which is cheap enough to leave on in production.
+///
+/// A library can't choose the allocator, so install it in your binary.
+/// Without it, [`Overhead`] measures time, but not allocations.
+///
+/// ```
+/// use metrics_tracing_example::CountingAllocator;
+///
+/// #[global_allocator]
+/// static GLOBAL: CountingAllocator = CountingAllocator;
+///
+/// fn main() {
+///     assert!(CountingAllocator::is_installed());
+///     let before = CountingAllocator::thread();
+///     let boxed = std::hint::black_box(Box::new(42));
+///     assert_eq!(CountingAllocator::thread() - before, 1);
+///     # drop(boxed);
+/// }
+/// ```
+///
+/// [`Overhead`]: crate::Overhead
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    /// The number of allocations made by the process so far.
+    pub fn total() -> u64 {
+        TOTAL_ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    /// The number of allocations made by the current thread so far.
+    pub fn thread() -> u64 {
+        THREAD_ALLOCATIONS.try_with(Cell::get).unwrap_or(0)
+    }
+
+    /// Whether this is the global allocator. Checked by allocating, and
+    /// seeing if it was counted.
+    pub fn is_installed() -> bool {
+        let before = Self::thread();
+        drop(std::hint::black_box(Box::new(0u8)));
+        Self::thread() != before
+    }
+
+    fn count() {
+        TOTAL_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        // The thread-local is gone while the thread is being torn down.
+        let _ = THREAD_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+    }
+}
+
+// SAFETY: all allocation is delegated to the system allocator.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Self::count();
+        // SAFETY: upheld by the caller.
+        unsafe { SystemAlloc.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: upheld by the caller.
+        unsafe { SystemAlloc.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        Self::count();
+        // SAFETY: upheld by the caller.
+        unsafe { SystemAlloc.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// The default number of times [`Overhead`] repeats each operation.
+#[cfg(feature = "sysinfo")]
+pub const DEFAULT_OVERHEAD_ITERATIONS: u32 = 100;
+
+/// What one operation costs, on average.
+#[cfg(feature = "sysinfo")]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Cost {
+    /// The wall-clock time, in nanoseconds.
+    pub nanos: f64,
+    /// The number of heap allocations, or `None` if the
+    /// [`CountingAllocator`] isn't installed.
+    pub allocations: Option<f64>,
+}
+
+#[cfg(feature = "sysinfo")]
+impl Add for Cost {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            nanos: self.nanos + other.nanos,
+            allocations: self.allocations.zip(other.allocations).map(|(a, b)| a + b),
+        }
+    }
+}
+
+/// The result of an [`Overhead`] measurement.
+#[cfg(feature = "sysinfo")]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct OverheadReport {
+    /// How many times each operation was repeated.
+    pub iterations: u32,
+    /// The number of CPUs in each observation. The cost of taking and
+    /// recording an observation grows with it.
+    pub cpus: usize,
+    /// Refreshing the CPUs, and building an [`Observation`] from them, like
+    /// the [`SysMonitor`] does on each tick.
+    ///
+    /// [`SysMonitor`]: crate::SysMonitor
+    pub observation: Cost,
+    /// Recording an observation's metrics. `None` without the `prometheus`
+    /// feature, as there's no recorder to measure.
+    pub metrics: Option<Cost>,
+    /// Creating, entering, and closing an observation's span, with whatever
+    /// tracing subscriber is installed.
+    pub span: Cost,
+}
+
+#[cfg(feature = "sysinfo")]
+impl OverheadReport {
+    /// The cost of each operation that was measured, labeled by op.
+    pub fn costs(&self) -> impl Iterator<Item = (&'static str, Cost)> {
+        [
+            ("observation", Some(self.observation)),
+            ("metrics", self.metrics),
+            ("span", Some(self.span)),
+        ]
+        .into_iter()
+        .filter_map(|(op, cost)| Some((op, cost?)))
+    }
+
+    /// The total cost of one observation: taking it, recording its metrics,
+    /// and its span. Multiply by the observations per second to get what the
+    /// monitoring costs you.
+    pub fn per_observation(&self) -> Cost {
+        self.costs()
+            .map(|(_, cost)| cost)
+            .reduce(Add::add)
+            .expect("the observation is always measured")
+    }
+}
+
+/// Measures what the monitoring itself costs: how long it takes, and how
+/// much it allocates, to take an observation, record its metrics, and emit
+/// its span.
+///
+/// Monitoring isn't free, and the question always comes up: what does it
+/// cost me? Rather than guess, measure it on the machine in question. Each
+/// operation is repeated, and the average reported, as an info event, and
+/// as the `my_cute_app.overhead_nanos` and `my_cute_app.overhead_allocations`
+/// gauges.
+///
+/// The measurement runs the real code, with care not to pollute the real
+/// telemetry:
+/// - Observations are taken with the default [`RefreshSpec`], reusing one
+///   buffer, like the monitor's steady state.
+/// - Metrics are recorded into a private Prometheus recorder, so the
+///   observation counters aren't inflated. OTEL metrics aren't measured.
+/// - Spans are emitted with the installed subscriber, as that's what they
+///   cost. They are real spans, named `Observation`, under a `Measuring
+///   overhead` span, and they're exported like any others.
+///
+/// Allocations are only counted with the [`CountingAllocator`] installed.
+///
+/// This blocks for as long as it measures, so call it on the blocking
+/// thread pool:
+///
+/// ```no_run
+/// use metrics_tracing_example::Overhead;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let report = tokio::task::spawn_blocking(|| Overhead::new().measure()).await?;
+/// println!("{}", serde_json::to_string_pretty(&report)?);
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "sysinfo")]
+#[derive(Debug, Clone, Copy)]
+pub struct Overhead {
+    iterations: u32,
+}
+
+#[cfg(feature = "sysinfo")]
+impl Default for Overhead {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "sysinfo")]
+impl Overhead {
+    /// Measure each operation [`DEFAULT_OVERHEAD_ITERATIONS`] times.
+    pub const fn new() -> Self {
+        Self {
+            iterations: DEFAULT_OVERHEAD_ITERATIONS,
+        }
+    }
+
+    /// Repeat each operation `iterations` times, at least once. More is
+    /// steadier, but each observation refreshes the CPUs, which takes a
+    /// while on a big machine.
+    pub const fn with_iterations(mut self, iterations: u32) -> Self {
+        self.iterations = if iterations == 0 { 1 } else { iterations };
+        self
+    }
+
+    /// Run the measurement, record it as metrics, and log it.
+    #[instrument(skip(self), fields(iterations = self.iterations), name = "Measuring overhead")]
+    pub fn measure(&self) -> OverheadReport {
+        let kind = RefreshSpec::default().refresh_kind();
+        // Creating the system refreshes it already.
+        let mut system = System::new_with_specifics(kind);
+        let mut times = CpuTimesReader::default();
+        let _ = times.refresh();
+        let names = intern_names(&system);
+        let packages = read_packages(&names);
+        let mut buffer: Arc<[CpuStats]> = collect_cpus(&system, &times, &names, &packages).into();
+
+        let observation = self.time(|| {
+            system.refresh_specifics(kind);
+            let _ = times.refresh();
+            let buf = Arc::get_mut(&mut buffer).expect("the last observation was dropped");
+            fill_cpus(buf, &system, &times, &names, &packages);
+            Observation::new(buffer.clone(), tracing::Span::none())
+        });
+
+        #[cfg(feature = "prometheus")]
+        let metrics = Some(crate::metrics::with_private_recorder(|| {
+            let mut metrics = crate::metrics::ObservationMetrics::default();
+            let span = tracing::Span::none();
+            self.time(|| metrics.record_in_recorder(&buffer, &span))
+        }));
+        #[cfg(not(feature = "prometheus"))]
+        let metrics = None;
+
+        let mut counter = 0u64;
+        let span = self.time(|| {
+            counter += 1;
+            let span = info_span!("Observation", { OBSERVATION_ID } = fields::obs_id(counter));
+            span.in_scope(|| {});
+            span
+        });
+
+        let report = OverheadReport {
+            iterations: self.iterations,
+            cpus: buffer.len(),
+            observation,
+            metrics,
+            span,
+        };
+        crate::metrics::record_overhead(&report);
+        let total = report.per_observation();
+        info!(
+            cpus = report.cpus,
+            observation_ns = report.observation.nanos,
+            metrics_ns = report.metrics.map(|cost| cost.nanos),
+            span_ns = report.span.nanos,
+            total_ns = total.nanos,
+            total_allocations = total.allocations,
+            "measured monitoring overhead"
+        );
+        report
+    }
+
+    /// Time `op`, and count its allocations, on average over the iterations.
+    /// What `op` returns is dropped each time, and counted too. One call
+    /// beforehand warms up caches, and whatever else happens only once.
+    fn time<T>(&self, mut op: impl FnMut() -> T) -> Cost {
+        let counting = CountingAllocator::is_installed();
+        black_box(op());
+
+        let allocations = CountingAllocator::thread();
+        let start = Instant::now();
+        for _ in 0..self.iterations {
+            black_box(op());
+        }
+        let nanos = start.elapsed().as_nanos() as f64;
+        let allocations = CountingAllocator::thread() - allocations;
+
+        let iterations = f64::from(self.iterations);
+        Cost {
+            nanos: nanos / iterations,
+            allocations: counting.then(|| allocations as f64 / iterations),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sysinfo"))]
+mod tests {
+    use super::*;
+
+    /// The tests run with the [`CountingAllocator`] installed, by the stats
+    /// tests, so allocations are counted too.
+    #[test]
+    fn measures_every_op() {
+        let report = Overhead::new().with_iterations(4).measure();
+
+        assert_eq!(report.iterations, 4);
+        assert!(report.cpus > 0);
+        assert!(report.observation.nanos > 0.0);
+        assert_eq!(report.metrics.is_some(), cfg!(feature = "prometheus"));
+        assert!(report.costs().all(|(_, cost)| cost.allocations.is_some()));
+
+        let total = report.per_observation();
+        let sum: f64 = report.costs().map(|(_, cost)| cost.nanos).sum();
+        assert_eq!(total.nanos, sum);
+    }
+}
@@ -0,0 +1,920 @@
+//! The [`PipelineBuilder`] wires the actors together.
+
+#[cfg(all(feature = "sysinfo", feature = "cron"))]
+use crate::CronSchedule;
+use crate::MAX_BUSIEST_CORES;
+#[cfg(feature = "sysinfo")]
+use crate::{
+    AdaptiveInterval, CpuView, DEFAULT_BASELINE_WINDOW, DEFAULT_BUSIEST_CORES,
+    DEFAULT_IMBALANCE_THRESHOLD, Dispatcher, Eviction, Health, MemoryMonitor, MemoryReport,
+    MemoryStats, MissedTicks, Observation, PipelineConfig, ProcessSelector, RefreshSpec, Rollup,
+    ShutdownCoordinator, ShutdownReport, StatsQuerier, StatsReport, SysMonitor, SysStats,
+    TracingHandle, Watchdog, baggage::baggage_context, report::PipelineCounters, set_label_limit,
+    stats::StatsCallback,
+};
+#[cfg(feature = "sysinfo")]
+use opentelemetry::KeyValue;
+#[cfg(feature = "sysinfo")]
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "sysinfo")]
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::{AbortHandle, JoinError, JoinHandle},
+};
+#[cfg(feature = "sysinfo")]
+use tokio_util::sync::CancellationToken;
+
+/// The default number of observations in the stats window.
+pub const DEFAULT_WINDOW: usize = 10;
+
+/// The largest stats window we accept. The window holds a copy of every
+/// observation in it, so an enormous window is almost certainly a typo.
+pub const MAX_WINDOW: usize = 10_000;
+
+/// The default capacity of the channel between the monitor and the stats
+/// processor.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 2;
+
+/// Errors returned by [`PipelineBuilder::spawn`] and
+/// [`Pipeline::from_config`] for invalid configuration, or for a process
+/// that can't be set up the way the configuration asks.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ConfigError {
+    /// The observation interval was zero. The monitor would spin without
+    /// sleeping between observations.
+    #[error("observation interval must be non-zero")]
+    ZeroInterval,
+
+    /// The stats window was zero, or larger than [`MAX_WINDOW`].
+    #[error("stats window must be between 1 and {MAX_WINDOW} observations, got {0}")]
+    InvalidWindow(usize),
+
+    /// The baseline window was zero.
+    #[error("baseline window must be non-zero")]
+    ZeroBaselineWindow,
+
+    /// The stats cadence was zero. Stats would never be computed.
+    #[error("stats cadence must be non-zero")]
+    ZeroEmitEvery,
+
+    /// More busiest cores were asked for than [`MAX_BUSIEST_CORES`].
+    #[error("at most {MAX_BUSIEST_CORES} busiest cores can be reported, got {0}")]
+    TooManyBusiestCores(usize),
+
+    /// The channel capacity was zero. Tokio channels must have room for at
+    /// least one message.
+    #[error("channel capacity must be non-zero")]
+    ZeroChannelCapacity,
+
+    /// The watchdog tolerance was zero. The monitor would be flagged as
+    /// stalled between every pair of observations.
+    #[error("watchdog tolerance must be non-zero")]
+    ZeroWatchdogTolerance,
+
+    /// The trace sample ratio was outside of 0 to 1.
+    #[error("sample ratio must be between 0 and 1")]
+    InvalidSampleRatio,
+
+    /// The rollup period was zero.
+    #[error("rollup period must be non-zero")]
+    ZeroRollupPeriod,
+
+    /// The adaptive interval's shortest interval was zero, or longer than
+    /// its longest.
+    #[error("adaptive interval must be non-zero, with min no longer than max")]
+    InvalidAdaptiveInterval,
+
+    /// Tracing was initialized outside of a `tokio` runtime. The OTEL
+    /// exporter needs one.
+    #[error("tracing must be initialized from within a tokio runtime")]
+    NoRuntime,
+
+    /// A global tracing subscriber was already set, by this crate or
+    /// another.
+    #[error("a global tracing subscriber has already been set")]
+    SubscriberAlreadySet,
+
+    /// The Prometheus exporter couldn't be installed: its port was taken,
+    /// or a metrics recorder was already set.
+    #[error("failed to install the Prometheus exporter: {0}")]
+    MetricsExporter(String),
+}
+
+/// Builder for the observation pipeline.
+///
+/// [`run_observations`] covers the common case. The builder exposes the
+/// optional pieces, such as [`Health`] tracking and the [`Watchdog`].
+///
+/// ```no_run
+/// use metrics_tracing_example::{Health, PipelineBuilder, serve_health};
+/// use std::time::Duration;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let every = Duration::from_secs(5);
+/// let health = Health::new(every * 3);
+///
+/// let _health_server = serve_health(health.clone(), 9001).await?;
+/// let _pipeline = PipelineBuilder::new(every)
+///     .with_window(30)
+///     .with_health(health)
+///     .spawn()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`run_observations`]: crate::run_observations
+#[cfg(feature = "sysinfo")]
+#[derive(Debug)]
+pub struct PipelineBuilder {
+    interval: Duration,
+    adaptive: Option<AdaptiveInterval>,
+    missed_ticks: MissedTicks,
+    cpu_view: CpuView,
+    refresh: RefreshSpec,
+    #[cfg(feature = "cron")]
+    cron: Option<CronSchedule>,
+    memory_interval: Option<Duration>,
+    process: Option<ProcessSelector>,
+    window: usize,
+    baseline_window: usize,
+    eviction: Eviction,
+    emit_every: usize,
+    imbalance_threshold: f64,
+    busiest_cores: usize,
+    channel_capacity: usize,
+    callbacks: Vec<StatsCallback>,
+    outbound: Option<mpsc::Sender<Observation>>,
+    health: Option<Health>,
+    watchdog: Option<Duration>,
+    baggage: Option<opentelemetry::Context>,
+    span_links: bool,
+    host: Option<Arc<str>>,
+    #[cfg(feature = "systemd")]
+    systemd: bool,
+}
+
+#[cfg(feature = "sysinfo")]
+impl PipelineBuilder {
+    /// Create a new builder that takes observations every `interval`.
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            adaptive: None,
+            missed_ticks: MissedTicks::Burst,
+            cpu_view: CpuView::Host,
+            refresh: RefreshSpec::cpu(),
+            #[cfg(feature = "cron")]
+            cron: None,
+            memory_interval: None,
+            process: None,
+            window: DEFAULT_WINDOW,
+            baseline_window: DEFAULT_BASELINE_WINDOW,
+            eviction: Eviction::Sliding,
+            emit_every: 1,
+            imbalance_threshold: DEFAULT_IMBALANCE_THRESHOLD,
+            busiest_cores: DEFAULT_BUSIEST_CORES,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            callbacks: Vec::new(),
+            outbound: None,
+            health: None,
+            watchdog: None,
+            baggage: None,
+            span_links: false,
+            host: None,
+            #[cfg(feature = "systemd")]
+            systemd: false,
+        }
+    }
+
+    /// Create a new builder from the pipeline settings in `config`: the
+    /// interval, windows, stats cadence, channel capacity, missed ticks, CPU
+    /// view, imbalance threshold, and watchdog. The tracing, metrics, and
+    /// sinks sections are not used. See
+    /// [`Pipeline::from_config`] for a pipeline with all of them.
+    pub fn from_config(config: &PipelineConfig) -> Self {
+        let mut builder = Self::new(config.interval)
+            .with_window(config.window)
+            .with_baseline_window(config.baseline_window)
+            .with_eviction(config.eviction)
+            .with_emit_every(config.emit_every)
+            .with_imbalance_threshold(config.alerting.imbalance_threshold)
+            .with_busiest_cores(config.busiest_cores)
+            .with_channel_capacity(config.channel_capacity)
+            .with_missed_ticks(config.missed_ticks)
+            .with_cpu_view(config.cpu_view);
+        if let Some(tolerance) = config.alerting.watchdog {
+            builder = builder.with_watchdog(tolerance);
+        }
+        if config.span_links {
+            builder = builder.with_span_links();
+        }
+        #[cfg(feature = "systemd")]
+        if config.alerting.systemd {
+            builder = builder.with_systemd();
+        }
+        builder
+    }
+
+    /// Compute stats over the last `window` observations. Defaults to
+    /// [`DEFAULT_WINDOW`].
+    pub const fn with_window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Compare the stats window's average usage against the last `window`
+    /// observations. Defaults to [`DEFAULT_BASELINE_WINDOW`]. See
+    /// [`SysStats::with_baseline_window`].
+    pub const fn with_baseline_window(mut self, window: usize) -> Self {
+        self.baseline_window = window;
+        self
+    }
+
+    /// What to do with the stats window once it's full. Defaults to
+    /// [`Eviction::Sliding`]. See [`SysStats::with_eviction`].
+    pub const fn with_eviction(mut self, eviction: Eviction) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    /// Compute stats once every `count` observations. Defaults to every
+    /// observation. See [`SysStats::with_emit_every`].
+    pub const fn with_emit_every(mut self, count: usize) -> Self {
+        self.emit_every = count;
+        self
+    }
+
+    /// Warn when the spread between the busiest and idlest core exceeds
+    /// `threshold` percentage points. See
+    /// [`SysStats::with_imbalance_threshold`].
+    pub const fn with_imbalance_threshold(mut self, threshold: f64) -> Self {
+        self.imbalance_threshold = threshold;
+        self
+    }
+
+    /// Report the `count` busiest cores. Defaults to
+    /// [`DEFAULT_BUSIEST_CORES`]. See [`SysStats::with_busiest_cores`].
+    pub const fn with_busiest_cores(mut self, count: usize) -> Self {
+        self.busiest_cores = count;
+        self
+    }
+
+    /// Set the capacity of the channel between the monitor and the stats
+    /// processor. Defaults to [`DEFAULT_CHANNEL_CAPACITY`].
+    pub const fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Call `callback` with every [`StatsReport`] the stats processor
+    /// computes. See [`SysStats::on_stats`].
+    pub fn on_stats(mut self, callback: impl FnMut(&StatsReport) + Send + 'static) -> Self {
+        self.callbacks.push(StatsCallback::new(callback));
+        self
+    }
+
+    /// Send observations to `outbound` after processing them.
+    pub fn with_outbound(mut self, outbound: mpsc::Sender<Observation>) -> Self {
+        self.outbound = Some(outbound);
+        self
+    }
+
+    /// Record actor heartbeats in `health`.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Run a [`Watchdog`] that flags the monitor as stalled if it goes
+    /// `tolerance` without producing an observation. If no [`Health`] was
+    /// provided, one is created internally.
+    pub const fn with_watchdog(mut self, tolerance: Duration) -> Self {
+        self.watchdog = Some(tolerance);
+        self
+    }
+
+    /// Attach `entries` as OTEL baggage to every observation's span. See
+    /// [`baggage_context`] for what that means.
+    ///
+    /// [`baggage_context`]: crate::baggage::baggage_context
+    pub fn with_baggage(mut self, entries: impl IntoIterator<Item = KeyValue>) -> Self {
+        self.baggage = Some(baggage_context(entries));
+        self
+    }
+
+    /// Link each observation's span to the previous one's, so that trace
+    /// viewers can show the chain of samples. See
+    /// [`SysMonitor::with_span_links`].
+    pub const fn with_span_links(mut self) -> Self {
+        self.span_links = true;
+        self
+    }
+
+    /// Sample faster when the machine is busy, and slower when it's idle,
+    /// starting from the builder's interval. See
+    /// [`SysMonitor::with_adaptive_interval`].
+    pub const fn with_adaptive_interval(mut self, adaptive: AdaptiveInterval) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Catch up on missed ticks as `missed` says. Defaults to
+    /// [`MissedTicks::Burst`]. See [`SysMonitor::with_missed_ticks`].
+    pub const fn with_missed_ticks(mut self, missed: MissedTicks) -> Self {
+        self.missed_ticks = missed;
+        self
+    }
+
+    /// Report CPU usage as `view` says. Defaults to [`CpuView::Host`]. See
+    /// [`SysMonitor::with_cpu_view`].
+    pub const fn with_cpu_view(mut self, view: CpuView) -> Self {
+        self.cpu_view = view;
+        self
+    }
+
+    /// Refresh the subsystems in `spec` on each observation. Defaults to
+    /// [`RefreshSpec::cpu`]. See [`SysMonitor::with_refresh`].
+    pub const fn with_refresh(mut self, spec: RefreshSpec) -> Self {
+        self.refresh = spec;
+        self
+    }
+
+    /// Take observations when `schedule` fires, instead of every interval.
+    /// See [`SysMonitor::with_cron`].
+    #[cfg(feature = "cron")]
+    pub fn with_cron(mut self, schedule: CronSchedule) -> Self {
+        self.cron = Some(schedule);
+        self
+    }
+
+    /// Also read the host's memory every `interval`, with a
+    /// [`MemoryMonitor`]. Both monitors feed one [`Dispatcher`], which sends
+    /// CPU observations to the stats processor, and memory observations to
+    /// a [`MemoryStats`] over the same window. See
+    /// [`PipelineHandle::subscribe_memory`].
+    pub const fn with_memory_interval(mut self, interval: Duration) -> Self {
+        self.memory_interval = Some(interval);
+        self
+    }
+
+    /// Also watch the process picked out by `selector`. See
+    /// [`SysMonitor::for_process`].
+    pub fn with_process(mut self, selector: ProcessSelector) -> Self {
+        self.process = Some(selector);
+        self
+    }
+
+    /// Label the stats processor's gauges and events with `host`. See
+    /// [`SysStats::with_host`].
+    pub fn with_host(mut self, host: impl Into<Arc<str>>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Notify systemd of readiness, and ping its watchdog after every
+    /// observation. See [`SysMonitor::with_systemd`].
+    #[cfg(feature = "systemd")]
+    pub const fn with_systemd(mut self) -> Self {
+        self.systemd = true;
+        self
+    }
+
+    /// Check the configuration, returning the first problem found.
+    pub const fn validate(&self) -> Result<(), ConfigError> {
+        if self.interval.is_zero() {
+            return Err(ConfigError::ZeroInterval);
+        }
+        if let Some(interval) = self.memory_interval
+            && interval.is_zero()
+        {
+            return Err(ConfigError::ZeroInterval);
+        }
+        if let Some(adaptive) = &self.adaptive
+            && !adaptive.is_valid()
+        {
+            return Err(ConfigError::InvalidAdaptiveInterval);
+        }
+        if self.window == 0 || self.window > MAX_WINDOW {
+            return Err(ConfigError::InvalidWindow(self.window));
+        }
+        if self.baseline_window == 0 {
+            return Err(ConfigError::ZeroBaselineWindow);
+        }
+        if self.emit_every == 0 {
+            return Err(ConfigError::ZeroEmitEvery);
+        }
+        if self.busiest_cores > MAX_BUSIEST_CORES {
+            return Err(ConfigError::TooManyBusiestCores(self.busiest_cores));
+        }
+        if self.channel_capacity == 0 {
+            return Err(ConfigError::ZeroChannelCapacity);
+        }
+        if let Some(tolerance) = self.watchdog
+            && tolerance.is_zero()
+        {
+            return Err(ConfigError::ZeroWatchdogTolerance);
+        }
+        Ok(())
+    }
+
+    /// Validate the configuration, then spawn the monitor and stats actors.
+    /// Returns a [`PipelineHandle`] that will resolve if either task panics
+    /// or exits.
+    pub fn spawn(self) -> Result<PipelineHandle, ConfigError> {
+        self.validate()?;
+
+        let (tx, rx) = mpsc::channel(self.channel_capacity);
+
+        let shutdown = CancellationToken::new();
+        let counters = Arc::new(PipelineCounters::default());
+        let started = Instant::now();
+
+        // With a memory monitor, both monitors send to a dispatcher, which
+        // sends CPU observations on to the stats processor.
+        let mut memory = None;
+        let tx = match self.memory_interval {
+            Some(interval) => {
+                let (memory_tx, memory_rx) = mpsc::channel(self.channel_capacity);
+                let dispatcher = Dispatcher::new(self.channel_capacity)
+                    .with_cpu_route(tx)
+                    .with_memory_route(memory_tx);
+                let monitor = MemoryMonitor::new(interval, dispatcher.source())
+                    .with_shutdown(shutdown.clone());
+                let stats = MemoryStats::new(memory_rx).with_window(self.window);
+                let tx = dispatcher.source();
+                memory = Some((monitor, stats, dispatcher));
+                tx
+            }
+            None => tx,
+        };
+
+        let mut monitor =
+            SysMonitor::new_with_specifics(self.refresh.refresh_kind(), self.interval, tx)
+                .with_refresh(self.refresh)
+                .with_shutdown(shutdown.clone())
+                .with_missed_ticks(self.missed_ticks)
+                .with_cpu_view(self.cpu_view)
+                .with_counters(counters.clone());
+        let mut stats = SysStats::new(rx, self.outbound)
+            .with_window(self.window)
+            .with_baseline_window(self.baseline_window)
+            .with_eviction(self.eviction)
+            .with_emit_every(self.emit_every)
+            .with_imbalance_threshold(self.imbalance_threshold)
+            .with_busiest_cores(self.busiest_cores)
+            .with_callbacks(self.callbacks)
+            .with_counters(counters.clone());
+        if let Some(host) = self.host {
+            stats = stats.with_host(host);
+        }
+
+        let health = match (self.health, self.watchdog) {
+            (Some(health), _) => Some(health),
+            (None, Some(tolerance)) => Some(Health::new(tolerance)),
+            (None, None) => None,
+        };
+
+        // The tasks the supervising task spawns, to abort along with it.
+        let mut inner = Vec::new();
+
+        let watchdog_handle = self
+            .watchdog
+            .zip(health.clone())
+            .map(|(tolerance, health)| Watchdog::new(health, tolerance).spawn());
+
+        if let Some(baggage) = self.baggage {
+            monitor = monitor.with_baggage(baggage);
+        }
+        if self.span_links {
+            monitor = monitor.with_span_links();
+        }
+        if let Some(adaptive) = self.adaptive {
+            monitor = monitor.with_adaptive_interval(adaptive);
+        }
+        #[cfg(feature = "cron")]
+        if let Some(schedule) = self.cron {
+            monitor = monitor.with_cron(schedule);
+        }
+        if let Some(selector) = self.process {
+            monitor = monitor.for_process(selector);
+        }
+        #[cfg(feature = "systemd")]
+        if self.systemd {
+            monitor = monitor.with_systemd();
+        }
+
+        if let Some(health) = health {
+            monitor = monitor.with_health(health.clone());
+            stats = stats.with_health(health);
+        }
+
+        let stats_reports = stats.subscribe();
+        let stats_querier = stats.querier();
+
+        let memory_reports = memory.as_ref().map(|(_, stats, _)| stats.subscribe());
+        let memory_handles = memory.map(|(monitor, stats, dispatcher)| {
+            inner.push(monitor.spawn().abort_handle());
+            (stats.spawn(), dispatcher.spawn())
+        });
+        if let Some((memory_stats, dispatcher)) = &memory_handles {
+            inner.push(memory_stats.abort_handle());
+            inner.push(dispatcher.abort_handle());
+        }
+        if let Some(watchdog_handle) = &watchdog_handle {
+            inner.push(watchdog_handle.abort_handle());
+        }
+
+        let mut monitor_handle = monitor.spawn();
+        let mut stats_handle = stats.spawn();
+        inner.push(monitor_handle.abort_handle());
+        inner.push(stats_handle.abort_handle());
+
+        let memory_shutdown = shutdown.clone();
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                _ = &mut monitor_handle => {
+                    // The monitor has dropped its sender. Give the stats
+                    // processor a chance to drain the channel.
+                    tracing::debug!("Monitor task exited, draining stats");
+                    memory_shutdown.cancel();
+                    let _ = stats_handle.await;
+                }
+                _ = &mut stats_handle => {
+                    tracing::debug!("Stats task exited");
+                    memory_shutdown.cancel();
+                    monitor_handle.abort();
+                }
+            }
+
+            // Once both monitors have stopped, the dispatcher closes its
+            // routes, and the memory stats drain.
+            if let Some((memory_stats, dispatcher)) = memory_handles {
+                let _ = dispatcher.await;
+                let _ = memory_stats.await;
+            }
+
+            if let Some(watchdog_handle) = watchdog_handle {
+                watchdog_handle.abort();
+            }
+
+            let report = counters.report(started.elapsed());
+            report.emit();
+            report
+        });
+
+        Ok(PipelineHandle {
+            shutdown,
+            stats_reports,
+            stats_querier,
+            memory_reports,
+            task,
+            inner,
+            consumer: None,
+        })
+    }
+}
+
+/// A handle to a running pipeline, returned by [`PipelineBuilder::spawn`].
+///
+/// Like a [`JoinHandle`], this is a future that resolves when the pipeline
+/// exits, with a [`ShutdownReport`]. Unlike a [`JoinHandle`], it can also
+/// ask the pipeline to shut down gracefully. See [`PipelineHandle::shutdown`].
+#[cfg(feature = "sysinfo")]
+#[derive(Debug)]
+pub struct PipelineHandle {
+    shutdown: CancellationToken,
+    stats_reports: watch::Receiver<StatsReport>,
+    stats_querier: StatsQuerier,
+    memory_reports: Option<watch::Receiver<MemoryReport>>,
+    task: JoinHandle<ShutdownReport>,
+    /// The monitor, stats, and other tasks that `task` supervises. Aborting
+    /// `task` doesn't abort them, so [`PipelineHandle::abort`] does.
+    inner: Vec<AbortHandle>,
+    /// Whatever consumes the pipeline's outbound channel, if the pipeline
+    /// started it. See [`run_observations_with`].
+    ///
+    /// [`run_observations_with`]: crate::run_observations_with
+    consumer: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "sysinfo")]
+impl PipelineHandle {
+    /// Subscribe to the [`StatsReport`]s computed by the stats processor.
+    /// See [`SysStats::subscribe`].
+    pub fn subscribe_stats(&self) -> watch::Receiver<StatsReport> {
+        self.stats_reports.clone()
+    }
+
+    /// Get a [`StatsQuerier`], to ask the stats processor for the current
+    /// state of its window. See [`SysStats::querier`].
+    pub fn stats_querier(&self) -> StatsQuerier {
+        self.stats_querier.clone()
+    }
+
+    /// Subscribe to the [`MemoryReport`]s computed by the memory stats
+    /// processor. `None` unless the pipeline was built
+    /// [`with_memory_interval`].
+    ///
+    /// [`with_memory_interval`]: PipelineBuilder::with_memory_interval
+    pub fn subscribe_memory(&self) -> Option<watch::Receiver<MemoryReport>> {
+        self.memory_reports.clone()
+    }
+
+    /// Wait for `consumer` too, on shutdown.
+    pub(crate) fn with_consumer(mut self, consumer: JoinHandle<()>) -> Self {
+        self.consumer = Some(consumer);
+        self
+    }
+
+    /// Ask the pipeline to shut down, without waiting for it to finish.
+    pub fn trigger_shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Shut down the pipeline gracefully, and wait for it to finish.
+    ///
+    /// The monitor stops taking observations, and closes its channel. The
+    /// stats processor then drains any observations already in the channel,
+    /// computes stats one final time, and forwards everything to the outbound
+    /// channel, if any. Nothing that was observed is dropped on the floor.
+    ///
+    /// Compare this with aborting the task, or simply returning from `main`,
+    /// which drops in-flight observations (and their spans!) mid-processing.
+    ///
+    /// Returns a [`ShutdownReport`] summarizing the run. The pipeline, and
+    /// then its consumer, if any, are stopped by a [`ShutdownCoordinator`],
+    /// so each gets [`DEFAULT_STAGE_TIMEOUT`] to stop before it's aborted.
+    ///
+    /// [`ShutdownCoordinator`]: crate::ShutdownCoordinator
+    /// [`DEFAULT_STAGE_TIMEOUT`]: crate::DEFAULT_STAGE_TIMEOUT
+    pub async fn shutdown(self) -> Result<ShutdownReport, JoinError> {
+        let (report_tx, report_rx) = oneshot::channel();
+        self.into_coordinator(report_tx).shutdown().await?;
+        Ok(report_rx
+            .await
+            .expect("sent before the pipeline stage stopped"))
+    }
+
+    /// A coordinator that stops the pipeline, then its consumer, and sends
+    /// the [`ShutdownReport`] to `report`.
+    fn into_coordinator(self, report: oneshot::Sender<ShutdownReport>) -> ShutdownCoordinator {
+        let coordinator = ShutdownCoordinator::new(self.shutdown).with_stats_then(
+            self.task,
+            self.inner,
+            move |summary| {
+                let _ = report.send(summary);
+            },
+        );
+        match self.consumer {
+            Some(consumer) => coordinator.with_sink("consumer", consumer),
+            None => coordinator,
+        }
+    }
+
+    /// Abort the pipeline immediately. In-flight observations are dropped.
+    pub fn abort(&self) {
+        self.shutdown.cancel();
+        self.task.abort();
+        for task in &self.inner {
+            task.abort();
+        }
+        if let Some(consumer) = &self.consumer {
+            consumer.abort();
+        }
+    }
+}
+
+#[cfg(feature = "sysinfo")]
+impl Future for PipelineHandle {
+    type Output = Result<ShutdownReport, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx)
+    }
+}
+
+/// The whole pipeline, started from a [`PipelineConfig`]: tracing, metrics,
+/// the monitor and stats actors, and any sinks.
+///
+/// This is everything `sysmon` does, minus the command line. An embedding
+/// application that is happy with the defaults can start it all in one call,
+/// and configure it from its own config file.
+///
+/// ```no_run
+/// use metrics_tracing_example::{Pipeline, PipelineConfig};
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let config: PipelineConfig = serde_json::from_str(&std::fs::read_to_string("sysmon.json")?)?;
+/// let pipeline = Pipeline::from_config(config)?;
+///
+/// tokio::signal::ctrl_c().await?;
+/// let report = pipeline.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "sysinfo")]
+#[derive(Debug)]
+pub struct Pipeline {
+    handle: PipelineHandle,
+    rollup: Option<JoinHandle<()>>,
+    emf: Option<JoinHandle<std::io::Result<()>>>,
+    tracing: TracingHandle,
+}
+
+#[cfg(feature = "sysinfo")]
+impl Pipeline {
+    /// Validate `config`, then initialize tracing and metrics, and spawn the
+    /// actors. Call this once, early in `main`.
+    ///
+    /// Without the `prometheus` feature, a metrics port in the config is
+    /// ignored, with a warning.
+    ///
+    /// ## Errors
+    ///
+    /// If `config` is invalid. And like [`TracingBuilder::try_init`] and
+    /// [`try_init_metrics`]: if called outside of a `tokio` runtime, if a
+    /// global subscriber or metrics recorder has already been set, or if
+    /// the metrics port can't be bound.
+    ///
+    /// [`TracingBuilder::try_init`]: crate::TracingBuilder::try_init
+    /// [`try_init_metrics`]: crate::try_init_metrics
+    pub fn from_config(config: PipelineConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+
+        let tracing = config.tracing.builder().with_panic_hook().try_init()?;
+
+        set_label_limit(config.metrics.max_label_values);
+        if let Some(port) = config.metrics.port {
+            #[cfg(feature = "prometheus")]
+            crate::try_init_metrics(Some(port))
+                .map_err(|error| ConfigError::MetricsExporter(error.to_string()))?;
+            #[cfg(not(feature = "prometheus"))]
+            tracing::warn!(
+                port,
+                "built without the prometheus feature, not serving metrics"
+            );
+        }
+
+        let mut builder = PipelineBuilder::from_config(&config);
+        let mut rollup = None;
+        if let Some(period) = config.sinks.rollup {
+            let (tx, rx) = mpsc::channel(config.channel_capacity);
+            builder = builder.with_outbound(tx);
+            rollup = Some(Rollup::new(rx, None).with_period(period).spawn());
+        }
+
+        let handle = builder.spawn()?;
+        let emf = config
+            .sinks
+            .emf
+            .as_ref()
+            .map(|emf| emf.sink(handle.subscribe_stats()).spawn());
+
+        Ok(Self {
+            handle,
+            rollup,
+            emf,
+            tracing,
+        })
+    }
+
+    /// The running pipeline, e.g. to subscribe to its stats.
+    pub const fn handle(&self) -> &PipelineHandle {
+        &self.handle
+    }
+
+    /// The tracing provider, e.g. to flush spans at a checkpoint.
+    pub const fn tracing(&self) -> &TracingHandle {
+        &self.tracing
+    }
+
+    /// Shut the pipeline down gracefully, wait for the sinks to finish, then
+    /// export the remaining spans, with a [`ShutdownCoordinator`]. See
+    /// [`PipelineHandle::shutdown`].
+    ///
+    /// A failure to export the last spans is logged, not returned. Usually
+    /// the collector is unreachable, and there is nothing left to do about
+    /// it.
+    ///
+    /// [`ShutdownCoordinator`]: crate::ShutdownCoordinator
+    pub async fn shutdown(self) -> Result<ShutdownReport, JoinError> {
+        let (report_tx, report_rx) = oneshot::channel();
+        let mut coordinator = self.handle.into_coordinator(report_tx);
+        if let Some(rollup) = self.rollup {
+            coordinator = coordinator.with_sink("rollup", rollup);
+        }
+        if let Some(emf) = self.emf {
+            coordinator = coordinator.with_sink_then("emf", emf, |result| {
+                if let Err(error) = result {
+                    tracing::warn!(%error, "EMF sink failed");
+                }
+            });
+        }
+        coordinator.with_tracing(self.tracing).shutdown().await?;
+        Ok(report_rx
+            .await
+            .expect("sent before the pipeline stage stopped"))
+    }
+}
+
+#[cfg(all(test, feature = "sysinfo"))]
+mod tests {
+    use super::*;
+
+    const EVERY: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(PipelineBuilder::new(EVERY).validate(), Ok(()));
+    }
+
+    #[test]
+    fn zero_intervals_are_rejected() {
+        let builder = PipelineBuilder::new(Duration::ZERO);
+        assert_eq!(builder.validate(), Err(ConfigError::ZeroInterval));
+
+        let builder = PipelineBuilder::new(EVERY).with_memory_interval(Duration::ZERO);
+        assert_eq!(builder.validate(), Err(ConfigError::ZeroInterval));
+    }
+
+    #[test]
+    fn inverted_adaptive_intervals_are_rejected() {
+        let adaptive = AdaptiveInterval::new(EVERY * 2, EVERY);
+        let builder = PipelineBuilder::new(EVERY).with_adaptive_interval(adaptive);
+        assert_eq!(
+            builder.validate(),
+            Err(ConfigError::InvalidAdaptiveInterval)
+        );
+    }
+
+    #[test]
+    fn windows_out_of_range_are_rejected() {
+        let builder = PipelineBuilder::new(EVERY).with_window(0);
+        assert_eq!(builder.validate(), Err(ConfigError::InvalidWindow(0)));
+
+        let builder = PipelineBuilder::new(EVERY).with_window(MAX_WINDOW + 1);
+        assert_eq!(
+            builder.validate(),
+            Err(ConfigError::InvalidWindow(MAX_WINDOW + 1))
+        );
+    }
+
+    #[test]
+    fn zero_baseline_windows_are_rejected() {
+        let builder = PipelineBuilder::new(EVERY).with_baseline_window(0);
+        assert_eq!(builder.validate(), Err(ConfigError::ZeroBaselineWindow));
+    }
+
+    #[test]
+    fn zero_emit_every_is_rejected() {
+        let builder = PipelineBuilder::new(EVERY).with_emit_every(0);
+        assert_eq!(builder.validate(), Err(ConfigError::ZeroEmitEvery));
+    }
+
+    #[test]
+    fn too_many_busiest_cores_are_rejected() {
+        let builder = PipelineBuilder::new(EVERY).with_busiest_cores(MAX_BUSIEST_CORES + 1);
+        assert_eq!(
+            builder.validate(),
+            Err(ConfigError::TooManyBusiestCores(MAX_BUSIEST_CORES + 1))
+        );
+    }
+
+    #[test]
+    fn zero_channel_capacities_are_rejected() {
+        let builder = PipelineBuilder::new(EVERY).with_channel_capacity(0);
+        assert_eq!(builder.validate(), Err(ConfigError::ZeroChannelCapacity));
+    }
+
+    #[test]
+    fn zero_watchdog_tolerances_are_rejected() {
+        let builder = PipelineBuilder::new(EVERY).with_watchdog(Duration::ZERO);
+        assert_eq!(builder.validate(), Err(ConfigError::ZeroWatchdogTolerance));
+    }
+
+    #[tokio::test]
+    async fn abort_stops_the_inner_tasks() {
+        let pipeline = PipelineBuilder::new(Duration::from_millis(10))
+            .with_memory_interval(Duration::from_millis(10))
+            .spawn()
+            .unwrap();
+        let mut reports = pipeline.subscribe_stats();
+        let mut memory = pipeline.subscribe_memory().unwrap();
+
+        pipeline.abort();
+
+        // Each receiver errors once its processor has dropped the sender.
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while reports.changed().await.is_ok() {}
+            while memory.changed().await.is_ok() {}
+        })
+        .await
+        .expect("the stats processors should have been aborted");
+        assert!(pipeline.await.unwrap_err().is_cancelled());
+    }
+}
@@ -0,0 +1,226 @@
+//! Watching a single process, rather than the whole host. See
+//! [`ProcessSelector`].
+
+use std::{str::FromStr, sync::Arc};
+#[cfg(feature = "sysinfo")]
+use sysinfo::{Pid, Process, ProcessRefreshKind, ProcessesToUpdate, System};
+#[cfg(feature = "sysinfo")]
+use tracing::{debug, info};
+
+/// Which process a [`SysMonitor`] watches. See [`SysMonitor::for_process`].
+///
+/// Parsed from a string, a number is a PID, and anything else is a process
+/// name:
+///
+/// ```
+/// use metrics_tracing_example::ProcessSelector;
+///
+/// assert_eq!("1234".parse(), Ok(ProcessSelector::Pid(1234)));
+/// assert_eq!("postgres".parse(), Ok(ProcessSelector::Name("postgres".into())));
+/// # Ok::<_, String>(())
+/// ```
+///
+/// [`SysMonitor`]: crate::SysMonitor
+/// [`SysMonitor::for_process`]: crate::SysMonitor::for_process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessSelector {
+    /// The process with this PID. When it exits, there is nothing left to
+    /// watch.
+    Pid(u32),
+    /// The process with this exact name. When it exits, the monitor looks
+    /// for another process of the same name, e.g. the one a supervisor
+    /// restarted, and follows that one instead.
+    Name(String),
+}
+
+impl FromStr for ProcessSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("process name or PID must not be empty".to_owned());
+        }
+        Ok(s.parse()
+            .map_or_else(|_| Self::Name(s.to_owned()), Self::Pid))
+    }
+}
+
+impl std::fmt::Display for ProcessSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pid(pid) => write!(f, "{pid}"),
+            Self::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A process's resource usage at a point in time. See
+/// [`Observation::process`].
+///
+/// [`Observation::process`]: crate::Observation::process
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ProcessUsage {
+    /// The process's PID. Following a process by name, this changes when it
+    /// restarts.
+    pub pid: u32,
+    /// The process's name.
+    pub name: Arc<str>,
+    /// The process's CPU usage percentage, since the previous observation.
+    /// Unlike a single CPU's, this goes above 100 for a process that keeps
+    /// more than one CPU busy. It is 0 for the first observation after the
+    /// process was found.
+    pub cpu_usage: f32,
+    /// The process's resident memory, in bytes.
+    pub memory: u64,
+    /// The process's virtual memory, in bytes.
+    pub virtual_memory: u64,
+}
+
+/// Finds the selected process, and reads its usage, after each refresh of
+/// the [`System`].
+#[cfg(feature = "sysinfo")]
+#[derive(Debug)]
+pub(crate) struct ProcessTracker {
+    selector: ProcessSelector,
+    /// The PID being followed, once the process has been found.
+    pid: Option<Pid>,
+    /// The name of the process being followed, interned for every
+    /// observation.
+    name: Option<Arc<str>>,
+    /// The usage read by the last refresh. `None` if the process wasn't
+    /// found.
+    latest: Option<ProcessUsage>,
+}
+
+#[cfg(feature = "sysinfo")]
+impl ProcessTracker {
+    pub(crate) fn new(selector: ProcessSelector) -> Self {
+        let pid = match &selector {
+            ProcessSelector::Pid(pid) => Some(Pid::from_u32(*pid)),
+            ProcessSelector::Name(_) => None,
+        };
+        Self {
+            selector,
+            pid,
+            name: None,
+            latest: None,
+        }
+    }
+
+    /// What to refresh for the process: its CPU usage and memory, nothing
+    /// else.
+    fn refresh_kind() -> ProcessRefreshKind {
+        ProcessRefreshKind::nothing().with_cpu().with_memory()
+    }
+
+    /// Whether `process` is the one we are looking for.
+    fn matches(&self, process: &Process) -> bool {
+        match &self.selector {
+            ProcessSelector::Pid(pid) => process.pid().as_u32() == *pid,
+            ProcessSelector::Name(name) => process.name() == name.as_str(),
+        }
+    }
+
+    /// Refresh the followed process in `system`, finding it again by name
+    /// if it has gone, and read its usage.
+    ///
+    /// Refreshing one PID is cheap. Finding a process by name means
+    /// refreshing every process on the host, so that only happens while
+    /// the followed process isn't running.
+    pub(crate) fn refresh(&mut self, system: &mut System) {
+        let kind = Self::refresh_kind();
+        if let Some(pid) = self.pid {
+            system.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, kind);
+        }
+
+        let followed = self.pid.and_then(|pid| system.process(pid));
+        let process = match followed {
+            Some(process) if self.matches(process) => Some(process),
+            _ => match &self.selector {
+                ProcessSelector::Pid(pid) => {
+                    if self.latest.is_some() {
+                        info!(pid, "watched process exited");
+                    }
+                    None
+                }
+                ProcessSelector::Name(name) => {
+                    system.refresh_processes_specifics(ProcessesToUpdate::All, true, kind);
+                    // The oldest of them is most likely the parent of the
+                    // rest, e.g. a server and its workers.
+                    let found = system
+                        .processes_by_exact_name(name.as_ref())
+                        .min_by_key(|process| process.pid());
+                    match (found, self.pid) {
+                        (Some(process), Some(previous)) => info!(
+                            process = %name,
+                            previous = previous.as_u32(),
+                            pid = process.pid().as_u32(),
+                            "watched process restarted, following its new PID"
+                        ),
+                        (Some(process), None) => info!(
+                            process = %name,
+                            pid = process.pid().as_u32(),
+                            "found watched process"
+                        ),
+                        (None, _) if self.latest.is_some() => {
+                            info!(process = %name, "watched process exited")
+                        }
+                        (None, _) => debug!(process = %name, "watched process not running"),
+                    }
+                    // Keep the previous PID until there is a new one, so that
+                    // the restart is logged as one.
+                    if let Some(process) = found {
+                        self.pid = Some(process.pid());
+                    }
+                    found
+                }
+            },
+        };
+
+        self.latest = process.map(|process| {
+            let process_name = process.name().to_string_lossy();
+            let name = match &self.name {
+                Some(name) if **name == *process_name => name.clone(),
+                _ => Arc::from(process_name),
+            };
+            self.name = Some(name.clone());
+            ProcessUsage {
+                pid: process.pid().as_u32(),
+                name,
+                cpu_usage: process.cpu_usage(),
+                memory: process.memory(),
+                virtual_memory: process.virtual_memory(),
+            }
+        });
+    }
+
+    /// The usage read by the last refresh, if the process was found.
+    pub(crate) fn latest(&self) -> Option<ProcessUsage> {
+        self.latest.clone()
+    }
+}
+
+#[cfg(all(test, feature = "sysinfo"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_a_process_by_pid_or_name() {
+        let mut system = System::new();
+        let pid = std::process::id();
+
+        let mut by_pid = ProcessTracker::new(ProcessSelector::Pid(pid));
+        by_pid.refresh(&mut system);
+        let usage = by_pid.latest().expect("this process is running");
+        assert_eq!(usage.pid, pid);
+        assert!(usage.memory > 0);
+
+        let mut by_name = ProcessTracker::new(ProcessSelector::Name(usage.name.to_string()));
+        by_name.refresh(&mut system);
+        assert_eq!(by_name.latest().map(|usage| usage.name), Some(usage.name));
+
+        let mut missing = ProcessTracker::new(ProcessSelector::Name("no such process".into()));
+        missing.refresh(&mut system);
+        assert_eq!(missing.latest(), None);
+    }
+}
@@ -0,0 +1,93 @@
+//! Asking the [`SysStats`] actor about its window. See [`StatsQuerier`].
+//!
+//! [`SysStats`]: crate::SysStats
+
+use crate::{CpuStats, StatsReport};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// The state of the [`SysStats`] window at the moment a query was handled.
+///
+/// [`SysStats`]: crate::SysStats
+#[derive(Debug, Clone, Default)]
+pub struct WindowState {
+    /// The maximum number of observations the window holds.
+    pub capacity: usize,
+    /// The per-CPU stats of every observation in the window, oldest first.
+    /// These are shared with the window, not copied.
+    pub observations: Vec<Arc<[CpuStats]>>,
+    /// The most recently computed report.
+    pub report: StatsReport,
+}
+
+/// A request for the current [`WindowState`], answered by the [`SysStats`]
+/// actor on the `reply` channel.
+///
+/// You usually don't need to build these yourself. Use a [`StatsQuerier`].
+///
+/// [`SysStats`]: crate::SysStats
+#[derive(Debug)]
+pub struct StatsQuery {
+    pub(crate) reply: oneshot::Sender<WindowState>,
+}
+
+impl StatsQuery {
+    /// Create a query, and the receiver its answer will arrive on.
+    pub fn new() -> (Self, oneshot::Receiver<WindowState>) {
+        let (reply, rx) = oneshot::channel();
+        (Self { reply }, rx)
+    }
+}
+
+/// Asks a [`SysStats`] actor for the current state of its window. Get one
+/// from [`SysStats::querier`], or [`PipelineHandle::stats_querier`]. Cheap
+/// to clone, so every task that wants to ask can have its own.
+///
+/// ## The ask pattern
+///
+/// Actors own their state, and nobody else can touch it. That's what makes
+/// them easy to reason about: no locks, no data races. But sometimes another
+/// task needs to know what an actor knows _right now_. Sharing the state
+/// behind a mutex would undo the benefits. Instead, we send the actor a
+/// message with a [`oneshot`] channel attached, and the actor replies on
+/// it, between observations. This is the _ask_ pattern, as opposed to the
+/// _tell_ pattern of fire-and-forget messages like observations.
+///
+/// Compare with [`SysStats::subscribe`], which pushes a summary to every
+/// subscriber after every observation, whether they want it or not. A query
+/// pulls, and only costs anything when someone asks. That makes it the
+/// right place for bigger answers, like the whole window.
+///
+/// ```no_run
+/// use metrics_tracing_example::PipelineBuilder;
+/// use std::time::Duration;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let pipeline = PipelineBuilder::new(Duration::from_secs(1)).spawn()?;
+/// let querier = pipeline.stats_querier();
+///
+/// if let Some(state) = querier.window().await {
+///     println!("{} of {} observations", state.observations.len(), state.capacity);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`SysStats`]: crate::SysStats
+/// [`SysStats::querier`]: crate::SysStats::querier
+/// [`SysStats::subscribe`]: crate::SysStats::subscribe
+/// [`PipelineHandle::stats_querier`]: crate::PipelineHandle::stats_querier
+#[derive(Debug, Clone)]
+pub struct StatsQuerier {
+    pub(crate) queries: mpsc::Sender<StatsQuery>,
+}
+
+impl StatsQuerier {
+    /// Ask for the current state of the window. Returns `None` if the stats
+    /// actor has exited.
+    pub async fn window(&self) -> Option<WindowState> {
+        let (query, reply) = StatsQuery::new();
+        self.queries.send(query).await.ok()?;
+        reply.await.ok()
+    }
+}
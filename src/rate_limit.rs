@@ -0,0 +1,339 @@
+//! Capping how fast observations flow. See [`RateLimit`].
+
+use crate::{CpuData, Observation, ObservationData};
+use std::time::{Duration, Instant};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{debug, info};
+
+/// What a [`RateLimit`] does with the observations over its limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Drop them. The sink gets the observations that were on time, and
+    /// none of the rest.
+    #[default]
+    Drop,
+    /// Hold on to the latest one, and send it as soon as the limit allows.
+    /// Each newer observation replaces the one held, so the sink always
+    /// gets the freshest data, at the cost of the ones in between.
+    Coalesce,
+}
+
+impl RateLimitPolicy {
+    /// The name of the policy, for logs.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Drop => "drop",
+            Self::Coalesce => "coalesce",
+        }
+    }
+}
+
+/// How long to wait for a token that is too far off to tell, like `tokio`'s
+/// idea of never: about 30 years.
+const FAR_FUTURE: Duration = Duration::from_secs(86_400 * 365 * 30);
+
+/// A token bucket: `rate` tokens a second, up to `capacity` at once.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    /// A full bucket.
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.capacity);
+        self.refilled = now;
+    }
+
+    /// Take a token, if there is one.
+    fn try_take(&mut self) -> bool {
+        self.refill();
+        let taken = self.tokens >= 1.0;
+        if taken {
+            self.tokens -= 1.0;
+        }
+        taken
+    }
+
+    /// When the next token will be there. At a tiny rate, that may be
+    /// further off than an `Instant` can go, and then it's [`FAR_FUTURE`].
+    fn next_token(&mut self) -> Instant {
+        self.refill();
+        let missing = (1.0 - self.tokens).max(0.0);
+        let wait = Duration::try_from_secs_f64(missing / self.rate).unwrap_or(Duration::MAX);
+        self.refilled
+            .checked_add(wait)
+            .unwrap_or_else(|| self.refilled + FAR_FUTURE)
+    }
+}
+
+/// A pass-through actor that caps how many observations a second it sends
+/// on, to protect an expensive sink downstream.
+///
+/// Sinks that talk to the network, like the [`TcpSink`], or an HTTP
+/// exporter, cost something per observation: a request, a row, a line on
+/// the bill. Most dashboards don't need an observation every 100
+/// milliseconds. Put a rate limit in front of the sink, and it gets as many
+/// as it needs, however fast the monitor goes.
+///
+/// The limit is a token bucket. It holds one observation's worth by default,
+/// which spaces observations out evenly. A bigger [burst] lets a few
+/// through at once after a quiet spell, while keeping the same average rate.
+/// What happens to the observations over the limit is up to the
+/// [`RateLimitPolicy`]: they're dropped, by default, or coalesced into the
+/// latest one.
+///
+/// Each observation is counted on the `my_cute_app.rate_limit_observations`
+/// counter, labeled by outcome: `forwarded`, `dropped`, or `coalesced`. The
+/// ones that don't make it also get a `debug!` event in their span. When the
+/// inbound channel closes, an observation that is being held is sent once
+/// the limit allows, and the totals are logged.
+///
+/// ```no_run
+/// use metrics_tracing_example::{PipelineBuilder, RateLimit, RateLimitPolicy, TcpSink};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(16);
+/// let (limited_tx, limited_rx) = mpsc::channel(16);
+///
+/// let _pipeline = PipelineBuilder::new(Duration::from_millis(100))
+///     .with_outbound(tx)
+///     .spawn()?;
+/// let _limit = RateLimit::new(rx, limited_tx, 1.0)
+///     .with_policy(RateLimitPolicy::Coalesce)
+///     .spawn();
+/// let _sink = TcpSink::new(limited_rx, "collector.local:7000").spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`TcpSink`]: crate::TcpSink
+/// [burst]: RateLimit::with_burst
+#[derive(Debug)]
+pub struct RateLimit<T: ObservationData = CpuData> {
+    inbound: mpsc::Receiver<Observation<T>>,
+    outbound: mpsc::Sender<Observation<T>>,
+    per_second: f64,
+    burst: u32,
+    policy: RateLimitPolicy,
+}
+
+impl<T: ObservationData> RateLimit<T> {
+    /// Create a rate limit that sends at most `per_second` observations a
+    /// second from `inbound` on to `outbound`, and drops the rest.
+    ///
+    /// ## Panics
+    ///
+    /// If `per_second` isn't positive and finite.
+    pub fn new(
+        inbound: mpsc::Receiver<Observation<T>>,
+        outbound: mpsc::Sender<Observation<T>>,
+        per_second: f64,
+    ) -> Self {
+        assert!(
+            per_second.is_finite() && per_second > 0.0,
+            "rate limit must be positive"
+        );
+        Self {
+            inbound,
+            outbound,
+            per_second,
+            burst: 1,
+            policy: RateLimitPolicy::Drop,
+        }
+    }
+
+    /// Let up to `burst` observations through at once, after a quiet spell,
+    /// instead of 1.
+    ///
+    /// ## Panics
+    ///
+    /// If `burst` is zero.
+    pub const fn with_burst(mut self, burst: u32) -> Self {
+        assert!(burst > 0, "rate limit burst must be non-zero");
+        self.burst = burst;
+        self
+    }
+
+    /// Handle the observations over the limit with `policy`, instead of
+    /// dropping them.
+    pub const fn with_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Spawn the rate limit task. It exits when the inbound channel closes,
+    /// or the outbound receiver is dropped.
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut bucket = Bucket::new(self.per_second, f64::from(self.burst));
+            let mut held: Option<Observation<T>> = None;
+            let mut totals = Totals::default();
+
+            loop {
+                let next_token = bucket.next_token();
+                let obs = tokio::select! {
+                    obs = self.inbound.recv() => obs,
+                    _ = tokio::time::sleep_until(next_token.into()), if held.is_some() => {
+                        if bucket.try_take() {
+                            let obs = held.take().expect("checked by the select guard");
+                            if !totals.forward(&self.outbound, obs).await {
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let Some(obs) = obs else {
+                    // Send what's held, once the limit allows, then exit.
+                    if let Some(obs) = held.take() {
+                        tokio::time::sleep_until(bucket.next_token().into()).await;
+                        totals.forward(&self.outbound, obs).await;
+                    }
+                    break;
+                };
+
+                // Coalesced observations wait their turn, so a new one
+                // replaces the one held, even if a token is there.
+                if let Some(old) = held.take() {
+                    totals.over_limit(OverLimit::Coalesced, &old);
+                    held = Some(obs);
+                } else if bucket.try_take() {
+                    if !totals.forward(&self.outbound, obs).await {
+                        break;
+                    }
+                } else {
+                    match self.policy {
+                        RateLimitPolicy::Drop => totals.over_limit(OverLimit::Dropped, &obs),
+                        RateLimitPolicy::Coalesce => held = Some(obs),
+                    }
+                }
+            }
+
+            info!(
+                per_second = self.per_second,
+                policy = self.policy.as_str(),
+                forwarded = totals.forwarded,
+                dropped = totals.dropped,
+                coalesced = totals.coalesced,
+                "rate limit exiting"
+            );
+        })
+    }
+}
+
+/// What happened to an observation over the limit.
+#[derive(Debug, Clone, Copy)]
+enum OverLimit {
+    Dropped,
+    Coalesced,
+}
+
+impl OverLimit {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Dropped => "dropped",
+            Self::Coalesced => "coalesced",
+        }
+    }
+}
+
+/// What a [`RateLimit`] did with its observations.
+#[derive(Debug, Default)]
+struct Totals {
+    forwarded: u64,
+    dropped: u64,
+    coalesced: u64,
+}
+
+impl Totals {
+    /// Send `obs` on. `false` if the receiver was dropped.
+    async fn forward<T: ObservationData>(
+        &mut self,
+        outbound: &mpsc::Sender<Observation<T>>,
+        obs: Observation<T>,
+    ) -> bool {
+        if outbound.send(obs).await.is_err() {
+            debug!("rate limit receiver dropped, exiting");
+            return false;
+        }
+        self.forwarded += 1;
+        crate::metrics::record_rate_limited("forwarded");
+        true
+    }
+
+    /// Count an observation that didn't make it.
+    fn over_limit<T: ObservationData>(&mut self, outcome: OverLimit, obs: &Observation<T>) {
+        match outcome {
+            OverLimit::Dropped => self.dropped += 1,
+            OverLimit::Coalesced => self.coalesced += 1,
+        }
+        let outcome = outcome.as_str();
+        obs.span()
+            .in_scope(|| debug!(outcome, "over the rate limit"));
+        crate::metrics::record_rate_limited(outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::observation;
+
+    /// Send observations with the given usages at once, close the channel,
+    /// and collect the usages that made it through.
+    async fn limit(policy: RateLimitPolicy, usages: &[f32]) -> Vec<f32> {
+        let (tx, rx) = mpsc::channel(usages.len());
+        let (out_tx, mut out_rx) = mpsc::channel(usages.len());
+        for &usage in usages {
+            tx.send(observation(1, usage)).await.unwrap();
+        }
+        drop(tx);
+
+        let limit = RateLimit::new(rx, out_tx, 20.0).with_policy(policy).spawn();
+        let mut passed = Vec::new();
+        while let Some(obs) = out_rx.recv().await {
+            passed.push(obs[0].usage);
+        }
+        limit.await.unwrap();
+        passed
+    }
+
+    #[tokio::test]
+    async fn drops_over_the_limit() {
+        let passed = limit(RateLimitPolicy::Drop, &[1.0, 2.0, 3.0, 4.0]).await;
+        assert_eq!(passed, [1.0]);
+    }
+
+    #[test]
+    fn tiny_rates_wait_forever() {
+        let mut bucket = Bucket::new(f64::MIN_POSITIVE, 1.0);
+        assert!(bucket.try_take());
+        assert!(bucket.next_token() >= bucket.refilled + FAR_FUTURE);
+    }
+
+    #[tokio::test]
+    async fn coalesces_into_the_latest() {
+        let started = Instant::now();
+        let passed = limit(RateLimitPolicy::Coalesce, &[1.0, 2.0, 3.0, 4.0]).await;
+        assert_eq!(passed, [1.0, 4.0]);
+        // The latest waited for its token.
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,316 @@
+//! Capturing observations to a file, and playing them back. See
+//! [`ObservationRecorder`] and [`ObservationReplayer`].
+
+use crate::{
+    CpuStats, Observation, ObservationSink,
+    fields::{self, OBSERVATION_ID},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, info_span};
+
+/// A single line of a recorded observation file.
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedObservation {
+    /// Milliseconds since the first recorded observation.
+    offset_ms: u64,
+    /// The observed CPU stats.
+    cpus: Arc<[CpuStats]>,
+}
+
+/// An [`ObservationSink`] that writes every observation to a file, as JSON
+/// lines, for an [`ObservationReplayer`] to play back later.
+///
+/// Each line holds the CPU stats, and when the observation was taken,
+/// relative to the first. Anything else on the observation, like its
+/// memory, or its span, isn't recorded.
+///
+/// A recording captures what a pipeline saw, so it can be run again: to
+/// reproduce an alert, to try out a new stats window on yesterday's spike,
+/// or as a fixture for a test. Run it with a [`SinkDriver`]:
+///
+/// ```no_run
+/// use metrics_tracing_example::{ObservationRecorder, SinkDriver, run_observations};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(16);
+/// let pipeline = run_observations(Duration::from_secs(1), Some(tx));
+/// let recorder = SinkDriver::new(ObservationRecorder::to_file("obs.jsonl").await?, rx).spawn();
+///
+/// tokio::signal::ctrl_c().await?;
+/// pipeline.shutdown().await?;
+/// // The file is flushed once the channel closes.
+/// recorder.await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`SinkDriver`]: crate::SinkDriver
+#[derive(Debug)]
+pub struct ObservationRecorder {
+    file: BufWriter<File>,
+    first: Option<Instant>,
+    recorded: u64,
+}
+
+impl ObservationRecorder {
+    /// Record to the file at `path`, replacing it if it exists.
+    ///
+    /// ## Errors
+    ///
+    /// If the file can't be created.
+    pub async fn to_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path).await?),
+            first: None,
+            recorded: 0,
+        })
+    }
+
+    /// The number of observations recorded so far.
+    pub const fn recorded(&self) -> u64 {
+        self.recorded
+    }
+}
+
+impl ObservationSink for ObservationRecorder {
+    type Error = io::Error;
+
+    fn name(&self) -> &'static str {
+        "recorder"
+    }
+
+    async fn handle(&mut self, obs: Observation) -> Result<(), Self::Error> {
+        let first = *self.first.get_or_insert(obs.taken_at());
+        let record = RecordedObservation {
+            offset_ms: obs.taken_at().duration_since(first).as_millis() as u64,
+            cpus: obs.cpus().clone(),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        self.recorded += 1;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), Self::Error> {
+        self.file.flush().await
+    }
+}
+
+/// Plays a file written by an [`ObservationRecorder`] back, as a source of
+/// observations.
+///
+/// The replayer keeps the same channel contract as the [`SysMonitor`], so
+/// whatever was downstream of the monitor can be downstream of a replay
+/// instead. It sends each observation at the pace it was recorded, or
+/// faster, with [`with_speed`]. Each gets a fresh `Replayed observation`
+/// span, with an `observation_id`.
+///
+/// The task exits, and drops its sender, at the end of the file, when the
+/// [shutdown token] is cancelled, or when the receiver is dropped. It returns
+/// the number of observations it sent, or the error that stopped it early,
+/// e.g. a line that isn't a recorded observation.
+///
+/// ```no_run
+/// use metrics_tracing_example::{ObservationReplayer, SysStats};
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(16);
+/// let replay = ObservationReplayer::from_file("obs.jsonl")
+///     .await?
+///     .with_speed(10.0)
+///     .spawn(tx);
+/// let stats = SysStats::new(rx, None).spawn();
+///
+/// let replayed = replay.await??;
+/// stats.await?;
+/// println!("replayed {replayed} observations");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`SysMonitor`]: crate::SysMonitor
+/// [`with_speed`]: ObservationReplayer::with_speed
+/// [shutdown token]: ObservationReplayer::with_shutdown
+#[derive(Debug)]
+pub struct ObservationReplayer {
+    lines: Lines<BufReader<File>>,
+    speed: f64,
+    shutdown: CancellationToken,
+}
+
+impl ObservationReplayer {
+    /// Play back the file at `path`.
+    ///
+    /// ## Errors
+    ///
+    /// If the file can't be opened.
+    pub async fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            lines: BufReader::new(File::open(path).await?).lines(),
+            speed: 1.0,
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Play back at `speed` times the recorded pace: `1` is as recorded,
+    /// `10` is ten times faster.
+    ///
+    /// ## Panics
+    ///
+    /// If `speed` isn't positive and finite.
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        assert!(
+            speed.is_finite() && speed > 0.0,
+            "replay speed must be positive"
+        );
+        self.speed = speed;
+        self
+    }
+
+    /// Stop when `shutdown` is cancelled, like the [`SysMonitor`].
+    ///
+    /// [`SysMonitor`]: crate::SysMonitor
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Spawn the replay task, sending each observation to `outbound`.
+    pub fn spawn(mut self, outbound: mpsc::Sender<Observation>) -> JoinHandle<io::Result<u64>> {
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let mut replayed = 0u64;
+            let mut line_number = 0u64;
+
+            loop {
+                let line = tokio::select! {
+                    _ = self.shutdown.cancelled() => break,
+                    _ = outbound.closed() => break,
+                    line = self.lines.next_line() => line?,
+                };
+                let Some(line) = line else { break };
+                line_number += 1;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let record: RecordedObservation = serde_json::from_str(&line).map_err(|error| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {line_number}: {error}"),
+                    )
+                })?;
+
+                // Sleep until the observation is due, relative to the start
+                // of the replay. This keeps the replay from drifting if the
+                // receiver is slow. A slow enough replay of a late enough
+                // observation is due after the end of time.
+                let due = Duration::try_from_secs_f64(
+                    Duration::from_millis(record.offset_ms).as_secs_f64() / self.speed,
+                )
+                .ok()
+                .and_then(|due| started.checked_add(due))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {line_number}: offset is too far in the future"),
+                    )
+                })?;
+                tokio::select! {
+                    _ = self.shutdown.cancelled() => break,
+                    _ = tokio::time::sleep_until(due.into()) => {}
+                }
+
+                let span = info_span!(
+                    "Replayed observation",
+                    { OBSERVATION_ID } = fields::obs_id(replayed)
+                );
+                if outbound
+                    .send(Observation::new(record.cpus, span))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                replayed += 1;
+            }
+
+            info!(observations = replayed, "replay finished");
+            Ok(replayed)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SinkDriver, testing::observation};
+
+    #[tokio::test]
+    async fn replays_what_was_recorded() {
+        let path = std::env::temp_dir().join(format!("recording-{}.jsonl", std::process::id()));
+
+        let (tx, rx) = mpsc::channel(4);
+        let recorder =
+            SinkDriver::new(ObservationRecorder::to_file(&path).await.unwrap(), rx).spawn();
+        for usage in [10.0, 20.0, 30.0] {
+            tx.send(observation(2, usage)).await.unwrap();
+        }
+        drop(tx);
+        assert_eq!(recorder.await.unwrap().recorded(), 3);
+
+        let (tx, mut rx) = mpsc::channel(4);
+        let replay = ObservationReplayer::from_file(&path)
+            .await
+            .unwrap()
+            .with_speed(1000.0)
+            .spawn(tx);
+        let mut usages = Vec::new();
+        while let Some(obs) = rx.recv().await {
+            assert_eq!(obs.len(), 2);
+            usages.push(obs[0].usage);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replay.await.unwrap().unwrap(), 3);
+        assert_eq!(usages, [10.0, 20.0, 30.0]);
+    }
+
+    #[tokio::test]
+    async fn rejects_offsets_past_the_end_of_time() {
+        let path = std::env::temp_dir().join(format!("far-future-{}.jsonl", std::process::id()));
+        let record = RecordedObservation {
+            offset_ms: u64::MAX,
+            cpus: Arc::new([]),
+        };
+        std::fs::write(&path, serde_json::to_string(&record).unwrap()).unwrap();
+
+        let (tx, _rx) = mpsc::channel(1);
+        let replay = ObservationReplayer::from_file(&path)
+            .await
+            .unwrap()
+            .with_speed(f64::MIN_POSITIVE)
+            .spawn(tx);
+        let error = replay.await.unwrap().unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,196 @@
+//! What the monitor refreshes on each observation. See [`RefreshSpec`].
+
+use std::{fmt, str::FromStr, sync::Arc};
+#[cfg(feature = "sysinfo")]
+use sysinfo::{CpuRefreshKind, MemoryRefreshKind, ProcessRefreshKind, RefreshKind};
+
+/// Which `sysinfo` subsystems the [`SysMonitor`] refreshes on each
+/// observation. See [`SysMonitor::with_refresh`].
+///
+/// Every subsystem costs something to refresh. CPU usage is one read of
+/// `/proc/stat`, but frequencies are a file per CPU, every process is a
+/// handful of files per process, and every sensor is a file or two. The
+/// default refreshes CPU usage and frequency, which is what the stats
+/// processor uses. Ask for more only if something consumes it: each
+/// subsystem adds to the [`Observation`], and to the metrics.
+///
+/// - `memory`: the host's [`MemoryUsage`], on [`Observation::memory`], and
+///   the `my_cute_app.memory_bytes` gauges.
+/// - `processes`: the number of processes, on
+///   [`Observation::process_count`], and the `my_cute_app.processes` gauge.
+/// - `components`: the temperature of each sensor, on
+///   [`Observation::temperatures`], and the
+///   `my_cute_app.component_temperature_celsius` gauges.
+///
+/// CPU usage is always refreshed. An observation without it has nothing to
+/// compute stats over.
+///
+/// Parsed from a string, it's a comma-separated list of subsystems, where
+/// `cpu` is usage and frequency, and `usage` is usage alone:
+///
+/// ```
+/// use metrics_tracing_example::RefreshSpec;
+///
+/// let spec: RefreshSpec = "cpu,memory".parse()?;
+/// assert_eq!(spec, RefreshSpec::cpu().with_memory());
+/// assert_eq!("usage".parse(), Ok(RefreshSpec::cpu().without_frequency()));
+/// # Ok::<_, String>(())
+/// ```
+///
+/// [`SysMonitor`]: crate::SysMonitor
+/// [`SysMonitor::with_refresh`]: crate::SysMonitor::with_refresh
+/// [`Observation`]: crate::Observation
+/// [`Observation::memory`]: crate::Observation::memory
+/// [`Observation::process_count`]: crate::Observation::process_count
+/// [`Observation::temperatures`]: crate::Observation::temperatures
+/// [`MemoryUsage`]: crate::MemoryUsage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefreshSpec {
+    frequency: bool,
+    memory: bool,
+    processes: bool,
+    components: bool,
+}
+
+impl Default for RefreshSpec {
+    fn default() -> Self {
+        Self::cpu()
+    }
+}
+
+impl RefreshSpec {
+    /// CPU usage and frequency, and nothing else. The default.
+    pub const fn cpu() -> Self {
+        Self {
+            frequency: true,
+            memory: false,
+            processes: false,
+            components: false,
+        }
+    }
+
+    /// Every subsystem.
+    pub const fn everything() -> Self {
+        Self {
+            frequency: true,
+            memory: true,
+            processes: true,
+            components: true,
+        }
+    }
+
+    /// Don't refresh CPU frequencies. Every CPU's frequency reads 0.
+    pub const fn without_frequency(mut self) -> Self {
+        self.frequency = false;
+        self
+    }
+
+    /// Also refresh the host's memory.
+    pub const fn with_memory(mut self) -> Self {
+        self.memory = true;
+        self
+    }
+
+    /// Also refresh the list of processes.
+    pub const fn with_processes(mut self) -> Self {
+        self.processes = true;
+        self
+    }
+
+    /// Also refresh the temperature sensors.
+    pub const fn with_components(mut self) -> Self {
+        self.components = true;
+        self
+    }
+
+    /// Whether CPU frequencies are refreshed.
+    pub const fn frequency(&self) -> bool {
+        self.frequency
+    }
+
+    /// Whether the host's memory is refreshed.
+    pub const fn memory(&self) -> bool {
+        self.memory
+    }
+
+    /// Whether the list of processes is refreshed.
+    pub const fn processes(&self) -> bool {
+        self.processes
+    }
+
+    /// Whether the temperature sensors are refreshed.
+    pub const fn components(&self) -> bool {
+        self.components
+    }
+
+    /// The parts of the spec that a `sysinfo` [`System`] refreshes. The
+    /// sensors aren't part of a [`System`], and are refreshed separately.
+    ///
+    /// [`System`]: sysinfo::System
+    #[cfg(feature = "sysinfo")]
+    pub fn refresh_kind(&self) -> RefreshKind {
+        let mut cpu = CpuRefreshKind::nothing().with_cpu_usage();
+        if self.frequency {
+            cpu = cpu.with_frequency();
+        }
+        let mut kind = RefreshKind::nothing().with_cpu(cpu);
+        if self.memory {
+            kind = kind.with_memory(MemoryRefreshKind::everything());
+        }
+        if self.processes {
+            kind = kind.with_processes(ProcessRefreshKind::nothing());
+        }
+        kind
+    }
+}
+
+impl FromStr for RefreshSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut spec = Self::cpu().without_frequency();
+        for subsystem in s.split(',').map(str::trim) {
+            spec = match subsystem {
+                "cpu" | "frequency" => Self {
+                    frequency: true,
+                    ..spec
+                },
+                "usage" => spec,
+                "memory" => spec.with_memory(),
+                "processes" => spec.with_processes(),
+                "components" => spec.with_components(),
+                other => {
+                    return Err(format!(
+                        "unknown subsystem `{other}`, expected one of: cpu, usage, frequency, \
+                         memory, processes, components"
+                    ));
+                }
+            };
+        }
+        Ok(spec)
+    }
+}
+
+impl fmt::Display for RefreshSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.frequency { "cpu" } else { "usage" })?;
+        let extras = [
+            (self.memory, "memory"),
+            (self.processes, "processes"),
+            (self.components, "components"),
+        ];
+        for (_, name) in extras.iter().filter(|(enabled, _)| *enabled) {
+            write!(f, ",{name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A temperature sensor's reading. See [`RefreshSpec::with_components`].
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Temperature {
+    /// The sensor's label, e.g. `coretemp Package id 0`.
+    pub label: Arc<str>,
+    /// The temperature, in degrees Celsius.
+    pub celsius: f32,
+}
@@ -0,0 +1,91 @@
+//! The [`ShutdownReport`] summarizes a pipeline run.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+#[cfg(feature = "sysinfo")]
+use tracing::info;
+
+/// Counters shared between the actors of a single pipeline run.
+#[derive(Debug, Default)]
+pub(crate) struct PipelineCounters {
+    #[cfg(feature = "sysinfo")]
+    taken: AtomicU64,
+    processed: AtomicU64,
+    latency_nanos: AtomicU64,
+}
+
+impl PipelineCounters {
+    /// Record that the monitor took an observation.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn record_taken(&self) {
+        self.taken.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that the stats processor processed an observation, `latency`
+    /// after it was taken.
+    pub(crate) fn record_processed(&self, latency: Duration) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        self.latency_nanos
+            .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Produce the final report for a run that lasted `run_duration`.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn report(&self, run_duration: Duration) -> ShutdownReport {
+        let taken = self.taken.load(Ordering::Relaxed);
+        let processed = self.processed.load(Ordering::Relaxed);
+        let latency_nanos = self.latency_nanos.load(Ordering::Relaxed);
+
+        ShutdownReport {
+            observations_taken: taken,
+            observations_dropped: taken.saturating_sub(processed),
+            observations_processed: processed,
+            average_latency: Duration::from_nanos(
+                latency_nanos.checked_div(processed).unwrap_or_default(),
+            ),
+            run_duration,
+        }
+    }
+}
+
+/// A summary of a pipeline run, produced when the pipeline stops.
+///
+/// The report is returned by awaiting the [`PipelineHandle`], and emitted as
+/// a structured `info!` event. This is a good habit for any long-running
+/// task: a single event at exit that says what the task did is much easier
+/// to find than the same information scattered across thousands of
+/// per-observation events.
+///
+/// [`PipelineHandle`]: crate::PipelineHandle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// The number of observations taken by the monitor.
+    pub observations_taken: u64,
+    /// The number of observations taken, but never processed by the stats
+    /// processor. With a graceful shutdown, this should be zero.
+    pub observations_dropped: u64,
+    /// The number of observations processed by the stats processor.
+    pub observations_processed: u64,
+    /// The average time between an observation being taken and it being
+    /// processed by the stats processor.
+    pub average_latency: Duration,
+    /// How long the pipeline ran.
+    pub run_duration: Duration,
+}
+
+impl ShutdownReport {
+    /// Emit the report as a structured event.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn emit(&self) {
+        info!(
+            observations_taken = self.observations_taken,
+            observations_dropped = self.observations_dropped,
+            observations_processed = self.observations_processed,
+            average_latency_us = self.average_latency.as_micros() as u64,
+            run_duration_ms = self.run_duration.as_millis() as u64,
+            "pipeline stopped"
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Retry with exponential backoff. See [`Backoff`] and [`retry`].
+
+use crate::rng::Rng;
+use std::{fmt::Display, time::Duration};
+use tracing::warn;
+
+/// Exponential backoff with jitter.
+///
+/// The delay before retry `n` (starting at `0`) is drawn uniformly from
+/// `[d / 2, d]`, where `d = min(max, initial * multiplier^n)`. The jitter
+/// spreads retries out, so that many clients failing at the same moment (e.g.
+/// because the collector restarted) do not all retry at the same moment and
+/// knock it over again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// The base delay before the first retry.
+    pub initial: Duration,
+    /// The maximum delay between retries.
+    pub max: Duration,
+    /// The factor by which the delay grows after each retry.
+    pub multiplier: f64,
+    /// The maximum number of retries. The operation is attempted at most
+    /// `max_retries + 1` times.
+    pub max_retries: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+            max_retries: 5,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay to wait before retry number `attempt`, including jitter.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.min(i32::MAX as u32) as i32);
+        let ceiling = self.initial.mul_f64(exp).min(self.max);
+
+        ceiling / 2 + (ceiling / 2).mul_f64(Rng::random().unit())
+    }
+}
+
+/// Retry an async operation with the given [`Backoff`].
+///
+/// Each failed attempt that will be retried emits a `warn!` event, and
+/// increments the `my_cute_app.retries` counter, labeled with `operation`.
+/// If every attempt fails, the last error is returned.
+///
+/// ```no_run
+/// use metrics_tracing_example::{Backoff, retry};
+///
+/// # async fn _main() -> std::io::Result<()> {
+/// let stream = retry("connect", &Backoff::default(), || {
+///     tokio::net::TcpStream::connect("localhost:4318")
+/// })
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn retry<T, E, F, Fut>(
+    operation: &'static str,
+    backoff: &Backoff,
+    mut f: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(t) => return Ok(t),
+            Err(error) if attempt < backoff.max_retries => {
+                let delay = record_retry(operation, backoff, attempt, &error);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Retry a blocking operation with the given [`Backoff`]. This sleeps the
+/// current thread between attempts, so it must not be called from async code
+/// that is expected to make progress. See [`retry`] for details.
+pub fn retry_blocking<T, E, F>(operation: &'static str, backoff: &Backoff, mut f: F) -> Result<T, E>
+where
+    F: FnMut() -> Result<T, E>,
+    E: Display,
+{
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(t) => return Ok(t),
+            Err(error) if attempt < backoff.max_retries => {
+                let delay = record_retry(operation, backoff, attempt, &error);
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Emit the event and metric for a retry, and return the delay to wait.
+fn record_retry(
+    operation: &'static str,
+    backoff: &Backoff,
+    attempt: u32,
+    error: &dyn Display,
+) -> Duration {
+    let delay = backoff.delay(attempt);
+    warn!(
+        operation,
+        attempt = attempt + 1,
+        max_retries = backoff.max_retries,
+        delay_ms = delay.as_millis() as u64,
+        %error,
+        "operation failed, retrying"
+    );
+    crate::metrics::record_retry(operation);
+    delay
+}
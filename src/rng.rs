@@ -0,0 +1,60 @@
+//! A small random number generator, for jitter, IDs, and chaos. See [`Rng`].
+
+use std::hash::{BuildHasher, RandomState};
+
+/// A xorshift64* generator. Not fit for anything but jitter, IDs that only
+/// need to be unlikely to collide, and rolling dice, which is all we need,
+/// and saves us a dependency on `rand`.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) const fn new(seed: u64) -> Self {
+        // Zero is a fixed point of xorshift.
+        Self(if seed == 0 { 1 } else { seed })
+    }
+
+    /// Seeded from `RandomState`, which is randomly seeded, and different
+    /// every time.
+    pub(crate) fn random() -> Self {
+        Self::new(RandomState::new().hash_one(()))
+    }
+
+    /// A uniform `u64`.
+    pub(crate) const fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A uniform float in `[0, 1)`.
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// `true` with the given probability.
+    pub(crate) fn roll(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.unit() < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_rolls_repeat() {
+        let rolls = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..16).map(|_| rng.roll(0.5)).collect::<Vec<_>>()
+        };
+        assert_eq!(rolls(7), rolls(7));
+        assert_ne!(rolls(7), rolls(8));
+    }
+
+    #[test]
+    fn random_rngs_differ() {
+        assert_ne!(Rng::random().next_u64(), Rng::random().next_u64());
+    }
+}
@@ -0,0 +1,309 @@
+//! The [`Rollup`] actor downsamples observations.
+
+use crate::{CpuStats, Observation};
+use std::time::Duration;
+use tokio::{
+    sync::mpsc,
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::{debug, info, info_span};
+
+/// The default length of each [`Rollup`] period.
+pub const DEFAULT_ROLLUP_PERIOD: Duration = Duration::from_secs(60);
+
+/// The minimum, average, and maximum of a value over a rollup period.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MinAvgMax {
+    /// The smallest value.
+    pub min: f64,
+    /// The mean value.
+    pub avg: f64,
+    /// The largest value.
+    pub max: f64,
+}
+
+/// A single rollup period's aggregates, computed by the [`Rollup`] actor.
+///
+/// Each value is aggregated over the observations' averages across CPUs.
+/// `usage.max` is the busiest the machine as a whole was during the period,
+/// not the busiest single core.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RollupReport {
+    /// The number of observations in the period.
+    pub observations: usize,
+    /// CPU usage percentage.
+    pub usage: MinAvgMax,
+    /// CPU frequency in MHz.
+    pub freq_mhz: MinAvgMax,
+}
+
+/// Running min, sum, and max of a value.
+#[derive(Debug, Clone, Copy)]
+struct Accumulator {
+    min: f64,
+    sum: f64,
+    max: f64,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self {
+            min: f64::INFINITY,
+            sum: 0.0,
+            max: f64::NEG_INFINITY,
+        }
+    }
+}
+
+impl Accumulator {
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.sum += value;
+        self.max = self.max.max(value);
+    }
+
+    fn finish(self, count: usize) -> MinAvgMax {
+        MinAvgMax {
+            min: self.min,
+            avg: self.sum / count as f64,
+            max: self.max,
+        }
+    }
+}
+
+/// The aggregates of the current period, so far.
+#[derive(Debug, Clone, Copy, Default)]
+struct Period {
+    observations: usize,
+    usage: Accumulator,
+    freq: Accumulator,
+}
+
+impl Period {
+    fn add(&mut self, cpus: &[CpuStats]) {
+        if cpus.is_empty() {
+            return;
+        }
+        let count = cpus.len() as f64;
+        self.observations += 1;
+        self.usage
+            .add(cpus.iter().map(|cpu| cpu.usage as f64).sum::<f64>() / count);
+        self.freq
+            .add(cpus.iter().map(|cpu| cpu.frequency as f64).sum::<f64>() / count);
+    }
+
+    /// The period's report, and reset for the next one. `None` if there were
+    /// no observations.
+    fn take(&mut self) -> Option<RollupReport> {
+        let period = std::mem::take(self);
+        (period.observations > 0).then(|| RollupReport {
+            observations: period.observations,
+            usage: period.usage.finish(period.observations),
+            freq_mhz: period.freq.finish(period.observations),
+        })
+    }
+}
+
+/// Downsamples observations into fixed periods, 1 minute by default, and
+/// reports the minimum, average, and maximum of each.
+///
+/// The [`SysStats`] window answers "what is happening right now?" at full
+/// resolution. Over a day, that's a lot of data, and most of it is noise.
+/// The rollup answers "what happened this minute?" in a single
+/// [`RollupReport`]. Chain actors like these, and you get a
+/// _multi-resolution_ pipeline: fine-grained data for the recent past,
+/// coarse data for the long run. This is how time-series databases keep
+/// years of history without keeping every sample.
+///
+/// The rollup is a pass-through actor, like [`SysStats`]. Put it on the
+/// stats processor's outbound channel, and it forwards every observation to
+/// its own outbound channel, if any. At the end of each period, it:
+///
+/// - emits a `finished rollup` event, with the aggregates as fields,
+/// - records them on the `my_cute_app.rollup_usage` and
+///   `my_cute_app.rollup_freq_mhz` gauges, labeled by `stat`, and
+/// - sends the [`RollupReport`] to the reports channel, if any. See
+///   [`Rollup::with_reports`].
+///
+/// Periods without observations are skipped.
+///
+/// ```no_run
+/// use metrics_tracing_example::{PipelineBuilder, Rollup};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(2);
+/// let (reports_tx, mut reports) = mpsc::channel(2);
+///
+/// let _pipeline = PipelineBuilder::new(Duration::from_secs(1))
+///     .with_outbound(tx)
+///     .spawn()?;
+/// let _rollup = Rollup::new(rx, None).with_reports(reports_tx).spawn();
+///
+/// while let Some(report) = reports.recv().await {
+///     println!("average usage this minute: {:.2}%", report.usage.avg);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`SysStats`]: crate::SysStats
+#[derive(Debug)]
+pub struct Rollup {
+    inbound: mpsc::Receiver<Observation>,
+    outbound: Option<mpsc::Sender<Observation>>,
+    reports: Option<mpsc::Sender<RollupReport>>,
+    period: Duration,
+}
+
+impl Rollup {
+    /// Create a new rollup actor, that forwards observations to `outbound`.
+    pub const fn new(
+        inbound: mpsc::Receiver<Observation>,
+        outbound: Option<mpsc::Sender<Observation>>,
+    ) -> Self {
+        Self {
+            inbound,
+            outbound,
+            reports: None,
+            period: DEFAULT_ROLLUP_PERIOD,
+        }
+    }
+
+    /// Aggregate over periods of `period`, instead of the default of
+    /// [`DEFAULT_ROLLUP_PERIOD`].
+    ///
+    /// ## Panics
+    ///
+    /// If `period` is zero.
+    pub const fn with_period(mut self, period: Duration) -> Self {
+        assert!(!period.is_zero(), "rollup period must be non-zero");
+        self.period = period;
+        self
+    }
+
+    /// Send a [`RollupReport`] to `reports` at the end of each period.
+    pub fn with_reports(mut self, reports: mpsc::Sender<RollupReport>) -> Self {
+        self.reports = Some(reports);
+        self
+    }
+
+    /// Emit, record, and send the report for the period, if it had any
+    /// observations.
+    async fn finish(&mut self, period: &mut Period) {
+        let Some(report) = period.take() else {
+            return;
+        };
+
+        info_span!("Rollup").in_scope(|| {
+            info!(
+                observations = report.observations,
+                usage_min = report.usage.min,
+                usage_avg = report.usage.avg,
+                usage_max = report.usage.max,
+                freq_min_mhz = report.freq_mhz.min,
+                freq_avg_mhz = report.freq_mhz.avg,
+                freq_max_mhz = report.freq_mhz.max,
+                "finished rollup"
+            );
+        });
+        crate::metrics::record_rollup(&report);
+
+        if let Some(reports) = &self.reports
+            && reports.send(report).await.is_err()
+        {
+            debug!("Rollup report receiver dropped, stopping reports");
+            self.reports = None;
+        }
+    }
+
+    /// Spawn the rollup task.
+    ///
+    /// Like [`SysStats::spawn`], the task runs until the inbound channel is
+    /// closed. It then reports the last, partial, period, and drops its
+    /// senders.
+    ///
+    /// [`SysStats::spawn`]: crate::SysStats::spawn
+    pub fn spawn(mut self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            // The first tick of a plain `interval` completes immediately,
+            // which would end the first period before it started.
+            let mut ticks = tokio::time::interval_at(Instant::now() + self.period, self.period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut period = Period::default();
+
+            loop {
+                tokio::select! {
+                    obs = self.inbound.recv() => {
+                        let Some(obs) = obs else {
+                            break;
+                        };
+                        period.add(&obs);
+
+                        if let Some(outbound) = &self.outbound
+                            && outbound.send(obs).await.is_err()
+                        {
+                            debug!("Outbound receiver dropped, stopping forwarding");
+                            self.outbound = None;
+                        }
+                    }
+                    _ = ticks.tick() => self.finish(&mut period).await,
+                }
+            }
+
+            self.finish(&mut period).await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpus(usage: [f32; 2], frequency: u64) -> Vec<CpuStats> {
+        usage
+            .iter()
+            .enumerate()
+            .map(|(i, &usage)| CpuStats {
+                name: format!("cpu{i}").into(),
+                usage,
+                frequency,
+                times: None,
+                package: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn period_aggregates_observation_averages() {
+        let mut period = Period::default();
+        assert_eq!(period.take(), None);
+
+        period.add(&cpus([10.0, 30.0], 2_000));
+        period.add(&cpus([100.0, 0.0], 3_000));
+        period.add(&cpus([5.0, 5.0], 1_000));
+
+        let report = period.take().expect("three observations");
+        assert_eq!(report.observations, 3);
+        assert_eq!(
+            report.usage,
+            MinAvgMax {
+                min: 5.0,
+                avg: 25.0,
+                max: 50.0
+            }
+        );
+        assert_eq!(
+            report.freq_mhz,
+            MinAvgMax {
+                min: 1_000.0,
+                avg: 2_000.0,
+                max: 3_000.0
+            }
+        );
+
+        // The next period starts from scratch.
+        assert_eq!(period.take(), None);
+    }
+}
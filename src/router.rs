@@ -0,0 +1,167 @@
+//! The endpoints as an [`axum::Router`]. See [`RouterBuilder`].
+
+use crate::{
+    Health, OPENMETRICS_CONTENT_TYPE, StatsReport, health::health_body, render_openmetrics,
+};
+use axum::{
+    Router,
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+    routing::get,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::sync::watch;
+
+/// Builds an [`axum::Router`] serving `/metrics`, `/healthz`, and `/stats`,
+/// for an application to merge into its own server.
+///
+/// On its own, this crate binds its own listeners: [`init_metrics`] for
+/// Prometheus, and [`serve_health`] for the health check. That's fine for a
+/// standalone binary like `sysmon`. An application that already runs an
+/// HTTP server doesn't want two more ports, two more things to firewall,
+/// and two more things to configure in its probes. Instead, it can mount
+/// these routes next to its own:
+///
+/// - `GET /metrics` renders the Prometheus metrics. Install the recorder
+///   with [`init_metrics_recorder`], rather than [`init_metrics`], and pass
+///   its handle to [`RouterBuilder::with_metrics`]. Scrapers that accept
+///   OpenMetrics get it, with exemplars. See [`render_openmetrics`].
+/// - `GET /healthz` reports the [`Health`] of the pipeline, exactly like
+///   [`serve_health`] does.
+/// - `GET /stats` returns the most recent [`StatsReport`], as JSON.
+///
+/// Only the routes you provide a source for are added.
+///
+/// ```no_run
+/// use axum::{Router, routing::get};
+/// use metrics_tracing_example::{Health, PipelineBuilder, RouterBuilder, init_metrics_recorder};
+/// use std::time::Duration;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let every = Duration::from_secs(5);
+/// let health = Health::new(every * 3);
+/// let metrics = init_metrics_recorder();
+/// let pipeline = PipelineBuilder::new(every)
+///     .with_health(health.clone())
+///     .spawn()?;
+///
+/// let app = Router::new()
+///     .route("/", get(|| async { "my app" }))
+///     .merge(
+///         RouterBuilder::new()
+///             .with_metrics(metrics)
+///             .with_health(health)
+///             .with_stats(pipeline.subscribe_stats())
+///             .build(),
+///     );
+///
+/// let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
+/// axum::serve(listener, app).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`init_metrics`]: crate::init_metrics
+/// [`init_metrics_recorder`]: crate::init_metrics_recorder
+/// [`serve_health`]: crate::serve_health
+#[derive(Debug, Default)]
+pub struct RouterBuilder {
+    metrics: Option<PrometheusHandle>,
+    health: Option<Health>,
+    stats: Option<watch::Receiver<StatsReport>>,
+}
+
+impl RouterBuilder {
+    /// Create a builder with no routes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `GET /metrics` from the Prometheus recorder behind `handle`.
+    pub fn with_metrics(mut self, handle: PrometheusHandle) -> Self {
+        self.metrics = Some(handle);
+        self
+    }
+
+    /// Serve `GET /healthz`, reporting the state of `health`.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Serve `GET /stats`, returning the most recent report on `reports`.
+    /// See [`PipelineHandle::subscribe_stats`].
+    ///
+    /// [`PipelineHandle::subscribe_stats`]: crate::PipelineHandle::subscribe_stats
+    pub fn with_stats(mut self, reports: watch::Receiver<StatsReport>) -> Self {
+        self.stats = Some(reports);
+        self
+    }
+
+    /// Build the router. Each handler clones its source, so the router can
+    /// be merged or nested anywhere.
+    pub fn build(self) -> Router {
+        let mut router = Router::new();
+
+        if let Some(handle) = self.metrics {
+            router = router.route(
+                "/metrics",
+                get(move |headers: HeaderMap| async move {
+                    // Prometheus asks for OpenMetrics when it stores
+                    // exemplars. Everything else gets the plain text format.
+                    let openmetrics = headers
+                        .get(header::ACCEPT)
+                        .and_then(|accept| accept.to_str().ok())
+                        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+                    if openmetrics {
+                        (
+                            [(header::CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)],
+                            render_openmetrics(&handle),
+                        )
+                            .into_response()
+                    } else {
+                        handle.render().into_response()
+                    }
+                }),
+            );
+        }
+
+        if let Some(health) = self.health {
+            router = router.route(
+                "/healthz",
+                get(move || async move {
+                    let status = health.check();
+                    let code = if status.is_healthy() {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    };
+                    (code, json(health_body(&status)))
+                }),
+            );
+        }
+
+        if let Some(reports) = self.stats {
+            router = router.route(
+                "/stats",
+                get(move || async move {
+                    // Serialize straight from the borrow, rather than cloning
+                    // the report. The lock is held only while we write JSON.
+                    match serde_json::to_string(&*reports.borrow()) {
+                        Ok(body) => json(body).into_response(),
+                        Err(error) => {
+                            (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response()
+                        }
+                    }
+                }),
+            );
+        }
+
+        router
+    }
+}
+
+/// A JSON response body.
+fn json(body: String) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], body)
+}
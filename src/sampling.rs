@@ -0,0 +1,224 @@
+//! Head sampling for OTEL export. See [`TargetSampler`].
+
+use opentelemetry::{
+    Context, KeyValue, Value,
+    trace::{Link, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceId},
+};
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+use std::sync::Arc;
+use tracing::Level;
+
+/// The attribute `tracing-opentelemetry` records a span's level in.
+const LEVEL: &str = "level";
+/// The attribute `tracing-opentelemetry` records a span's target in.
+const TARGET: &str = "target";
+
+/// A sampler that keeps a fraction of traces, with per-target fractions, and
+/// always keeps spans at `WARN` level or above.
+///
+/// ## Why sample?
+///
+/// A program that creates a trace per request, or per observation, can
+/// produce far more traces than anyone will look at, and every one of them
+/// costs CPU to export and money to store. Sampling keeps a representative
+/// fraction. The decision is made once, for the root span, from a hash of the
+/// trace ID. Every span below the root follows its parent's decision, so
+/// traces are kept or dropped whole, never in pieces.
+///
+/// ```no_run
+/// use metrics_tracing_example::{TargetSampler, TracingBuilder};
+///
+/// # async fn _main() {
+/// // Keep 10% of traces, but every trace started by the `sysmon` binary.
+/// let sampler = TargetSampler::new(0.1).with_target("sysmon", 1.0);
+/// let _provider = TracingBuilder::new().with_sampler(sampler).init();
+/// # }
+/// ```
+///
+/// ## Keeping errors
+///
+/// A 10% sample drops 90% of the traces with errors in them, too. These are
+/// the ones you most want to keep. This sampler always keeps spans declared
+/// at `WARN` level or above, e.g. with `error_span!`, regardless of the
+/// ratio, or of their parent's decision. See
+/// [`TargetSampler::with_always_sample_level`]. If the parent was dropped,
+/// the kept span shows up in the viewer with a missing parent.
+///
+/// This is _head_ sampling: the decision is made when the span starts. At
+/// that point, an `Observation` span doesn't know that an `error!` event
+/// will be emitted inside it a few milliseconds later. Keeping every trace
+/// that _ended up_ containing an error takes _tail_ sampling, where the
+/// collector buffers whole traces and decides once they are complete. The
+/// OTEL collector's [`tail_sampling` processor] does exactly that. A common
+/// setup is to export everything with a generous head ratio, and let the
+/// collector do the rest. Declaring fallible operations as `WARN` or `ERROR`
+/// spans is how the head sampler can help.
+///
+/// [`tail_sampling` processor]: https://github.com/open-telemetry/opentelemetry-collector-contrib/tree/main/processor/tailsamplingprocessor
+#[derive(Debug, Clone)]
+pub struct TargetSampler {
+    ratio: f64,
+    targets: Arc<[(&'static str, f64)]>,
+    always_sample_level: Level,
+}
+
+impl TargetSampler {
+    /// Create a new sampler that keeps `ratio` of all traces, between `0.0`
+    /// and `1.0`.
+    pub fn new(ratio: f64) -> Self {
+        Self {
+            ratio,
+            targets: Arc::new([]),
+            always_sample_level: Level::WARN,
+        }
+    }
+
+    /// Keep `ratio` of the traces whose root span's target is `prefix`, or a
+    /// module under it, instead of the default ratio. `sysmon` matches
+    /// `sysmon::collect`, but not `sysmonitor`. If several prefixes match,
+    /// the longest wins.
+    pub fn with_target(mut self, prefix: &'static str, ratio: f64) -> Self {
+        self.targets = self
+            .targets
+            .iter()
+            .copied()
+            .chain([(prefix, ratio)])
+            .collect();
+        self
+    }
+
+    /// Always keep spans at `level` or above, instead of the default of
+    /// `WARN`. E.g. `Level::ERROR` only keeps error spans.
+    pub const fn with_always_sample_level(mut self, level: Level) -> Self {
+        self.always_sample_level = level;
+        self
+    }
+
+    /// The ratio for a root span with the given attributes.
+    fn ratio_for(&self, attributes: &[KeyValue]) -> f64 {
+        let Some(target) = string_attribute(attributes, TARGET) else {
+            return self.ratio;
+        };
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| in_module(target, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.ratio, |(_, ratio)| *ratio)
+    }
+
+    fn is_always_sampled(&self, attributes: &[KeyValue]) -> bool {
+        string_attribute(attributes, LEVEL)
+            .and_then(|level| level.parse::<Level>().ok())
+            // More severe levels compare as _less_ than less severe ones.
+            .is_some_and(|level| level <= self.always_sample_level)
+    }
+}
+
+/// Whether `target` is the module `prefix`, or a module under it.
+fn in_module(target: &str, prefix: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Find the string value of the attribute `key`.
+fn string_attribute<'a>(attributes: &'a [KeyValue], key: &str) -> Option<&'a str> {
+    attributes.iter().find_map(|kv| match &kv.value {
+        Value::String(value) if kv.key.as_str() == key => Some(value.as_str()),
+        _ => None,
+    })
+}
+
+fn decision(decision: SamplingDecision, parent_context: Option<&Context>) -> SamplingResult {
+    SamplingResult {
+        decision,
+        attributes: Vec::new(),
+        trace_state: parent_context
+            .map(|cx| cx.span().span_context().trace_state().clone())
+            .unwrap_or_default(),
+    }
+}
+
+impl ShouldSample for TargetSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        if self.is_always_sampled(attributes) {
+            return decision(SamplingDecision::RecordAndSample, parent_context);
+        }
+
+        // Follow the parent's decision, so that traces are kept whole.
+        if let Some(cx) = parent_context.filter(|cx| cx.has_active_span()) {
+            let sampled = cx.span().span_context().is_sampled();
+            return decision(
+                if sampled {
+                    SamplingDecision::RecordAndSample
+                } else {
+                    SamplingDecision::Drop
+                },
+                parent_context,
+            );
+        }
+
+        Sampler::TraceIdRatioBased(self.ratio_for(attributes)).should_sample(
+            parent_context,
+            trace_id,
+            name,
+            span_kind,
+            attributes,
+            links,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(target: &str, level: &str) -> Vec<KeyValue> {
+        vec![
+            KeyValue::new(TARGET, target.to_string()),
+            KeyValue::new(LEVEL, level.to_string()),
+        ]
+    }
+
+    #[test]
+    fn longest_matching_prefix_wins() {
+        let sampler = TargetSampler::new(0.1)
+            .with_target("sysmon", 0.5)
+            .with_target("sysmon::collect", 1.0);
+
+        assert_eq!(sampler.ratio_for(&attributes("sysmon", "INFO")), 0.5);
+        assert_eq!(sampler.ratio_for(&attributes("sysmon::agent", "INFO")), 0.5);
+        assert_eq!(
+            sampler.ratio_for(&attributes("sysmon::collect::host", "INFO")),
+            1.0
+        );
+        assert_eq!(sampler.ratio_for(&attributes("other", "INFO")), 0.1);
+        assert_eq!(sampler.ratio_for(&[]), 0.1);
+    }
+
+    #[test]
+    fn prefixes_match_on_module_boundaries() {
+        let sampler = TargetSampler::new(0.1).with_target("sysmon", 1.0);
+        assert_eq!(sampler.ratio_for(&attributes("sysmonitor", "INFO")), 0.1);
+        assert_eq!(sampler.ratio_for(&attributes("sysmon:", "INFO")), 0.1);
+    }
+
+    #[test]
+    fn severe_spans_are_always_sampled() {
+        let sampler = TargetSampler::new(0.0);
+        assert!(sampler.is_always_sampled(&attributes("other", "WARN")));
+        assert!(sampler.is_always_sampled(&attributes("other", "ERROR")));
+        assert!(!sampler.is_always_sampled(&attributes("other", "INFO")));
+
+        let sampler = sampler.with_always_sample_level(Level::ERROR);
+        assert!(!sampler.is_always_sampled(&attributes("other", "WARN")));
+    }
+}
@@ -0,0 +1,90 @@
+//! Taking observations on a calendar, rather than every interval. See
+//! [`CronSchedule`].
+
+use chrono::{DateTime, Local, TimeZone};
+use std::{str::FromStr, time::Duration};
+
+/// A cron expression for when to take observations. See
+/// [`SysMonitor::with_cron`].
+///
+/// A fixed interval samples around the clock. For a monitor that runs for
+/// months, that's a lot of observations of a machine nobody is using. A cron
+/// expression restricts sampling to the times that matter: business hours,
+/// the minutes around a nightly batch job, or just the top of every minute,
+/// lined up across every host.
+///
+/// Expressions have six or seven fields: seconds, minutes, hours, day of
+/// month, month, day of week, and, optionally, year. Note the leading
+/// seconds: a five field crontab line needs a `0` in front. Times are in
+/// the local time zone.
+///
+/// ```
+/// use metrics_tracing_example::CronSchedule;
+///
+/// // Every 10 seconds, 9 to 5, Monday to Friday.
+/// let schedule: CronSchedule = "*/10 * 9-16 * * Mon-Fri".parse()?;
+/// assert!("* * *".parse::<CronSchedule>().is_err());
+/// # Ok::<_, String>(())
+/// ```
+///
+/// [`SysMonitor::with_cron`]: crate::SysMonitor::with_cron
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    schedule: cron::Schedule,
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        expression
+            .parse()
+            .map(|schedule| Self { schedule })
+            .map_err(|e| format!("invalid cron expression `{expression}`: {e}"))
+    }
+}
+
+impl std::fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.schedule.source())
+    }
+}
+
+impl CronSchedule {
+    /// The next time the schedule fires, strictly after `after`. `None` if
+    /// it never fires again, e.g. because its years are in the past.
+    pub(crate) fn next_after<Z: TimeZone>(&self, after: &DateTime<Z>) -> Option<DateTime<Z>> {
+        self.schedule.after(after).next()
+    }
+
+    /// How long until the schedule next fires, from now. `None` if it never
+    /// fires again.
+    pub fn until_next(&self) -> Option<Duration> {
+        let now = Local::now();
+        let next = self.next_after(&now)?;
+        // The next time is strictly after now, so this can't be negative.
+        Some((next - now).to_std().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn fires_on_schedule() {
+        let schedule: CronSchedule = "0 */15 9-16 * * Mon-Fri".parse().unwrap();
+        let at = |d, h, m| Utc.with_ymd_and_hms(2026, 10, d, h, m, 0).unwrap();
+
+        // Friday afternoon, then nothing until Monday morning.
+        assert_eq!(schedule.next_after(&at(16, 16, 40)), Some(at(16, 16, 45)));
+        assert_eq!(schedule.next_after(&at(16, 16, 45)), Some(at(19, 9, 0)));
+    }
+
+    #[test]
+    fn schedules_can_end() {
+        let schedule: CronSchedule = "0 0 0 1 1 * 2020".parse().unwrap();
+        assert_eq!(schedule.until_next(), None);
+    }
+}
@@ -0,0 +1,100 @@
+//! Reporting error events to Sentry. See [`init_sentry`].
+
+use sentry::{
+    ClientInitGuard, ClientOptions,
+    integrations::tracing::{EventMapping, breadcrumb_from_event, event_from_event},
+    protocol::{Context as SentryContext, Map},
+    types::ParseDsnError,
+};
+use tracing::{Event, Level};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::{Layer, Registry, filter::LevelFilter, layer::Context};
+
+/// Start the Sentry client, reporting to `dsn`, or to `SENTRY_DSN` if
+/// `None`. If neither is set, the client is disabled, and reports nothing.
+///
+/// Pass [`TracingBuilder::with_sentry`] to the tracing builder, and every
+/// `ERROR` event becomes a Sentry issue. That includes panics in the actors,
+/// caught by the [panic hook], and exporter failures. `WARN` and `INFO`
+/// events become breadcrumbs, so each issue shows what led up to it.
+///
+/// ## Sentry and OTEL
+///
+/// Sentry can do tracing too, but the traces are already going to the OTLP
+/// collector. Sending them twice would double the overhead, for two
+/// disagreeing copies. Instead, each Sentry issue is tagged with the
+/// `trace_id` and `span_id` of the OTEL span the event was emitted in, and
+/// the names of the enclosing spans, e.g. `Observation > Stats`. Sentry is
+/// good at "what broke, how often, since which release". The trace is good at
+/// "what was this observation doing when it broke". The IDs get you from one
+/// to the other.
+///
+/// Hold on to the returned guard until the end of `main`. Dropping it
+/// flushes the events still queued.
+///
+/// ```no_run
+/// use metrics_tracing_example::{TracingBuilder, init_sentry};
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let _sentry = init_sentry(None)?;
+/// let provider = TracingBuilder::new()
+///     .with_panic_hook()
+///     .with_sentry()
+///     .init();
+/// // ... run the pipeline ...
+/// provider.shutdown()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## Errors
+///
+/// If `dsn` is not a valid DSN.
+///
+/// [`TracingBuilder::with_sentry`]: crate::TracingBuilder::with_sentry
+/// [panic hook]: crate::TracingBuilder::with_panic_hook
+pub fn init_sentry(dsn: Option<&str>) -> Result<ClientInitGuard, ParseDsnError> {
+    let mut options = ClientOptions::new();
+    options.dsn = dsn.map(str::parse).transpose()?;
+    options.release = sentry::release_name!();
+    Ok(sentry::init(options))
+}
+
+/// The Sentry [`Layer`]: issues for `ERROR` events, breadcrumbs for `WARN`
+/// and `INFO`. Sentry's own spans are off. See [`init_sentry`].
+pub(crate) fn sentry_layer() -> impl Layer<Registry> + Send + Sync + use<> {
+    sentry::integrations::tracing::layer()
+        .span_filter(|_| false)
+        .event_mapper(map_event)
+        .with_filter(LevelFilter::INFO)
+}
+
+fn map_event(event: &Event<'_>, ctx: Context<'_, Registry>) -> EventMapping {
+    match *event.metadata().level() {
+        Level::ERROR => {
+            let mut report = event_from_event::<Registry>(event, None);
+            if let Some(span) = ctx.event_span(event) {
+                if let Some(otel) = span.extensions().get::<OtelData>() {
+                    if let Some(trace_id) = otel.trace_id() {
+                        report.tags.insert("trace_id".into(), trace_id.to_string());
+                    }
+                    if let Some(span_id) = otel.span_id() {
+                        report.tags.insert("span_id".into(), span_id.to_string());
+                    }
+                }
+
+                let spans: Vec<_> = span.scope().from_root().map(|span| span.name()).collect();
+                let mut context = Map::new();
+                context.insert("spans".into(), spans.join(" > ").into());
+                report
+                    .contexts
+                    .insert("tracing".into(), SentryContext::Other(context));
+            }
+            EventMapping::Event(Box::new(report))
+        }
+        Level::WARN | Level::INFO => {
+            EventMapping::Breadcrumb(breadcrumb_from_event::<Registry>(event, None))
+        }
+        _ => EventMapping::Ignore,
+    }
+}
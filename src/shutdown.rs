@@ -0,0 +1,403 @@
+//! Tearing the actors down in order. See [`ShutdownCoordinator`].
+
+use crate::TracingHandle;
+use std::{fmt, future::Future, pin::Pin, time::Duration};
+use tokio::task::{AbortHandle, JoinError, JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, error, info_span, warn};
+
+/// The default time each stage gets to stop, before it's aborted. See
+/// [`ShutdownCoordinator::with_stage_timeout`].
+pub const DEFAULT_STAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A task to wait for during shutdown, and what to call it in the logs.
+struct Stage {
+    name: &'static str,
+    /// The task, and any tasks it supervises, which aborting it would
+    /// otherwise leave running.
+    abort: Vec<AbortHandle>,
+    task: Pin<Box<dyn Future<Output = Result<(), JoinError>> + Send>>,
+}
+
+impl Stage {
+    fn new<T: Send + 'static>(name: &'static str, task: JoinHandle<T>) -> Self {
+        Self::then(name, task, drop)
+    }
+
+    /// A stage that hands what the task returned to `then`, once it exits.
+    fn then<T: Send + 'static>(
+        name: &'static str,
+        task: JoinHandle<T>,
+        then: impl FnOnce(T) + Send + 'static,
+    ) -> Self {
+        Self {
+            name,
+            abort: vec![task.abort_handle()],
+            task: Box::pin(async move { task.await.map(then) }),
+        }
+    }
+
+    /// Wait up to `timeout` for the task to exit, then abort it.
+    async fn stop(self, timeout: Duration) -> Result<(), JoinError> {
+        let mut task = self.task;
+        match tokio::time::timeout(timeout, &mut task).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!(
+                    stage = self.name,
+                    ?timeout,
+                    "stage didn't stop in time, aborting it"
+                );
+                for abort in &self.abort {
+                    abort.abort();
+                }
+                task.await
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stage").field("name", &self.name).finish()
+    }
+}
+
+/// Shuts the actors down in the right order.
+///
+/// Each actor exits once the one before it has, and closed its channel. So
+/// the order is always the same:
+///
+/// 1. Stop the monitors, by cancelling their [shutdown token]. Each one
+///    finishes the observation it is taking, and drops its sender.
+/// 2. Wait for the stats processors, and anything else between the monitors
+///    and the sinks, to drain their channels, and exit.
+/// 3. Wait for the sinks, to flush what they were sent.
+/// 4. Shut down the [`TracingHandle`], exporting the spans of everything
+///    above. This goes last, as every step before it makes spans.
+///
+/// Get the order wrong, and something is lost. Shutting down tracing first
+/// loses the spans of the last observations. Aborting the stats processor
+/// loses the observations still in its channel. And a sink that is never
+/// waited for is killed mid-write when `main` returns.
+///
+/// A task that panicked is logged, and the rest are still waited for, so one
+/// broken sink doesn't cost the spans. The first panic is returned.
+///
+/// A task that never exits would hold shutdown up forever, and the spans
+/// with it. So each stage gets [`DEFAULT_STAGE_TIMEOUT`] to stop, and is
+/// aborted past it, which counts as a failure too. See
+/// [`ShutdownCoordinator::with_stage_timeout`].
+///
+/// ```no_run
+/// use metrics_tracing_example::{ShutdownCoordinator, SysMonitor, SysStats, TracingBuilder};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+/// use tokio_util::sync::CancellationToken;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let tracing = TracingBuilder::new().init();
+/// let shutdown = CancellationToken::new();
+/// let (monitor_tx, monitor_rx) = mpsc::channel(2);
+/// let (stats_tx, mut stats_rx) = mpsc::channel(2);
+///
+/// let monitor = SysMonitor::new_with_specifics(
+///     SysMonitor::default_refresh_kind(),
+///     Duration::from_secs(1),
+///     monitor_tx,
+/// )
+/// .with_shutdown(shutdown.clone())
+/// .spawn();
+/// let stats = SysStats::new(monitor_rx, Some(stats_tx)).spawn();
+/// let sink = tokio::spawn(async move {
+///     while let Some(obs) = stats_rx.recv().await {
+///         obs.in_scope(|cpus| tracing::info!(cpus = cpus.len(), "sunk"));
+///     }
+/// });
+///
+/// tokio::signal::ctrl_c().await?;
+/// ShutdownCoordinator::new(shutdown)
+///     .with_monitor(monitor)
+///     .with_stats(stats)
+///     .with_sink("logger", sink)
+///     .with_tracing(tracing)
+///     .shutdown()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The [`Pipeline`] and [`PipelineHandle`] shut themselves down with a
+/// coordinator too.
+///
+/// [`PipelineHandle`]: crate::PipelineHandle
+/// [shutdown token]: crate::SysMonitor::with_shutdown
+/// [`Pipeline`]: crate::Pipeline
+#[derive(Debug)]
+#[must_use = "nothing is shut down until `shutdown` is called"]
+pub struct ShutdownCoordinator {
+    token: CancellationToken,
+    monitors: Vec<Stage>,
+    stats: Vec<Stage>,
+    sinks: Vec<Stage>,
+    tracing: Option<TracingHandle>,
+    stage_timeout: Duration,
+}
+
+impl ShutdownCoordinator {
+    /// Create a coordinator that stops the monitors by cancelling `token`.
+    /// Hand the monitors a clone of the same token.
+    pub const fn new(token: CancellationToken) -> Self {
+        Self {
+            token,
+            monitors: Vec::new(),
+            stats: Vec::new(),
+            sinks: Vec::new(),
+            tracing: None,
+            stage_timeout: DEFAULT_STAGE_TIMEOUT,
+        }
+    }
+
+    /// The token that stops the monitors, to hand to monitors spawned after
+    /// the coordinator was created.
+    pub const fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Wait for the monitor `task` to exit, after cancelling the token. The
+    /// monitor must stop on the coordinator's token, e.g. with
+    /// [`SysMonitor::with_shutdown`], or this waits forever.
+    ///
+    /// [`SysMonitor::with_shutdown`]: crate::SysMonitor::with_shutdown
+    pub fn with_monitor<T: Send + 'static>(mut self, task: JoinHandle<T>) -> Self {
+        self.monitors.push(Stage::new("monitor", task));
+        self
+    }
+
+    /// Wait for the stats processor `task` to drain its channel and exit,
+    /// after the monitors have. Anything else between the monitors and the
+    /// sinks, like a filter, or a [`Dispatcher`], belongs here too. They are
+    /// waited for in the order they were added, so add them in the order
+    /// observations flow through them.
+    ///
+    /// [`Dispatcher`]: crate::Dispatcher
+    pub fn with_stats<T: Send + 'static>(mut self, task: JoinHandle<T>) -> Self {
+        self.stats.push(Stage::new("stats", task));
+        self
+    }
+
+    /// Like [`ShutdownCoordinator::with_stats`], handing what the task
+    /// returned to `then`. If the task has to be aborted, the `inner` tasks
+    /// it supervises are aborted too.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn with_stats_then<T: Send + 'static>(
+        mut self,
+        task: JoinHandle<T>,
+        inner: Vec<AbortHandle>,
+        then: impl FnOnce(T) + Send + 'static,
+    ) -> Self {
+        let mut stage = Stage::then("stats", task, then);
+        stage.abort.extend(inner);
+        self.stats.push(stage);
+        self
+    }
+
+    /// Wait for the sink `task` to flush, and exit, after the stats
+    /// processors have. The `name` is for the logs, if it panics.
+    ///
+    /// Whatever the task returns is dropped. A sink that can fail, like the
+    /// [`EmfSink`], should log its own errors.
+    ///
+    /// [`EmfSink`]: crate::EmfSink
+    pub fn with_sink<T: Send + 'static>(mut self, name: &'static str, task: JoinHandle<T>) -> Self {
+        self.sinks.push(Stage::new(name, task));
+        self
+    }
+
+    /// Like [`ShutdownCoordinator::with_sink`], handing what the task
+    /// returned to `then`.
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn with_sink_then<T: Send + 'static>(
+        mut self,
+        name: &'static str,
+        task: JoinHandle<T>,
+        then: impl FnOnce(T) + Send + 'static,
+    ) -> Self {
+        self.sinks.push(Stage::then(name, task, then));
+        self
+    }
+
+    /// Shut down `tracing` last, exporting the spans that are left.
+    pub fn with_tracing(mut self, tracing: TracingHandle) -> Self {
+        self.tracing = Some(tracing);
+        self
+    }
+
+    /// Give each stage `timeout` to stop, instead of
+    /// [`DEFAULT_STAGE_TIMEOUT`], before aborting it. The tracing shutdown
+    /// has a timeout of its own. See [`TracingBuilder::with_shutdown_timeout`].
+    ///
+    /// ## Panics
+    ///
+    /// If `timeout` is zero.
+    ///
+    /// [`TracingBuilder::with_shutdown_timeout`]: crate::TracingBuilder::with_shutdown_timeout
+    pub const fn with_stage_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "stage timeout must be non-zero");
+        self.stage_timeout = timeout;
+        self
+    }
+
+    /// Shut everything down, in order, and wait for it to finish.
+    ///
+    /// A failure to export the last spans is logged, not returned, like
+    /// [`Pipeline::shutdown`].
+    ///
+    /// [`Pipeline::shutdown`]: crate::Pipeline::shutdown
+    pub async fn shutdown(self) -> Result<(), JoinError> {
+        let Self {
+            token,
+            monitors,
+            stats,
+            sinks,
+            tracing,
+            stage_timeout,
+        } = self;
+
+        let result = async move {
+            token.cancel();
+            let mut first_error = None;
+            for stage in monitors.into_iter().chain(stats).chain(sinks) {
+                let name = stage.name;
+                match stage.stop(stage_timeout).await {
+                    Ok(()) => debug!(stage = name, "stopped"),
+                    Err(error) => {
+                        error!(stage = name, %error, "task failed during shutdown");
+                        first_error.get_or_insert(error);
+                    }
+                }
+            }
+            first_error.map_or(Ok(()), Err)
+        }
+        .instrument(info_span!("Shutting down"))
+        .await;
+
+        // The span above is closed by now, so it is exported too. Shutting
+        // the provider down blocks until the export is done, so it runs off
+        // the runtime's worker threads.
+        if let Some(tracing) = tracing {
+            match tokio::task::spawn_blocking(move || tracing.shutdown()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => warn!(%error, "failed to export the last spans"),
+                Err(error) => warn!(%error, "tracing shutdown panicked"),
+            }
+        }
+        result
+    }
+
+    /// Wait for `signal`, e.g. `tokio::signal::ctrl_c()`, then
+    /// [shut down](Self::shutdown).
+    pub async fn shutdown_on<F: Future>(self, signal: F) -> Result<(), JoinError> {
+        signal.await;
+        self.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{EventMatcher, capture};
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+    use tracing::Level;
+
+    #[tokio::test]
+    async fn stages_stop_in_order_past_a_panic() {
+        let (captured, _guard) = capture();
+        let token = CancellationToken::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (tx, mut rx) = mpsc::channel::<u32>(1);
+        let (sink_tx, mut sink_rx) = mpsc::channel::<u32>(1);
+
+        let monitor = tokio::spawn({
+            let token = token.clone();
+            let order = order.clone();
+            async move {
+                token.cancelled().await;
+                order.lock().unwrap().push("monitor");
+                drop(tx);
+            }
+        });
+        let stats = tokio::spawn({
+            let order = order.clone();
+            async move {
+                while rx.recv().await.is_some() {}
+                order.lock().unwrap().push("stats");
+                drop(sink_tx);
+            }
+        });
+        let broken = tokio::spawn(async { panic!("broken sink") });
+        let sink = tokio::spawn({
+            let order = order.clone();
+            async move {
+                while sink_rx.recv().await.is_some() {}
+                order.lock().unwrap().push("sink");
+            }
+        });
+
+        let result = ShutdownCoordinator::new(token)
+            .with_monitor(monitor)
+            .with_stats(stats)
+            .with_sink("broken", broken)
+            .with_sink("sink", sink)
+            .shutdown()
+            .await;
+
+        assert!(result.unwrap_err().is_panic());
+        assert_eq!(*order.lock().unwrap(), ["monitor", "stats", "sink"]);
+        captured.assert_event(
+            &EventMatcher::new()
+                .with_level(Level::ERROR)
+                .with_field("stage", "broken")
+                .in_span("Shutting down"),
+        );
+    }
+
+    #[tokio::test]
+    async fn stuck_stages_are_aborted() {
+        let (captured, _guard) = capture();
+        let stuck = tokio::spawn(std::future::pending::<()>());
+        let sink = tokio::spawn(async {});
+
+        let result = ShutdownCoordinator::new(CancellationToken::new())
+            .with_stats(stuck)
+            .with_sink("sink", sink)
+            .with_stage_timeout(Duration::from_millis(10))
+            .shutdown()
+            .await;
+
+        assert!(result.unwrap_err().is_cancelled());
+        captured.assert_event(
+            &EventMatcher::new()
+                .with_level(Level::WARN)
+                .with_field("stage", "stats"),
+        );
+        captured.assert_event(&EventMatcher::new().with_field("stage", "sink"));
+    }
+
+    #[cfg(feature = "sysinfo")]
+    #[tokio::test]
+    async fn aborting_a_stuck_stage_aborts_its_inner_tasks() {
+        let inner = tokio::spawn(std::future::pending::<()>());
+        let stuck = tokio::spawn(std::future::pending::<()>());
+
+        let result = ShutdownCoordinator::new(CancellationToken::new())
+            .with_stats_then(stuck, vec![inner.abort_handle()], drop)
+            .with_stage_timeout(Duration::from_millis(10))
+            .shutdown()
+            .await;
+
+        assert!(result.unwrap_err().is_cancelled());
+        assert!(inner.await.unwrap_err().is_cancelled());
+    }
+}
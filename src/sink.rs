@@ -0,0 +1,191 @@
+//! A common shape for the end of the pipeline. See [`ObservationSink`].
+
+use crate::{CpuData, Observation, ObservationData};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{Instrument, debug, debug_span, warn};
+
+/// Something that consumes observations at the end of a pipeline: writes
+/// them to a file, posts them somewhere, or raises an alert. Run it with a
+/// [`SinkDriver`].
+///
+/// Every sink needs the same loop around it: receive until the channel
+/// closes, handle each observation within its span, decide what an error
+/// means, and clean up at the end. Written out in each sink, those loops
+/// drift apart. One logs errors and carries on, another exits, a third
+/// forgets the span. With this trait, a sink is just the part that differs,
+/// and the [`SinkDriver`] is the loop, the same for all of them.
+///
+/// Sinks take CPU observations by default. A sink of some other
+/// [`ObservationData`] implements `ObservationSink<ThatData>` instead.
+///
+/// Implement it with `async fn`:
+///
+/// ```
+/// use metrics_tracing_example::{Observation, ObservationSink};
+///
+/// /// Prints the average usage of each observation.
+/// struct Printer;
+///
+/// impl ObservationSink for Printer {
+///     type Error = std::convert::Infallible;
+///
+///     fn name(&self) -> &'static str {
+///         "printer"
+///     }
+///
+///     async fn handle(&mut self, obs: Observation) -> Result<(), Self::Error> {
+///         let total: f32 = obs.iter().map(|cpu| cpu.usage).sum();
+///         println!("{:.1}%", total / obs.len() as f32);
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait ObservationSink<T: ObservationData = CpuData>: Send + 'static {
+    /// What can go wrong while handling an observation.
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// The sink's name, for the span around each observation, logs, and
+    /// the `sink` metric label, e.g. `csv`.
+    fn name(&self) -> &'static str;
+
+    /// Handle one observation. The driver calls this within a span that is
+    /// a child of the observation's, so events need no extra effort to end
+    /// up in its trace.
+    ///
+    /// An error is logged and counted, and the driver moves on to the next
+    /// observation. A sink that can't carry on after an error should say so
+    /// in its own state, and skip the observations that follow.
+    fn handle(
+        &mut self,
+        obs: Observation<T>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Clean up, once the channel has closed and every observation has been
+    /// handled, e.g. flush a buffered writer. Does nothing by default.
+    fn close(&mut self) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Runs an [`ObservationSink`] off a channel, until the channel closes.
+///
+/// For each observation, the driver opens a `Sinking observation` span, a
+/// child of the observation's, with `sink` and `kind` fields, and calls
+/// [`ObservationSink::handle`] within it. Outcomes are counted on the
+/// `my_cute_app.sink_observations` counter, labeled by sink and outcome.
+/// Errors are logged, in the observation's trace. Once the channel closes,
+/// the driver calls [`ObservationSink::close`], and hands the sink back.
+///
+/// ```no_run
+/// # use metrics_tracing_example::{Observation, ObservationSink};
+/// # struct Printer;
+/// # impl ObservationSink for Printer {
+/// #     type Error = std::convert::Infallible;
+/// #     fn name(&self) -> &'static str { "printer" }
+/// #     async fn handle(&mut self, _obs: Observation) -> Result<(), Self::Error> { Ok(()) }
+/// # }
+/// use metrics_tracing_example::{SinkDriver, run_observations};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(2);
+/// let pipeline = run_observations(Duration::from_secs(5), Some(tx));
+/// let printer = SinkDriver::new(Printer, rx).spawn();
+///
+/// tokio::signal::ctrl_c().await?;
+/// pipeline.shutdown().await?;
+/// let _printer: Printer = printer.await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct SinkDriver<S, T: ObservationData = CpuData> {
+    sink: S,
+    inbound: mpsc::Receiver<Observation<T>>,
+}
+
+impl<S: ObservationSink<T>, T: ObservationData> SinkDriver<S, T> {
+    /// Create a driver that runs `sink` on every observation from
+    /// `inbound`.
+    pub const fn new(sink: S, inbound: mpsc::Receiver<Observation<T>>) -> Self {
+        Self { sink, inbound }
+    }
+
+    /// Spawn the driver. It runs until the inbound channel is closed, then
+    /// closes the sink, and returns it.
+    pub fn spawn(mut self) -> JoinHandle<S> {
+        tokio::spawn(async move {
+            let name = self.sink.name();
+            while let Some(obs) = self.inbound.recv().await {
+                let span = debug_span!(parent: obs.span(), "Sinking observation", sink = name, kind = T::KIND);
+                let result = self.sink.handle(obs).instrument(span.clone()).await;
+                crate::metrics::record_sink_observation(name, result.is_ok());
+                if let Err(error) = result {
+                    span.in_scope(
+                        || warn!(sink = name, %error, "sink failed to handle observation"),
+                    );
+                }
+            }
+
+            debug!(sink = name, "Inbound channel closed, closing sink");
+            if let Err(error) = self.sink.close().await {
+                warn!(sink = name, %error, "sink failed to close");
+            }
+            self.sink
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::observation;
+
+    /// Fails on every observation with a CPU above 50%, and counts the rest.
+    #[derive(Default)]
+    struct Picky {
+        handled: usize,
+        closed: bool,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("too busy")]
+    struct TooBusy;
+
+    impl ObservationSink for Picky {
+        type Error = TooBusy;
+
+        fn name(&self) -> &'static str {
+            "picky"
+        }
+
+        async fn handle(&mut self, obs: Observation) -> Result<(), Self::Error> {
+            if obs.iter().any(|cpu| cpu.usage > 50.0) {
+                return Err(TooBusy);
+            }
+            self.handled += 1;
+            Ok(())
+        }
+
+        async fn close(&mut self) -> Result<(), Self::Error> {
+            self.closed = true;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn driver_carries_on_after_errors_and_closes() {
+        let (tx, rx) = mpsc::channel(4);
+        let driver = SinkDriver::new(Picky::default(), rx).spawn();
+
+        for usage in [10.0, 90.0, 20.0] {
+            tx.send(observation(1, usage)).await.unwrap();
+        }
+        drop(tx);
+
+        let sink = driver.await.unwrap();
+        assert_eq!(sink.handled, 2);
+        assert!(sink.closed);
+    }
+}
@@ -0,0 +1,171 @@
+//! A diagnostic [`Layer`] that counts open spans. See [`SpanCountLayer`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        Arc, PoisonError, RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tracing::{Subscriber, span};
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+
+/// The number of open spans for a single target.
+///
+/// We set the gauge to our own count, rather than incrementing it, and look
+/// the gauge up each time rather than caching the handle. Tracing is usually
+/// initialized before metrics, so the first spans are opened before there is
+/// a recorder to send them to. Setting the gauge means it catches up as soon
+/// as the recorder is installed.
+#[derive(Debug)]
+struct TargetCount {
+    target: &'static str,
+    open: AtomicUsize,
+}
+
+impl TargetCount {
+    const fn new(target: &'static str) -> Self {
+        Self {
+            target,
+            open: AtomicUsize::new(0),
+        }
+    }
+
+    fn opened(&self) {
+        let open = self.open.fetch_add(1, Ordering::Relaxed) + 1;
+        crate::metrics::record_spans_open(self.target, open);
+    }
+
+    fn closed(&self) {
+        let open = self.open.fetch_sub(1, Ordering::Relaxed) - 1;
+        crate::metrics::record_spans_open(self.target, open);
+    }
+}
+
+/// A [`Layer`] that tracks the number of open spans per target, and exports
+/// them as the `my_cute_app.spans_open` gauge.
+///
+/// ## Writing a layer
+///
+/// A [`Layer`] is a composable piece of a subscriber. The [`Registry`] at the
+/// bottom of the stack assigns span IDs and stores span data. Each layer on
+/// top of it is notified as things happen, via callbacks. Every callback has
+/// a default implementation that does nothing, so a layer only implements
+/// what it cares about. This one needs just two:
+///
+/// - [`Layer::on_new_span`], called when a span is created.
+/// - [`Layer::on_close`], called when the last handle to a span is dropped,
+///   and the span is closed for good.
+///
+/// Note that these are _not_ [`Layer::on_enter`] and [`Layer::on_exit`]. A
+/// span is entered and exited every time its future is polled, or
+/// `in_scope` is called. It is open from creation until it is closed,
+/// regardless of how often it is entered.
+///
+/// The close callback only gets the span's ID, not its metadata. The
+/// [`Context`] lets us look the span up in the registry to find its target.
+/// That lookup is why the layer requires `S: LookupSpan`.
+///
+/// ## Finding leaks
+///
+/// If a target's count keeps growing, something is holding on to its spans.
+/// The [`SysStats`] docs describe the most common way this happens: storing
+/// a struct that owns a span in a long-lived collection. A growing
+/// `my_cute_app.spans_open` gauge is often the first sign of this, long
+/// before the memory usage becomes noticeable.
+///
+/// ## Usage
+///
+/// The layer is cheap to clone, and clones share their counts. Keep a clone
+/// to read the counts in code:
+///
+/// ```no_run
+/// use metrics_tracing_example::{SpanCountLayer, TracingBuilder};
+///
+/// # async fn _main() {
+/// let spans = SpanCountLayer::new();
+/// let _provider = TracingBuilder::new().with_span_counts(spans.clone()).init();
+///
+/// // ... later
+/// for (target, open) in spans.open_spans() {
+///     println!("{target}: {open}");
+/// }
+/// # }
+/// ```
+///
+/// Without a filter, a layer is interested in every span, which means spans
+/// at every level are created, even if no other layer records them. The
+/// [`TracingBuilder`] applies the console log filter to this layer, so only
+/// spans that pass the filter are counted.
+///
+/// [`Registry`]: tracing_subscriber::Registry
+/// [`SysStats`]: crate::SysStats
+/// [`TracingBuilder`]: crate::TracingBuilder
+#[derive(Debug, Clone, Default)]
+pub struct SpanCountLayer {
+    targets: Arc<RwLock<HashMap<&'static str, TargetCount>>>,
+}
+
+impl SpanCountLayer {
+    /// Create a new layer with no open spans.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of open spans for each target that has ever had one.
+    pub fn open_spans(&self) -> BTreeMap<&'static str, usize> {
+        self.targets
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(target, count)| (*target, count.open.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// The total number of open spans, across all targets.
+    pub fn total(&self) -> usize {
+        self.open_spans().values().sum()
+    }
+}
+
+impl<S> Layer<S> for SpanCountLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, _id: &span::Id, _ctx: Context<'_, S>) {
+        let target = attrs.metadata().target();
+
+        // Only the first span for each target needs the write lock.
+        if let Some(count) = self
+            .targets
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(target)
+        {
+            count.opened();
+            return;
+        }
+
+        self.targets
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(target)
+            .or_insert_with(|| TargetCount::new(target))
+            .opened();
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(metadata) = ctx.metadata(&id) else {
+            return;
+        };
+
+        if let Some(count) = self
+            .targets
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(metadata.target())
+        {
+            count.closed();
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! A [`Layer`] that turns span durations into metrics. See
+//! [`SpanDurationLayer`].
+
+use std::{sync::Arc, time::Instant};
+use tracing::{Metadata, Subscriber, span};
+use tracing_subscriber::{
+    Layer,
+    filter::{FilterFn, filter_fn},
+    layer::Context,
+    registry::LookupSpan,
+};
+
+/// The spans timed by [`SpanDurationLayer::new`].
+pub const DEFAULT_TIMED_SPANS: &[&str] = &["Taking observation", "Computing stats"];
+
+/// Stored in a span's extensions when it is created.
+struct OpenedAt(Instant);
+
+/// A [`Layer`] that measures how long selected spans are open, and records
+/// it in the `my_cute_app.span_duration_seconds` histogram, labeled by span
+/// name.
+///
+/// ## Metrics from traces
+///
+/// A span already knows when it starts and when it ends. Rather than
+/// sprinkling timers through the code, and recording them by hand, we can
+/// derive latency metrics from the spans we already have. The code being
+/// timed doesn't change at all. Add a `#[instrument]`, and list the span's
+/// name here.
+///
+/// The [`Registry`] gives every span a type map, its _extensions_, where
+/// layers can stash their own per-span data. When a selected span is
+/// created, we store the current time in its extensions. When it closes, we
+/// take it back out, and record the elapsed time.
+///
+/// This is the _wall_ time between the span being created and closed. For
+/// an async span, it includes time spent waiting between polls, which is
+/// usually what you want for latency. If you only want the time spent
+/// actually running, record the time between [`Layer::on_enter`] and
+/// [`Layer::on_exit`] instead.
+///
+/// ## Choosing spans
+///
+/// Every distinct span name is a separate histogram. Time a handful of
+/// interesting spans, not all of them, and especially not spans whose names
+/// are generated at runtime.
+///
+/// ```no_run
+/// use metrics_tracing_example::{SpanDurationLayer, TracingBuilder};
+///
+/// # async fn _main() {
+/// let durations = SpanDurationLayer::new().with_span("Snapshot");
+/// let _provider = TracingBuilder::new().with_span_durations(durations).init();
+/// # }
+/// ```
+///
+/// [`Registry`]: tracing_subscriber::Registry
+#[derive(Debug, Clone)]
+pub struct SpanDurationLayer {
+    spans: Arc<[&'static str]>,
+}
+
+impl Default for SpanDurationLayer {
+    fn default() -> Self {
+        Self {
+            spans: DEFAULT_TIMED_SPANS.into(),
+        }
+    }
+}
+
+impl SpanDurationLayer {
+    /// Create a new layer that times the [`DEFAULT_TIMED_SPANS`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also time spans named `name`.
+    pub fn with_span(mut self, name: &'static str) -> Self {
+        self.spans = self.spans.iter().copied().chain([name]).collect();
+        self
+    }
+
+    /// Time only the spans named in `names`, instead of the
+    /// [`DEFAULT_TIMED_SPANS`].
+    pub fn with_spans(mut self, names: impl IntoIterator<Item = &'static str>) -> Self {
+        self.spans = names.into_iter().collect();
+        self
+    }
+
+    fn is_timed(&self, name: &str) -> bool {
+        self.spans.contains(&name)
+    }
+
+    /// A per-layer filter that enables exactly the timed spans.
+    pub(crate) fn filter(&self) -> FilterFn<impl Fn(&Metadata<'_>) -> bool + use<>> {
+        let this = self.clone();
+        filter_fn(move |metadata| metadata.is_span() && this.is_timed(metadata.name()))
+    }
+}
+
+impl<S> Layer<S> for SpanDurationLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if !self.is_timed(attrs.metadata().name()) {
+            return;
+        }
+
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(OpenedAt(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+
+        if let Some(OpenedAt(opened_at)) = span.extensions_mut().remove::<OpenedAt>() {
+            crate::metrics::record_span_duration(span.name(), opened_at.elapsed());
+        }
+    }
+}
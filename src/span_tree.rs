@@ -0,0 +1,347 @@
+//! A [`Layer`] that draws the tree of open spans in the terminal. See
+//! [`SpanTreeLayer`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Write as _},
+    io::Write as _,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+    time::{Duration, Instant},
+};
+use tracing::{
+    Level, Subscriber,
+    field::{Field, Visit},
+    span,
+};
+use tracing_subscriber::{Layer, filter::LevelFilter, layer::Context, registry::LookupSpan};
+
+/// How often the tree is redrawn, by default.
+const DEFAULT_REFRESH: Duration = Duration::from_millis(100);
+
+/// How long closed spans stay on screen, by default.
+const DEFAULT_LINGER: Duration = Duration::from_secs(1);
+
+/// Move the cursor to the top left of the screen.
+const HOME: &str = "\x1b[H";
+/// Clear from the cursor to the end of the line.
+const CLEAR_LINE: &str = "\x1b[K";
+/// Clear from the cursor to the end of the screen.
+const CLEAR_BELOW: &str = "\x1b[J";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// A span in the tree.
+#[derive(Debug)]
+struct Node {
+    name: &'static str,
+    /// The span's fields, formatted as ` key=value` pairs.
+    fields: String,
+    /// The key of the parent node, if the parent is in the tree.
+    parent: Option<u64>,
+    opened_at: Instant,
+    closed_at: Option<Instant>,
+    /// The number of threads currently inside the span.
+    entered: usize,
+}
+
+/// The spans in the tree, and a map from span IDs to their keys.
+///
+/// The registry reuses the IDs of closed spans. Closed spans stay in the tree
+/// for a while, so nodes are keyed by the order they were created in, rather
+/// than by ID. This also means that iterating the nodes visits them in
+/// creation order.
+#[derive(Debug, Default)]
+struct Tree {
+    nodes: BTreeMap<u64, Node>,
+    open: HashMap<span::Id, u64>,
+    next_key: u64,
+    dirty: bool,
+}
+
+impl Tree {
+    fn node_mut(&mut self, id: &span::Id) -> Option<&mut Node> {
+        let key = self.open.get(id)?;
+        self.nodes.get_mut(key)
+    }
+
+    /// Forget closed spans that have been on screen for longer than
+    /// `linger`.
+    fn expire(&mut self, now: Instant, linger: Duration) {
+        let before = self.nodes.len();
+        self.nodes.retain(|_, node| {
+            node.closed_at
+                .is_none_or(|closed_at| now.duration_since(closed_at) < linger)
+        });
+        self.dirty |= self.nodes.len() != before;
+    }
+
+    /// Draw the tree, one line per span, children below their parents.
+    fn render(&self, now: Instant) -> String {
+        let mut children: BTreeMap<Option<u64>, Vec<u64>> = BTreeMap::new();
+        for (key, node) in &self.nodes {
+            let parent = node.parent.filter(|parent| self.nodes.contains_key(parent));
+            children.entry(parent).or_default().push(*key);
+        }
+
+        let mut out = String::from(HOME);
+        let _ = writeln!(
+            out,
+            "{BOLD}open spans{RESET} ({}){CLEAR_LINE}",
+            self.open.len()
+        );
+        self.render_children(&mut out, &children, None, "", now);
+        out.push_str(CLEAR_BELOW);
+        out
+    }
+
+    fn render_children(
+        &self,
+        out: &mut String,
+        children: &BTreeMap<Option<u64>, Vec<u64>>,
+        parent: Option<u64>,
+        prefix: &str,
+        now: Instant,
+    ) {
+        let Some(keys) = children.get(&parent) else {
+            return;
+        };
+
+        for (i, key) in keys.iter().enumerate() {
+            let node = &self.nodes[key];
+            let last = i + 1 == keys.len();
+            let (branch, indent) = if last {
+                ("└─ ", "   ")
+            } else {
+                ("├─ ", "│  ")
+            };
+            let style = match (node.closed_at, node.entered) {
+                (Some(_), _) => DIM,
+                (None, 0) => "",
+                (None, _) => BOLD,
+            };
+            let open_for = node.closed_at.unwrap_or(now).duration_since(node.opened_at);
+            let _ = writeln!(
+                out,
+                "{prefix}{branch}{style}{}{}{RESET} {DIM}{open_for:.1?}{RESET}{CLEAR_LINE}",
+                node.name, node.fields,
+            );
+            self.render_children(out, children, Some(*key), &format!("{prefix}{indent}"), now);
+        }
+    }
+}
+
+fn lock(tree: &Mutex<Tree>) -> MutexGuard<'_, Tree> {
+    tree.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Formats span fields as ` key=value` pairs.
+struct FieldWriter<'a>(&'a mut String);
+
+impl Visit for FieldWriter<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let _ = write!(self.0, " {}={value}", field.name());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = write!(self.0, " {}={value:?}", field.name());
+    }
+}
+
+/// A [`Layer`] that keeps a tree of the open spans, and continuously redraws
+/// it in the terminal.
+///
+/// ## Watching the hierarchy
+///
+/// Traces are usually viewed after the fact, in a trace viewer. This layer
+/// shows them as they happen. Each span is drawn below its parent, with the
+/// time it has been open. Spans that some thread is currently _inside_ are
+/// drawn in bold. When a span closes, it is dimmed, and stays on screen for
+/// a moment so that you can see it at all. Most of the spans in this program
+/// are only open for a few milliseconds.
+///
+/// Run the pipeline with this layer, and you can watch each `Observation`
+/// open, `Taking observation` and `Refreshing system` appear below it, then
+/// `Computing stats`, and the whole tree close once the observation is
+/// dropped. Hold on to observations, like the `bad_holding_span` example
+/// does, and you'll see the tree grow instead.
+///
+/// ## How it works
+///
+/// Like the [`SpanCountLayer`], this layer keeps its own state in response
+/// to [`Layer::on_new_span`] and [`Layer::on_close`]. It also implements
+/// [`Layer::on_enter`] and [`Layer::on_exit`], to know which spans are
+/// active, and [`Layer::on_record`], to pick up fields recorded after the
+/// span was created.
+///
+/// Drawing the tree from the callbacks would redraw the screen for every
+/// span, and slow down every thread that opens one. Instead, like the
+/// [`LongSpanLayer`], a background thread redraws the tree at a fixed rate,
+/// and only if it has changed.
+///
+/// ## Usage
+///
+/// The tree is drawn over the whole of stderr, so switch console logging
+/// off, e.g. with [`TracingBuilder::with_log_filter`]`("off")`. Only spans
+/// at `DEBUG` level or above are drawn by default, regardless of the log
+/// filter. See [`SpanTreeLayer::with_max_level`].
+///
+/// ```no_run
+/// use metrics_tracing_example::{SpanTreeLayer, TracingBuilder};
+///
+/// # async fn _main() {
+/// let _provider = TracingBuilder::new()
+///     .with_log_filter("off")
+///     .with_span_tree(SpanTreeLayer::new())
+///     .init();
+/// # }
+/// ```
+///
+/// [`SpanCountLayer`]: crate::SpanCountLayer
+/// [`LongSpanLayer`]: crate::LongSpanLayer
+/// [`TracingBuilder::with_log_filter`]: crate::TracingBuilder::with_log_filter
+#[derive(Debug, Clone)]
+pub struct SpanTreeLayer {
+    max_level: Level,
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl Default for SpanTreeLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpanTreeLayer {
+    /// Create a new layer that redraws the tree 10 times per second, and
+    /// keeps closed spans on screen for a second.
+    pub fn new() -> Self {
+        Self::with_timing(DEFAULT_REFRESH, DEFAULT_LINGER)
+    }
+
+    /// Create a new layer that redraws the tree every `refresh`, and keeps
+    /// closed spans on screen for `linger`.
+    ///
+    /// This starts the background thread that draws the tree. The thread
+    /// exits once the layer, and all of its clones, are dropped.
+    pub fn with_timing(refresh: Duration, linger: Duration) -> Self {
+        let tree = Arc::new(Mutex::new(Tree::default()));
+        let weak = Arc::downgrade(&tree);
+        std::thread::Builder::new()
+            .name("span-tree-draw".into())
+            .spawn(move || draw_loop(weak, refresh, linger))
+            .expect("failed to spawn span tree draw thread");
+
+        Self {
+            max_level: Level::DEBUG,
+            tree,
+        }
+    }
+
+    /// Draw spans up to `level`, instead of the default of `DEBUG`. E.g.
+    /// `Level::INFO` draws only the top-level spans.
+    pub const fn with_max_level(mut self, level: Level) -> Self {
+        self.max_level = level;
+        self
+    }
+
+    /// A per-layer filter that enables the drawn spans.
+    pub(crate) fn filter(&self) -> LevelFilter {
+        LevelFilter::from_level(self.max_level)
+    }
+}
+
+/// Periodically redraw the tree, if it has changed.
+fn draw_loop(tree: Weak<Mutex<Tree>>, refresh: Duration, linger: Duration) {
+    loop {
+        std::thread::sleep(refresh);
+        let Some(tree) = tree.upgrade() else {
+            return;
+        };
+
+        let now = Instant::now();
+        let frame = {
+            let mut tree = lock(&tree);
+            tree.expire(now, linger);
+            // Open spans' durations change every frame, so there's always
+            // something to redraw while any are open.
+            if !tree.dirty && tree.open.is_empty() {
+                continue;
+            }
+            tree.dirty = false;
+            tree.render(now)
+        };
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = stderr.write_all(frame.as_bytes());
+        let _ = stderr.flush();
+    }
+}
+
+impl<S> Layer<S> for SpanTreeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = String::new();
+        attrs.record(&mut FieldWriter(&mut fields));
+        let parent_id = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .map(|parent| parent.id());
+
+        let mut tree = lock(&self.tree);
+        let key = tree.next_key;
+        tree.next_key += 1;
+        let parent = parent_id.and_then(|parent| tree.open.get(&parent).copied());
+        tree.nodes.insert(
+            key,
+            Node {
+                name: attrs.metadata().name(),
+                fields,
+                parent,
+                opened_at: Instant::now(),
+                closed_at: None,
+                entered: 0,
+            },
+        );
+        tree.open.insert(id.clone(), key);
+        tree.dirty = true;
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, _ctx: Context<'_, S>) {
+        let mut tree = lock(&self.tree);
+        if let Some(node) = tree.node_mut(id) {
+            values.record(&mut FieldWriter(&mut node.fields));
+            tree.dirty = true;
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, _ctx: Context<'_, S>) {
+        let mut tree = lock(&self.tree);
+        if let Some(node) = tree.node_mut(id) {
+            node.entered += 1;
+            tree.dirty = true;
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, _ctx: Context<'_, S>) {
+        let mut tree = lock(&self.tree);
+        if let Some(node) = tree.node_mut(id) {
+            node.entered = node.entered.saturating_sub(1);
+            tree.dirty = true;
+        }
+    }
+
+    fn on_close(&self, id: span::Id, _ctx: Context<'_, S>) {
+        let mut tree = lock(&self.tree);
+        let Some(key) = tree.open.remove(&id) else {
+            return;
+        };
+        if let Some(node) = tree.nodes.get_mut(&key) {
+            node.closed_at = Some(Instant::now());
+            node.entered = 0;
+        }
+        tree.dirty = true;
+    }
+}
@@ -1,9 +1,454 @@
 //! Read [`SysStats`] instead, it's more interesting.
 
-use crate::{CpuStats, Observation};
-use std::collections::VecDeque;
-use tokio::sync::mpsc;
-use tracing::{debug, info, instrument};
+use crate::{
+    CpuStats, DEFAULT_CHANNEL_CAPACITY, DEFAULT_WINDOW, Health, Observation, StatsQuerier,
+    StatsQuery, Window, WindowState,
+    fields::{self, HOST},
+    metrics::{BusiestCoreMetrics, SocketMetrics},
+    report::PipelineCounters,
+};
+use std::{sync::Arc, time::Instant};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, info_span, instrument, warn};
+
+/// The result of a single stats computation by [`SysStats`].
+///
+/// The most recent report is published on a [`watch`] channel. See
+/// [`SysStats::subscribe`].
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct StatsReport {
+    /// The number of observations in the window.
+    pub observations: usize,
+    /// The number of CPUs per observation.
+    pub cpus: usize,
+    /// The average CPU usage percentage over the window.
+    pub average_usage: f64,
+    /// The standard deviation of CPU usage percentage over the window.
+    pub usage_stddev: f64,
+    /// The average CPU frequency in MHz over the window.
+    pub average_freq_mhz: f64,
+    /// The [Pearson correlation] between CPU usage and frequency over every
+    /// sample in the window, from -1 to 1. Zero if either one didn't change
+    /// at all.
+    ///
+    /// A governor that boosts the clock under load gives a positive
+    /// correlation: busy cores run fast. A negative correlation means cores
+    /// slow down when they get busy, which is what thermal throttling, or an
+    /// aggressive power-saving governor, looks like.
+    ///
+    /// [Pearson correlation]: https://en.wikipedia.org/wiki/Pearson_correlation_coefficient
+    pub usage_freq_correlation: f64,
+    /// The number of CPU usage samples in the window in each 10% bucket:
+    /// 0-10% first, 90-100% last. Each bucket includes its lower bound.
+    ///
+    /// The mean hides the shape of the load. An average of 50% could be
+    /// every core at 50%, or half the samples idle and half pegged. The
+    /// buckets tell them apart: one tall bar in the middle, or two at the
+    /// ends.
+    pub usage_buckets: [usize; USAGE_BUCKETS],
+    /// The spread, in percentage points, between the average usage of the
+    /// busiest and the idlest CPU over the window. Zero with a single CPU.
+    pub core_imbalance: f64,
+    /// How fast the average usage is changing, in percentage points per
+    /// minute: the slope of a least-squares line through each observation's
+    /// average usage over the window. Zero until there are two observations.
+    ///
+    /// Noise that comes and goes puts points on both sides of the line, and
+    /// mostly cancels out. A sustained ramp doesn't. A steady positive trend
+    /// is the early warning that something is about to saturate the machine.
+    ///
+    /// Time is measured between the observations arriving at the stats
+    /// processor. A replay at twice the recorded speed reports twice the
+    /// trend.
+    pub usage_trend_per_min: f64,
+    /// The average CPU usage percentage over the longer [baseline window].
+    ///
+    /// [baseline window]: SysStats::with_baseline_window
+    pub baseline_usage: f64,
+    /// The ratio of [`average_usage`] to [`baseline_usage`]. Above 1, the
+    /// machine is busier now than it has been recently. 1 when both are
+    /// zero.
+    ///
+    /// What's normal differs from machine to machine, so a fixed threshold
+    /// on usage either misses a quiet machine waking up, or fires all day on
+    /// a busy one. Comparing against the machine's own recent history works
+    /// for both.
+    ///
+    /// [`average_usage`]: StatsReport::average_usage
+    /// [`baseline_usage`]: StatsReport::baseline_usage
+    pub usage_vs_baseline: f64,
+    /// The busiest CPUs by average usage over the window, busiest first.
+    /// Holds as many as were asked for with [`SysStats::with_busiest_cores`],
+    /// or as there are CPUs, whichever is fewer. The rest are `None`.
+    pub busiest_cores: [Option<CoreUsage>; MAX_BUSIEST_CORES],
+    /// The per-CPU stats of the most recent observation in the window.
+    pub latest: Arc<[CpuStats]>,
+}
+
+/// A CPU's average usage over the window. See
+/// [`StatsReport::busiest_cores`].
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct CoreUsage {
+    /// The name of the CPU.
+    pub name: Arc<str>,
+    /// The CPU's average usage percentage over the window.
+    pub usage: f64,
+}
+
+/// A physical package's average usage over the window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SocketUsage {
+    /// The physical package ID. See [`CpuStats::package`].
+    pub(crate) package: u32,
+    /// The number of CPUs in the package.
+    pub(crate) cpus: usize,
+    /// The average usage percentage of the package's CPUs.
+    pub(crate) usage: f64,
+}
+
+/// The maximum number of busiest cores [`SysStats`] can report. Each is two
+/// more fields on the stats event, and `tracing` events are limited to 32.
+pub const MAX_BUSIEST_CORES: usize = 4;
+
+/// The default number of busiest cores reported. See
+/// [`SysStats::with_busiest_cores`].
+pub const DEFAULT_BUSIEST_CORES: usize = 3;
+
+/// The number of buckets in [`StatsReport::usage_buckets`].
+pub const USAGE_BUCKETS: usize = 10;
+
+/// The default [`StatsReport::core_imbalance`] above which [`SysStats`]
+/// warns. See [`SysStats::with_imbalance_threshold`].
+pub const DEFAULT_IMBALANCE_THRESHOLD: f64 = 50.0;
+
+/// The default number of observations in the baseline window: 10 minutes at
+/// the default 5 second interval. See [`SysStats::with_baseline_window`].
+pub const DEFAULT_BASELINE_WINDOW: usize = 120;
+
+/// What [`SysStats`] does with the window once it's full. See
+/// [`SysStats::with_eviction`].
+///
+/// A sliding window moves one observation at a time, so consecutive reports
+/// share all but one observation, and change smoothly. A tumbling window
+/// starts over once it's full, so that no observation is in two windows.
+/// Paired with a report every `window` observations, see
+/// [`SysStats::with_emit_every`], each report summarizes a batch of its own,
+/// like a rollup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Eviction {
+    /// Evict the oldest observation to make room for each new one.
+    #[default]
+    Sliding,
+    /// Empty the window when an observation arrives and it's full.
+    Tumbling,
+}
+
+impl Eviction {
+    /// The name of the policy, as used in flags and config files.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Sliding => "sliding",
+            Self::Tumbling => "tumbling",
+        }
+    }
+}
+
+/// A callback registered with [`SysStats::on_stats`].
+pub(crate) struct StatsCallback(Box<dyn FnMut(&StatsReport) + Send>);
+
+impl StatsCallback {
+    pub(crate) fn new(callback: impl FnMut(&StatsReport) + Send + 'static) -> Self {
+        Self(Box::new(callback))
+    }
+}
+
+impl std::fmt::Debug for StatsCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StatsCallback")
+    }
+}
+
+/// The index of the 10% bucket that `usage` falls in. 100% goes in the last
+/// bucket.
+fn usage_bucket(usage: f32) -> usize {
+    // Negative and NaN usage saturate to bucket 0.
+    ((usage / 10.0) as usize).min(USAGE_BUCKETS - 1)
+}
+
+/// Running totals over every CPU sample in the window.
+///
+/// Rather than summing the whole window on every observation, which is
+/// `O(window * cores)`, we add each observation's samples when it enters the
+/// window, and subtract them when it is evicted. Each update is `O(cores)`.
+///
+/// Subtracting floats accumulates rounding error over time. Usage values are
+/// small, so in practice the drift is far below anything we display, but it
+/// can make the variance very slightly negative when all samples are equal.
+/// We clamp it to zero.
+#[derive(Debug, Default, Clone)]
+struct RunningSums {
+    samples: usize,
+    usage: f64,
+    usage_sq: f64,
+    freq: f64,
+    freq_sq: f64,
+    usage_freq: f64,
+    usage_buckets: [usize; USAGE_BUCKETS],
+    /// Usage per CPU, by position in the observation. Grows to the number
+    /// of CPUs on the first observation, and never again.
+    core_usage: Vec<f64>,
+}
+
+impl RunningSums {
+    fn add(&mut self, cpus: &[CpuStats]) {
+        self.samples += cpus.len();
+        if self.core_usage.len() < cpus.len() {
+            self.core_usage.resize(cpus.len(), 0.0);
+        }
+        for (core, cpu) in self.core_usage.iter_mut().zip(cpus) {
+            *core += cpu.usage as f64;
+        }
+        for cpu in cpus {
+            let usage = cpu.usage as f64;
+            let freq = cpu.frequency as f64;
+            self.usage += usage;
+            self.usage_sq += usage * usage;
+            self.freq += freq;
+            self.freq_sq += freq * freq;
+            self.usage_freq += usage * freq;
+            self.usage_buckets[usage_bucket(cpu.usage)] += 1;
+        }
+    }
+
+    /// Forget every sample, keeping the allocation of the per-CPU sums.
+    fn clear(&mut self) {
+        let mut core_usage = std::mem::take(&mut self.core_usage);
+        core_usage.fill(0.0);
+        *self = Self {
+            core_usage,
+            ..Self::default()
+        };
+    }
+
+    fn remove(&mut self, cpus: &[CpuStats]) {
+        self.samples -= cpus.len();
+        for (core, cpu) in self.core_usage.iter_mut().zip(cpus) {
+            *core -= cpu.usage as f64;
+        }
+        for cpu in cpus {
+            let usage = cpu.usage as f64;
+            let freq = cpu.frequency as f64;
+            self.usage -= usage;
+            self.usage_sq -= usage * usage;
+            self.freq -= freq;
+            self.freq_sq -= freq * freq;
+            self.usage_freq -= usage * freq;
+            self.usage_buckets[usage_bucket(cpu.usage)] -= 1;
+        }
+    }
+
+    fn average_usage(&self) -> f64 {
+        self.usage / self.samples as f64
+    }
+
+    fn usage_stddev(&self) -> f64 {
+        let mean = self.average_usage();
+        (self.usage_sq / self.samples as f64 - mean * mean)
+            .max(0.0)
+            .sqrt()
+    }
+
+    fn average_freq(&self) -> f64 {
+        self.freq / self.samples as f64
+    }
+
+    /// The Pearson correlation between usage and frequency.
+    ///
+    /// This is the covariance divided by both standard deviations. Each is
+    /// scaled by `samples²` here, which cancels out, and saves dividing.
+    /// Frequencies are whole numbers of MHz, so their sums are exact, and a
+    /// constant frequency has a variance of exactly zero, not rounding noise.
+    ///
+    /// Usage is not so lucky. A constant usage leaves a little noise in its
+    /// variance, which would correlate with anything, so a variance this
+    /// small relative to the sum of squares counts as zero.
+    fn usage_freq_correlation(&self) -> f64 {
+        /// How much of the sum of squares is rounding noise, at most.
+        const EPSILON: f64 = 1e-9;
+
+        let n = self.samples as f64;
+        let covariance = n * self.usage_freq - self.usage * self.freq;
+        let usage_variance = (n * self.usage_sq - self.usage * self.usage).max(0.0);
+        let freq_variance = n * self.freq_sq - self.freq * self.freq;
+        if usage_variance <= EPSILON * n * self.usage_sq || freq_variance <= 0.0 {
+            return 0.0;
+        }
+        (covariance / (usage_variance * freq_variance).sqrt()).clamp(-1.0, 1.0)
+    }
+
+    /// The positions of the busiest and the idlest CPU, and the spread
+    /// between their average usage over `observations`.
+    fn core_imbalance(&self, observations: usize) -> (usize, usize, f64) {
+        let by_usage = |a: &(usize, &f64), b: &(usize, &f64)| a.1.total_cmp(b.1);
+        let busiest = self.core_usage.iter().enumerate().max_by(by_usage);
+        let idlest = self.core_usage.iter().enumerate().min_by(by_usage);
+        match (busiest, idlest) {
+            (Some((busiest, high)), Some((idlest, low))) => {
+                (busiest, idlest, (high - low) / observations as f64)
+            }
+            _ => (0, 0, 0.0),
+        }
+    }
+
+    /// Group the window's usage by the physical package of each CPU in
+    /// `latest`, and write each package's average to `sockets`. CPUs whose
+    /// package is unknown are left out.
+    ///
+    /// `sockets` is reused between calls, so this only allocates the first
+    /// time it sees a package.
+    fn socket_usage(
+        &self,
+        latest: &[CpuStats],
+        observations: usize,
+        sockets: &mut Vec<SocketUsage>,
+    ) {
+        sockets.clear();
+        for (cpu, &usage) in latest.iter().zip(&self.core_usage) {
+            let Some(package) = cpu.package else {
+                continue;
+            };
+            match sockets.iter_mut().find(|socket| socket.package == package) {
+                Some(socket) => {
+                    socket.cpus += 1;
+                    socket.usage += usage;
+                }
+                None => sockets.push(SocketUsage {
+                    package,
+                    cpus: 1,
+                    usage,
+                }),
+            }
+        }
+        for socket in sockets.iter_mut() {
+            socket.usage /= (socket.cpus * observations) as f64;
+        }
+    }
+
+    /// The positions of the `count` busiest CPUs, busiest first.
+    ///
+    /// Picking the busiest, then the busiest of the rest, and so on, is
+    /// `O(count * cores)`. Sorting would be `O(cores * log(cores))`, but
+    /// would need somewhere to sort, and `count` is tiny.
+    fn busiest(&self, count: usize) -> [Option<usize>; MAX_BUSIEST_CORES] {
+        let mut busiest = [None; MAX_BUSIEST_CORES];
+        for rank in 0..count.min(MAX_BUSIEST_CORES) {
+            busiest[rank] = self
+                .core_usage
+                .iter()
+                .enumerate()
+                .filter(|(position, _)| !busiest[..rank].contains(&Some(*position)))
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .map(|(position, _)| position);
+        }
+        busiest
+    }
+}
+
+/// The average usage of each observation in the window, and when it was
+/// taken, for fitting the [usage trend].
+///
+/// Unlike the [`RunningSums`], this is recomputed from scratch on every
+/// observation. Timestamps keep increasing, so running sums of their squares
+/// would grow without bound and lose precision. The window is small, and
+/// the fit is a single pass over it.
+///
+/// [usage trend]: StatsReport::usage_trend_per_min
+#[derive(Debug, Clone)]
+struct Trend {
+    points: Window<(Instant, f64)>,
+}
+
+impl Trend {
+    fn new(window: usize) -> Self {
+        Self {
+            points: Window::new(window),
+        }
+    }
+
+    fn push(&mut self, taken_at: Instant, average_usage: f64) {
+        self.points.push((taken_at, average_usage));
+    }
+
+    fn clear(&mut self) {
+        self.points.clear();
+    }
+
+    fn grow(&mut self, window: usize) {
+        self.points.grow(window);
+    }
+
+    /// The slope of the least-squares line through the points, per minute.
+    fn per_minute(&self) -> f64 {
+        let Some(&(start, _)) = self.points.oldest() else {
+            return 0.0;
+        };
+        // Seconds since the oldest point, so the numbers stay small.
+        let points = || {
+            self.points
+                .iter()
+                .map(move |&(at, usage)| (at.duration_since(start).as_secs_f64(), usage))
+        };
+
+        let n = self.points.len() as f64;
+        let (sum_t, sum_usage) =
+            points().fold((0.0, 0.0), |(t, u), (at, usage)| (t + at, u + usage));
+        let (mean_t, mean_usage) = (sum_t / n, sum_usage / n);
+        let (covariance, variance) = points().fold((0.0, 0.0), |(cov, var), (at, usage)| {
+            let dt = at - mean_t;
+            (cov + dt * (usage - mean_usage), var + dt * dt)
+        });
+
+        // A single point, or every point at the same instant, has no slope.
+        if variance == 0.0 {
+            return 0.0;
+        }
+        covariance / variance * 60.0
+    }
+}
+
+/// The average usage of each observation over the baseline window, with a
+/// running total, like the [`RunningSums`].
+///
+/// Holding the full observations for this long would hold a lot of memory
+/// for a single number. One average per observation is all we need.
+#[derive(Debug, Clone)]
+struct Baseline {
+    averages: Window<f64>,
+    sum: f64,
+}
+
+impl Baseline {
+    fn new(window: usize) -> Self {
+        Self {
+            averages: Window::new(window),
+            sum: 0.0,
+        }
+    }
+
+    fn push(&mut self, average_usage: f64) {
+        self.sum += average_usage;
+        if let Some(evicted) = self.averages.push(average_usage) {
+            self.sum -= evicted;
+        }
+    }
+
+    fn average(&self) -> f64 {
+        // Clamped, for the same rounding drift as the running sums.
+        (self.sum / self.averages.len() as f64).max(0.0)
+    }
+}
 
 /// A simple stats processor.
 pub struct SysStats {
@@ -17,7 +462,44 @@ pub struct SysStats {
     ///
     /// If you see unknown spans in your tracing output, you're likely holding
     /// them somewhere like this.
-    previous_obs: VecDeque<Vec<CpuStats>>,
+    ///
+    /// Instead, we hold the [`Arc`] of CPU stats from each observation. This
+    /// shares the data with the observation we forward downstream, without
+    /// copying it, and without holding its span.
+    previous_obs: Window<Arc<[CpuStats]>>,
+    sums: RunningSums,
+    trend: Trend,
+    baseline: Baseline,
+
+    eviction: Eviction,
+    /// Compute stats every this many observations.
+    emit_every: usize,
+    /// The number of observations since stats were last computed.
+    pending: usize,
+
+    imbalance_threshold: f64,
+    imbalanced: bool,
+
+    busiest_cores: usize,
+    busiest_metrics: BusiestCoreMetrics,
+
+    sockets: Vec<SocketUsage>,
+    socket_metrics: SocketMetrics,
+
+    host: Option<Arc<str>>,
+
+    health: Option<Health>,
+
+    counters: Option<Arc<PipelineCounters>>,
+
+    callbacks: Vec<StatsCallback>,
+
+    reports: watch::Sender<StatsReport>,
+
+    queries: mpsc::Receiver<StatsQuery>,
+    /// Kept so that [`SysStats::querier`] can hand out clones. It also keeps
+    /// the query channel open for as long as the actor runs.
+    querier: StatsQuerier,
 }
 
 impl SysStats {
@@ -26,25 +508,370 @@ impl SysStats {
         inbound: mpsc::Receiver<Observation>,
         outbound: Option<mpsc::Sender<Observation>>,
     ) -> Self {
+        let (query_tx, queries) = mpsc::channel(DEFAULT_CHANNEL_CAPACITY);
         Self {
             inbound,
             outbound,
-            previous_obs: VecDeque::with_capacity(10),
+            previous_obs: Window::new(DEFAULT_WINDOW),
+            sums: RunningSums::default(),
+            trend: Trend::new(DEFAULT_WINDOW),
+            baseline: Baseline::new(DEFAULT_BASELINE_WINDOW),
+            eviction: Eviction::Sliding,
+            emit_every: 1,
+            pending: 0,
+            imbalance_threshold: DEFAULT_IMBALANCE_THRESHOLD,
+            imbalanced: false,
+            busiest_cores: DEFAULT_BUSIEST_CORES,
+            busiest_metrics: BusiestCoreMetrics::default(),
+            sockets: Vec::new(),
+            socket_metrics: SocketMetrics::default(),
+            host: None,
+            health: None,
+            counters: None,
+            callbacks: Vec::new(),
+            reports: watch::Sender::default(),
+            queries,
+            querier: StatsQuerier { queries: query_tx },
         }
     }
 
-    /// Compute stats over previous observations and emit a tracing event.
-    #[instrument(skip(self), name = "Computing stats")]
-    fn run_stats(&self) {
-        let iter = self.previous_obs.iter().flat_map(|obs| obs.iter());
+    /// Compute stats over the last `window` observations, instead of the
+    /// default of [`DEFAULT_WINDOW`].
+    ///
+    /// ## Panics
+    ///
+    /// If `window` is zero.
+    pub fn with_window(mut self, window: usize) -> Self {
+        assert!(window > 0, "stats window must be non-zero");
+        self.previous_obs = Window::new(window);
+        self.trend = Trend::new(window);
+        self
+    }
+
+    /// Compare the window's average usage against the average over the last
+    /// `window` observations, instead of the default of
+    /// [`DEFAULT_BASELINE_WINDOW`]. See [`StatsReport::usage_vs_baseline`].
+    ///
+    /// The baseline should be several times longer than the stats window,
+    /// or the two averages will be too alike to tell anything apart.
+    ///
+    /// ## Panics
+    ///
+    /// If `window` is zero.
+    pub fn with_baseline_window(mut self, window: usize) -> Self {
+        assert!(window > 0, "baseline window must be non-zero");
+        self.baseline = Baseline::new(window);
+        self
+    }
 
-        let count = iter.clone().count() as f64;
+    /// What to do with the window once it's full, instead of the default of
+    /// [`Eviction::Sliding`].
+    pub const fn with_eviction(mut self, eviction: Eviction) -> Self {
+        self.eviction = eviction;
+        self
+    }
 
-        let total_usage: f64 = iter.clone().map(|cpu| cpu.usage as f64).sum();
-        let total_freq: f64 = iter.map(|cpu| cpu.frequency as f64).sum();
+    /// Compute, emit, and publish stats once every `count` observations,
+    /// instead of on every observation. Every observation is still added to
+    /// the window, and forwarded downstream.
+    ///
+    /// At a 1 second interval, a stats event per observation is a lot of
+    /// logs to say that not much changed. Every 10 observations, with a
+    /// window of 10, each event covers its own 10 seconds. The final stats,
+    /// computed when the inbound channel closes, are emitted regardless.
+    ///
+    /// The [`Health`] heartbeat is recorded when stats are published, so
+    /// give it a `max_age` of more than `count` observation intervals.
+    ///
+    /// ## Panics
+    ///
+    /// If `count` is zero.
+    pub fn with_emit_every(mut self, count: usize) -> Self {
+        assert!(count > 0, "stats cadence must be non-zero");
+        self.emit_every = count;
+        self
+    }
 
-        let average_usage = total_usage / count;
-        let average_freq_mhz = total_freq / count;
+    /// Warn when the [core imbalance] rises above `threshold` percentage
+    /// points, instead of the default of [`DEFAULT_IMBALANCE_THRESHOLD`].
+    ///
+    /// A single-threaded bottleneck looks harmless in the averages: one
+    /// core pegged at 100% on an 8-core machine is 12.5% average usage. The
+    /// spread between the busiest and idlest core gives it away. The OS
+    /// moves threads between cores, so a sustained imbalance over the whole
+    /// window is the signal, not a spike in one observation.
+    ///
+    /// [core imbalance]: StatsReport::core_imbalance
+    pub const fn with_imbalance_threshold(mut self, threshold: f64) -> Self {
+        self.imbalance_threshold = threshold;
+        self
+    }
+
+    /// Report the `count` busiest cores in each [`StatsReport`], instead of
+    /// the default of [`DEFAULT_BUSIEST_CORES`]. They are also emitted as
+    /// `busiest_1`, `busiest_1_usage`, and so on, on the stats event, and
+    /// recorded on the `my_cute_app.busiest_core_usage` gauge.
+    ///
+    /// The [core imbalance] says _that_ the load is uneven. The busiest
+    /// cores say _where_ it is, so a dashboard can point right at them.
+    ///
+    /// ## Panics
+    ///
+    /// If `count` is larger than [`MAX_BUSIEST_CORES`].
+    ///
+    /// [core imbalance]: StatsReport::core_imbalance
+    pub fn with_busiest_cores(mut self, count: usize) -> Self {
+        assert!(
+            count <= MAX_BUSIEST_CORES,
+            "at most {MAX_BUSIEST_CORES} busiest cores can be reported"
+        );
+        self.busiest_cores = count;
+        self
+    }
+
+    /// Record a heartbeat in `health` each time stats are computed.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Label the gauges, and the stats events, with `host`, so that the
+    /// stats of several hosts don't collapse into one series. The
+    /// [`Collector`] runs a processor per host, labeled with its host.
+    ///
+    /// Like the CPU names, host names count towards the [label limit]. Past
+    /// it, the gauges are labeled `other`. The events keep the real name.
+    ///
+    /// [`Collector`]: crate::Collector
+    /// [label limit]: crate::set_label_limit
+    pub fn with_host(mut self, host: impl Into<Arc<str>>) -> Self {
+        let host = host.into();
+        self.busiest_metrics = BusiestCoreMetrics::with_host(&host);
+        self.socket_metrics = SocketMetrics::with_host(&host);
+        self.host = Some(host);
+        self
+    }
+
+    /// Count processed observations in `counters`.
+    #[cfg(any(test, feature = "sysinfo"))]
+    pub(crate) fn with_counters(mut self, counters: Arc<PipelineCounters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// Call `callback` with every [`StatsReport`] this processor computes,
+    /// right after computing it, in the `Computing stats` span.
+    ///
+    /// A [subscription] is the way to follow the reports from another task.
+    /// A callback is for the small things that don't deserve a task of their
+    /// own, like setting a flag, or tripping a circuit breaker. It runs on
+    /// the stats task, before the next observation is processed, so it sees
+    /// every report, not just the latest. It also holds up the pipeline for
+    /// as long as it runs, so it must be quick, and must not block. Hand
+    /// anything slow to another task.
+    ///
+    /// Callbacks run in the order they were registered. A callback that
+    /// panics takes the stats task down with it.
+    ///
+    /// ```
+    /// use metrics_tracing_example::SysStats;
+    /// use std::sync::{
+    ///     Arc,
+    ///     atomic::{AtomicBool, Ordering},
+    /// };
+    /// use tokio::sync::mpsc;
+    ///
+    /// let overloaded = Arc::new(AtomicBool::new(false));
+    /// let flag = overloaded.clone();
+    ///
+    /// let (_tx, rx) = mpsc::channel(2);
+    /// let stats = SysStats::new(rx, None).on_stats(move |report| {
+    ///     flag.store(report.average_usage > 90.0, Ordering::Relaxed);
+    /// });
+    /// ```
+    ///
+    /// [subscription]: SysStats::subscribe
+    pub fn on_stats(mut self, callback: impl FnMut(&StatsReport) + Send + 'static) -> Self {
+        self.callbacks.push(StatsCallback::new(callback));
+        self
+    }
+
+    /// Register callbacks collected elsewhere, e.g. by the
+    /// [`PipelineBuilder`].
+    ///
+    /// [`PipelineBuilder`]: crate::PipelineBuilder
+    #[cfg(feature = "sysinfo")]
+    pub(crate) fn with_callbacks(mut self, callbacks: Vec<StatsCallback>) -> Self {
+        self.callbacks.extend(callbacks);
+        self
+    }
+
+    /// Subscribe to the [`StatsReport`]s computed by this processor.
+    ///
+    /// A [`watch`] channel only holds the most recent value. Slow receivers
+    /// skip intermediate reports, rather than building up a backlog. This is
+    /// exactly what we want for a UI, which only ever needs to draw the
+    /// current state.
+    pub fn subscribe(&self) -> watch::Receiver<StatsReport> {
+        self.reports.subscribe()
+    }
+
+    /// Get a [`StatsQuerier`], to ask this processor for the current state of
+    /// its window.
+    pub fn querier(&self) -> StatsQuerier {
+        self.querier.clone()
+    }
+
+    /// Answer a [`StatsQuery`] with the current state of the window.
+    fn answer(&self, query: StatsQuery) {
+        let state = WindowState {
+            capacity: self.previous_obs.capacity(),
+            observations: self.previous_obs.iter().cloned().collect(),
+            report: self.reports.borrow().clone(),
+        };
+        // The asker gave up waiting. That's their business, not ours.
+        let _ = query.reply.send(state);
+    }
+
+    /// Add an observation's stats to the window. If the window is full, the
+    /// oldest is evicted, or the window is emptied first, depending on the
+    /// [`Eviction`] policy.
+    ///
+    /// An observation without CPUs has no average usage, only a NaN that
+    /// would poison the running sums for good. It is skipped, and `false`
+    /// returned.
+    fn push(&mut self, cpus: Arc<[CpuStats]>, taken_at: Instant) -> bool {
+        if cpus.is_empty() {
+            debug!("skipping an observation without CPUs");
+            return false;
+        }
+        if self.eviction == Eviction::Tumbling && self.previous_obs.is_full() {
+            self.previous_obs.clear();
+            self.trend.clear();
+            self.sums.clear();
+        }
+        let average = cpus.iter().map(|cpu| cpu.usage as f64).sum::<f64>() / cpus.len() as f64;
+        self.trend.push(taken_at, average);
+        self.baseline.push(average);
+        self.sums.add(&cpus);
+        if let Some(evicted) = self.previous_obs.push(cpus) {
+            self.sums.remove(&evicted);
+        }
+        true
+    }
+
+    /// Process a single observation: add it to the window, compute and
+    /// publish the stats, and record that it was processed.
+    ///
+    /// This runs once per observation, so it must not allocate. The window
+    /// is preallocated to its full size, and holds shared handles to the
+    /// observations' stats rather than copies. The running sums are plain
+    /// floats, and the report shares the latest stats with the window. The
+    /// allocation-counting test below keeps it that way.
+    fn process(&mut self, obs: &Observation) {
+        obs.span().in_scope(|| self.observe(obs));
+
+        if let Some(counters) = &self.counters {
+            counters.record_processed(obs.taken_at().elapsed());
+        }
+    }
+
+    /// Add an observation to the window, and compute and publish the stats if
+    /// they are due, in the current span. The [`Collector`] calls this directly, to run
+    /// one window per host, and one for the whole fleet, in a single task.
+    ///
+    /// [`Collector`]: crate::Collector
+    pub(crate) fn observe(&mut self, obs: &Observation) {
+        if let Some(gap) = obs.gap_before() {
+            info!(
+                gap_ms = gap.as_millis() as u64,
+                dropped = self.previous_obs.len(),
+                "emptying the stats window after a gap"
+            );
+            self.resize_window(self.previous_obs.capacity());
+        }
+        if !self.push(obs.cpus().clone(), obs.taken_at()) {
+            return;
+        }
+        self.pending += 1;
+        if self.pending >= self.emit_every {
+            self.pending = 0;
+            self.run_stats();
+            if let Some(health) = &self.health {
+                health.stats_beat();
+            }
+        }
+    }
+
+    /// Replace the window with an empty one of `window` observations,
+    /// forgetting everything seen so far, except for the baseline. The
+    /// [`SysStats::with_emit_every`] count starts over too.
+    pub(crate) fn resize_window(&mut self, window: usize) {
+        self.previous_obs = Window::new(window);
+        self.trend = Trend::new(window);
+        self.sums = RunningSums::default();
+        self.pending = 0;
+    }
+
+    /// Make room for `window` observations, keeping the ones already in the
+    /// window. A smaller `window` does nothing.
+    pub(crate) fn grow_window(&mut self, window: usize) {
+        self.previous_obs.grow(window);
+        self.trend.grow(window);
+    }
+
+    /// The most recently published report.
+    pub(crate) fn latest_report(&self) -> StatsReport {
+        self.reports.borrow().clone()
+    }
+
+    /// Send an observation downstream, if there's anyone left to send it to.
+    pub(crate) async fn forward(&mut self, obs: Observation) {
+        if let Some(outbound) = &self.outbound
+            && outbound.send(obs).await.is_err()
+        {
+            debug!("Outbound receiver dropped, stopping forwarding");
+            self.outbound = None;
+        }
+    }
+
+    /// Compute stats over previous observations, emit a tracing event, and
+    /// publish a [`StatsReport`].
+    #[instrument(skip(self), name = "Computing stats")]
+    fn run_stats(&mut self) {
+        let (busiest, idlest, core_imbalance) = self.sums.core_imbalance(self.previous_obs.len());
+        let average_usage = self.sums.average_usage();
+        let baseline_usage = self.baseline.average();
+        let latest = self.previous_obs.latest().cloned().unwrap_or_default();
+        let busiest_positions = self.sums.busiest(self.busiest_cores);
+        let busiest_cores = busiest_positions.map(|position| {
+            let position = position?;
+            Some(CoreUsage {
+                name: latest.get(position)?.name.clone(),
+                usage: self.sums.core_usage[position] / self.previous_obs.len() as f64,
+            })
+        });
+        let report = StatsReport {
+            observations: self.previous_obs.len(),
+            cpus: self.sums.samples / self.previous_obs.len(),
+            average_usage,
+            usage_stddev: self.sums.usage_stddev(),
+            average_freq_mhz: self.sums.average_freq(),
+            usage_freq_correlation: self.sums.usage_freq_correlation(),
+            usage_buckets: self.sums.usage_buckets,
+            core_imbalance,
+            usage_trend_per_min: self.trend.per_minute(),
+            baseline_usage,
+            // The baseline covers the window too, so it is only zero if the
+            // window's usage is zero as well.
+            usage_vs_baseline: if baseline_usage > 0.0 {
+                average_usage / baseline_usage
+            } else {
+                1.0
+            },
+            busiest_cores,
+            latest,
+        };
 
         // Attaching fields puts structured data into your tracing
         // event, which may then be automatically parsed by your collector or
@@ -59,35 +886,606 @@ impl SysStats {
         //     "{} observations, {} CPUs: avg usage {:.2}%, avg freq {:.2}MHz",
         //     self.previous_obs.len(),
         // ```
+        //
+        // The same goes for collections. Each bucket gets a field of its
+        // own, rather than `buckets = ?report.usage_buckets`, so that
+        // backends can query and chart them individually.
+        //
+        // Missing busiest cores, and the host of a processor without one,
+        // are `None`, and `None` fields are left out of the event entirely.
+        let [b0, b1, b2, b3, b4, b5, b6, b7, b8, b9] = report.usage_buckets;
+        let name = |rank: usize| report.busiest_cores[rank].as_ref().map(|core| &*core.name);
+        let usage = |rank: usize| report.busiest_cores[rank].as_ref().map(|core| core.usage);
         info!(
-            count = self.previous_obs.len(),
-            cpus = count / self.previous_obs.len() as f64,
-            average_usage,
-            average_freq_mhz,
+            { HOST } = self.host.as_deref(),
+            { fields::stats::OBSERVATIONS } = report.observations,
+            { fields::stats::CPUS } = report.cpus,
+            { fields::stats::AVERAGE_USAGE } = report.average_usage,
+            { fields::stats::USAGE_STDDEV } = report.usage_stddev,
+            { fields::stats::AVERAGE_FREQ_MHZ } = report.average_freq_mhz,
+            { fields::stats::USAGE_FREQ_CORRELATION } = report.usage_freq_correlation,
+            { fields::stats::USAGE_TREND_PER_MIN } = report.usage_trend_per_min,
+            { fields::stats::BASELINE_USAGE } = report.baseline_usage,
+            { fields::stats::USAGE_VS_BASELINE } = report.usage_vs_baseline,
+            { fields::stats::USAGE_BUCKETS[0] } = b0,
+            { fields::stats::USAGE_BUCKETS[1] } = b1,
+            { fields::stats::USAGE_BUCKETS[2] } = b2,
+            { fields::stats::USAGE_BUCKETS[3] } = b3,
+            { fields::stats::USAGE_BUCKETS[4] } = b4,
+            { fields::stats::USAGE_BUCKETS[5] } = b5,
+            { fields::stats::USAGE_BUCKETS[6] } = b6,
+            { fields::stats::USAGE_BUCKETS[7] } = b7,
+            { fields::stats::USAGE_BUCKETS[8] } = b8,
+            { fields::stats::USAGE_BUCKETS[9] } = b9,
+            { fields::stats::BUSIEST[0] } = name(0),
+            { fields::stats::BUSIEST_USAGE[0] } = usage(0),
+            { fields::stats::BUSIEST[1] } = name(1),
+            { fields::stats::BUSIEST_USAGE[1] } = usage(1),
+            { fields::stats::BUSIEST[2] } = name(2),
+            { fields::stats::BUSIEST_USAGE[2] } = usage(2),
+            { fields::stats::BUSIEST[3] } = name(3),
+            { fields::stats::BUSIEST_USAGE[3] } = usage(3),
             "finished cpu stats"
         );
+
+        self.busiest_metrics
+            .record(&busiest_positions, &report.busiest_cores);
+        self.run_socket_stats(&report);
+
+        self.check_imbalance(&report, busiest, idlest);
+        for StatsCallback(callback) in &mut self.callbacks {
+            callback(&report);
+        }
+        self.reports.send_replace(report);
+    }
+
+    /// Compute the average usage of each physical package, i.e. socket, over
+    /// the window, emit a tracing event per socket, and record them on the
+    /// `my_cute_app.socket_usage` gauge.
+    ///
+    /// On a multi-socket server, each socket has its own memory and caches.
+    /// A process pinned to one socket can saturate it while the machine
+    /// average looks fine. Most machines have a single socket, where its
+    /// average is the machine average, so the events are skipped.
+    fn run_socket_stats(&mut self, report: &StatsReport) {
+        self.sums
+            .socket_usage(&report.latest, report.observations, &mut self.sockets);
+
+        if self.sockets.len() > 1 {
+            for socket in &self.sockets {
+                info!(
+                    socket = socket.package,
+                    { fields::stats::CPUS } = socket.cpus,
+                    { fields::stats::AVERAGE_USAGE } = socket.usage,
+                    "finished socket stats"
+                );
+            }
+        }
+        self.socket_metrics.record(&self.sockets);
+    }
+
+    /// Warn when the core imbalance rises above the threshold, and say so
+    /// when it falls back below. Warning on every observation instead would
+    /// bury everything else in the logs for as long as the imbalance lasts.
+    fn check_imbalance(&mut self, report: &StatsReport, busiest: usize, idlest: usize) {
+        let imbalanced = report.core_imbalance > self.imbalance_threshold;
+        if imbalanced == self.imbalanced {
+            return;
+        }
+        self.imbalanced = imbalanced;
+
+        if imbalanced {
+            let name = |position: usize| report.latest.get(position).map(|cpu| &*cpu.name);
+            warn!(
+                core_imbalance = report.core_imbalance,
+                threshold = self.imbalance_threshold,
+                busiest = name(busiest),
+                idlest = name(idlest),
+                "CPU load is imbalanced across cores, is a single thread the bottleneck?"
+            );
+        } else {
+            info!(
+                core_imbalance = report.core_imbalance,
+                threshold = self.imbalance_threshold,
+                "CPU load is balanced across cores again"
+            );
+        }
     }
 
     /// Spawn the stats processor task.
+    ///
+    /// The task runs until the inbound channel is closed, i.e. until the
+    /// monitor exits. While it waits for observations, it answers
+    /// [`StatsQuery`]s. Closing a tokio channel does not discard messages that
+    /// are already queued, so the task drains them before it sees the close.
+    /// It then computes the stats one final time, and drops the outbound
+    /// sender. Downstream receivers therefore see every observation before
+    /// they see the channel close.
+    ///
+    /// ## Channels
+    ///
+    /// The inbound channel may have any number of senders: a [`SysMonitor`],
+    /// a network source, a replay, or several of them at once. The task
+    /// exits once all of them have been dropped. Every observation received
+    /// is forwarded to the outbound channel, if there is one, after its stats
+    /// are computed, waiting for room, so a slow receiver holds up the stats
+    /// processor, and the monitor behind it. Dropping the outbound receiver
+    /// is fine: the task stops forwarding, and carries on computing stats.
+    ///
+    /// See [`SysMonitor::spawn`] for the two wired together by hand.
+    ///
+    /// [`SysMonitor`]: crate::SysMonitor
+    /// [`SysMonitor::spawn`]: crate::SysMonitor::spawn
     pub fn spawn(mut self) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
-            while let Some(obs) = self.inbound.recv().await {
-                obs.span().in_scope(|| {
-                    if self.previous_obs.len() == 10 {
-                        self.previous_obs.pop_front();
+            loop {
+                // We hold a sender, so the query channel never closes, and
+                // only the inbound channel closing ends the loop. Queries
+                // are answered between observations, never in the middle of
+                // processing one. Observations go first, so that a flood of
+                // queries can't stall the pipeline, and so that an answer
+                // includes everything sent before the question.
+                let obs = tokio::select! {
+                    biased;
+                    obs = self.inbound.recv() => obs,
+                    Some(query) = self.queries.recv() => {
+                        debug!("Answering stats query");
+                        self.answer(query);
+                        continue;
                     }
-                    self.previous_obs.push_back((*obs).clone());
+                };
+                let Some(obs) = obs else {
+                    break;
+                };
+                self.process(&obs);
+                self.forward(obs).await;
+            }
 
+            if !self.previous_obs.is_empty() {
+                info_span!("Final stats").in_scope(|| {
+                    debug!(
+                        window = self.previous_obs.len(),
+                        "Inbound channel closed, computing final stats"
+                    );
                     self.run_stats();
                 });
-
-                if let Some(outbound) = &mut self.outbound
-                    && outbound.send(obs).await.is_err()
-                {
-                    debug!("Outbound receiver dropped, stopping forwarding");
-                    break;
-                }
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CountingAllocator,
+        testing::{EventMatcher, observation},
+    };
+    use std::time::Duration;
+
+    /// Counts allocations made by each thread, so that tests running in
+    /// parallel don't see each other's allocations.
+    #[global_allocator]
+    static GLOBAL: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> u64 {
+        CountingAllocator::thread()
+    }
+
+    /// No tracing subscriber is installed, so this measures our own code,
+    /// not the cost of formatting events.
+    #[test]
+    fn process_does_not_allocate() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None)
+            .with_window(8)
+            .with_health(Health::new(Duration::from_secs(1)))
+            .with_counters(Arc::default());
+        let reports = stats.subscribe();
+
+        let observations: Vec<_> = (0..64).map(|i| observation(4, i as f32)).collect();
+
+        // Fill the window, so that every tick below evicts an observation.
+        for obs in &observations[..8] {
+            stats.process(obs);
+        }
+
+        let before = allocations();
+        for obs in &observations[8..] {
+            stats.process(obs);
+        }
+        let allocated = allocations() - before;
+
+        assert_eq!(allocated, 0, "stats hot path allocated {allocated} times");
+        assert_eq!(reports.borrow().observations, 8);
+    }
+
+    #[test]
+    fn usage_buckets_follow_the_window() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None).with_window(2);
+        let reports = stats.subscribe();
+
+        stats.process(&observation(4, 5.0));
+        stats.process(&observation(4, 100.0));
+        let buckets = reports.borrow().usage_buckets;
+        assert_eq!(buckets[0], 4);
+        assert_eq!(buckets[9], 4);
+
+        // Evicts the 5% observation.
+        stats.process(&observation(4, 42.0));
+        let buckets = reports.borrow().usage_buckets;
+        assert_eq!(buckets, [0, 0, 0, 0, 4, 0, 0, 0, 0, 4]);
+    }
+
+    #[test]
+    fn observations_without_cpus_are_skipped() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None).with_window(4);
+        let reports = stats.subscribe();
+
+        stats.process(&observation(4, 10.0));
+        stats.process(&Observation::new(Vec::new(), tracing::Span::none()));
+        stats.process(&observation(4, 30.0));
+
+        let report = reports.borrow();
+        assert_eq!(report.observations, 2);
+        assert_eq!(report.average_usage, 20.0);
+        assert_eq!(report.baseline_usage, 20.0);
+    }
+
+    #[test]
+    fn gaps_empty_the_window() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None).with_window(4);
+        let reports = stats.subscribe();
+
+        stats.process(&observation(4, 10.0));
+        stats.process(&observation(4, 20.0));
+        stats.process(&observation(4, 90.0).with_gap_before(Duration::from_secs(3600)));
+
+        let report = reports.borrow();
+        assert_eq!(report.observations, 1);
+        assert_eq!(report.average_usage, 90.0);
+        // The baseline spans the gap.
+        assert_eq!(report.baseline_usage, 40.0);
+    }
+
+    #[test]
+    fn gaps_restart_the_cadence() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None).with_window(4).with_emit_every(2);
+        let reports = stats.subscribe();
+
+        stats.process(&observation(4, 10.0));
+        stats.process(&observation(4, 90.0).with_gap_before(Duration::from_secs(3600)));
+        // A single observation since the gap isn't a report's worth.
+        assert_eq!(reports.borrow().observations, 0);
+
+        stats.process(&observation(4, 30.0));
+        let report = reports.borrow();
+        assert_eq!(report.observations, 2);
+        assert_eq!(report.average_usage, 60.0);
+    }
+
+    #[test]
+    fn tumbling_windows_report_once_per_batch() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None)
+            .with_window(2)
+            .with_eviction(Eviction::Tumbling)
+            .with_emit_every(2);
+        let reports = stats.subscribe();
+
+        stats.process(&observation(4, 10.0));
+        assert_eq!(reports.borrow().observations, 0);
+        stats.process(&observation(4, 20.0));
+        assert_eq!(reports.borrow().average_usage, 15.0);
+
+        // The next batch shares nothing with the first.
+        stats.process(&observation(4, 50.0));
+        stats.process(&observation(4, 70.0));
+        let report = reports.borrow();
+        assert_eq!(report.observations, 2);
+        assert_eq!(report.average_usage, 60.0);
+        assert_eq!(report.usage_buckets[1], 0);
+    }
+
+    #[test]
+    fn callbacks_see_every_report() {
+        let (_tx, rx) = mpsc::channel(1);
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let mut stats = SysStats::new(rx, None)
+            .with_window(2)
+            .on_stats(move |report| log.lock().unwrap().push(report.average_usage));
+
+        for usage in [10.0, 30.0, 50.0] {
+            stats.process(&observation(4, usage));
+        }
+        assert_eq!(*seen.lock().unwrap(), [10.0, 20.0, 40.0]);
+    }
+
+    #[test]
+    fn core_imbalance_averages_over_the_window() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None)
+            .with_window(2)
+            .with_imbalance_threshold(30.0);
+        let reports = stats.subscribe();
+
+        // One busy core, three idle ones.
+        let skewed = |busy: f32| {
+            let cpus: Arc<[CpuStats]> = (0..4)
+                .map(|i| CpuStats {
+                    name: format!("cpu{i}").into(),
+                    usage: if i == 0 { busy } else { 0.0 },
+                    frequency: 2_000,
+                    times: None,
+                    package: None,
+                })
+                .collect();
+            Observation::new(cpus, tracing::Span::none())
+        };
+
+        stats.process(&skewed(100.0));
+        assert_eq!(reports.borrow().core_imbalance, 100.0);
+        assert!(stats.imbalanced);
+
+        stats.process(&skewed(0.0));
+        assert_eq!(reports.borrow().core_imbalance, 50.0);
+
+        // The busy observation is evicted.
+        stats.process(&skewed(0.0));
+        assert_eq!(reports.borrow().core_imbalance, 0.0);
+        assert!(!stats.imbalanced);
+    }
+
+    #[tokio::test]
+    async fn queries_see_the_window() {
+        let (tx, rx) = mpsc::channel(1);
+        let stats = SysStats::new(rx, None).with_window(2);
+        let querier = stats.querier();
+        let handle = stats.spawn();
+
+        for usage in [10.0, 20.0, 30.0] {
+            tx.send(observation(4, usage)).await.unwrap();
+        }
+        // Observations already in the channel are processed before the
+        // query, so it sees all three.
+        let state = querier.window().await.expect("stats actor is running");
+        assert_eq!(state.capacity, 2);
+        let usage: Vec<_> = state
+            .observations
+            .iter()
+            .map(|cpus| cpus[0].usage)
+            .collect();
+        assert_eq!(usage, [20.0, 30.0]);
+        assert_eq!(state.report.average_usage, 25.0);
+
+        drop(tx);
+        handle.await.unwrap();
+        assert!(querier.window().await.is_none());
+    }
+
+    #[test]
+    fn socket_usage_groups_cpus_by_package() {
+        let with_packages = |usage: f32| -> Arc<[CpuStats]> {
+            [Some(0), Some(1), Some(0), None]
+                .into_iter()
+                .enumerate()
+                .map(|(i, package)| CpuStats {
+                    name: format!("cpu{i}").into(),
+                    usage: if package == Some(1) { usage } else { 10.0 },
+                    frequency: 2_000,
+                    times: None,
+                    package,
+                })
+                .collect()
+        };
+
+        let mut sums = RunningSums::default();
+        sums.add(&with_packages(50.0));
+        sums.add(&with_packages(100.0));
+
+        let mut sockets = Vec::new();
+        sums.socket_usage(&with_packages(100.0), 2, &mut sockets);
+        assert_eq!(
+            sockets,
+            [
+                SocketUsage {
+                    package: 0,
+                    cpus: 2,
+                    usage: 10.0
+                },
+                SocketUsage {
+                    package: 1,
+                    cpus: 1,
+                    usage: 75.0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn busiest_cores_are_ranked_over_the_window() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None).with_window(2).with_busiest_cores(2);
+        let reports = stats.subscribe();
+
+        let with_usage = |usage: [f32; 4]| {
+            let cpus: Arc<[CpuStats]> = usage
+                .iter()
+                .enumerate()
+                .map(|(i, &usage)| CpuStats {
+                    name: format!("cpu{i}").into(),
+                    usage,
+                    frequency: 2_000,
+                    times: None,
+                    package: None,
+                })
+                .collect();
+            Observation::new(cpus, tracing::Span::none())
+        };
+        let busiest = |report: &StatsReport| {
+            report
+                .busiest_cores
+                .iter()
+                .map(|core| {
+                    core.as_ref()
+                        .map(|core| (core.name.to_string(), core.usage))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        stats.process(&with_usage([10.0, 80.0, 30.0, 50.0]));
+        assert_eq!(
+            busiest(&reports.borrow()),
+            [
+                Some(("cpu1".into(), 80.0)),
+                Some(("cpu3".into(), 50.0)),
+                None,
+                None
+            ]
+        );
+
+        // cpu1 spiked, but cpu2 is busier on average.
+        stats.process(&with_usage([10.0, 0.0, 90.0, 50.0]));
+        assert_eq!(
+            busiest(&reports.borrow()),
+            [
+                Some(("cpu2".into(), 60.0)),
+                Some(("cpu3".into(), 50.0)),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn usage_freq_correlation_detects_throttling() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None).with_window(3);
+        let reports = stats.subscribe();
+
+        let with_freq = |usage: f32, frequency: u64| {
+            let cpus: Arc<[CpuStats]> = (0..4)
+                .map(|i| CpuStats {
+                    name: format!("cpu{i}").into(),
+                    usage,
+                    frequency,
+                    times: None,
+                    package: None,
+                })
+                .collect();
+            Observation::new(cpus, tracing::Span::none())
+        };
+
+        // The frequency never changed, there is nothing to correlate.
+        stats.process(&observation(4, 10.0));
+        stats.process(&observation(4, 90.0));
+        assert_eq!(reports.borrow().usage_freq_correlation, 0.0);
+
+        // Busier, slower.
+        stats.process(&with_freq(10.0, 3_000));
+        stats.process(&with_freq(50.0, 2_000));
+        stats.process(&with_freq(90.0, 1_000));
+        assert!((reports.borrow().usage_freq_correlation + 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn stats_event_uses_the_standard_fields() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None);
+
+        let captured = crate::testing::with_captured_events(|| {
+            stats.process(&observation(4, 10.0));
+        });
+        captured.assert_event(
+            &EventMatcher::new()
+                .with_message("finished cpu stats")
+                .with_field(fields::stats::OBSERVATIONS, 1)
+                .with_field(fields::stats::CPUS, 4)
+                .with_field(fields::stats::AVERAGE_USAGE, "10.0")
+                .with_field(fields::stats::USAGE_BUCKETS[1], 4)
+                .with_field(fields::stats::BUSIEST_USAGE[0], "10.0"),
+        );
+    }
+
+    #[test]
+    fn constant_usage_has_no_correlation() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None).with_window(3);
+        let reports = stats.subscribe();
+
+        let with_freq = |usage: f32, frequency: u64| {
+            let cpus: Vec<CpuStats> = observation(4, usage)
+                .cpus()
+                .iter()
+                .map(|cpu| CpuStats {
+                    frequency,
+                    ..cpu.clone()
+                })
+                .collect();
+            Observation::new(cpus, tracing::Span::none())
+        };
+
+        // These leave some rounding error behind in the running sums.
+        stats.process(&with_freq(0.1, 2_000));
+        stats.process(&with_freq(1e-5, 2_000));
+        stats.process(&with_freq(33.3, 3_000));
+        for frequency in [1_000, 2_000, 3_000] {
+            stats.process(&with_freq(42.42, frequency));
+        }
+        assert_eq!(reports.borrow().usage_freq_correlation, 0.0);
+    }
+
+    #[test]
+    fn baseline_spans_a_longer_window() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut stats = SysStats::new(rx, None)
+            .with_window(1)
+            .with_baseline_window(4);
+        let reports = stats.subscribe();
+
+        stats.process(&observation(4, 0.0));
+        assert_eq!(reports.borrow().usage_vs_baseline, 1.0);
+
+        for _ in 0..3 {
+            stats.process(&observation(4, 10.0));
+        }
+        assert_eq!(reports.borrow().baseline_usage, 7.5);
+
+        // Evicts the idle observation from the baseline.
+        stats.process(&observation(4, 40.0));
+        let report = reports.borrow();
+        assert_eq!(report.baseline_usage, 17.5);
+        assert_eq!(report.usage_vs_baseline, 40.0 / 17.5);
+    }
+
+    #[test]
+    fn trend_fits_a_line_through_the_window() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut trend = Trend::new(4);
+        assert_eq!(trend.per_minute(), 0.0);
+
+        trend.push(at(0), 10.0);
+        assert_eq!(trend.per_minute(), 0.0);
+
+        // 1 point per second is 60 points per minute.
+        trend.push(at(1), 11.0);
+        trend.push(at(2), 12.0);
+        assert!((trend.per_minute() - 60.0).abs() < 1e-9);
+
+        // Points off the line pull it towards them.
+        trend.push(at(3), 43.0);
+        assert!((trend.per_minute() - 600.0).abs() < 1e-9);
+
+        // Evicts the first point, and levels off.
+        for secs in 4..8 {
+            trend.push(at(secs), 20.0);
+        }
+        assert_eq!(trend.per_minute(), 0.0);
+    }
+}
@@ -0,0 +1,90 @@
+//! Observations as a [`Stream`]. See [`ObservationStream`].
+
+use crate::{CpuData, Observation, ObservationData};
+use futures_core::Stream;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+/// A [`Stream`] of the observations sent to an outbound channel.
+///
+/// A channel is a perfectly good way to consume observations, one at a time.
+/// Anything fancier, like filtering, batching, or rate limiting, means
+/// writing the loop by hand. Streams come with those loops already written,
+/// as combinators in `StreamExt`, from `tokio-stream` or `futures`. This
+/// wraps the outbound receiver, so they all work on observations.
+///
+/// Streams of any [`ObservationData`] work the same way, e.g.
+/// `ObservationStream<QueueDepth>` for observations of a queue's depth.
+///
+/// Each observation still carries its span. Combinators that run a closure
+/// don't enter it for you, so use [`Observation::in_scope`] in the closure
+/// to log within the observation's trace.
+///
+/// ```no_run
+/// use metrics_tracing_example::{ObservationStream, run_observations};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+/// use tokio_stream::StreamExt;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(2);
+/// let _pipeline = run_observations(Duration::from_secs(1), Some(tx));
+///
+/// // Batches of busy observations, at most 10 at a time, every 30 seconds.
+/// let batches = ObservationStream::new(rx)
+///     .filter(|obs| obs.iter().any(|cpu| cpu.usage > 80.0))
+///     .chunks_timeout(10, Duration::from_secs(30));
+/// tokio::pin!(batches);
+/// while let Some(batch) = batches.next().await {
+///     tracing::info!(busy = batch.len(), "busy observations");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ObservationStream<T: ObservationData = CpuData> {
+    inbound: mpsc::Receiver<Observation<T>>,
+}
+
+impl<T: ObservationData> ObservationStream<T> {
+    /// Wrap `inbound`, e.g. the receiving end of a pipeline's outbound
+    /// channel.
+    pub const fn new(inbound: mpsc::Receiver<Observation<T>>) -> Self {
+        Self { inbound }
+    }
+
+    /// Close the channel, without dropping the observations already in it.
+    /// The stream ends once they have been taken. See
+    /// [`mpsc::Receiver::close`].
+    pub fn close(&mut self) {
+        self.inbound.close();
+    }
+
+    /// Unwrap the receiver.
+    pub fn into_inner(self) -> mpsc::Receiver<Observation<T>> {
+        self.inbound
+    }
+}
+
+impl<T: ObservationData> From<mpsc::Receiver<Observation<T>>> for ObservationStream<T> {
+    fn from(inbound: mpsc::Receiver<Observation<T>>) -> Self {
+        Self::new(inbound)
+    }
+}
+
+impl<T: ObservationData> Stream for ObservationStream<T> {
+    type Item = Observation<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inbound.poll_recv(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // At least what is already queued. There is no telling how many
+        // more will come.
+        (self.inbound.len(), None)
+    }
+}
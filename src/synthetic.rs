@@ -0,0 +1,297 @@
+//! Made-up observations, for demos. See [`SyntheticMonitor`].
+
+use crate::{
+    CpuStats, Observation,
+    fields::{self, OBSERVATION_ID},
+};
+use std::{f64::consts::TAU, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span};
+
+/// The frequency of every synthetic CPU, in MHz.
+const SYNTHETIC_FREQUENCY: u64 = 2_000;
+
+/// The shape of the CPU usage a [`SyntheticMonitor`] makes up. Usages are
+/// percentages, and are clamped to 0–100.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadPattern {
+    /// Usage rises from halfway between `min` and `max` to `max`, falls to
+    /// `min`, and rises back, once every `period`. A daily traffic cycle,
+    /// sped up.
+    Sine {
+        /// The lowest usage.
+        min: f32,
+        /// The highest usage.
+        max: f32,
+        /// How long one full cycle takes.
+        period: Duration,
+    },
+    /// Usage holds at `low` for half of every `period`, then at `high` for
+    /// the other half. A batch job starting and stopping.
+    Step {
+        /// The usage in the first half of each period.
+        low: f32,
+        /// The usage in the second half of each period.
+        high: f32,
+        /// How long one low and one high take, together.
+        period: Duration,
+    },
+    /// Usage sits at `base`, and jumps to `peak` for `duration`, at the
+    /// start of every `every`. What alerts should catch.
+    Spike {
+        /// The usage between spikes.
+        base: f32,
+        /// The usage during a spike.
+        peak: f32,
+        /// How often a spike starts.
+        every: Duration,
+        /// How long each spike lasts.
+        duration: Duration,
+    },
+}
+
+impl LoadPattern {
+    /// The pattern's name, for the observation spans, e.g. `sine`.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sine { .. } => "sine",
+            Self::Step { .. } => "step",
+            Self::Spike { .. } => "spike",
+        }
+    }
+
+    /// The usage `elapsed` into the pattern.
+    pub fn usage_at(&self, elapsed: Duration) -> f32 {
+        let usage = match *self {
+            Self::Sine { min, max, period } => {
+                let phase = elapsed.as_secs_f64() / period.as_secs_f64().max(f64::EPSILON);
+                let (mid, amplitude) = ((min + max) / 2.0, (max - min) / 2.0);
+                mid + amplitude * (phase * TAU).sin() as f32
+            }
+            Self::Step { low, high, period } => {
+                let half = (period / 2).as_nanos().max(1);
+                if (elapsed.as_nanos() / half).is_multiple_of(2) {
+                    low
+                } else {
+                    high
+                }
+            }
+            Self::Spike {
+                base,
+                peak,
+                every,
+                duration,
+            } => {
+                if elapsed.as_nanos() % every.as_nanos().max(1) < duration.as_nanos() {
+                    peak
+                } else {
+                    base
+                }
+            }
+        };
+        usage.clamp(0.0, 100.0)
+    }
+}
+
+/// A stand-in for the [`SysMonitor`] that makes observations up, following
+/// a [`LoadPattern`], instead of reading the machine.
+///
+/// Demoing an alert on a real machine means stressing it, and hoping it
+/// crosses the threshold while the audience watches. A synthetic monitor
+/// crosses it on cue, every time. Its observations go through the same
+/// stats, sinks, and, with the `sysinfo` feature, the same metrics, as real
+/// ones, so everything downstream can be shown off deterministically.
+///
+/// The pattern is followed by tick, not by the wall clock: observation `n`
+/// gets the usage `n` intervals into the pattern, however late it's taken.
+/// So two runs make the same observations. Every CPU gets the same usage,
+/// at a frequency of 2000 MHz.
+///
+/// It keeps the [`SysMonitor`]'s channel contract. It sends one observation
+/// per tick, each with an `Observation` span, and a `pattern` field. It
+/// exits, and drops its sender, when the [shutdown token] is cancelled, or
+/// when the receiver is dropped.
+///
+/// ```no_run
+/// use metrics_tracing_example::{LoadPattern, SyntheticMonitor, SysStats};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(2);
+/// let pattern = LoadPattern::Spike {
+///     base: 20.0,
+///     peak: 95.0,
+///     every: Duration::from_secs(30),
+///     duration: Duration::from_secs(5),
+/// };
+/// let _monitor = SyntheticMonitor::new(pattern, Duration::from_secs(1), tx)
+///     .with_cpus(4)
+///     .spawn();
+/// let _stats = SysStats::new(rx, None).spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`SysMonitor`]: crate::SysMonitor
+/// [shutdown token]: SyntheticMonitor::with_shutdown
+#[derive(Debug)]
+pub struct SyntheticMonitor {
+    pattern: LoadPattern,
+    interval: Duration,
+    names: Vec<Arc<str>>,
+    outbound: mpsc::Sender<Observation>,
+    shutdown: CancellationToken,
+}
+
+impl SyntheticMonitor {
+    /// Create a monitor that makes up an observation following `pattern`
+    /// every `interval`, with one CPU.
+    ///
+    /// ## Panics
+    ///
+    /// If `interval` is zero.
+    pub fn new(
+        pattern: LoadPattern,
+        interval: Duration,
+        outbound: mpsc::Sender<Observation>,
+    ) -> Self {
+        assert!(!interval.is_zero(), "synthetic interval must be non-zero");
+        Self {
+            pattern,
+            interval,
+            names: Vec::new(),
+            outbound,
+            shutdown: CancellationToken::new(),
+        }
+        .with_cpus(1)
+    }
+
+    /// Make up `cpus` CPUs, named `cpu0`, `cpu1`, and so on, at least one.
+    pub fn with_cpus(mut self, cpus: usize) -> Self {
+        self.names = (0..cpus.max(1))
+            .map(|i| Arc::from(format!("cpu{i}")))
+            .collect();
+        self
+    }
+
+    /// Stop making up observations when `shutdown` is cancelled.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// The CPU stats `elapsed` into the pattern.
+    fn stats(&self, elapsed: Duration) -> Arc<[CpuStats]> {
+        let usage = self.pattern.usage_at(elapsed);
+        self.names
+            .iter()
+            .map(|name| CpuStats {
+                name: name.clone(),
+                usage,
+                frequency: SYNTHETIC_FREQUENCY,
+                times: None,
+                package: None,
+            })
+            .collect()
+    }
+
+    /// Spawn the monitor task. See the channel contract above.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            #[cfg(feature = "sysinfo")]
+            let mut metrics = crate::metrics::ObservationMetrics::default();
+
+            let mut counter = 0u64;
+            let mut elapsed = Duration::ZERO;
+            loop {
+                tokio::select! {
+                    _ = self.shutdown.cancelled() => break,
+                    _ = self.outbound.closed() => break,
+                    _ = interval.tick() => {}
+                }
+
+                let span = info_span!(
+                    "Observation",
+                    { OBSERVATION_ID } = fields::obs_id(counter),
+                    pattern = self.pattern.as_str()
+                );
+                let cpus = self.stats(elapsed);
+                #[cfg(feature = "sysinfo")]
+                let obs = Observation::new_with_metrics(cpus, span, &mut metrics);
+                #[cfg(not(feature = "sysinfo"))]
+                let obs = Observation::new(cpus, span);
+
+                if self.outbound.send(obs).await.is_err() {
+                    break;
+                }
+                counter += 1;
+                elapsed += self.interval;
+            }
+            debug!("synthetic monitor exiting");
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn patterns_follow_their_shape() {
+        let sine = LoadPattern::Sine {
+            min: 20.0,
+            max: 80.0,
+            period: SECOND * 4,
+        };
+        let usages: Vec<_> = (0..5).map(|s| sine.usage_at(SECOND * s).round()).collect();
+        assert_eq!(usages, [50.0, 80.0, 50.0, 20.0, 50.0]);
+
+        let step = LoadPattern::Step {
+            low: 10.0,
+            high: 90.0,
+            period: SECOND * 4,
+        };
+        let usages: Vec<_> = (0..5).map(|s| step.usage_at(SECOND * s)).collect();
+        assert_eq!(usages, [10.0, 10.0, 90.0, 90.0, 10.0]);
+
+        let spike = LoadPattern::Spike {
+            base: 5.0,
+            peak: 200.0,
+            every: SECOND * 3,
+            duration: SECOND,
+        };
+        let usages: Vec<_> = (0..4).map(|s| spike.usage_at(SECOND * s)).collect();
+        assert_eq!(usages, [100.0, 5.0, 5.0, 100.0]);
+    }
+
+    /// However late each tick runs, the usages follow the ticks.
+    #[tokio::test]
+    async fn sends_the_pattern_by_tick() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let every = Duration::from_millis(1);
+        let pattern = LoadPattern::Step {
+            low: 10.0,
+            high: 90.0,
+            period: every * 2,
+        };
+        let monitor = SyntheticMonitor::new(pattern, every, tx)
+            .with_cpus(2)
+            .spawn();
+
+        let mut usages = Vec::new();
+        for _ in 0..4 {
+            let obs = rx.recv().await.unwrap();
+            assert_eq!(obs.len(), 2);
+            usages.push(obs[1].usage);
+        }
+        drop(rx);
+        monitor.await.unwrap();
+
+        assert_eq!(usages, [10.0, 90.0, 10.0, 90.0]);
+    }
+}
@@ -0,0 +1,102 @@
+//! Telling systemd how the monitor is doing. See [`SystemdNotifier`].
+
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Sends `sd_notify` messages on behalf of the [`SysMonitor`].
+///
+/// A `Type=notify` service tells systemd it has started by sending
+/// `READY=1`, and a service with `WatchdogSec=` set must send `WATCHDOG=1`
+/// at least that often, or systemd kills and restarts it. We send both after
+/// successful observations, never on a timer. A timer would keep pinging
+/// from a process whose monitor is stuck, which is exactly the failure the
+/// watchdog exists to catch.
+///
+/// Outside of systemd, `NOTIFY_SOCKET` is unset, and every message is a
+/// no-op.
+///
+/// [`SysMonitor`]: crate::SysMonitor
+#[derive(Debug, Default)]
+pub(crate) struct SystemdNotifier {
+    ready: bool,
+}
+
+impl SystemdNotifier {
+    /// Check the watchdog timeout systemd gave us against the observation
+    /// `interval`. If we can't observe at least twice per timeout, a single
+    /// slow tick gets the service killed.
+    pub(crate) fn check_interval(&self, interval: Duration) {
+        let Some(timeout) = watchdog_timeout() else {
+            return;
+        };
+        let timeout_ms = timeout.as_millis() as u64;
+        let interval_ms = interval.as_millis() as u64;
+        if interval * 2 > timeout {
+            warn!(
+                timeout_ms,
+                interval_ms, "systemd watchdog timeout is less than twice the observation interval"
+            );
+        } else {
+            info!(timeout_ms, interval_ms, "systemd watchdog enabled");
+        }
+    }
+
+    /// Record a successful observation. The first one tells systemd we are
+    /// ready, and every one pings the watchdog.
+    pub(crate) fn observed(&mut self) {
+        if !self.ready {
+            self.ready = true;
+            notify([Message::Ready, Message::Watchdog]);
+        } else {
+            notify([Message::Watchdog]);
+        }
+    }
+
+    /// Tell systemd we are shutting down, so that a slow drain isn't
+    /// mistaken for a hang.
+    pub(crate) fn stopping(&self) {
+        notify([Message::Stopping]);
+    }
+}
+
+/// The subset of `sd_notify` messages we send.
+#[derive(Debug, Clone, Copy)]
+enum Message {
+    Ready,
+    Watchdog,
+    Stopping,
+}
+
+#[cfg(unix)]
+fn notify<const N: usize>(messages: [Message; N]) {
+    use sd_notify::NotifyState;
+
+    let states = messages.map(|message| match message {
+        Message::Ready => NotifyState::Ready,
+        Message::Watchdog => NotifyState::Watchdog,
+        Message::Stopping => NotifyState::Stopping,
+    });
+    // A failed notification is systemd's problem to notice, not ours. If it
+    // keeps failing, the watchdog restarts us, which is the right outcome.
+    if let Err(error) = sd_notify::notify(&states) {
+        debug!(%error, ?messages, "failed to notify systemd");
+    }
+}
+
+#[cfg(not(unix))]
+fn notify<const N: usize>(messages: [Message; N]) {
+    debug!(
+        ?messages,
+        "systemd notifications are only supported on unix"
+    );
+}
+
+#[cfg(unix)]
+fn watchdog_timeout() -> Option<Duration> {
+    sd_notify::watchdog_enabled()
+}
+
+#[cfg(not(unix))]
+fn watchdog_timeout() -> Option<Duration> {
+    None
+}
@@ -0,0 +1,581 @@
+//! Shipping observations over TCP. See [`TcpSink`] and [`TcpSource`].
+
+use crate::{
+    Backoff, Observation, SharedSecret, WIRE_VERSION, WireCompression, WireEncoding,
+    wire::{Frame, Rejection, WireFormat, local_host},
+};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc},
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{debug, info, info_span, warn};
+
+/// The transport label of the remote observation metrics.
+const TRANSPORT: &str = "tcp";
+
+/// How long a single observation may take to send. A collector that stops
+/// reading fills the socket buffer, and without a limit, the sink would wait
+/// for it forever.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for the collector to accept a connection. Without a
+/// limit, a collector behind a firewall that drops packets holds the sink up
+/// for minutes.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The default number of agents a [`TcpSource`] serves at once.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// The default time a [`TcpSource`] waits for an agent's next frame, before
+/// closing the connection.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sends observations to a remote [`TcpSource`], e.g. another `sysmon`
+/// running as a collector.
+///
+/// With a sink on one host and a source on another, the pipeline becomes a
+/// (very) small distributed system: _agents_ observe their own host, and
+/// stream every observation to a central _collector_, which computes stats
+/// over all of them. Each observation is sent as a length-prefixed frame,
+/// with the W3C trace context of its span. The collector's span for the
+/// observation joins the agent's trace, so a single trace shows the
+/// observation being taken on one host, and processed on another.
+///
+/// Frames are JSON, unless the sink is told otherwise with
+/// [`with_encoding`] and [`with_compression`]. The collector reads every
+/// combination, so agents can be switched over one at a time.
+///
+/// [`with_encoding`]: Self::with_encoding
+/// [`with_compression`]: Self::with_compression
+///
+/// The sink is a pass-through actor, like the [`Rollup`]. Put it on the
+/// stats processor's outbound channel, and it forwards every observation to
+/// its own outbound channel, if any, after sending it.
+///
+/// ```no_run
+/// use metrics_tracing_example::{PipelineBuilder, TcpSink};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(16);
+/// let _pipeline = PipelineBuilder::new(Duration::from_secs(5))
+///     .with_outbound(tx)
+///     .spawn()?;
+/// let _sink = TcpSink::new(rx, "collector.local:7000").spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## When the collector is away
+///
+/// Networks fail, and collectors restart. The sink must not stall the
+/// pipeline while they do. If the collector can't be reached, the
+/// observation is dropped, counted on the `my_cute_app.remote_dropped`
+/// counter, and the sink keeps connecting in the background, with
+/// [`retry`], so every failed attempt is counted on `my_cute_app.retries`.
+/// Observations that arrive in the meantime are dropped without waiting.
+/// Once connected, every observation is sent, or the connection is dropped
+/// and the cycle starts over. A collector that accepts the
+/// connection, but stops reading, gets a second to take each observation.
+/// Where losing observations is fine, the [`UdpSink`] never waits at all.
+///
+/// [`Rollup`]: crate::Rollup
+/// [`UdpSink`]: crate::UdpSink
+/// [`retry`]: crate::retry
+#[derive(Debug)]
+pub struct TcpSink {
+    inbound: mpsc::Receiver<Observation>,
+    outbound: Option<mpsc::Sender<Observation>>,
+    addr: String,
+    backoff: Backoff,
+    host: Arc<str>,
+    format: WireFormat,
+    secret: Option<SharedSecret>,
+}
+
+impl TcpSink {
+    /// Create a new sink that sends observations from `inbound` to the
+    /// collector at `addr`, e.g. `collector.local:7000`.
+    pub fn new(inbound: mpsc::Receiver<Observation>, addr: impl Into<String>) -> Self {
+        Self {
+            inbound,
+            outbound: None,
+            addr: addr.into(),
+            backoff: Backoff::default(),
+            host: local_host(),
+            format: WireFormat::default(),
+            secret: None,
+        }
+    }
+
+    /// Tag observations with `host`, instead of this host's name.
+    /// Observations that are tagged already, e.g. by a source upstream, keep
+    /// their tag.
+    pub fn with_host(mut self, host: impl Into<Arc<str>>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    /// Encode observations with `encoding`, instead of JSON.
+    pub const fn with_encoding(mut self, encoding: WireEncoding) -> Self {
+        self.format.encoding = encoding;
+        self
+    }
+
+    /// Compress observations with `compression`. See [`WireCompression`].
+    pub const fn with_compression(mut self, compression: WireCompression) -> Self {
+        self.format.compression = compression;
+        self
+    }
+
+    /// Write version `version` of the wire format, instead of
+    /// [`WIRE_VERSION`], for a collector that is older than the agent.
+    ///
+    /// ## Panics
+    ///
+    /// If `version` is newer than [`WIRE_VERSION`].
+    pub const fn with_wire_version(mut self, version: u8) -> Self {
+        assert!(version <= WIRE_VERSION, "unknown wire version");
+        self.format.version = version;
+        self
+    }
+
+    /// Sign observations with `secret`, so that a source with the same
+    /// secret accepts them. See [`SharedSecret`].
+    pub fn with_secret(mut self, secret: SharedSecret) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Forward observations to `outbound` after sending them.
+    pub fn with_outbound(mut self, outbound: mpsc::Sender<Observation>) -> Self {
+        self.outbound = Some(outbound);
+        self
+    }
+
+    /// Wait between reconnection attempts according to `backoff`, instead
+    /// of [`Backoff::default`]. After [`Backoff::max_retries`] retries, the
+    /// sink gives up until the next observation arrives, and starts over.
+    pub const fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Connect to the collector in the background, retrying with the
+    /// backoff.
+    fn connect(&self) -> JoinHandle<io::Result<TcpStream>> {
+        let addr = self.addr.clone();
+        let backoff = self.backoff;
+        tokio::spawn(async move {
+            crate::retry("tcp_connect", &backoff, || async {
+                tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr))
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+            })
+            .await
+        })
+    }
+
+    /// Spawn the sink task. It runs until the inbound channel is closed.
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut stream = None;
+            // Connect right away, so the first observation doesn't have to
+            // wait, or be dropped.
+            let mut connecting = Some(self.connect());
+            let mut buf = Vec::new();
+            // Counts every observation, sent or dropped, so the collector
+            // can tell how many it missed.
+            let mut next_seq = 0;
+
+            while let Some(obs) = self.inbound.recv().await {
+                let seq = next_seq;
+                next_seq += 1;
+                if stream.is_none() {
+                    let task = connecting.get_or_insert_with(|| self.connect());
+                    if task.is_finished() {
+                        match task.await {
+                            Ok(Ok(connected)) => {
+                                info!(addr = %self.addr, "connected to collector");
+                                stream = Some(connected);
+                            }
+                            Ok(Err(error)) => warn!(
+                                addr = %self.addr,
+                                %error,
+                                "failed to connect to collector, starting over"
+                            ),
+                            Err(error) => warn!(%error, "collector connection task failed"),
+                        }
+                        connecting = None;
+                    }
+                }
+
+                match &mut stream {
+                    Some(connected) => {
+                        let sent = async {
+                            let encoded = Frame::new(&obs, &self.host, seq).encode(
+                                &mut buf,
+                                self.format,
+                                self.secret.as_ref(),
+                            )?;
+                            tokio::time::timeout(WRITE_TIMEOUT, connected.write_all(&buf))
+                                .await
+                                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                            Ok::<_, io::Error>(encoded)
+                        };
+                        // A failed or timed out write may have sent part of
+                        // a frame, so the connection can't be reused.
+                        match sent.await {
+                            Ok(encoded) => {
+                                crate::metrics::record_remote_sent(TRANSPORT);
+                                crate::metrics::record_remote_bytes(
+                                    TRANSPORT,
+                                    self.format,
+                                    encoded,
+                                    buf.len(),
+                                );
+                            }
+                            Err(error) => {
+                                obs.in_scope(|_| {
+                                    warn!(addr = %self.addr, %error, "failed to send observation")
+                                });
+                                crate::metrics::record_remote_dropped(TRANSPORT);
+                                stream = None;
+                            }
+                        }
+                    }
+                    None => crate::metrics::record_remote_dropped(TRANSPORT),
+                }
+
+                if let Some(outbound) = &self.outbound
+                    && outbound.send(obs).await.is_err()
+                {
+                    debug!("Outbound receiver dropped, stopping forwarding");
+                    self.outbound = None;
+                }
+            }
+
+            if let Some(connecting) = connecting {
+                connecting.abort();
+            }
+        })
+    }
+}
+
+/// Receives observations from remote [`TcpSink`]s, and sends them on to a
+/// channel, e.g. the inbound channel of a [`SysStats`], or a [`Collector`].
+///
+/// Each connection is served by its own task, so one slow agent doesn't
+/// hold up the others. Each received observation gets a new `Remote
+/// observation` span, with the agent's address as the `peer` field, and its
+/// host name as the `host` field, that continues the agent's trace. The
+/// observation is [tagged] with the host name too. A connection that sends a
+/// malformed frame, or one [rejected] for its signature, is closed.
+///
+/// Each connection costs a task and a buffer, so the source serves at most
+/// [`DEFAULT_MAX_CONNECTIONS`] at once, and refuses the rest until one
+/// closes. A connection that goes [`DEFAULT_IDLE_TIMEOUT`] without a whole
+/// frame is closed too, so agents that vanish without a goodbye, or peers
+/// that trickle bytes, don't hold a slot forever. Agents reconnect on their
+/// own. See [`with_max_connections`] and [`with_idle_timeout`].
+///
+/// ```no_run
+/// use metrics_tracing_example::{SysStats, TcpSource};
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(16);
+/// let _source = TcpSource::bind("0.0.0.0:7000").await?.spawn(tx);
+/// let _stats = SysStats::new(rx, None).spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`SysStats`]: crate::SysStats
+/// [`Collector`]: crate::Collector
+/// [tagged]: Observation::with_host
+/// [rejected]: TcpSource::with_secret
+/// [`with_max_connections`]: TcpSource::with_max_connections
+/// [`with_idle_timeout`]: TcpSource::with_idle_timeout
+#[derive(Debug)]
+pub struct TcpSource {
+    listener: TcpListener,
+    secret: Option<SharedSecret>,
+    max_connections: usize,
+    idle_timeout: Duration,
+}
+
+impl TcpSource {
+    /// Listen for agents on `addr`.
+    ///
+    /// ## Errors
+    ///
+    /// If the listener can't be bound, e.g. because the port is in use.
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            secret: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        })
+    }
+
+    /// Only accept observations signed with `secret`. See
+    /// [`SharedSecret`].
+    pub fn with_secret(mut self, secret: SharedSecret) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Serve at most `max` agents at once, instead of
+    /// [`DEFAULT_MAX_CONNECTIONS`].
+    ///
+    /// ## Panics
+    ///
+    /// If `max` is zero.
+    pub const fn with_max_connections(mut self, max: usize) -> Self {
+        assert!(max > 0, "max connections must be non-zero");
+        self.max_connections = max;
+        self
+    }
+
+    /// Close connections that go `timeout` without a whole frame, instead of
+    /// [`DEFAULT_IDLE_TIMEOUT`]. Keep it well above the agents' interval.
+    ///
+    /// ## Panics
+    ///
+    /// If `timeout` is zero.
+    pub const fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "idle timeout must be non-zero");
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// The address the source is listening on. Useful after binding port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Spawn the source task. It accepts connections until `outbound` is
+    /// closed. Aborting the task closes all connections, and drops all
+    /// clones of `outbound`.
+    pub fn spawn(self, outbound: mpsc::Sender<Observation>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            // Dropping the set aborts the connection tasks with it.
+            let mut connections = JoinSet::new();
+            let slots = Arc::new(Semaphore::new(self.max_connections));
+            loop {
+                let accepted = tokio::select! {
+                    _ = outbound.closed() => break,
+                    accepted = self.listener.accept() => accepted,
+                    // Reap finished connections, so the set doesn't grow
+                    // with every agent that ever connected.
+                    Some(_) = connections.join_next() => continue,
+                };
+                match accepted {
+                    Ok((stream, peer)) => {
+                        // Dropping the stream closes it, and the agent
+                        // retries later, with backoff.
+                        let Ok(slot) = slots.clone().try_acquire_owned() else {
+                            warn!(%peer, max = self.max_connections, "too many agent connections, refusing");
+                            continue;
+                        };
+                        info!(%peer, "agent connected");
+                        connections.spawn(serve(
+                            stream,
+                            peer,
+                            outbound.clone(),
+                            self.secret.clone(),
+                            self.idle_timeout,
+                            slot,
+                        ));
+                    }
+                    Err(error) => warn!(%error, "failed to accept agent connection"),
+                }
+            }
+            debug!("Outbound receiver dropped, closing TCP source");
+        })
+    }
+}
+
+/// Read frames from a single agent until it disconnects, or goes
+/// `idle_timeout` without sending one. The connection holds its `_slot`
+/// until then.
+async fn serve(
+    stream: TcpStream,
+    peer: SocketAddr,
+    outbound: mpsc::Sender<Observation>,
+    secret: Option<SharedSecret>,
+    idle_timeout: Duration,
+    _slot: OwnedSemaphorePermit,
+) {
+    let mut reader = BufReader::new(stream);
+    let mut buf = Vec::new();
+    let mut warned_version = false;
+    loop {
+        let frame = tokio::select! {
+            _ = outbound.closed() => return,
+            frame = tokio::time::timeout(idle_timeout, Frame::read(&mut reader, &mut buf, secret.as_ref())) => frame,
+        };
+        let Ok(frame) = frame else {
+            info!(%peer, "closing idle agent connection");
+            return;
+        };
+        match frame {
+            Ok(Some(frame)) => {
+                crate::metrics::record_remote_received(TRANSPORT);
+                // Frames from agents that don't say which host they are on are
+                // tagged with the address they came from.
+                let host = frame
+                    .host()
+                    .cloned()
+                    .unwrap_or_else(|| peer.ip().to_string().into());
+                let span = info_span!("Remote observation", %peer, %host);
+                let obs = frame.into_observation(span).with_host(host);
+                if outbound.send(obs).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {
+                info!(%peer, "agent disconnected");
+                return;
+            }
+            Err(error) => {
+                let rejection = Rejection::of(&error);
+                if let Some(rejection) = rejection {
+                    crate::metrics::record_remote_rejected(TRANSPORT, rejection);
+                }
+                // The frame was read whole, so the stream is still in step,
+                // and the next frame can be read. Once an agent has sent one
+                // frame we can't read, it will send them all: say so once.
+                if let Some(Rejection::UnsupportedVersion(_)) = rejection {
+                    if !warned_version {
+                        warn!(%peer, %error, "skipping frames from a newer agent");
+                        warned_version = true;
+                    }
+                    continue;
+                }
+                warn!(%peer, %error, "closing agent connection");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{EventMatcher, capture, observation};
+    use tokio::io::AsyncReadExt;
+
+    /// Whether the source closed `stream`, within a second.
+    async fn closed(stream: &mut TcpStream) -> bool {
+        let mut byte = [0];
+        matches!(
+            tokio::time::timeout(Duration::from_secs(1), stream.read(&mut byte)).await,
+            Ok(Ok(0) | Err(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_past_the_cap() {
+        let source = TcpSource::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_max_connections(1);
+        let addr = source.local_addr().unwrap();
+        let (tx, _rx) = mpsc::channel(1);
+        let _source = source.spawn(tx);
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(closed(&mut second).await);
+
+        // The first still holds its slot.
+        let mut byte = [0];
+        let read = tokio::time::timeout(Duration::from_millis(100), first.read(&mut byte));
+        assert!(read.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let source = TcpSource::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_idle_timeout(Duration::from_millis(50))
+            .with_max_connections(1);
+        let addr = source.local_addr().unwrap();
+        let (tx, _rx) = mpsc::channel(1);
+        let _source = source.spawn(tx);
+
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        assert!(closed(&mut idle).await);
+
+        // Its slot is free again.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let mut next = TcpStream::connect(addr).await.unwrap();
+        let mut byte = [0];
+        let read = tokio::time::timeout(Duration::from_millis(20), next.read(&mut byte));
+        assert!(read.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_to_the_collector() {
+        let source = TcpSource::bind("127.0.0.1:0").await.unwrap();
+        let addr = source.local_addr().unwrap();
+        let (source_tx, mut source_rx) = mpsc::channel(1);
+        let _source = source.spawn(source_tx);
+
+        let (tx, rx) = mpsc::channel(1);
+        let sink = TcpSink::new(rx, addr.to_string()).spawn();
+        // Give the sink a moment to connect.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(observation(1, 42.0)).await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(1), source_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received[0].usage, 42.0);
+        drop(tx);
+        sink.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_the_connection() {
+        let (captured, _guard) = capture();
+        // A port nothing listens on.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(1),
+            multiplier: 1.0,
+            max_retries: 2,
+        };
+
+        let (tx, rx) = mpsc::channel(1);
+        let sink = TcpSink::new(rx, addr.to_string())
+            .with_backoff(backoff)
+            .spawn();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(observation(1, 42.0)).await.unwrap();
+        drop(tx);
+        sink.await.unwrap();
+
+        captured.assert_event(
+            &EventMatcher::new()
+                .with_message("operation failed, retrying")
+                .with_field("operation", "tcp_connect"),
+        );
+        captured.assert_event(
+            &EventMatcher::new().with_message("failed to connect to collector, starting over"),
+        );
+    }
+}
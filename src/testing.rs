@@ -0,0 +1,530 @@
+//! Helpers for asserting on the spans and events your code emits.
+//!
+//! Spans and events are part of a program's interface: dashboards, alerts,
+//! and the [`EventMetricsLayer`] all depend on their names and fields. So
+//! they deserve tests, like any other output. If you build on the pipeline,
+//! with your own sinks and actors, this module lets you test them the same
+//! way this crate tests its own.
+//!
+//! [`with_captured_events`] runs a closure with a subscriber that records
+//! everything, and returns what it recorded. Matchers pick out the spans and
+//! events you care about:
+//!
+//! ```
+//! use metrics_tracing_example::testing::{EventMatcher, SpanMatcher, with_captured_events};
+//! use tracing::{Level, info_span, warn};
+//!
+//! let captured = with_captured_events(|| {
+//!     info_span!("Observation", observation_id = 7).in_scope(|| {
+//!         warn!(average = 93.5, "cpu is hot");
+//!     });
+//! });
+//!
+//! captured.assert_event(
+//!     &EventMatcher::new()
+//!         .with_level(Level::WARN)
+//!         .with_message("cpu is hot")
+//!         .with_field("average", 93.5)
+//!         .in_span("Observation"),
+//! );
+//! captured.assert_span(&SpanMatcher::new("Observation").with_field("observation_id", 7));
+//! ```
+//!
+//! Async tests on a current-thread runtime, like `#[tokio::test]`, can hold
+//! the guard from [`capture`] across awaits instead.
+//!
+//! To feed your sinks and actors, [`observation`] makes one up.
+//!
+//! This module is behind the `testing` feature. Enable it in your
+//! `[dev-dependencies]`.
+//!
+//! [`EventMetricsLayer`]: crate::EventMetricsLayer
+
+use crate::{CpuStats, Observation};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tracing::{
+    Event, Level, Subscriber,
+    dispatcher::DefaultGuard,
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+};
+use tracing_subscriber::{Layer, layer::Context, prelude::*, registry::LookupSpan};
+
+/// The field `tracing` stores an event's message in.
+const MESSAGE: &str = "message";
+
+/// A recorded event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedEvent {
+    /// The event's level.
+    pub level: Level,
+    /// The event's target, usually the module that emitted it.
+    pub target: String,
+    /// The event's message, if it has one.
+    pub message: Option<String>,
+    /// The event's other fields, in the order they were recorded, formatted
+    /// as they'd be printed: strings and `%` fields with [`fmt::Display`],
+    /// and everything else with [`fmt::Debug`].
+    pub fields: Vec<(&'static str, String)>,
+    /// The names of the spans the event was emitted in, outermost first.
+    pub spans: Vec<&'static str>,
+}
+
+impl CapturedEvent {
+    /// The value of `field`, if the event has it.
+    pub fn field(&self, field: &str) -> Option<&str> {
+        find_field(&self.fields, field)
+    }
+}
+
+/// A recorded span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedSpan {
+    /// The span's name.
+    pub name: &'static str,
+    /// The span's level.
+    pub level: Level,
+    /// The span's target, usually the module that created it.
+    pub target: String,
+    /// The span's fields, including those recorded after it was created.
+    /// Formatted like [`CapturedEvent::fields`].
+    pub fields: Vec<(&'static str, String)>,
+    /// The name of the span's parent, if it has one.
+    pub parent: Option<&'static str>,
+    /// Whether the span has closed.
+    pub closed: bool,
+}
+
+impl CapturedSpan {
+    /// The value of `field`, if the span has it.
+    pub fn field(&self, field: &str) -> Option<&str> {
+        find_field(&self.fields, field)
+    }
+}
+
+fn find_field<'a>(fields: &'a [(&'static str, String)], field: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find_map(|(name, value)| (*name == field).then_some(value.as_str()))
+}
+
+/// Collects field values as strings.
+#[derive(Default)]
+struct FieldRecorder {
+    message: Option<String>,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl FieldRecorder {
+    fn push(&mut self, field: &Field, value: String) {
+        if field.name() == MESSAGE {
+            self.message = Some(value);
+        } else if let Some(slot) = self
+            .fields
+            .iter_mut()
+            .find(|(name, _)| *name == field.name())
+        {
+            // Recorded again, with `Span::record`.
+            slot.1 = value;
+        } else {
+            self.fields.push((field.name(), value));
+        }
+    }
+}
+
+impl Visit for FieldRecorder {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format!("{value:?}"));
+    }
+}
+
+/// Everything recorded so far. Spans are indexed by their position in
+/// `spans`, which is stored in their extensions.
+#[derive(Debug, Default)]
+struct Recorded {
+    events: Vec<CapturedEvent>,
+    spans: Vec<CapturedSpan>,
+}
+
+/// The index of a span in [`Recorded::spans`].
+struct SpanIndex(usize);
+
+/// The spans and events recorded by [`with_captured_events`], or by
+/// [`capture`].
+///
+/// Cloning is cheap, and clones share the same recording.
+#[derive(Debug, Clone, Default)]
+pub struct Captured {
+    recorded: Arc<Mutex<Recorded>>,
+}
+
+impl Captured {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Recorded> {
+        // A failed assertion in the closure poisons nothing we care about.
+        self.recorded.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Every event recorded so far, in the order they were emitted.
+    pub fn events(&self) -> Vec<CapturedEvent> {
+        self.lock().events.clone()
+    }
+
+    /// Every span recorded so far, in the order they were created.
+    pub fn spans(&self) -> Vec<CapturedSpan> {
+        self.lock().spans.clone()
+    }
+
+    /// The events that match `matcher`.
+    pub fn events_matching(&self, matcher: &EventMatcher) -> Vec<CapturedEvent> {
+        let recorded = self.lock();
+        recorded
+            .events
+            .iter()
+            .filter(|event| matcher.matches(event))
+            .cloned()
+            .collect()
+    }
+
+    /// The spans that match `matcher`.
+    pub fn spans_matching(&self, matcher: &SpanMatcher) -> Vec<CapturedSpan> {
+        let recorded = self.lock();
+        recorded
+            .spans
+            .iter()
+            .filter(|span| matcher.matches(span))
+            .cloned()
+            .collect()
+    }
+
+    /// Panic unless at least one event matches `matcher`. The message lists
+    /// every event that was recorded, to see what went wrong.
+    #[track_caller]
+    pub fn assert_event(&self, matcher: &EventMatcher) {
+        let recorded = self.lock();
+        if !recorded.events.iter().any(|event| matcher.matches(event)) {
+            panic!(
+                "no event matched {matcher:?}, recorded events: {:#?}",
+                recorded.events
+            );
+        }
+    }
+
+    /// Panic if any event matches `matcher`.
+    #[track_caller]
+    pub fn assert_no_event(&self, matcher: &EventMatcher) {
+        let recorded = self.lock();
+        if let Some(event) = recorded.events.iter().find(|event| matcher.matches(event)) {
+            panic!("expected no event to match {matcher:?}, but {event:#?} did");
+        }
+    }
+
+    /// Panic unless at least one span matches `matcher`. The message lists
+    /// every span that was recorded.
+    #[track_caller]
+    pub fn assert_span(&self, matcher: &SpanMatcher) {
+        let recorded = self.lock();
+        if !recorded.spans.iter().any(|span| matcher.matches(span)) {
+            panic!(
+                "no span matched {matcher:?}, recorded spans: {:#?}",
+                recorded.spans
+            );
+        }
+    }
+
+    /// A [`Layer`] that records into this.
+    fn layer(&self) -> CaptureLayer {
+        CaptureLayer {
+            captured: self.clone(),
+        }
+    }
+}
+
+/// Records spans and events into a [`Captured`].
+struct CaptureLayer {
+    captured: Captured,
+}
+
+impl<S> Layer<S> for CaptureLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut recorder = FieldRecorder::default();
+        attrs.record(&mut recorder);
+
+        let metadata = span.metadata();
+        let mut recorded = self.captured.lock();
+        span.extensions_mut()
+            .insert(SpanIndex(recorded.spans.len()));
+        recorded.spans.push(CapturedSpan {
+            name: metadata.name(),
+            level: *metadata.level(),
+            target: metadata.target().to_owned(),
+            fields: recorder.fields,
+            parent: span.parent().map(|parent| parent.name()),
+            closed: false,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let Some(&SpanIndex(index)) = span.extensions().get::<SpanIndex>() else {
+            return;
+        };
+        let mut recorded = self.captured.lock();
+        let mut recorder = FieldRecorder {
+            message: None,
+            fields: std::mem::take(&mut recorded.spans[index].fields),
+        };
+        values.record(&mut recorder);
+        recorded.spans[index].fields = recorder.fields;
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut recorder = FieldRecorder::default();
+        event.record(&mut recorder);
+        let spans = ctx
+            .event_scope(event)
+            .map(|scope| scope.from_root().map(|span| span.name()).collect())
+            .unwrap_or_default();
+
+        let metadata = event.metadata();
+        self.captured.lock().events.push(CapturedEvent {
+            level: *metadata.level(),
+            target: metadata.target().to_owned(),
+            message: recorder.message,
+            fields: recorder.fields,
+            spans,
+        });
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        if let Some(&SpanIndex(index)) = span.extensions().get::<SpanIndex>() {
+            self.captured.lock().spans[index].closed = true;
+        }
+    }
+}
+
+/// Run `f` with a subscriber that records every span and event, at every
+/// level, on the current thread, and return what it recorded.
+///
+/// Spans and events on other threads, e.g. in tasks on a multi-threaded
+/// runtime, aren't recorded. See [`capture`] for async tests.
+pub fn with_captured_events(f: impl FnOnce()) -> Captured {
+    let captured = Captured::default();
+    let subscriber = tracing_subscriber::registry().with(captured.layer());
+    tracing::subscriber::with_default(subscriber, f);
+    captured
+}
+
+/// Start recording every span and event on the current thread, until the
+/// guard is dropped.
+///
+/// Like [`with_captured_events`], for code that doesn't fit in a closure,
+/// like an async test on a current-thread runtime:
+///
+/// ```
+/// use metrics_tracing_example::testing::{EventMatcher, capture};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let (captured, _guard) = capture();
+/// tokio::spawn(async { tracing::info!("from a task") }).await.unwrap();
+/// captured.assert_event(&EventMatcher::new().with_message("from a task"));
+/// # }
+/// ```
+pub fn capture() -> (Captured, DefaultGuard) {
+    let captured = Captured::default();
+    let subscriber = tracing_subscriber::registry().with(captured.layer());
+    let guard = tracing::subscriber::set_default(subscriber);
+    (captured, guard)
+}
+
+/// Matches [`CapturedEvent`]s. Every condition must hold, and one with no
+/// conditions matches every event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventMatcher {
+    level: Option<Level>,
+    message: Option<String>,
+    fields: Vec<(&'static str, String)>,
+    span: Option<&'static str>,
+}
+
+impl EventMatcher {
+    /// Match every event.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match events at `level`.
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Match events with exactly this message.
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// Match events whose `field` prints as `value`. Strings match without
+    /// quotes, so `"eth0"` matches both `iface = "eth0"` and
+    /// `iface = %name`.
+    pub fn with_field(mut self, field: &'static str, value: impl fmt::Display) -> Self {
+        self.fields.push((field, value.to_string()));
+        self
+    }
+
+    /// Match events emitted inside a span named `span`, directly or not.
+    pub fn in_span(mut self, span: &'static str) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// Whether `event` matches.
+    pub fn matches(&self, event: &CapturedEvent) -> bool {
+        self.level.is_none_or(|level| event.level == level)
+            && self
+                .message
+                .as_ref()
+                .is_none_or(|message| event.message.as_ref() == Some(message))
+            && self
+                .fields
+                .iter()
+                .all(|(field, value)| event.field(field) == Some(value.as_str()))
+            && self.span.is_none_or(|span| event.spans.contains(&span))
+    }
+}
+
+/// Matches [`CapturedSpan`]s by name, and optionally by field and parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanMatcher {
+    name: &'static str,
+    fields: Vec<(&'static str, String)>,
+    parent: Option<&'static str>,
+}
+
+impl SpanMatcher {
+    /// Match spans named `name`.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            fields: Vec::new(),
+            parent: None,
+        }
+    }
+
+    /// Match spans whose `field` prints as `value`, when created or since.
+    /// See [`EventMatcher::with_field`].
+    pub fn with_field(mut self, field: &'static str, value: impl fmt::Display) -> Self {
+        self.fields.push((field, value.to_string()));
+        self
+    }
+
+    /// Match spans whose parent is named `parent`.
+    pub const fn with_parent(mut self, parent: &'static str) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    /// Whether `span` matches.
+    pub fn matches(&self, span: &CapturedSpan) -> bool {
+        span.name == self.name
+            && self
+                .fields
+                .iter()
+                .all(|(field, value)| span.field(field) == Some(value.as_str()))
+            && self.parent.is_none_or(|parent| span.parent == Some(parent))
+    }
+}
+
+/// Make up an observation of `cpus` CPUs, named `cpu0`, `cpu1`, and so on,
+/// all at `usage` percent and 2 GHz.
+///
+/// It's in the current span, so that an observation made in an
+/// `Observation` span looks like one from the monitor.
+pub fn observation(cpus: usize, usage: f32) -> Observation {
+    let cpus: Arc<[CpuStats]> = (0..cpus)
+        .map(|i| CpuStats {
+            name: format!("cpu{i}").into(),
+            usage,
+            frequency: 2_000,
+            times: None,
+            package: None,
+        })
+        .collect();
+    Observation::new(cpus, tracing::Span::current())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{debug, debug_span, info, info_span};
+
+    #[test]
+    fn records_events_with_their_scope() {
+        let captured = with_captured_events(|| {
+            info!(count = 3, name = "eth0", "outside");
+            info_span!("outer").in_scope(|| {
+                debug_span!("inner").in_scope(|| debug!(ratio = 0.5, "inside"));
+            });
+        });
+
+        let events = captured.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].message.as_deref(), Some("outside"));
+        assert_eq!(events[0].field("count"), Some("3"));
+        assert_eq!(events[0].field("name"), Some("eth0"));
+        assert!(events[0].spans.is_empty());
+        assert_eq!(events[1].level, Level::DEBUG);
+        assert_eq!(events[1].spans, ["outer", "inner"]);
+
+        captured.assert_event(
+            &EventMatcher::new()
+                .in_span("outer")
+                .with_field("ratio", 0.5),
+        );
+        captured.assert_no_event(&EventMatcher::new().in_span("outer").with_message("outside"));
+    }
+
+    #[test]
+    fn records_span_fields_parents_and_close() {
+        let captured = with_captured_events(|| {
+            let outer = info_span!("outer");
+            let inner = info_span!(parent: &outer, "inner", id = 1, late = tracing::field::Empty);
+            inner.record("late", "yes");
+            drop(inner);
+        });
+
+        captured.assert_span(
+            &SpanMatcher::new("inner")
+                .with_parent("outer")
+                .with_field("id", 1)
+                .with_field("late", "yes"),
+        );
+        let spans = captured.spans();
+        assert!(spans.iter().all(|span| span.closed));
+        assert!(
+            captured
+                .spans_matching(&SpanMatcher::new("outer").with_parent("inner"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "no event matched")]
+    fn assert_event_lists_what_was_recorded() {
+        with_captured_events(|| info!("something else"))
+            .assert_event(&EventMatcher::new().with_message("expected"));
+    }
+}
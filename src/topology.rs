@@ -0,0 +1,20 @@
+//! The CPU topology. See [`physical_package`].
+
+/// The physical package, i.e. the socket, that the CPU named `name` belongs
+/// to, read from `/sys/devices/system/cpu/cpuN/topology/physical_package_id`.
+///
+/// `sysinfo` doesn't expose the topology, so this is Linux only, and relies
+/// on `sysinfo` naming CPUs `cpu0`, `cpu1`, and so on, like the kernel does.
+/// `None` if the name doesn't match, or the file can't be read.
+#[cfg(target_os = "linux")]
+pub(crate) fn physical_package(name: &str) -> Option<u32> {
+    let index: u32 = name.strip_prefix("cpu")?.parse().ok()?;
+    let path = format!("/sys/devices/system/cpu/cpu{index}/topology/physical_package_id");
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Always `None` outside of Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn physical_package(_name: &str) -> Option<u32> {
+    None
+}
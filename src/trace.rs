@@ -1,17 +1,955 @@
 //! The [`init_tracing`] function sets up tracing for the application.
 //! [`init_otel_provider`] is also interesting :)
 
+use crate::{
+    ConfigError, EventMetricsLayer, LongSpanLayer, SpanCountLayer, SpanDurationLayer,
+    SpanTreeLayer, TargetSampler,
+    datadog::{self, DatadogFormat},
+};
 use opentelemetry::{KeyValue, trace::TracerProvider};
-use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::{
+    ExporterBuildError, OTEL_EXPORTER_OTLP_TIMEOUT_DEFAULT, SpanExporter, WithExportConfig,
+    WithHttpConfig, WithTonicConfig,
+};
+use opentelemetry_sdk::{
+    Resource, error::OTelSdkResult, resource::EnvResourceDetector, trace::SdkTracerProvider,
+};
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::{
+    error::OTelSdkError,
+    trace::{BatchConfig, BatchConfigBuilder, BatchSpanProcessor, SpanData},
+};
 use opentelemetry_semantic_conventions::{
     SCHEMA_URL,
-    attribute::{DEPLOYMENT_ENVIRONMENT_NAME, SERVICE_NAME, SERVICE_VERSION},
+    attribute::{DEPLOYMENT_ENVIRONMENT_NAME, HOST_NAME, SERVICE_NAME, SERVICE_VERSION},
 };
+use std::{fs::File, panic::PanicHookInfo, sync::Arc, time::Duration};
+#[cfg(feature = "otlp")]
+use tonic::{
+    metadata::{MetadataKey, MetadataMap, MetadataValue},
+    transport::{Certificate, ClientTlsConfig},
+};
+use tracing::level_filters::LevelFilter;
+use tracing_log::{AsLog, LogTracer};
 use tracing_subscriber::{
-    Layer, filter::EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt,
+    Layer, Registry,
+    filter::EnvFilter,
+    fmt::{self, writer::BoxMakeWriter},
+    layer::SubscriberExt,
 };
 
 const OTEL_FILTER: &str = "OTEL_FILTER";
+const OTEL_SERVICE_NAME: &str = "OTEL_SERVICE_NAME";
+#[cfg(feature = "otlp")]
+const OTEL_EXPORTER_OTLP_CERTIFICATE: &str = "OTEL_EXPORTER_OTLP_CERTIFICATE";
+
+/// How long [`TracingHandle::shutdown`] waits for the last spans to be
+/// exported, unless set with [`TracingBuilder::with_shutdown_timeout`].
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A type-erased layer on the [`Registry`]. Layers with different types can
+/// be collected into a `Vec`, which itself implements [`Layer`]. This is the
+/// easiest way to build a subscriber whose layers are chosen at runtime.
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// The output format of the console [`fmt::Layer`].
+///
+/// - `Full` is the default. One line per event, with all span context.
+/// - `Pretty` spreads each event over several lines. Nice for local
+///   development, terrible for anything else.
+/// - `Compact` is like `Full`, but abbreviates span context.
+/// - `Json` writes one JSON object per line. Use this when a log shipper
+///   (e.g. vector, fluentbit, or your cloud provider's agent) is reading
+///   stdout. Structured fields stay structured all the way to your backend.
+/// - `Datadog` is `Json`, shaped for the Datadog agent's log collection. Each
+///   line carries `dd.trace_id` and `dd.span_id`, so Datadog links the log to
+///   its trace, and the unified service tags from `DD_SERVICE`, `DD_ENV`, and
+///   `DD_VERSION`. Pair it with OTLP export to the Datadog agent, which
+///   accepts OTLP on the usual ports once `otlp_config` is enabled in its
+///   `datadog.yaml`. The same `DD_*` env vars set the trace resource, so
+///   traces and logs agree on the service.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum LogFormat {
+    /// The default `tracing_subscriber` format.
+    #[default]
+    Full,
+    /// Multi-line, human-friendly output.
+    Pretty,
+    /// Single-line output with abbreviated span context.
+    Compact,
+    /// Newline-delimited JSON.
+    Json,
+    /// Newline-delimited JSON, with Datadog's trace correlation attributes.
+    Datadog,
+}
+
+/// The transport used to export spans to the OTLP collector.
+///
+/// OTLP comes in two flavors. Most collectors accept both, on different
+/// ports, but some managed backends and sidecars only accept one. The
+/// OTEL-standard way to choose is the `OTEL_EXPORTER_OTLP_PROTOCOL` env var,
+/// which is read if no protocol is set with
+/// [`TracingBuilder::with_otlp_protocol`].
+///
+/// - `HttpProtobuf` is the default. Protobuf-encoded spans are `POST`ed to
+///   `/v1/traces`, on port 4318. Plain HTTP is easy to proxy and debug.
+/// - `Grpc` sends spans over a gRPC stream, on port 4317.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum OtlpProtocol {
+    /// Protobuf over HTTP.
+    #[default]
+    #[serde(rename = "http/protobuf")]
+    #[cfg_attr(feature = "cli", value(name = "http/protobuf"))]
+    HttpProtobuf,
+    /// gRPC.
+    #[serde(rename = "grpc")]
+    #[cfg_attr(feature = "cli", value(name = "grpc"))]
+    Grpc,
+}
+
+impl OtlpProtocol {
+    /// The spec-defined env var that selects the protocol.
+    pub const ENV_VAR: &str = "OTEL_EXPORTER_OTLP_PROTOCOL";
+
+    /// Read the protocol from `OTEL_EXPORTER_OTLP_PROTOCOL`, falling back to
+    /// the default if it is unset or unrecognized.
+    pub fn from_env() -> Self {
+        std::env::var(Self::ENV_VAR)
+            .ok()
+            .and_then(|value| Self::from_name(&value))
+            .unwrap_or_default()
+    }
+
+    /// Parse a protocol name, as used in `OTEL_EXPORTER_OTLP_PROTOCOL`.
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "http/protobuf" => Some(Self::HttpProtobuf),
+            "grpc" => Some(Self::Grpc),
+            _ => None,
+        }
+    }
+
+    /// The collector endpoint used if none is configured, per the OTEL spec.
+    pub const fn default_endpoint(self) -> &'static str {
+        match self {
+            Self::HttpProtobuf => "http://localhost:4318",
+            Self::Grpc => "http://localhost:4317",
+        }
+    }
+}
+
+/// Overrides for the span batch processor's [`BatchConfig`]. Unset values
+/// come from the `OTEL_BSP_*` env vars, or the spec defaults.
+#[cfg(feature = "otlp")]
+#[derive(Debug, Clone, Copy, Default)]
+struct BatchTuning {
+    max_queue_size: Option<usize>,
+    scheduled_delay: Option<Duration>,
+    max_export_batch_size: Option<usize>,
+    /// Skip batching, and export with the simple processor instead. See
+    /// [`TracingBuilder::with_immediate_export`].
+    immediate: bool,
+}
+
+#[cfg(feature = "otlp")]
+impl BatchTuning {
+    fn config(self) -> BatchConfig {
+        let mut builder = BatchConfigBuilder::default();
+        if let Some(size) = self.max_queue_size {
+            builder = builder.with_max_queue_size(size);
+        }
+        if let Some(delay) = self.scheduled_delay {
+            builder = builder.with_scheduled_delay(delay);
+        }
+        if let Some(size) = self.max_export_batch_size {
+            builder = builder.with_max_export_batch_size(size);
+        }
+        builder.build()
+    }
+}
+
+/// Connection settings shared by the OTLP exporters: extra headers, and an
+/// extra CA certificate to trust.
+///
+/// `https` endpoints always use TLS, trusting the platform's root
+/// certificates. The CA certificate is only needed for collectors with a
+/// self-signed or private certificate.
+#[cfg(feature = "otlp")]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OtlpTransport {
+    headers: Vec<(String, String)>,
+    ca_certificate: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "otlp")]
+impl OtlpTransport {
+    /// Read the CA certificate from the PEM file at
+    /// `OTEL_EXPORTER_OTLP_CERTIFICATE`, unless one is already set. Headers
+    /// from `OTEL_EXPORTER_OTLP_HEADERS` are read by the exporters
+    /// themselves.
+    pub(crate) fn or_env(mut self) -> Result<Self, ExporterBuildError> {
+        if self.ca_certificate.is_none()
+            && let Ok(path) = std::env::var(OTEL_EXPORTER_OTLP_CERTIFICATE)
+            && !path.is_empty()
+        {
+            let pem = std::fs::read(&path).map_err(|error| {
+                ExporterBuildError::InternalFailure(format!("failed to read {path}: {error}"))
+            })?;
+            self.ca_certificate = Some(pem);
+        }
+        Ok(self)
+    }
+
+    /// Apply the headers and CA certificate to an HTTP exporter builder.
+    pub(crate) fn http<B: WithHttpConfig>(&self, mut builder: B) -> Result<B, ExporterBuildError> {
+        if !self.headers.is_empty() {
+            builder = builder.with_headers(self.headers.iter().cloned().collect());
+        }
+        if let Some(pem) = &self.ca_certificate {
+            builder = builder.with_http_client(http_client(pem)?);
+        }
+        Ok(builder)
+    }
+
+    /// Apply the headers and CA certificate to a gRPC exporter builder.
+    pub(crate) fn grpc<B: WithTonicConfig>(&self, mut builder: B) -> Result<B, ExporterBuildError> {
+        if !self.headers.is_empty() {
+            let mut metadata = MetadataMap::new();
+            for (name, value) in &self.headers {
+                let invalid =
+                    || ExporterBuildError::InternalFailure(format!("invalid OTLP header {name}"));
+                let key = MetadataKey::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+                let value = MetadataValue::try_from(value.as_str()).map_err(|_| invalid())?;
+                metadata.insert(key, value);
+            }
+            builder = builder.with_metadata(metadata);
+        }
+        if let Some(pem) = &self.ca_certificate {
+            builder = builder.with_tls_config(
+                ClientTlsConfig::new()
+                    .with_enabled_roots()
+                    .ca_certificate(Certificate::from_pem(pem)),
+            );
+        }
+        Ok(builder)
+    }
+}
+
+/// Build an HTTP client that also trusts the CA certificate in `pem`.
+#[cfg(feature = "otlp")]
+fn http_client(pem: &[u8]) -> Result<reqwest::blocking::Client, ExporterBuildError> {
+    let certificate = reqwest::Certificate::from_pem(pem)
+        .map_err(|error| ExporterBuildError::InternalFailure(error.to_string()))?;
+    // The blocking client starts its own runtime, which panics on a thread
+    // that is already running one.
+    std::thread::spawn(move || {
+        reqwest::blocking::Client::builder()
+            .timeout(OTEL_EXPORTER_OTLP_TIMEOUT_DEFAULT)
+            .add_root_certificate(certificate)
+            .build()
+    })
+    .join()
+    .map_err(|_| ExporterBuildError::ThreadSpawnFailed)?
+    .map_err(|error| ExporterBuildError::InternalFailure(error.to_string()))
+}
+
+/// Builder for the tracing setup performed by [`init_tracing`].
+///
+/// [`init_tracing`] is equivalent to `TracingBuilder::new().init()`. The
+/// builder exists so that optional pieces of the setup can be switched on
+/// without changing the default behavior.
+///
+/// ```no_run
+/// use metrics_tracing_example::TracingBuilder;
+///
+/// # async fn _main() {
+/// let _provider = TracingBuilder::new().with_panic_hook().init();
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TracingBuilder {
+    panic_hook: bool,
+    format: LogFormat,
+    log_file: Option<Arc<File>>,
+    disable_console: bool,
+    #[cfg(feature = "journald")]
+    journald: bool,
+    #[cfg(feature = "otlp")]
+    disable_otlp: bool,
+    disable_log_bridge: bool,
+    log_filter: Option<String>,
+    #[cfg(feature = "otlp")]
+    otlp_endpoint: Option<String>,
+    #[cfg(feature = "otlp")]
+    otlp_protocol: Option<OtlpProtocol>,
+    sampler: Option<TargetSampler>,
+    #[cfg(feature = "otlp")]
+    batch: BatchTuning,
+    shutdown_timeout: Option<Duration>,
+    #[cfg(feature = "otlp")]
+    transport: OtlpTransport,
+    #[cfg(feature = "otel-logs")]
+    otel_logs: Option<opentelemetry_sdk::logs::SdkLoggerProvider>,
+    span_counts: Option<SpanCountLayer>,
+    span_durations: Option<SpanDurationLayer>,
+    long_spans: Option<LongSpanLayer>,
+    event_metrics: Option<EventMetricsLayer>,
+    #[cfg(feature = "eventlog")]
+    event_log: Option<crate::EventLogLayer>,
+    #[cfg(feature = "sentry")]
+    sentry: bool,
+    span_tree: Option<SpanTreeLayer>,
+}
+
+impl TracingBuilder {
+    /// Create a new builder with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Install a panic hook that emits a structured `error!` event before
+    /// unwinding.
+    ///
+    /// By default, a panic in an actor task is printed to stderr and then
+    /// swallowed by the [`JoinHandle`]. It never reaches the `fmt` layer, or
+    /// the OTEL collector. The hook records the panic payload and location as
+    /// fields on an event, which is emitted in the context of whatever span
+    /// was current on the panicking thread. This means the panic shows up in
+    /// the trace for the observation that caused it.
+    ///
+    /// The previously installed hook is still run afterwards, so the usual
+    /// stderr output is preserved.
+    ///
+    /// [`JoinHandle`]: tokio::task::JoinHandle
+    pub fn with_panic_hook(mut self) -> Self {
+        self.panic_hook = true;
+        self
+    }
+
+    /// Use `directives` as the console log filter, instead of reading
+    /// `RUST_LOG`. The syntax is the same as `RUST_LOG`, e.g.
+    /// `"info,metrics_tracing_example=debug"`. Invalid directives are
+    /// ignored.
+    ///
+    /// If `OTEL_FILTER` is not set, this filter is also used for OTEL export.
+    pub fn with_log_filter(mut self, directives: impl Into<String>) -> Self {
+        self.log_filter = Some(directives.into());
+        self
+    }
+
+    /// Export spans to the OTLP collector at `endpoint`, instead of reading
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT`.
+    ///
+    /// Like the env var, this is the base URL of the collector, e.g.
+    /// `http://localhost:4318`. When exporting over HTTP, the `/v1/traces`
+    /// path is appended for you.
+    #[cfg(feature = "otlp")]
+    pub fn with_otlp_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.otlp_endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Export spans using `protocol`, instead of reading
+    /// `OTEL_EXPORTER_OTLP_PROTOCOL`. See [`OtlpProtocol`].
+    ///
+    /// If no endpoint is set, the protocol's
+    /// [default endpoint](OtlpProtocol::default_endpoint) is used. If you set
+    /// one, make sure it's the port for this protocol. Sending gRPC to the
+    /// HTTP port, or vice versa, fails on every export, not at startup.
+    #[cfg(feature = "otlp")]
+    pub const fn with_otlp_protocol(mut self, protocol: OtlpProtocol) -> Self {
+        self.otlp_protocol = Some(protocol);
+        self
+    }
+
+    /// Send the header `name: value` with every OTLP export request, e.g. an
+    /// `Authorization` header for a managed backend.
+    ///
+    /// Headers can also be set with the `OTEL_EXPORTER_OTLP_HEADERS` env
+    /// var, as comma-separated `name=value` pairs. Those take precedence
+    /// over the headers set here. For gRPC, headers are sent as metadata,
+    /// and names must be lowercase.
+    #[cfg(feature = "otlp")]
+    pub fn with_otlp_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.transport.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Trust the PEM-encoded CA certificate `pem` when exporting over TLS,
+    /// instead of reading it from the file at
+    /// `OTEL_EXPORTER_OTLP_CERTIFICATE`.
+    ///
+    /// TLS is used for `https` endpoints, and the platform's root
+    /// certificates are always trusted, so managed backends work without
+    /// this. It is for collectors with a self-signed or private certificate.
+    #[cfg(feature = "otlp")]
+    pub fn with_otlp_ca_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.transport.ca_certificate = Some(pem.into());
+        self
+    }
+
+    /// Decide which traces are exported with `sampler`. See
+    /// [`TargetSampler`]. By default, every trace is exported.
+    ///
+    /// Sampling only affects OTEL export. The console logs are unaffected.
+    pub fn with_sampler(mut self, sampler: TargetSampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Buffer at most `size` finished spans for export. Spans that finish
+    /// while the buffer is full are dropped. Defaults to 2048, or
+    /// `OTEL_BSP_MAX_QUEUE_SIZE`.
+    ///
+    /// ## Tuning the batch processor
+    ///
+    /// Spans are not exported as they finish. The batch processor queues
+    /// them, and a background thread exports them in batches, either every
+    /// [scheduled delay], or as soon as a [full batch] is queued. Each export
+    /// is an HTTP request, so batching trades latency for throughput:
+    ///
+    /// - A short delay gets spans to the collector sooner, at the cost of
+    ///   more, smaller requests. Try `Duration::from_millis(100)` while
+    ///   watching the `good_tracing` example in a viewer.
+    /// - A long delay, or a big batch, means fewer requests, but spans show
+    ///   up later, and more of them are lost if the program crashes.
+    /// - A small queue bounds memory, but drops spans under load, or when
+    ///   the collector is slow. Span drops are reported by the SDK's own
+    ///   internal logs.
+    ///
+    /// Remember that only _closed_ spans are queued. No amount of tuning
+    /// gets the `bad_program_span` example's forever span exported.
+    ///
+    /// [scheduled delay]: Self::with_scheduled_delay
+    /// [full batch]: Self::with_max_export_batch_size
+    #[cfg(feature = "otlp")]
+    pub const fn with_max_queue_size(mut self, size: usize) -> Self {
+        self.batch.max_queue_size = Some(size);
+        self
+    }
+
+    /// Export queued spans at least every `delay`. Defaults to 5 seconds, or
+    /// `OTEL_BSP_SCHEDULE_DELAY`. See [`TracingBuilder::with_max_queue_size`].
+    #[cfg(feature = "otlp")]
+    pub const fn with_scheduled_delay(mut self, delay: Duration) -> Self {
+        self.batch.scheduled_delay = Some(delay);
+        self
+    }
+
+    /// Export at most `size` spans per request, and export as soon as that
+    /// many are queued. Defaults to 512, or `OTEL_BSP_MAX_EXPORT_BATCH_SIZE`.
+    /// See [`TracingBuilder::with_max_queue_size`].
+    #[cfg(feature = "otlp")]
+    pub const fn with_max_export_batch_size(mut self, size: usize) -> Self {
+        self.batch.max_export_batch_size = Some(size);
+        self
+    }
+
+    /// Export each span as soon as it closes, with the SDK's
+    /// [`SimpleSpanProcessor`], instead of in batches. The batch tuning above
+    /// is ignored.
+    ///
+    /// It takes batching out of the picture: a span shows up in the viewer
+    /// the moment it closes, and not a moment sooner. Run the `bad_*`
+    /// examples with it to see exactly when their spans close.
+    ///
+    /// It's for debugging, not production. The simple processor blocks the
+    /// thread that closed the span until the collector answers, with one
+    /// request per span. On a `tokio` worker thread, that stalls every task
+    /// scheduled on it. Over HTTP, the request is sent from a thread of its
+    /// own, so it finishes even on a single worker. Over gRPC, the request
+    /// needs the runtime, so use the multi-threaded runtime with a spare
+    /// worker.
+    ///
+    /// [`SimpleSpanProcessor`]: opentelemetry_sdk::trace::SimpleSpanProcessor
+    #[cfg(feature = "otlp")]
+    pub const fn with_immediate_export(mut self) -> Self {
+        self.batch.immediate = true;
+        self
+    }
+
+    /// Give up on exporting the last spans after `timeout`, when the
+    /// [`TracingHandle`] is shut down. Defaults to
+    /// [`DEFAULT_SHUTDOWN_TIMEOUT`].
+    ///
+    /// Shutting down exports every queued span. If the collector is down,
+    /// or slow, each attempt waits for the export timeout. A short shutdown
+    /// timeout loses those spans, but gets the program out of the way
+    /// promptly when someone presses Ctrl-C.
+    pub const fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the console output format. Defaults to [`LogFormat::Full`].
+    pub const fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Write console output to `file` instead of stdout. ANSI colors are
+    /// disabled, as they are unreadable outside of a terminal.
+    ///
+    /// Open the file in append mode, so that restarts do not truncate the
+    /// previous run's logs.
+    pub fn with_log_file(mut self, file: File) -> Self {
+        self.log_file = Some(Arc::new(file));
+        self
+    }
+
+    /// Enable or disable console output. Enabled by default.
+    ///
+    /// Disable it when another output already has the logs, e.g. with
+    /// [`with_journald`] under systemd, which would otherwise store every
+    /// line twice: once from the journal layer, and once from stdout.
+    ///
+    /// [`with_journald`]: Self::with_journald
+    pub const fn with_console(mut self, enabled: bool) -> Self {
+        self.disable_console = !enabled;
+        self
+    }
+
+    /// Also write events to the systemd journal.
+    ///
+    /// Console output is plain text. Once journald has it, the fields are
+    /// gone, squashed into the message. The journal layer sends each event
+    /// as a journal entry instead, with every field as a separate journal
+    /// field, prefixed with `F_` to stay clear of journald's own fields.
+    /// Levels are mapped to the journal's `PRIORITY`, and the names of the
+    /// enclosing spans are included as `SPAN_NAME`:
+    ///
+    /// ```sh
+    /// journalctl -u sysmon -p warning
+    /// journalctl -u sysmon F_CPU=cpu3 -o verbose
+    /// ```
+    ///
+    /// The journal layer uses the same filter as the console. Combine with
+    /// [`with_console(false)`] to write to the journal only. If the journal
+    /// socket can't be opened, e.g. outside of systemd, a warning is emitted,
+    /// and console output stays on, so the logs go somewhere.
+    ///
+    /// [`with_console(false)`]: Self::with_console
+    #[cfg(feature = "journald")]
+    pub const fn with_journald(mut self) -> Self {
+        self.journald = true;
+        self
+    }
+
+    /// Enable or disable OTLP span export. Enabled by default.
+    ///
+    /// With export disabled, no exporter is built, and no OTEL layer is
+    /// installed. The returned provider has no exporter, so shutting it down
+    /// is a no-op.
+    #[cfg(feature = "otlp")]
+    pub const fn with_otlp(mut self, enabled: bool) -> Self {
+        self.disable_otlp = !enabled;
+        self
+    }
+
+    /// Count open spans per target with `layer`. See [`SpanCountLayer`].
+    ///
+    /// The layer uses the same filter as the console output.
+    pub fn with_span_counts(mut self, layer: SpanCountLayer) -> Self {
+        self.span_counts = Some(layer);
+        self
+    }
+
+    /// Record the durations of selected spans with `layer`. See
+    /// [`SpanDurationLayer`].
+    ///
+    /// Unlike the other layers, this one is not subject to the log filter.
+    /// It has its own filter, which enables exactly the timed spans, so
+    /// turning logging down doesn't turn the latency metrics off with it.
+    pub fn with_span_durations(mut self, layer: SpanDurationLayer) -> Self {
+        self.span_durations = Some(layer);
+        self
+    }
+
+    /// Warn about spans held open for too long with `layer`. See
+    /// [`LongSpanLayer`].
+    ///
+    /// Like the [`SpanDurationLayer`], this layer has its own filter, and is
+    /// not subject to the log filter.
+    pub fn with_long_spans(mut self, layer: LongSpanLayer) -> Self {
+        self.long_spans = Some(layer);
+        self
+    }
+
+    /// Record metrics derived from tracing events with `layer`. See
+    /// [`EventMetricsLayer`].
+    ///
+    /// Like the [`SpanDurationLayer`], this layer has its own filter, which
+    /// enables only the events its rules apply to. Turning logging down
+    /// doesn't turn the metrics off.
+    pub fn with_event_metrics(mut self, layer: EventMetricsLayer) -> Self {
+        self.event_metrics = Some(layer);
+        self
+    }
+
+    /// Also write warnings and errors to the Windows Event Log with `layer`.
+    /// See [`EventLogLayer`].
+    ///
+    /// The layer has its own filter, which enables `WARN` and `ERROR` events
+    /// only, whatever the console filter says.
+    ///
+    /// [`EventLogLayer`]: crate::EventLogLayer
+    #[cfg(feature = "eventlog")]
+    pub fn with_event_log(mut self, layer: crate::EventLogLayer) -> Self {
+        self.event_log = Some(layer);
+        self
+    }
+
+    /// Draw the tree of open spans in the terminal with `layer`. See
+    /// [`SpanTreeLayer`].
+    ///
+    /// Like the [`LongSpanLayer`], this layer has its own filter, and is not
+    /// subject to the log filter. The tree is drawn over stderr, so you'll
+    /// probably want to turn the console logs off.
+    pub fn with_span_tree(mut self, layer: SpanTreeLayer) -> Self {
+        self.span_tree = Some(layer);
+        self
+    }
+
+    /// Also export events as OTEL log records, to `provider`. See
+    /// [`init_otel_logs`].
+    ///
+    /// Events are exported if they pass the OTEL filter, i.e. `OTEL_FILTER`,
+    /// or the console filter if that is unset. Events from the crates that do
+    /// the exporting, like `reqwest` and `opentelemetry_sdk`, are never
+    /// exported, as exporting them would cause more of them.
+    ///
+    /// [`init_otel_logs`]: crate::init_otel_logs
+    #[cfg(feature = "otel-logs")]
+    pub fn with_otel_logs(mut self, provider: &opentelemetry_sdk::logs::SdkLoggerProvider) -> Self {
+        self.otel_logs = Some(provider.clone());
+        self
+    }
+
+    /// Also report `ERROR` events to Sentry, as issues, with `WARN` and
+    /// `INFO` events as breadcrumbs. Start the client with [`init_sentry`]
+    /// first.
+    ///
+    /// The Sentry layer has its own filter, so the console filter doesn't
+    /// hide errors from it.
+    ///
+    /// [`init_sentry`]: crate::init_sentry
+    #[cfg(feature = "sentry")]
+    pub const fn with_sentry(mut self) -> Self {
+        self.sentry = true;
+        self
+    }
+
+    /// Enable or disable the [`log`] bridge. Enabled by default.
+    ///
+    /// Plenty of crates still log with the [`log`] crate, rather than
+    /// `tracing`. Here, that includes `mio` and `reqwest`, which sit
+    /// underneath tokio and the OTLP exporter. Their records go to whatever
+    /// [`log::Log`] implementation is installed, and without one, nowhere.
+    ///
+    /// The bridge is a [`LogTracer`], installed as the global logger, that
+    /// turns each log record into a `tracing` event. The events go through
+    /// the same filters and layers as everything else, with the record's
+    /// module path as their target, so e.g. `RUST_LOG=reqwest=debug` works
+    /// as you'd expect. They have no span context of their own, but they do
+    /// appear inside whatever span is current when they are logged.
+    ///
+    /// Disable the bridge if your program installs its own logger.
+    ///
+    /// [`log`]: https://docs.rs/log
+    /// [`log::Log`]: https://docs.rs/log/latest/log/trait.Log.html
+    pub const fn with_log_bridge(mut self, enabled: bool) -> Self {
+        self.disable_log_bridge = !enabled;
+        self
+    }
+
+    /// Build the console [`fmt::Layer`] in the configured format, writing to
+    /// the configured destination.
+    fn fmt_layer(&self, filter: EnvFilter) -> BoxedLayer {
+        let writer = match &self.log_file {
+            Some(file) => BoxMakeWriter::new(file.clone()),
+            None => BoxMakeWriter::new(std::io::stdout),
+        };
+        let layer = fmt::layer()
+            .with_writer(writer)
+            .with_ansi(self.log_file.is_none());
+
+        match self.format {
+            LogFormat::Full => layer.with_filter(filter).boxed(),
+            LogFormat::Pretty => layer.pretty().with_filter(filter).boxed(),
+            LogFormat::Compact => layer.compact().with_filter(filter).boxed(),
+            LogFormat::Json => layer.json().with_filter(filter).boxed(),
+            LogFormat::Datadog => layer
+                .event_format(DatadogFormat::from_env())
+                .with_filter(filter)
+                .boxed(),
+        }
+    }
+
+    /// Build the OTLP span exporter.
+    #[cfg(feature = "otlp")]
+    fn build_span_exporter(&self) -> Result<SpanExporter, ExporterBuildError> {
+        let protocol = self.otlp_protocol.unwrap_or_else(OtlpProtocol::from_env);
+        let transport = self.transport.clone().or_env()?;
+        build_span_exporter(protocol, self.otlp_endpoint.as_deref(), &transport)
+    }
+
+    /// Initialize the global tracing subscriber, and return a handle to the
+    /// OTEL provider. See [`init_tracing`] for a discussion of what is set
+    /// up.
+    ///
+    /// ## Panics
+    ///
+    /// If called outside of a `tokio` runtime, or if a global subscriber has
+    /// already been set. See [`TracingBuilder::try_init`] to handle those
+    /// instead.
+    pub fn init(self) -> TracingHandle {
+        match self.try_init() {
+            Ok(handle) => handle,
+            Err(ConfigError::NoRuntime) => panic!(
+                "init_tracing must be called from within a tokio runtime. This is a limitation of the opentelemetry exporter."
+            ),
+            Err(error) => panic!("failed to set global default subscriber: {error}"),
+        }
+    }
+
+    /// Like [`TracingBuilder::init`], but returns an error instead of
+    /// panicking.
+    ///
+    /// ## Errors
+    ///
+    /// [`ConfigError::NoRuntime`] if called outside of a `tokio` runtime, and
+    /// [`ConfigError::SubscriberAlreadySet`] if a global subscriber has
+    /// already been set. Nothing is installed in either case.
+    pub fn try_init(self) -> Result<TracingHandle, ConfigError> {
+        if tokio::runtime::Handle::try_current().is_err() {
+            return Err(ConfigError::NoRuntime);
+        }
+
+        let env_filter = match &self.log_filter {
+            Some(directives) => EnvFilter::new(directives),
+            None => EnvFilter::from_default_env(),
+        };
+
+        // load otel from env, if the var is present, otherwise just use fmt
+        let otel_filter = if std::env::var(OTEL_FILTER)
+            .as_ref()
+            .map(String::len)
+            .unwrap_or_default()
+            > 0
+        {
+            EnvFilter::from_env(OTEL_FILTER)
+        } else {
+            env_filter.clone()
+        };
+
+        let mut layers: Vec<BoxedLayer> = Vec::new();
+
+        if let Some(span_counts) = &self.span_counts {
+            layers.push(span_counts.clone().with_filter(env_filter.clone()).boxed());
+        }
+        if let Some(long_spans) = &self.long_spans {
+            let filter = long_spans.filter();
+            layers.push(long_spans.clone().with_filter(filter).boxed());
+        }
+        if let Some(span_durations) = &self.span_durations {
+            let filter = span_durations.filter();
+            layers.push(span_durations.clone().with_filter(filter).boxed());
+        }
+        if let Some(span_tree) = &self.span_tree {
+            let filter = span_tree.filter();
+            layers.push(span_tree.clone().with_filter(filter).boxed());
+        }
+        if let Some(event_metrics) = &self.event_metrics {
+            let filter = event_metrics.filter();
+            layers.push(event_metrics.clone().with_filter(filter).boxed());
+        }
+        #[cfg(feature = "sentry")]
+        if self.sentry {
+            layers.push(crate::sentry_hook::sentry_layer().boxed());
+        }
+        #[cfg(feature = "eventlog")]
+        if let Some(event_log) = &self.event_log {
+            let filter = event_log.filter();
+            layers.push(event_log.clone().with_filter(filter).boxed());
+        }
+        #[cfg(feature = "otel-logs")]
+        if let Some(logger_provider) = &self.otel_logs {
+            let layer = crate::otel_logs::bridge_layer(logger_provider, otel_filter.clone());
+            layers.push(layer.boxed());
+        }
+
+        // Like the exporter below, a journal error is held on to until the
+        // subscriber is installed.
+        let console = !self.disable_console;
+        #[cfg(feature = "journald")]
+        let journald_error = match self.journald.then(tracing_journald::layer) {
+            Some(Ok(layer)) => {
+                layers.push(layer.with_filter(env_filter.clone()).boxed());
+                None
+            }
+            Some(Err(error)) => Some(error),
+            None => None,
+        };
+        #[cfg(feature = "journald")]
+        let console = console || journald_error.is_some();
+        if console {
+            layers.push(self.fmt_layer(env_filter));
+        }
+
+        // The subscriber is not installed yet, so we hold on to the error
+        // and report it once it is.
+        #[cfg(feature = "otlp")]
+        let (otel_provider, exporter_error) = if self.disable_otlp {
+            (init_otel_provider(None, None, self.batch), None)
+        } else {
+            let (otel_provider, exporter_error) = match self.build_span_exporter() {
+                Ok(exporter) => (
+                    init_otel_provider(Some(exporter), self.sampler.clone(), self.batch),
+                    None,
+                ),
+                Err(error) => (init_otel_provider(None, None, self.batch), Some(error)),
+            };
+            layers.push(otel_layer(&otel_provider, otel_filter));
+            (otel_provider, exporter_error)
+        };
+        // Without an exporter, spans still get OTEL span contexts, so trace
+        // IDs are propagated to remote collectors, and make exemplars.
+        #[cfg(not(feature = "otlp"))]
+        let otel_provider = {
+            let otel_provider = init_otel_provider(self.sampler.clone());
+            layers.push(otel_layer(&otel_provider, otel_filter));
+            otel_provider
+        };
+
+        tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layers))
+            .map_err(|_| ConfigError::SubscriberAlreadySet)?;
+
+        // This must come after the subscriber is set, so that the bridge can
+        // use the subscriber's max level to skip log records early.
+        if !self.disable_log_bridge
+            && let Err(error) = LogTracer::builder()
+                .with_max_level(LevelFilter::current().as_log())
+                .init()
+        {
+            tracing::warn!(%error, "failed to install log bridge, is another logger installed?");
+        }
+
+        #[cfg(feature = "otlp")]
+        if let Some(error) = exporter_error {
+            tracing::error!(%error, "failed to build OTLP span exporter, spans will not be exported");
+        }
+        #[cfg(feature = "journald")]
+        if let Some(error) = journald_error {
+            tracing::warn!(%error, "failed to connect to journald, logging to the console instead");
+        }
+
+        if self.panic_hook {
+            install_panic_hook();
+        }
+
+        Ok(TracingHandle {
+            provider: otel_provider,
+            shutdown_timeout: self.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+        })
+    }
+}
+
+/// A handle to the OTEL provider, returned by [`init_tracing`] and
+/// [`TracingBuilder::init`].
+///
+/// Shut it down before exiting, to export the spans that are still queued.
+/// Dropping the last clone of the handle shuts the provider down too, but
+/// silently, with no way to see if it worked.
+///
+/// ## Blocking
+///
+/// [`flush`] and [`shutdown`] block the calling thread until the spans are
+/// exported, or the timeout passes. On a `tokio` worker thread, this stalls
+/// the worker's other tasks, and the gRPC exporter needs those workers to
+/// make progress. From async code, prefer [`spawn_blocking`]:
+///
+/// ```no_run
+/// # async fn _main() -> eyre::Result<()> {
+/// let tracing = metrics_tracing_example::init_tracing();
+/// // ... do some work ...
+/// let handle = tracing.clone();
+/// tokio::task::spawn_blocking(move || handle.flush()).await??;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`flush`]: Self::flush
+/// [`shutdown`]: Self::shutdown
+/// [`spawn_blocking`]: tokio::task::spawn_blocking
+#[derive(Debug, Clone)]
+pub struct TracingHandle {
+    provider: SdkTracerProvider,
+    shutdown_timeout: Duration,
+}
+
+impl TracingHandle {
+    /// The OTEL provider, e.g. for creating tracers directly.
+    pub const fn provider(&self) -> &SdkTracerProvider {
+        &self.provider
+    }
+
+    /// Export every finished span now, instead of waiting for the batch
+    /// processor's next scheduled export.
+    ///
+    /// Spans that are still open are not exported, flushed or not. This is
+    /// useful at checkpoints, e.g. after a job completes, or before a risky
+    /// operation, so that the spans so far survive a crash.
+    pub fn flush(&self) -> OTelSdkResult {
+        self.provider.force_flush()
+    }
+
+    /// Export the remaining spans, and shut the provider down, waiting at
+    /// most the [shutdown timeout]. Spans created afterwards are not
+    /// exported.
+    ///
+    /// [shutdown timeout]: TracingBuilder::with_shutdown_timeout
+    pub fn shutdown(&self) -> OTelSdkResult {
+        self.provider.shutdown_with_timeout(self.shutdown_timeout)
+    }
+}
+
+/// Build an OTLP span exporter for `protocol`, connecting with `transport`.
+///
+/// With no `endpoint`, the exporter reads `OTEL_EXPORTER_OTLP_ENDPOINT`, and
+/// falls back to the protocol's [default endpoint].
+///
+/// [default endpoint]: OtlpProtocol::default_endpoint
+#[cfg(feature = "otlp")]
+fn build_span_exporter(
+    protocol: OtlpProtocol,
+    endpoint: Option<&str>,
+    transport: &OtlpTransport,
+) -> Result<SpanExporter, ExporterBuildError> {
+    match protocol {
+        OtlpProtocol::HttpProtobuf => {
+            let builder = transport.http(SpanExporter::builder().with_http())?;
+            match endpoint {
+                Some(endpoint) => {
+                    builder.with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                }
+                None => builder,
+            }
+            .build()
+        }
+        OtlpProtocol::Grpc => {
+            let builder = transport.grpc(SpanExporter::builder().with_tonic())?;
+            match endpoint {
+                Some(endpoint) => builder.with_endpoint(endpoint),
+                None => builder,
+            }
+            .build()
+        }
+    }
+}
 
 /// This is the basic tracing initialization function. It sets up the following:
 ///
@@ -69,25 +1007,34 @@ const OTEL_FILTER: &str = "OTEL_FILTER";
 /// [`tracing::Span`]s, although conceptually related. The provider is the root
 /// of the [`opentelemetry::trace::Tracer`] hierarchy, and is a drop-guard for
 /// OTEL tracing setup and the OTLP exporter. Because dropping it will shut
-/// down the OTEL tracing system, this should be held for the lifetime of the
-/// program.  Many bins will want to store this in a [`std::sync::LazyLock`]
-/// like so:
+/// down the OTEL tracing system, the returned [`TracingHandle`] should be
+/// held for the lifetime of the program.  Many bins will want to store this
+/// in a [`std::sync::LazyLock`] like so:
 ///
 /// ```rust
 /// use std::sync::LazyLock;
-/// use metrics_tracing_example::init_tracing;
-/// use opentelemetry_sdk::trace::SdkTracerProvider;
+/// use metrics_tracing_example::{TracingHandle, init_tracing};
 ///
-/// static OTEL_PROVIDER: LazyLock<SdkTracerProvider> = LazyLock::new(init_tracing);
+/// static OTEL_PROVIDER: LazyLock<TracingHandle> = LazyLock::new(init_tracing);
 /// ```
 ///
 /// The [`SdkTracerProvider`] configures itself automatically using the
 /// following env vars:
 ///
-/// - `OTEL_EXPORTER_OTLP_ENDPOINT` - The endpoint to send spans to.
+/// - `OTEL_EXPORTER_OTLP_ENDPOINT` - The endpoint to send spans to. Overridden
+///   by [`TracingBuilder::with_otlp_endpoint`].
 /// - `OTEL_TRACES_EXPORTER` -  The exporter to use. Typically `otlp`
-/// - `OTEL_EXPORTER_OTLP_PROTOCOL` - The protocol to use. Typically `http/
-///   protobuf` or `grpc`.
+/// - `OTEL_EXPORTER_OTLP_PROTOCOL` - The protocol to use, `http/protobuf` or
+///   `grpc`. Overridden by [`TracingBuilder::with_otlp_protocol`].
+/// - `OTEL_SERVICE_NAME` - The service name spans are reported under. Defaults
+///   to the crate name.
+/// - `OTEL_RESOURCE_ATTRIBUTES` - Extra attributes describing this process,
+///   as comma-separated `key=value` pairs. These override the defaults, e.g.
+///   `deployment.environment.name=staging`.
+///
+/// Respecting the standard variables means this program can be configured
+/// the same way as any other OTEL-instrumented service, by whatever deploys
+/// it, without learning any program-specific flags.
 ///
 /// Additional configuration can be found in the [`opentelemetry_sdk`] crate.
 ///
@@ -97,40 +1044,34 @@ const OTEL_FILTER: &str = "OTEL_FILTER";
 /// runtime.
 ///
 /// [`Filter`]: tracing_subscriber::layer::Filter
-pub fn init_tracing() -> SdkTracerProvider {
-    if tokio::runtime::Handle::try_current().is_err() {
-        panic!(
-            "init_tracing must be called from within a tokio runtime. This is a limitation of the opentelemetry exporter."
-        );
-    }
-
-    let registry = tracing_subscriber::registry();
-
-    let env_filter = EnvFilter::from_default_env();
-
-    // load otel from env, if the var is present, otherwise just use fmt
-    let otel_filter = if std::env::var(OTEL_FILTER)
-        .as_ref()
-        .map(String::len)
-        .unwrap_or_default()
-        > 0
-    {
-        EnvFilter::from_env(OTEL_FILTER)
-    } else {
-        env_filter.clone()
-    };
-
-    let otel_provider = init_otel_provider();
-    let tracer = otel_provider.tracer("tracing-otel-subscriber");
+pub fn init_tracing() -> TracingHandle {
+    TracingBuilder::new().init()
+}
 
-    let otel_layer = tracing_opentelemetry::layer()
-        .with_tracer(tracer)
-        .with_filter(otel_filter);
-    let fmt_layer = fmt::layer().with_filter(env_filter);
+/// Install a panic hook that routes panics through [`tracing`].
+///
+/// The hook runs on the panicking thread, before unwinding begins. That means
+/// the current span is still entered, and the `error!` event becomes part of
+/// the trace that was in progress when the panic happened. After emitting the
+/// event, the previous hook is invoked, so stderr output is unchanged.
+fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info: &PanicHookInfo<'_>| {
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("<non-string panic payload>");
+        let location = info
+            .location()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| "<unknown>".to_owned());
 
-    registry.with(fmt_layer).with(otel_layer).init();
+        tracing::error!(panic.payload = payload, panic.location = location, "panic");
 
-    otel_provider
+        previous(info);
+    }));
 }
 
 /// Instantiate a new Otel provider. This is the simplest possible setup.
@@ -148,24 +1089,99 @@ pub fn init_tracing() -> SdkTracerProvider {
 /// `MetricExporter` (with the "metrics" feature), however, this example only
 /// uses the [`SpanExporter`].
 ///
+/// The provider also decides which spans are exported, using a sampler. By
+/// default, it exports all of them. See [`TargetSampler`] for an
+/// alternative.
+///
+/// Closed spans reach the exporter through a batch span processor, which
+/// exports them from a background thread. See
+/// [`TracingBuilder::with_max_queue_size`] for tuning it, and
+/// [`TracingBuilder::with_immediate_export`] for the simple processor,
+/// which exports each span as it closes.
+///
 /// For additional provider configuration, see the [`opentelemetry_sdk`] crate.
 ///
+/// Building the exporter doesn't connect to anything, so when it fails, it's
+/// the configuration that's wrong, and retrying won't help. The provider is
+/// then built without an exporter. Spans are then still created and
+/// shown by the `fmt` layer, but not exported. Losing telemetry is bad, but
+/// crashing the program because telemetry is unavailable is worse.
+///
 /// [`LogExporter`]: opentelemetry_otlp::LogExporter
 /// [`MetricExporter`]: opentelemetry_otlp::MetricExporter
-/// [`SpanExporter`]: opentelemetry_otlp::SpanExporter
 /// [standard env vars]: https://opentelemetry.io/docs/languages/sdk-configuration/otlp-exporter/
-fn init_otel_provider() -> SdkTracerProvider {
-    let exporter = opentelemetry_otlp::SpanExporter::builder()
-        .with_http()
-        .build()
-        .unwrap();
+fn init_otel_provider(
+    #[cfg(feature = "otlp")] exporter: Option<SpanExporter>,
+    sampler: Option<TargetSampler>,
+    #[cfg(feature = "otlp")] batch: BatchTuning,
+) -> SdkTracerProvider {
+    // If export trace to AWS X-Ray, you can use XrayIdGenerator
+    let mut builder = SdkTracerProvider::builder().with_resource(create_otel_resource());
 
-    SdkTracerProvider::builder()
-        // Customize sampling strategy
-        // If export trace to AWS X-Ray, you can use XrayIdGenerator
-        .with_resource(create_otel_resource())
-        .with_batch_exporter(exporter)
-        .build()
+    if let Some(sampler) = sampler {
+        builder = builder.with_sampler(sampler);
+    }
+
+    #[cfg(feature = "otlp")]
+    if let Some(exporter) = exporter {
+        builder = if batch.immediate {
+            builder.with_simple_exporter(OffRuntime(exporter))
+        } else {
+            builder.with_span_processor(
+                BatchSpanProcessor::builder(exporter)
+                    .with_batch_config(batch.config())
+                    .build(),
+            )
+        };
+    }
+    builder.build()
+}
+
+/// A span exporter that exports from a thread of its own.
+///
+/// The simple processor exports on the thread that closed the span, which is
+/// usually a `tokio` worker. The blocking HTTP client panics there, and
+/// blocking the worker leaves nothing to drive the export's I/O when it is
+/// the runtime's only one. A fresh thread has neither problem.
+#[cfg(feature = "otlp")]
+#[derive(Debug)]
+struct OffRuntime(SpanExporter);
+
+#[cfg(feature = "otlp")]
+impl opentelemetry_sdk::trace::SpanExporter for OffRuntime {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| futures_executor::block_on(self.0.export(batch)))
+                .join()
+        })
+        .unwrap_or_else(|_| Err(OTelSdkError::InternalFailure("span export panicked".into())))
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.0.set_resource(resource);
+    }
+}
+
+/// The layer that turns `tracing` spans into OTEL spans, made by `provider`.
+fn otel_layer(provider: &SdkTracerProvider, filter: EnvFilter) -> BoxedLayer {
+    let tracer = provider.tracer("tracing-otel-subscriber");
+
+    // The span level is recorded as an attribute, so that the sampler can
+    // see it.
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_level(true)
+        .with_filter(filter)
+        .boxed()
 }
 
 /// This creates a [`Resource`].
@@ -173,16 +1189,122 @@ fn init_otel_provider() -> SdkTracerProvider {
 /// Otel resources describe the application being instrumented. They're used by
 /// collectors to organize and label telemetry data.
 ///
-/// The resource should be fairly static, so we just hardcode some values here.
-fn create_otel_resource() -> Resource {
-    Resource::builder()
+/// The resource should be fairly static, so we hardcode some defaults here,
+/// and add the `host.name`, so that telemetry from several hosts can be told
+/// apart.
+/// The operator can override them with the [standard env vars], in order of
+/// increasing precedence:
+///
+/// - `DD_SERVICE`, `DD_ENV`, and `DD_VERSION` - Datadog's unified service
+///   tags, for the service name, environment, and version.
+/// - `OTEL_RESOURCE_ATTRIBUTES` - comma-separated `key=value` pairs, e.g.
+///   `deployment.environment.name=staging,host.name=box-1`.
+/// - `OTEL_SERVICE_NAME` - the `service.name` attribute.
+///
+/// Note that [`Resource::builder`] would also read these, but it puts the env
+/// _underneath_ our defaults, and its service name detector falls back to
+/// `unknown_service`, which then replaces ours. So we start from an empty
+/// resource and layer things up ourselves.
+///
+/// [standard env vars]: https://opentelemetry.io/docs/languages/sdk-configuration/general/
+pub(crate) fn create_otel_resource() -> Resource {
+    let builder = Resource::builder_empty()
         .with_schema_url(
             vec![
                 KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
                 KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
                 KeyValue::new(DEPLOYMENT_ENVIRONMENT_NAME, "production"),
+                KeyValue::new(HOST_NAME, crate::wire::local_host().to_string()),
             ],
             SCHEMA_URL,
         )
-        .build()
+        .with_attributes(datadog::resource_attributes())
+        .with_detector(Box::new(EnvResourceDetector::new()));
+
+    match std::env::var(OTEL_SERVICE_NAME) {
+        Ok(name) if !name.is_empty() => builder.with_service_name(name),
+        _ => builder,
+    }
+    .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_endpoints_match_spec_ports() {
+        assert_eq!(
+            OtlpProtocol::HttpProtobuf.default_endpoint(),
+            "http://localhost:4318"
+        );
+        assert_eq!(
+            OtlpProtocol::Grpc.default_endpoint(),
+            "http://localhost:4317"
+        );
+    }
+
+    #[test]
+    fn parses_spec_protocol_names() {
+        assert_eq!(
+            OtlpProtocol::from_name("http/protobuf"),
+            Some(OtlpProtocol::HttpProtobuf)
+        );
+        assert_eq!(OtlpProtocol::from_name(" grpc "), Some(OtlpProtocol::Grpc));
+        assert_eq!(OtlpProtocol::from_name("http/json"), None);
+    }
+
+    #[test]
+    fn try_init_needs_a_runtime() {
+        assert_eq!(
+            TracingBuilder::new().try_init().unwrap_err(),
+            ConfigError::NoRuntime
+        );
+    }
+
+    // Building an exporter doesn't connect to anything, so these pass without
+    // a collector running. The gRPC exporter needs a runtime for its channel.
+
+    #[cfg(feature = "otlp")]
+    #[tokio::test]
+    async fn http_exporter_builds() {
+        let transport = OtlpTransport::default();
+        build_span_exporter(OtlpProtocol::HttpProtobuf, None, &transport).unwrap();
+        build_span_exporter(
+            OtlpProtocol::HttpProtobuf,
+            Some(OtlpProtocol::HttpProtobuf.default_endpoint()),
+            &transport,
+        )
+        .unwrap();
+    }
+
+    #[cfg(feature = "otlp")]
+    #[tokio::test]
+    async fn grpc_exporter_builds() {
+        let transport = OtlpTransport::default();
+        build_span_exporter(OtlpProtocol::Grpc, None, &transport).unwrap();
+        build_span_exporter(
+            OtlpProtocol::Grpc,
+            Some(OtlpProtocol::Grpc.default_endpoint()),
+            &transport,
+        )
+        .unwrap();
+    }
+
+    #[cfg(feature = "otlp")]
+    #[tokio::test]
+    async fn exporters_build_with_headers() {
+        let transport = OtlpTransport {
+            headers: vec![("authorization".into(), "Bearer token".into())],
+            ..Default::default()
+        };
+        build_span_exporter(OtlpProtocol::HttpProtobuf, None, &transport).unwrap();
+        build_span_exporter(OtlpProtocol::Grpc, None, &transport).unwrap();
+
+        let transport = OtlpTransport {
+            headers: vec![("not a header".into(), "value".into())],
+            ..Default::default()
+        };
+        assert!(build_span_exporter(OtlpProtocol::Grpc, None, &transport).is_err());
+    }
 }
@@ -0,0 +1,125 @@
+//! A terminal dashboard. See [`TuiDashboard`].
+
+use crate::StatsReport;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, LineGauge, Paragraph},
+};
+use std::{io, time::Duration};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::debug;
+
+/// How long to wait for a key press before checking for a new report.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A terminal dashboard actor that draws the latest [`StatsReport`].
+///
+/// The dashboard shows the rolling averages over the stats window, and a
+/// usage bar for each core in the most recent observation. It reads reports
+/// from the [`watch`] channel returned by [`SysStats::subscribe`] or
+/// [`PipelineHandle::subscribe_stats`].
+///
+/// The dashboard takes over the terminal while it runs, so console logging
+/// should be disabled, e.g. with [`TracingBuilder::with_log_filter`]`("off")`.
+/// Otherwise log lines will be drawn over the dashboard. OTEL export is
+/// unaffected if `OTEL_FILTER` is set.
+///
+/// The dashboard exits when the user presses `q`, `Esc`, or `Ctrl-C`, or when
+/// the stats processor exits. Note that the terminal is in raw mode while the
+/// dashboard runs, so `Ctrl-C` is delivered as a key press rather than a
+/// signal. Await the [`JoinHandle`] to find out when the user quit.
+///
+/// [`SysStats::subscribe`]: crate::SysStats::subscribe
+/// [`PipelineHandle::subscribe_stats`]: crate::PipelineHandle::subscribe_stats
+/// [`TracingBuilder::with_log_filter`]: crate::TracingBuilder::with_log_filter
+#[derive(Debug)]
+pub struct TuiDashboard {
+    reports: watch::Receiver<StatsReport>,
+}
+
+impl TuiDashboard {
+    /// Create a new dashboard that draws reports from `reports`.
+    pub const fn new(reports: watch::Receiver<StatsReport>) -> Self {
+        Self { reports }
+    }
+
+    /// Spawn the dashboard.
+    ///
+    /// Terminal IO is blocking, so the dashboard runs on tokio's blocking
+    /// thread pool, rather than as an async task.
+    pub fn spawn(self) -> JoinHandle<io::Result<()>> {
+        tokio::task::spawn_blocking(move || {
+            let mut terminal = ratatui::try_init()?;
+            let res = self.run(&mut terminal);
+            ratatui::try_restore()?;
+            res
+        })
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            let report = self.reports.borrow_and_update().clone();
+            terminal.draw(|frame| draw(frame, &report))?;
+
+            if event::poll(POLL_INTERVAL)?
+                && let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    debug!("Dashboard closed by user");
+                    return Ok(());
+                }
+            }
+
+            // `has_changed` errors once the sender is dropped.
+            if self.reports.has_changed().is_err() {
+                debug!("Stats processor exited, closing dashboard");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Draw a single frame.
+fn draw(frame: &mut Frame<'_>, report: &StatsReport) {
+    let [summary_area, cores_area] =
+        Layout::vertical([Constraint::Length(4), Constraint::Min(0)]).areas(frame.area());
+
+    let summary = Paragraph::new(vec![
+        Line::from(format!(
+            "avg usage: {:>6.2}% (σ {:>5.2})   avg freq: {:>8.2} MHz",
+            report.average_usage, report.usage_stddev, report.average_freq_mhz
+        )),
+        Line::from(format!(
+            "window: {} observations   cpus: {}",
+            report.observations, report.cpus
+        )),
+    ])
+    .block(Block::bordered().title(" sysmon (q to quit) "));
+    frame.render_widget(summary, summary_area);
+
+    let block = Block::bordered().title(" per-core usage ");
+    let inner = block.inner(cores_area);
+    frame.render_widget(block, cores_area);
+
+    let rows = Layout::vertical(report.latest.iter().map(|_| Constraint::Length(1))).split(inner);
+    for (cpu, row) in report.latest.iter().zip(rows.iter()) {
+        let usage = f64::from(cpu.usage).clamp(0.0, 100.0);
+        let color = match usage {
+            u if u >= 90.0 => Color::Red,
+            u if u >= 60.0 => Color::Yellow,
+            _ => Color::Green,
+        };
+        let gauge = LineGauge::default()
+            .ratio(usage / 100.0)
+            .label(format!("{:<8} {:>6.2}%", cpu.name, usage))
+            .filled_style(Style::default().fg(color));
+        frame.render_widget(gauge, *row);
+    }
+}
@@ -0,0 +1,307 @@
+//! Shipping observations over UDP. See [`UdpSink`] and [`UdpSource`].
+
+use crate::{
+    Observation, SharedSecret, WIRE_VERSION, WireCompression, WireEncoding,
+    wire::{Frame, Rejection, WireFormat, local_host},
+};
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket, lookup_host},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tracing::{debug, info_span, warn};
+
+/// The transport label of the remote observation metrics.
+const TRANSPORT: &str = "udp";
+
+/// The largest UDP payload over IPv4. Observations of hosts with so many
+/// CPUs that they don't fit are dropped.
+const MAX_DATAGRAM_LEN: usize = 65_507;
+
+/// Sends observations to a remote [`UdpSource`], one datagram each.
+///
+/// The [`TcpSink`]'s little brother. Where the TCP sink delivers every
+/// observation in order, or knows that it didn't, the UDP sink fires and
+/// forgets. There is no connection to set up, so nothing to reconnect or
+/// back off from, and no acknowledgements, so a datagram lost on the way is
+/// never known to be lost. In exchange, sending never waits: if the socket's
+/// buffer is full, the observation is dropped, not queued.
+///
+/// That's the right trade for data that's only interesting in aggregate. The
+/// collector's stats over a window of observations barely move when one is
+/// missing, and a monitoring agent that never blocks can't be the thing that
+/// takes its host down. It's the wrong trade for anything that must add up.
+///
+/// Each datagram is a single observation, with the W3C trace context of its
+/// span, encoded and compressed as on the TCP path. Observations the sink
+/// knows it failed to send are counted on the `my_cute_app.remote_dropped`
+/// counter, with the `transport` label `udp`. Those lost on the network
+/// aren't counted anywhere. Compare the agent's `remote_sent` with the
+/// collector's `remote_received` to see how many.
+///
+/// The sink is a pass-through actor, like the [`TcpSink`].
+///
+/// ```no_run
+/// use metrics_tracing_example::{PipelineBuilder, UdpSink};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(16);
+/// let _pipeline = PipelineBuilder::new(Duration::from_secs(5))
+///     .with_outbound(tx)
+///     .spawn()?;
+/// let _sink = UdpSink::connect(rx, "collector.local:7001").await?.spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`TcpSink`]: crate::TcpSink
+#[derive(Debug)]
+pub struct UdpSink {
+    inbound: mpsc::Receiver<Observation>,
+    outbound: Option<mpsc::Sender<Observation>>,
+    socket: UdpSocket,
+    host: Arc<str>,
+    format: WireFormat,
+    secret: Option<SharedSecret>,
+}
+
+impl UdpSink {
+    /// Create a new sink that sends observations from `inbound` to the
+    /// collector at `addr`, e.g. `collector.local:7001`.
+    ///
+    /// ## Errors
+    ///
+    /// If `addr` doesn't resolve, or a socket can't be bound. Nothing is
+    /// sent, so a collector that isn't running isn't an error.
+    pub async fn connect(
+        inbound: mpsc::Receiver<Observation>,
+        addr: impl ToSocketAddrs,
+    ) -> io::Result<Self> {
+        let peer = lookup_host(addr).await?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing")
+        })?;
+        let local: SocketAddr = match peer {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(peer).await?;
+        Ok(Self {
+            inbound,
+            outbound: None,
+            socket,
+            host: local_host(),
+            format: WireFormat::default(),
+            secret: None,
+        })
+    }
+
+    /// Tag observations with `host`, instead of this host's name.
+    /// Observations that are tagged already, e.g. by a source upstream, keep
+    /// their tag.
+    pub fn with_host(mut self, host: impl Into<Arc<str>>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    /// Encode observations with `encoding`, instead of JSON.
+    pub const fn with_encoding(mut self, encoding: WireEncoding) -> Self {
+        self.format.encoding = encoding;
+        self
+    }
+
+    /// Compress observations with `compression`. See [`WireCompression`].
+    pub const fn with_compression(mut self, compression: WireCompression) -> Self {
+        self.format.compression = compression;
+        self
+    }
+
+    /// Write version `version` of the wire format, instead of
+    /// [`WIRE_VERSION`], for a collector that is older than the agent. See
+    /// [`TcpSink::with_wire_version`].
+    ///
+    /// ## Panics
+    ///
+    /// If `version` is newer than [`WIRE_VERSION`].
+    ///
+    /// [`TcpSink::with_wire_version`]: crate::TcpSink::with_wire_version
+    pub const fn with_wire_version(mut self, version: u8) -> Self {
+        assert!(version <= WIRE_VERSION, "unknown wire version");
+        self.format.version = version;
+        self
+    }
+
+    /// Sign observations with `secret`, so that a source with the same
+    /// secret accepts them. See [`SharedSecret`].
+    pub fn with_secret(mut self, secret: SharedSecret) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Forward observations to `outbound` after sending them.
+    pub fn with_outbound(mut self, outbound: mpsc::Sender<Observation>) -> Self {
+        self.outbound = Some(outbound);
+        self
+    }
+
+    /// Spawn the sink task. It runs until the inbound channel is closed.
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut buf = Vec::new();
+            // Counts every observation, sent or dropped, so the collector
+            // can tell how many it missed.
+            let mut next_seq = 0;
+            while let Some(obs) = self.inbound.recv().await {
+                let seq = next_seq;
+                next_seq += 1;
+                let sent = Frame::new(&obs, &self.host, seq)
+                    .encode_datagram(&mut buf, self.format, self.secret.as_ref())
+                    .and_then(|encoded| {
+                        if buf.len() > MAX_DATAGRAM_LEN {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "observation too large for a datagram",
+                            ));
+                        }
+                        // `try_send`, not `send`: a full socket buffer drops
+                        // the observation, rather than stalling the sink.
+                        self.socket.try_send(&buf).map(|sent| (encoded, sent))
+                    });
+                match sent {
+                    Ok((encoded, sent)) => {
+                        crate::metrics::record_remote_sent(TRANSPORT);
+                        crate::metrics::record_remote_bytes(TRANSPORT, self.format, encoded, sent);
+                    }
+                    Err(error) => {
+                        // The collector not listening is reported as a
+                        // refused connection, on the send after the one that
+                        // found out. Expected while it restarts, so not worth
+                        // a warning per observation.
+                        if error.kind() == io::ErrorKind::ConnectionRefused {
+                            debug!(%error, "failed to send observation");
+                        } else {
+                            obs.in_scope(|_| warn!(%error, "failed to send observation"));
+                        }
+                        crate::metrics::record_remote_dropped(TRANSPORT);
+                    }
+                }
+
+                if let Some(outbound) = &self.outbound
+                    && outbound.send(obs).await.is_err()
+                {
+                    debug!("Outbound receiver dropped, stopping forwarding");
+                    self.outbound = None;
+                }
+            }
+        })
+    }
+}
+
+/// Receives observations from remote [`UdpSink`]s, and sends them on to a
+/// channel, e.g. the inbound channel of a [`SysStats`], or a [`Collector`].
+///
+/// Each received observation gets a new `Remote observation` span, and a
+/// host tag, as with the [`TcpSource`]. Malformed datagrams, and those
+/// [rejected] for their signature, are logged and skipped.
+///
+/// ```no_run
+/// use metrics_tracing_example::{SysStats, UdpSource};
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let (tx, rx) = mpsc::channel(16);
+/// let _source = UdpSource::bind("0.0.0.0:7001").await?.spawn(tx);
+/// let _stats = SysStats::new(rx, None).spawn();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`SysStats`]: crate::SysStats
+/// [`Collector`]: crate::Collector
+/// [`TcpSource`]: crate::TcpSource
+/// [rejected]: UdpSource::with_secret
+#[derive(Debug)]
+pub struct UdpSource {
+    socket: UdpSocket,
+    secret: Option<SharedSecret>,
+}
+
+impl UdpSource {
+    /// Listen for agents on `addr`.
+    ///
+    /// ## Errors
+    ///
+    /// If the socket can't be bound, e.g. because the port is in use.
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await?,
+            secret: None,
+        })
+    }
+
+    /// Only accept observations signed with `secret`. See
+    /// [`SharedSecret`].
+    pub fn with_secret(mut self, secret: SharedSecret) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// The address the source is listening on. Useful after binding port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Spawn the source task. It receives datagrams until `outbound` is
+    /// closed.
+    pub fn spawn(self, outbound: mpsc::Sender<Observation>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            // One byte more than the largest datagram we send, so a larger
+            // one is truncated into a decoding error, not a valid prefix.
+            let mut buf = vec![0; MAX_DATAGRAM_LEN + 1];
+            loop {
+                let received = tokio::select! {
+                    _ = outbound.closed() => break,
+                    received = self.socket.recv_from(&mut buf) => received,
+                };
+                let (len, peer) = match received {
+                    Ok(received) => received,
+                    Err(error) => {
+                        warn!(%error, "failed to receive datagram");
+                        continue;
+                    }
+                };
+                match Frame::decode(&buf[..len], self.secret.as_ref()) {
+                    Ok(frame) => {
+                        crate::metrics::record_remote_received(TRANSPORT);
+                        // Frames from agents that don't say which host they are on are
+                        // tagged with the address they came from.
+                        let host = frame
+                            .host()
+                            .cloned()
+                            .unwrap_or_else(|| peer.ip().to_string().into());
+                        let span = info_span!("Remote observation", %peer, %host);
+                        let obs = frame.into_observation(span).with_host(host);
+                        if outbound.send(obs).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(error) => match Rejection::of(&error) {
+                        Some(rejection) => {
+                            crate::metrics::record_remote_rejected(TRANSPORT, rejection);
+                            warn!(%peer, %error, "rejecting datagram");
+                        }
+                        None => warn!(%peer, %error, "skipping malformed datagram"),
+                    },
+                }
+            }
+            debug!("Outbound receiver dropped, closing UDP source");
+        })
+    }
+}
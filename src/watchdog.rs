@@ -0,0 +1,123 @@
+//! The [`Watchdog`] actor flags a stalled monitor.
+
+use crate::Health;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// Watches the [`SysMonitor`] heartbeat, and flags it as stalled if no
+/// observation has been produced within `tolerance`.
+///
+/// A task that panics or exits is easy to notice: its [`JoinHandle`]
+/// resolves. A task that is alive but stuck, e.g. blocked on a syscall or a
+/// full channel, is much harder to spot. It emits nothing, and the absence of
+/// data is easy to miss on a dashboard. The watchdog turns that absence into
+/// a signal:
+///
+/// - An `error!` event when the monitor becomes stalled, and an `info!` event
+///   when it recovers. These are emitted once per transition, rather than on
+///   every check, to avoid flooding the logs.
+/// - The `my_cute_app.monitor_stalled` gauge, which is `1` while the monitor
+///   is stalled and `0` otherwise. Alert on this!
+///
+/// The watchdog reads the monitor's heartbeat from a shared [`Health`]. The
+/// [`PipelineBuilder`] sets this up for you.
+///
+/// [`SysMonitor`]: crate::SysMonitor
+/// [`PipelineBuilder`]: crate::PipelineBuilder
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    health: Health,
+    tolerance: Duration,
+}
+
+impl Watchdog {
+    /// Create a new watchdog that flags the monitor as stalled if it goes
+    /// `tolerance` without producing an observation.
+    pub const fn new(health: Health, tolerance: Duration) -> Self {
+        Self { health, tolerance }
+    }
+
+    /// Spawn the watchdog task. It checks the heartbeat twice per
+    /// `tolerance`, and runs until aborted.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let period = (self.tolerance / 2).max(Duration::from_millis(1));
+            let mut interval = tokio::time::interval(period);
+            let mut stalled = false;
+            crate::metrics::record_monitor_stalled(false);
+
+            loop {
+                interval.tick().await;
+
+                let age = self.health.check().monitor_age;
+                let now_stalled = age > self.tolerance;
+
+                match (stalled, now_stalled) {
+                    (false, true) => error!(
+                        age_ms = age.as_millis() as u64,
+                        tolerance_ms = self.tolerance.as_millis() as u64,
+                        "monitor stalled, no observation within tolerance"
+                    ),
+                    (true, false) => info!(age_ms = age.as_millis() as u64, "monitor recovered"),
+                    _ => continue,
+                }
+
+                crate::metrics::record_monitor_stalled(now_stalled);
+                stalled = now_stalled;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{EventMatcher, capture};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use tracing::Level;
+
+    #[tokio::test(start_paused = true)]
+    async fn stalls_and_recoveries_are_reported_once() {
+        let (captured, _guard) = capture();
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let _recorder = metrics::set_default_local_recorder(&recorder);
+        let stalled = || {
+            snapshotter
+                .snapshot()
+                .into_vec()
+                .into_iter()
+                .find(|(key, ..)| key.key().name() == "my_cute_app.monitor_stalled")
+                .map(|(.., value)| value)
+        };
+
+        let health = Health::new(Duration::from_millis(100));
+        let watchdog = Watchdog::new(health.clone(), Duration::from_millis(100)).spawn();
+
+        // No beats for several checks.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(stalled(), Some(DebugValue::Gauge(1.0.into())));
+
+        // Beats at every check.
+        for _ in 0..10 {
+            health.monitor_beat();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(stalled(), Some(DebugValue::Gauge(0.0.into())));
+        watchdog.abort();
+
+        let stalls = captured.events_matching(
+            &EventMatcher::new()
+                .with_level(Level::ERROR)
+                .with_message("monitor stalled, no observation within tolerance"),
+        );
+        assert_eq!(stalls.len(), 1);
+        let recoveries = captured.events_matching(
+            &EventMatcher::new()
+                .with_level(Level::INFO)
+                .with_message("monitor recovered"),
+        );
+        assert_eq!(recoveries.len(), 1);
+    }
+}
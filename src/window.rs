@@ -0,0 +1,240 @@
+//! A fixed-size sliding window. See [`Window`].
+
+/// A fixed-capacity ring buffer, holding the most recent `capacity` items.
+///
+/// The buffer is allocated once, at its full capacity, when the window is
+/// created. Pushing into a full window overwrites the oldest item in place,
+/// and hands it back to the caller. Nothing is ever shifted or reallocated,
+/// so every push is `O(1)` and allocation-free.
+///
+/// This is what the [`SysStats`] processor uses to hold its observation
+/// window. Handing back the evicted item lets the caller update any running
+/// totals, without having to look the item up before pushing.
+///
+/// ```
+/// use metrics_tracing_example::Window;
+///
+/// let mut window = Window::new(2);
+/// assert_eq!(window.push(1), None);
+/// assert_eq!(window.push(2), None);
+/// assert_eq!(window.push(3), Some(1));
+/// assert_eq!(window.iter().copied().collect::<Vec<_>>(), [2, 3]);
+/// ```
+///
+/// [`SysStats`]: crate::SysStats
+#[derive(Debug, Clone)]
+pub struct Window<T> {
+    /// Never grows beyond `capacity`. Until the window is full, items are
+    /// appended. After that, `head` is the index of the oldest item, which
+    /// the next push overwrites.
+    items: Vec<T>,
+    head: usize,
+    capacity: usize,
+}
+
+impl<T> Window<T> {
+    /// Create an empty window that holds up to `capacity` items.
+    ///
+    /// ## Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "window capacity must be non-zero");
+        Self {
+            items: Vec::with_capacity(capacity),
+            head: 0,
+            capacity,
+        }
+    }
+
+    /// Add an item to the window. If the window was full, the oldest item is
+    /// evicted and returned.
+    pub fn push(&mut self, item: T) -> Option<T> {
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+            return None;
+        }
+
+        let evicted = std::mem::replace(&mut self.items[self.head], item);
+        self.head = (self.head + 1) % self.capacity;
+        Some(evicted)
+    }
+
+    /// The number of items in the window.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Whether the window is empty.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Whether the window is full, i.e. whether the next push will evict an
+    /// item.
+    pub fn is_full(&self) -> bool {
+        self.items.len() == self.capacity
+    }
+
+    /// The maximum number of items in the window.
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The oldest item in the window.
+    pub fn oldest(&self) -> Option<&T> {
+        self.items.get(self.head)
+    }
+
+    /// The most recently pushed item in the window.
+    pub fn latest(&self) -> Option<&T> {
+        match self.head {
+            0 => self.items.last(),
+            head => self.items.get(head - 1),
+        }
+    }
+
+    /// The items in the window as two slices, oldest first. Either slice may
+    /// be empty.
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        let (newer, older) = self.items.split_at(self.head);
+        (older, newer)
+    }
+
+    /// Iterate over the items in the window, from oldest to newest.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        let (older, newer) = self.as_slices();
+        older.iter().chain(newer)
+    }
+
+    /// Make room for up to `capacity` items, keeping the ones already in
+    /// the window. A window never shrinks: a smaller `capacity` does
+    /// nothing.
+    ///
+    /// ```
+    /// use metrics_tracing_example::Window;
+    ///
+    /// let mut window = Window::new(2);
+    /// window.push(1);
+    /// window.push(2);
+    /// window.push(3);
+    /// window.grow(3);
+    /// assert_eq!(window.push(4), None);
+    /// assert_eq!(window.iter().copied().collect::<Vec<_>>(), [2, 3, 4]);
+    /// ```
+    pub fn grow(&mut self, capacity: usize) {
+        if capacity <= self.capacity {
+            return;
+        }
+        // Put the oldest item first, so that appending carries on from the
+        // newest.
+        self.items.rotate_left(self.head);
+        self.head = 0;
+        self.items.reserve_exact(capacity - self.items.len());
+        self.capacity = capacity;
+    }
+
+    /// Remove every item from the window, keeping its allocation.
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.head = 0;
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Window<T> {
+    type Item = &'a T;
+    type IntoIter = std::iter::Chain<std::slice::Iter<'a, T>, std::slice::Iter<'a, T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let (older, newer) = self.as_slices();
+        older.iter().chain(newer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(window: &Window<u32>) -> Vec<u32> {
+        window.iter().copied().collect()
+    }
+
+    #[test]
+    fn fills_without_evicting() {
+        let mut window = Window::new(3);
+        assert!(window.is_empty());
+        assert_eq!(window.latest(), None);
+        assert_eq!(window.oldest(), None);
+
+        assert_eq!(window.push(1), None);
+        assert_eq!(window.push(2), None);
+        assert!(!window.is_full());
+        assert_eq!(window.push(3), None);
+        assert!(window.is_full());
+
+        assert_eq!(contents(&window), [1, 2, 3]);
+        assert_eq!(window.oldest(), Some(&1));
+        assert_eq!(window.latest(), Some(&3));
+    }
+
+    #[test]
+    fn evicts_oldest_across_wraparound() {
+        let mut window = Window::new(3);
+        for i in 0..3 {
+            window.push(i);
+        }
+
+        // Go around the ring several times, checking the order at every
+        // possible head position.
+        for i in 3..20 {
+            assert_eq!(window.push(i), Some(i - 3));
+            assert_eq!(window.len(), 3);
+            assert_eq!(contents(&window), [i - 2, i - 1, i]);
+            assert_eq!(window.oldest(), Some(&(i - 2)));
+            assert_eq!(window.latest(), Some(&i));
+            assert_eq!(window.iter().next_back(), Some(&i));
+        }
+    }
+
+    #[test]
+    fn never_reallocates() {
+        let mut window = Window::new(4);
+        let ptr = window.items.as_ptr();
+        for i in 0..100 {
+            window.push(i);
+        }
+        assert_eq!(window.items.as_ptr(), ptr);
+        assert_eq!(window.items.capacity(), 4);
+    }
+
+    #[test]
+    fn capacity_one() {
+        let mut window = Window::new(1);
+        assert_eq!(window.push(1), None);
+        assert_eq!(window.push(2), Some(1));
+        assert_eq!(window.push(3), Some(2));
+        assert_eq!(contents(&window), [3]);
+        assert_eq!(window.oldest(), window.latest());
+    }
+
+    #[test]
+    fn clear_resets_order() {
+        let mut window = Window::new(3);
+        for i in 0..5 {
+            window.push(i);
+        }
+        window.clear();
+        assert!(window.is_empty());
+
+        window.push(10);
+        window.push(11);
+        assert_eq!(contents(&window), [10, 11]);
+        assert_eq!(window.latest(), Some(&11));
+    }
+
+    #[test]
+    #[should_panic(expected = "non-zero")]
+    fn zero_capacity_panics() {
+        Window::<u32>::new(0);
+    }
+}
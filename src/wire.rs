@@ -0,0 +1,1002 @@
+//! The wire format for shipping observations between hosts. See
+//! [`TcpSink`] and [`UdpSink`].
+//!
+//! Over TCP, each payload is preceded by its length, as a 4 byte big-endian
+//! integer. Over UDP, each datagram is a payload. Whatever else changes,
+//! that framing doesn't: it is what lets a receiver step over a payload it
+//! can't read, and carry on with the next one.
+//!
+//! Each payload starts with an envelope: the magic bytes `SM`, and the
+//! version of the format, [`WIRE_VERSION`], which explains what each version
+//! changed. Then comes a tag byte, saying how the rest is encoded and
+//! compressed: the low nibble is the [`WireEncoding`], and the high nibble
+//! the [`WireCompression`]. Payloads signed with a [`SharedSecret`] set the
+//! `0x08` bit of the tag, and end with the 32 byte HMAC-SHA256 of everything
+//! before it, envelope included.
+//!
+//! [`TcpSink`]: crate::TcpSink
+//! [`UdpSink`]: crate::UdpSink
+
+use crate::{CpuStats, Observation};
+use hmac::{Hmac, KeyInit, Mac};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The largest frame we accept, in bytes. A frame holds a single
+/// observation, which is a few hundred bytes per CPU. Anything bigger is
+/// garbage, or an attack, and not worth allocating for. Compressed frames
+/// are held to the same limit once decompressed.
+pub(crate) const MAX_FRAME_LEN: usize = 1 << 20;
+
+/// The W3C trace context header, in which the observation's span is
+/// propagated.
+const TRACEPARENT: &str = "traceparent";
+
+/// The version of the wire format that observations are shipped in by
+/// default, and the newest one that is read.
+///
+/// Agents and collectors are upgraded one host at a time, so for a while,
+/// they speak different versions. Receivers read every version up to their
+/// own, and reject newer ones, whose layout they can't know, counting them
+/// on the `my_cute_app.remote_rejected` counter with the reason `version`.
+/// Each payload is still length-prefixed, or a datagram of its own, in every
+/// version, so a [`TcpSource`] skips the payloads it can't read, rather
+/// than losing its place in the stream and dropping the connection.
+///
+/// So upgrade the collector first, then the agents. An agent that must talk
+/// to an older collector can be held back to its version with
+/// [`TcpSink::with_wire_version`].
+///
+/// - Version 2 added the sender's sequence number of each observation, and
+///   the time it was taken, so that the [`Collector`] can tell which
+///   observations it missed. They are in the JSON of older versions too,
+///   where older collectors ignore them. Postcard needed the new version.
+/// - Version 1 added an envelope to the start of each payload: the magic
+///   bytes `SM`, and the version.
+/// - Version 0 is everything before: a tag byte, saying how the payload is
+///   encoded, compressed, and signed, and the rest, with no envelope. The
+///   first senders sent bare JSON, without even a tag, which starts with `{`.
+///   Neither `{` nor `S` is a valid tag, so all three can be told apart by
+///   their first byte.
+///
+/// Within a version, fields are only ever added, with defaults, so that
+/// JSON from older agents still decodes. Postcard isn't self-describing, so
+/// adding a field to it needs a new version.
+///
+/// [`TcpSource`]: crate::TcpSource
+/// [`Collector`]: crate::Collector
+/// [`TcpSink::with_wire_version`]: crate::TcpSink::with_wire_version
+pub const WIRE_VERSION: u8 = 2;
+
+/// The first bytes of a payload with an envelope, i.e. of version 1 or
+/// newer.
+const MAGIC: [u8; 2] = *b"SM";
+
+/// The length of the envelope: the magic bytes, and the version.
+const ENVELOPE_LEN: usize = MAGIC.len() + 1;
+
+/// The first byte of a payload of bare JSON, from before payloads were
+/// tagged.
+const UNTAGGED_JSON: u8 = b'{';
+
+/// Set in the tag byte of a signed payload.
+const SIGNED: u8 = 0x08;
+
+/// The length of the HMAC-SHA256 at the end of a signed payload.
+const SIGNATURE_LEN: usize = 32;
+
+/// Whether `payload` says it is signed. Says nothing about whether the
+/// signature is any good.
+fn is_signed(payload: &[u8]) -> bool {
+    payload
+        .first()
+        .is_some_and(|&tag| tag != UNTAGGED_JSON && tag & SIGNED != 0)
+}
+
+/// A secret shared by agents and their collector, so that the collector
+/// only accepts observations from agents that know it.
+///
+/// A collector listening on a network accepts observations from anyone who
+/// can reach it. On a laptop, that's fine. On a network shared with other
+/// people, anyone can feed it made-up numbers, under any host name. Give the
+/// sinks and the sources the same secret, and the sinks sign every frame
+/// with an HMAC-SHA256 of it. The sources check the signature, and reject
+/// frames that are unsigned, or signed with a different secret, counting
+/// them on the `my_cute_app.remote_rejected` counter. A TCP connection that
+/// sends a rejected frame is closed.
+///
+/// Signing proves who sent a frame, and that it wasn't changed on the way.
+/// It doesn't hide it: frames are still readable by anyone on the network.
+/// Nor does it stop a frame from being recorded and sent again later.
+///
+/// Sources without a secret accept signed frames, without checking them, so
+/// the agents can be given the secret before the collector is.
+///
+/// ```no_run
+/// use metrics_tracing_example::{SharedSecret, TcpSink, TcpSource};
+/// use tokio::sync::mpsc;
+///
+/// # async fn _main() -> eyre::Result<()> {
+/// let secret = SharedSecret::new(std::env::var("SYSMON_SECRET")?);
+///
+/// let (tx, rx) = mpsc::channel(16);
+/// let _source = TcpSource::bind("0.0.0.0:7000")
+///     .await?
+///     .with_secret(secret.clone())
+///     .spawn(tx);
+///
+/// let (_tx, rx) = mpsc::channel(16);
+/// let _sink = TcpSink::new(rx, "collector.local:7000")
+///     .with_secret(secret)
+///     .spawn();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SharedSecret {
+    /// Keyed once, and cloned for every frame.
+    mac: Hmac<Sha256>,
+}
+
+impl std::fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedSecret").finish_non_exhaustive()
+    }
+}
+
+impl SharedSecret {
+    /// Create a new shared secret. Any bytes will do, but longer and more
+    /// random is better: 32 random bytes, hex-encoded, is plenty.
+    ///
+    /// ## Panics
+    ///
+    /// If `secret` is empty.
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        let secret = secret.as_ref();
+        assert!(!secret.is_empty(), "shared secret must not be empty");
+        Self {
+            mac: Hmac::new_from_slice(secret).expect("HMAC takes keys of any length"),
+        }
+    }
+
+    /// The signature of `signed`.
+    fn sign(&self, signed: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.mac
+            .clone()
+            .chain_update(signed)
+            .finalize()
+            .into_bytes()
+            .into()
+    }
+
+    /// Check the signature of `payload`, whose tag byte follows an
+    /// envelope of `envelope_len` bytes, and return the payload after the
+    /// envelope, without the signature.
+    fn verify<'a>(&self, payload: &'a [u8], envelope_len: usize) -> Result<&'a [u8], Rejection> {
+        if !is_signed(&payload[envelope_len..]) {
+            return Err(Rejection::Unsigned);
+        }
+        let (signed, signature) = payload
+            .split_at_checked(payload.len().saturating_sub(SIGNATURE_LEN))
+            .filter(|(signed, _)| signed.len() > envelope_len)
+            .ok_or(Rejection::BadSignature)?;
+        self.mac
+            .clone()
+            .chain_update(signed)
+            .verify_slice(signature)
+            .map_err(|_| Rejection::BadSignature)?;
+        Ok(&signed[envelope_len..])
+    }
+}
+
+/// Why a source rejected a frame. See [`SharedSecret`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum Rejection {
+    /// The frame wasn't signed, but the source has a secret.
+    #[error("frame is not signed")]
+    Unsigned,
+    /// The frame's signature doesn't match the source's secret.
+    #[error("frame signature does not match")]
+    BadSignature,
+    /// The frame is of a newer version than the source reads.
+    #[error("frame is wire version {0}, but only versions up to {WIRE_VERSION} are supported")]
+    UnsupportedVersion(u8),
+}
+
+impl Rejection {
+    /// The rejection, if `error` is one.
+    pub(crate) fn of(error: &io::Error) -> Option<Self> {
+        error.get_ref()?.downcast_ref().copied()
+    }
+
+    /// The reason, as a metric label.
+    pub(crate) const fn as_str(self) -> &'static str {
+        match self {
+            Self::Unsigned => "unsigned",
+            Self::BadSignature => "bad_signature",
+            Self::UnsupportedVersion(_) => "version",
+        }
+    }
+}
+
+/// How a shipped observation is serialized. See [`TcpSink::with_encoding`].
+///
+/// [`TcpSink::with_encoding`]: crate::TcpSink::with_encoding
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum WireEncoding {
+    /// JSON. Readable with `tcpdump -A`, and self-describing, so the
+    /// receiver can be older or newer than the sender. Every field name is
+    /// spelled out in every observation, for every CPU.
+    #[default]
+    Json,
+    /// [postcard], a compact binary format. Field names aren't sent at all,
+    /// and numbers are varints, so an observation is a fraction of the size
+    /// of its JSON. The price is that sender and receiver must agree on
+    /// exactly the same fields, in the same order. Needs the `postcard`
+    /// feature.
+    ///
+    /// [postcard]: https://postcard.jamesmunns.com/
+    #[cfg(feature = "postcard")]
+    Postcard,
+}
+
+impl WireEncoding {
+    /// The encoding's tag, in the low nibble of the tag byte.
+    const fn tag(self) -> u8 {
+        match self {
+            Self::Json => 0,
+            #[cfg(feature = "postcard")]
+            Self::Postcard => 1,
+        }
+    }
+
+    /// The encoding's name, as a metric label.
+    pub(crate) const fn as_str(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            #[cfg(feature = "postcard")]
+            Self::Postcard => "postcard",
+        }
+    }
+}
+
+/// How a shipped observation is compressed, after it is encoded. See
+/// [`TcpSink::with_compression`].
+///
+/// Compression trades CPU time on both ends for bytes on the network. One
+/// observation is small, and compressors need repetition to work with, so
+/// the savings on JSON, which repeats every field name per CPU, are much
+/// bigger than on postcard, which doesn't. Compare the
+/// `my_cute_app.remote_bytes_encoded` and `my_cute_app.remote_bytes_sent`
+/// counters to see what each combination buys.
+///
+/// [`TcpSink::with_compression`]: crate::TcpSink::with_compression
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum WireCompression {
+    /// No compression.
+    #[default]
+    None,
+    /// [Zstandard], at its default level. Compresses well, and fast enough
+    /// for anything sysmon sends. Needs the `zstd` feature.
+    ///
+    /// [Zstandard]: https://facebook.github.io/zstd/
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// [LZ4]. Compresses less than zstd, but is several times faster. Needs
+    /// the `lz4` feature.
+    ///
+    /// [LZ4]: https://lz4.org/
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl WireCompression {
+    /// The compression's tag, in the high nibble of the tag byte.
+    const fn tag(self) -> u8 {
+        match self {
+            Self::None => 0,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => 1 << 4,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => 2 << 4,
+        }
+    }
+
+    /// The compression's name, as a metric label.
+    pub(crate) const fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => "lz4",
+        }
+    }
+}
+
+/// How a sink encodes and compresses its frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WireFormat {
+    pub(crate) version: u8,
+    pub(crate) encoding: WireEncoding,
+    pub(crate) compression: WireCompression,
+}
+
+impl Default for WireFormat {
+    fn default() -> Self {
+        Self {
+            version: WIRE_VERSION,
+            encoding: WireEncoding::default(),
+            compression: WireCompression::default(),
+        }
+    }
+}
+
+/// Split the envelope off `payload`: its version, and the length of the
+/// envelope, which is 0 for version 0.
+fn split_envelope(payload: &[u8]) -> io::Result<(u8, usize)> {
+    match payload {
+        [m0, m1, version, ..] if [*m0, *m1] == MAGIC => match *version {
+            0 => Err(invalid_data("version 0 frames have no envelope")),
+            version if version > WIRE_VERSION => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                Rejection::UnsupportedVersion(version),
+            )),
+            version => Ok((version, ENVELOPE_LEN)),
+        },
+        _ => Ok((0, 0)),
+    }
+}
+
+/// The name of this host, to tag shipped observations with. `unknown` if the
+/// OS won't say.
+///
+/// Without the `sysinfo` feature, the name is read from `HOSTNAME`, which
+/// most shells and container runtimes set, then from the kernel on Linux.
+pub(crate) fn local_host() -> Arc<str> {
+    #[cfg(feature = "sysinfo")]
+    let name = sysinfo::System::host_name();
+    #[cfg(not(feature = "sysinfo"))]
+    let name = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty());
+    name.unwrap_or_else(|| "unknown".to_owned()).into()
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// A single observation, on the wire.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Frame {
+    /// The name of the host the observation was taken on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host: Option<Arc<str>>,
+    /// The W3C `traceparent` of the observation's span, so that the
+    /// receiver's span continues the sender's trace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    traceparent: Option<String>,
+    /// The sender's sequence number of the observation. It counts every
+    /// observation the sender was given, whether it could send it or not.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    /// When the observation was taken, in milliseconds since the Unix
+    /// epoch, by the sender's clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    taken_at_ms: Option<u64>,
+    /// The observed CPU stats.
+    cpus: Arc<[CpuStats]>,
+}
+
+impl Frame {
+    /// The frame for `obs`, the sender's `seq`th, with its span's trace
+    /// context. Observations that aren't tagged with a host already are
+    /// tagged with `host`.
+    pub(crate) fn new(obs: &Observation, host: &Arc<str>, seq: u64) -> Self {
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&obs.span().context(), &mut carrier);
+        // The observation's `Instant` means nothing on another host. The
+        // wall clock does, at least to the same host, later.
+        let taken_at = SystemTime::now() - obs.taken_at().elapsed();
+        Self {
+            host: Some(obs.host().unwrap_or(host).clone()),
+            traceparent: carrier.remove(TRACEPARENT),
+            seq: Some(seq),
+            taken_at_ms: taken_at
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|since| since.as_millis() as u64),
+            cpus: obs.cpus().clone(),
+        }
+    }
+
+    /// The name of the host the observation was taken on, if the sender said.
+    pub(crate) const fn host(&self) -> Option<&Arc<str>> {
+        self.host.as_ref()
+    }
+
+    /// Append the tagged payload to `buf`, in `format`, signed with
+    /// `secret`, if any. Returns the length of the encoded frame, before
+    /// compression.
+    fn encode_payload(
+        &self,
+        buf: &mut Vec<u8>,
+        format: WireFormat,
+        secret: Option<&SharedSecret>,
+    ) -> io::Result<usize> {
+        let start = buf.len();
+        if format.version > 0 {
+            buf.extend_from_slice(&MAGIC);
+            buf.push(format.version);
+        }
+        let signed = if secret.is_some() { SIGNED } else { 0 };
+        buf.push(format.encoding.tag() | format.compression.tag() | signed);
+        let encoded = self.encode_body(buf, format)?;
+        if let Some(secret) = secret {
+            let signature = secret.sign(&buf[start..]);
+            buf.extend_from_slice(&signature);
+        }
+        Ok(encoded)
+    }
+
+    /// Append the encoded, and compressed, frame to `buf`. Returns the length
+    /// of the encoded frame, before compression.
+    fn encode_body(&self, buf: &mut Vec<u8>, format: WireFormat) -> io::Result<usize> {
+        match format.compression {
+            WireCompression::None => {
+                let start = buf.len();
+                self.serialize(buf, format)?;
+                Ok(buf.len() - start)
+            }
+            #[cfg(feature = "zstd")]
+            WireCompression::Zstd => {
+                let mut encoded = Vec::new();
+                self.serialize(&mut encoded, format)?;
+                zstd::stream::copy_encode(&encoded[..], &mut *buf, 0)?;
+                Ok(encoded.len())
+            }
+            #[cfg(feature = "lz4")]
+            WireCompression::Lz4 => {
+                let mut encoded = Vec::new();
+                self.serialize(&mut encoded, format)?;
+                buf.extend_from_slice(&lz4_flex::compress_prepend_size(&encoded));
+                Ok(encoded.len())
+            }
+        }
+    }
+
+    /// Append the frame to `buf`, in `format`'s encoding, and version.
+    fn serialize(&self, buf: &mut Vec<u8>, format: WireFormat) -> io::Result<()> {
+        match format.encoding {
+            WireEncoding::Json => serde_json::to_writer(&mut *buf, self)?,
+            #[cfg(feature = "postcard")]
+            WireEncoding::Postcard => {
+                let encoded = std::mem::take(buf);
+                *buf = if format.version >= 2 {
+                    postcard::to_extend(&compact::FrameV2::from(self), encoded)
+                } else {
+                    postcard::to_extend(&compact::Frame::from(self), encoded)
+                }
+                .map_err(io::Error::other)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode the frame into `buf`, replacing its contents: a 4 byte
+    /// big-endian length, followed by the tagged payload, signed with
+    /// `secret`, if any. Returns the length of the encoded frame, before
+    /// compression.
+    pub(crate) fn encode(
+        &self,
+        buf: &mut Vec<u8>,
+        format: WireFormat,
+        secret: Option<&SharedSecret>,
+    ) -> io::Result<usize> {
+        buf.clear();
+        buf.extend_from_slice(&[0; 4]);
+        let encoded = self.encode_payload(buf, format, secret)?;
+        let len = u32::try_from(buf.len() - 4)
+            .ok()
+            .filter(|len| *len as usize <= MAX_FRAME_LEN)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        Ok(encoded)
+    }
+
+    /// Encode the frame into `buf`, replacing its contents, as the tagged
+    /// payload, signed with `secret`, if any, without a length prefix. A
+    /// datagram carries its own length. Returns the length of the encoded
+    /// frame, before compression.
+    pub(crate) fn encode_datagram(
+        &self,
+        buf: &mut Vec<u8>,
+        format: WireFormat,
+        secret: Option<&SharedSecret>,
+    ) -> io::Result<usize> {
+        buf.clear();
+        self.encode_payload(buf, format, secret)
+    }
+
+    /// Decode a tagged payload, without the length prefix. With a `secret`,
+    /// the payload must be signed with it, or the error is a
+    /// [`Rejection`].
+    ///
+    /// The CPU stats are checked, too: a frame without CPUs, or with a
+    /// usage that isn't a percentage, is invalid data. The stats processors
+    /// keep running sums, and a single infinite or NaN usage would poison
+    /// them for good.
+    pub(crate) fn decode(payload: &[u8], secret: Option<&SharedSecret>) -> io::Result<Self> {
+        let frame = Self::decode_unchecked(payload, secret)?;
+        if frame.cpus.is_empty() {
+            return Err(invalid_data("frame has no CPUs"));
+        }
+        if let Some(cpu) = frame
+            .cpus
+            .iter()
+            .find(|cpu| !(0.0..=100.0).contains(&cpu.usage))
+        {
+            return Err(invalid_data(format!(
+                "{} usage {} is not a percentage",
+                cpu.name, cpu.usage
+            )));
+        }
+        Ok(frame)
+    }
+
+    /// Decode a tagged payload, as [`Frame::decode`] does, without checking
+    /// the CPU stats.
+    fn decode_unchecked(payload: &[u8], secret: Option<&SharedSecret>) -> io::Result<Self> {
+        // Only postcard's layout depends on the version.
+        #[cfg_attr(not(feature = "postcard"), expect(unused_variables))]
+        let (version, envelope_len) = split_envelope(payload)?;
+        let payload = match secret {
+            Some(secret) => secret
+                .verify(payload, envelope_len)
+                .map_err(|rejection| io::Error::new(io::ErrorKind::PermissionDenied, rejection))?,
+            // Without a secret, there's nothing to check the signature
+            // against. Drop it, and carry on.
+            None if is_signed(&payload[envelope_len..]) => payload
+                .len()
+                .checked_sub(SIGNATURE_LEN)
+                .filter(|len| *len > envelope_len)
+                .map(|len| &payload[envelope_len..len])
+                .ok_or_else(|| invalid_data("signed frame too short"))?,
+            None => &payload[envelope_len..],
+        };
+        let Some((&tag, rest)) = payload.split_first() else {
+            return Err(invalid_data("empty frame"));
+        };
+        if tag == UNTAGGED_JSON {
+            return Ok(serde_json::from_slice(payload)?);
+        }
+        let tag = tag & !SIGNED;
+
+        #[cfg(any(feature = "zstd", feature = "lz4"))]
+        let decompressed;
+        let encoded = match tag & 0xf0 {
+            0 => rest,
+            #[cfg(feature = "zstd")]
+            compression if compression == WireCompression::Zstd.tag() => {
+                use std::io::Read;
+                let mut out = Vec::new();
+                zstd::stream::Decoder::new(rest)?
+                    .take(MAX_FRAME_LEN as u64 + 1)
+                    .read_to_end(&mut out)?;
+                decompressed = out;
+                &decompressed[..]
+            }
+            #[cfg(feature = "lz4")]
+            compression if compression == WireCompression::Lz4.tag() => {
+                // Check the size before decompressing. It's the first thing
+                // the decompressor allocates.
+                let size = rest
+                    .first_chunk::<4>()
+                    .map(|size| u32::from_le_bytes(*size) as usize);
+                if size.is_none_or(|size| size > MAX_FRAME_LEN) {
+                    return Err(invalid_data("LZ4 frame too large"));
+                }
+                decompressed = lz4_flex::decompress_size_prepended(rest)
+                    .map_err(|error| invalid_data(error.to_string()))?;
+                &decompressed[..]
+            }
+            _ => {
+                return Err(invalid_data(format!(
+                    "unknown compression in tag {tag:#04x}, is a feature missing?"
+                )));
+            }
+        };
+        if encoded.len() > MAX_FRAME_LEN {
+            return Err(invalid_data("decompressed frame too large"));
+        }
+
+        match tag & 0x0f {
+            0 => Ok(serde_json::from_slice(encoded)?),
+            #[cfg(feature = "postcard")]
+            1 if version >= 2 => postcard::from_bytes::<compact::FrameV2>(encoded)
+                .map(Self::from)
+                .map_err(|error| invalid_data(error.to_string())),
+            #[cfg(feature = "postcard")]
+            1 => postcard::from_bytes::<compact::Frame>(encoded)
+                .map(Self::from)
+                .map_err(|error| invalid_data(error.to_string())),
+            _ => Err(invalid_data(format!(
+                "unknown encoding in tag {tag:#04x}, is a feature missing?"
+            ))),
+        }
+    }
+
+    /// Read a length-prefixed frame from `reader`, reusing `buf`, and
+    /// decode it as [`Frame::decode`] does. `None` on a clean end of stream,
+    /// between frames.
+    pub(crate) async fn read(
+        reader: &mut (impl AsyncRead + Unpin),
+        buf: &mut Vec<u8>,
+        secret: Option<&SharedSecret>,
+    ) -> io::Result<Option<Self>> {
+        let len = match reader.read_u32().await {
+            Ok(len) => len as usize,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        if len > MAX_FRAME_LEN {
+            return Err(invalid_data(format!(
+                "frame of {len} bytes exceeds the {MAX_FRAME_LEN} byte limit"
+            )));
+        }
+        buf.resize(len, 0);
+        reader.read_exact(buf).await?;
+        Self::decode(buf, secret).map(Some)
+    }
+
+    /// Turn the frame back into an [`Observation`] in `span`, continuing the
+    /// sender's trace, if it had one.
+    pub(crate) fn into_observation(self, span: tracing::Span) -> Observation {
+        if let Some(traceparent) = self.traceparent {
+            let carrier = HashMap::from([(TRACEPARENT.to_owned(), traceparent)]);
+            let cx = TraceContextPropagator::new().extract(&carrier);
+            // This fails if the OTEL layer isn't installed, in which case
+            // there is no trace to continue.
+            let _ = span.set_parent(cx);
+        }
+        let obs = Observation::new(self.cpus, span);
+        match self.seq.zip(self.taken_at_ms) {
+            Some((seq, taken_at_ms)) => {
+                obs.with_sequence(seq, UNIX_EPOCH + Duration::from_millis(taken_at_ms))
+            }
+            None => obs,
+        }
+    }
+}
+
+/// The frame, as postcard sees it.
+///
+/// postcard doesn't write field names, so it can't skip a field: the
+/// receiver wouldn't know which one is missing. The `skip_serializing_if`
+/// attributes that keep the JSON short would corrupt it. These mirror types
+/// have none.
+#[cfg(feature = "postcard")]
+mod compact {
+    use crate::CpuTimes;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Arc;
+
+    /// The frame of versions 0 and 1.
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Frame {
+        host: Option<Arc<str>>,
+        traceparent: Option<String>,
+        cpus: Vec<CpuStats>,
+    }
+
+    /// The frame of version 2. postcard writes a nested struct as its
+    /// fields, in order, so this is a version 1 frame, followed by the new
+    /// fields.
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct FrameV2 {
+        frame: Frame,
+        seq: Option<u64>,
+        taken_at_ms: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct CpuStats {
+        name: Arc<str>,
+        usage: f32,
+        frequency: u64,
+        times: Option<CpuTimes>,
+        package: Option<u32>,
+    }
+
+    impl From<&super::Frame> for FrameV2 {
+        fn from(frame: &super::Frame) -> Self {
+            Self {
+                frame: frame.into(),
+                seq: frame.seq,
+                taken_at_ms: frame.taken_at_ms,
+            }
+        }
+    }
+
+    impl From<FrameV2> for super::Frame {
+        fn from(frame: FrameV2) -> Self {
+            Self {
+                seq: frame.seq,
+                taken_at_ms: frame.taken_at_ms,
+                ..frame.frame.into()
+            }
+        }
+    }
+
+    impl From<&super::Frame> for Frame {
+        fn from(frame: &super::Frame) -> Self {
+            Self {
+                host: frame.host.clone(),
+                traceparent: frame.traceparent.clone(),
+                cpus: frame
+                    .cpus
+                    .iter()
+                    .map(|cpu| CpuStats {
+                        name: cpu.name.clone(),
+                        usage: cpu.usage,
+                        frequency: cpu.frequency,
+                        times: cpu.times,
+                        package: cpu.package,
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    impl From<Frame> for super::Frame {
+        fn from(frame: Frame) -> Self {
+            Self {
+                host: frame.host,
+                traceparent: frame.traceparent,
+                seq: None,
+                taken_at_ms: None,
+                cpus: frame
+                    .cpus
+                    .into_iter()
+                    .map(|cpu| crate::CpuStats {
+                        name: cpu.name,
+                        usage: cpu.usage,
+                        frequency: cpu.frequency,
+                        times: cpu.times,
+                        package: cpu.package,
+                    })
+                    .collect(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CpuTimes;
+
+    fn frame() -> Frame {
+        let cpus: Arc<[CpuStats]> = (0..4)
+            .map(|i| CpuStats {
+                name: format!("cpu{i}").into(),
+                usage: 12.5 * i as f32,
+                frequency: 2_000 + i,
+                times: (i % 2 == 0).then_some(CpuTimes {
+                    user: 0.5,
+                    ..Default::default()
+                }),
+                package: None,
+            })
+            .collect();
+        Frame {
+            host: Some("box-1".into()),
+            traceparent: None,
+            seq: Some(7),
+            taken_at_ms: Some(1_700_000_000_000),
+            cpus,
+        }
+    }
+
+    fn formats() -> Vec<WireFormat> {
+        let encodings = [
+            WireEncoding::Json,
+            #[cfg(feature = "postcard")]
+            WireEncoding::Postcard,
+        ];
+        let compressions = [
+            WireCompression::None,
+            #[cfg(feature = "zstd")]
+            WireCompression::Zstd,
+            #[cfg(feature = "lz4")]
+            WireCompression::Lz4,
+        ];
+        encodings
+            .into_iter()
+            .flat_map(|encoding| {
+                compressions.map(|compression| WireFormat {
+                    encoding,
+                    compression,
+                    ..WireFormat::default()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frames_round_trip_in_every_format() {
+        let frame = frame();
+        let mut buf = Vec::new();
+        for format in formats() {
+            frame.encode_datagram(&mut buf, format, None).unwrap();
+            let decoded = Frame::decode(&buf, None).unwrap();
+            assert_eq!(decoded.host, frame.host, "{format:?}");
+            assert_eq!(decoded.seq, frame.seq, "{format:?}");
+            assert_eq!(decoded.taken_at_ms, frame.taken_at_ms, "{format:?}");
+            assert_eq!(decoded.cpus, frame.cpus, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn untagged_json_is_still_accepted() {
+        let frame = frame();
+        let json = serde_json::to_vec(&frame).unwrap();
+        assert_eq!(Frame::decode(&json, None).unwrap().cpus, frame.cpus);
+    }
+
+    #[test]
+    fn older_versions_are_still_read() {
+        let frame = frame();
+        let secret = SharedSecret::new("hunter2");
+        let mut buf = Vec::new();
+        for version in 0..WIRE_VERSION {
+            for format in formats() {
+                let format = WireFormat { version, ..format };
+                frame.encode_datagram(&mut buf, format, None).unwrap();
+                assert_eq!(buf[..2] == MAGIC, version > 0, "{format:?}");
+                let decoded = Frame::decode(&buf, None).unwrap();
+                assert_eq!(decoded.cpus, frame.cpus, "{format:?}");
+
+                frame
+                    .encode_datagram(&mut buf, format, Some(&secret))
+                    .unwrap();
+                let decoded = Frame::decode(&buf, Some(&secret)).unwrap();
+                assert_eq!(decoded.cpus, frame.cpus, "{format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let frame = frame();
+        let mut buf = Vec::new();
+        frame
+            .encode_datagram(&mut buf, WireFormat::default(), None)
+            .unwrap();
+        assert_eq!(buf[..ENVELOPE_LEN], [b'S', b'M', WIRE_VERSION]);
+
+        buf[2] = WIRE_VERSION + 1;
+        let err = Frame::decode(&buf, None).unwrap_err();
+        assert_eq!(
+            Rejection::of(&err),
+            Some(Rejection::UnsupportedVersion(WIRE_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn frames_without_cpus_are_rejected() {
+        let frame = Frame {
+            cpus: Arc::from([]),
+            ..frame()
+        };
+        let mut buf = Vec::new();
+        for format in formats() {
+            frame.encode_datagram(&mut buf, format, None).unwrap();
+            let err = Frame::decode(&buf, None).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn usages_that_are_not_percentages_are_rejected() {
+        let mut buf = Vec::new();
+        for usage in [f32::NAN, f32::INFINITY, -1.0, 100.5] {
+            let mut frame = frame();
+            Arc::get_mut(&mut frame.cpus).unwrap()[1].usage = usage;
+            for format in formats() {
+                frame.encode_datagram(&mut buf, format, None).unwrap();
+                let err = Frame::decode(&buf, None).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{usage} {format:?}");
+            }
+        }
+
+        // JSON has no infinity, but a big enough number overflows into one.
+        let json = serde_json::to_string(&frame()).unwrap().replacen(
+            "\"usage\":0.0",
+            "\"usage\":1e300",
+            1,
+        );
+        assert!(json.contains("1e300"));
+        let err = Frame::decode(json.as_bytes(), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_tags_are_rejected() {
+        let err = Frame::decode(&[0xf0, b'{', b'}'], None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn rejection(payload: &[u8], secret: &SharedSecret) -> Option<Rejection> {
+        Rejection::of(&Frame::decode(payload, Some(secret)).unwrap_err())
+    }
+
+    #[test]
+    fn signed_frames_are_verified() {
+        let frame = frame();
+        let secret = SharedSecret::new("hunter2");
+        let mut buf = Vec::new();
+        for format in formats() {
+            frame
+                .encode_datagram(&mut buf, format, Some(&secret))
+                .unwrap();
+            let decoded = Frame::decode(&buf, Some(&secret)).unwrap();
+            assert_eq!(decoded.cpus, frame.cpus, "{format:?}");
+            // Sources without a secret take them as they are.
+            let decoded = Frame::decode(&buf, None).unwrap();
+            assert_eq!(decoded.cpus, frame.cpus, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn unsigned_frames_are_rejected() {
+        let frame = frame();
+        let secret = SharedSecret::new("hunter2");
+        let mut buf = Vec::new();
+        frame
+            .encode_datagram(&mut buf, WireFormat::default(), None)
+            .unwrap();
+        assert_eq!(rejection(&buf, &secret), Some(Rejection::Unsigned));
+        let json = serde_json::to_vec(&frame).unwrap();
+        assert_eq!(rejection(&json, &secret), Some(Rejection::Unsigned));
+    }
+
+    #[test]
+    fn bad_signatures_are_rejected() {
+        let frame = frame();
+        let secret = SharedSecret::new("hunter2");
+        let mut buf = Vec::new();
+        frame
+            .encode_datagram(
+                &mut buf,
+                WireFormat::default(),
+                Some(&SharedSecret::new("hunter3")),
+            )
+            .unwrap();
+        assert_eq!(rejection(&buf, &secret), Some(Rejection::BadSignature));
+
+        frame
+            .encode_datagram(&mut buf, WireFormat::default(), Some(&secret))
+            .unwrap();
+        let last = buf.len() - SIGNATURE_LEN - 2;
+        buf[last] ^= 1;
+        assert_eq!(rejection(&buf, &secret), Some(Rejection::BadSignature));
+        assert_eq!(rejection(&[SIGNED], &secret), Some(Rejection::BadSignature));
+    }
+}